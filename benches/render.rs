@@ -0,0 +1,158 @@
+//! Benchmarks of parsing and rendering representative documents, run by `cargo bench`.
+//!
+//! Parsing and rendering are timed apart: the render benchmarks parse the documents with
+//! `Renderer::parse` before the timing.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use mini_poml_rs::MarkdownPomlRenderer;
+use mini_poml_rs::parser::PomlParser;
+use mini_poml_rs::render::file_loader::InMemoryLoader;
+use serde_json::{Map, Value, json};
+use std::hint::black_box;
+
+/// A benchmarked document with its context.
+struct Document {
+  name: &'static str,
+  doc: String,
+  variables: Map<String, Value>,
+  /// Files of the `<include>` tags by path
+  files: Vec<(String, String)>,
+}
+
+/// Sections nested in sections, each with a heading and a paragraph of inline tags.
+fn deep_nesting() -> Document {
+  let depth = 30;
+  let mut doc = String::from("<poml>");
+  for i in 0..depth {
+    doc += &format!("<section><h>Level {i}</h><p><b>Bold</b> and <i>italic</i> {i}</p>");
+  }
+  doc += &"</section>".repeat(depth);
+  doc += "</poml>";
+  Document {
+    name: "deep_nesting",
+    doc,
+    variables: Map::new(),
+    files: Vec::new(),
+  }
+}
+
+/// A list of a loop over many items, with conditions in the loop.
+fn long_loop() -> Document {
+  let items: Vec<Value> = (0..1000)
+    .map(|i| json!({"name": format!("item {i}"), "score": i}))
+    .collect();
+  let doc = "<poml><list listStyle=\"decimal\">\
+    <item for=\"item in items\">{{ item.name }}: {{ item.score * 2 }}\
+    <b if=\"item.score >= 990\"> (last ten)</b></item></list></poml>";
+  Document {
+    name: "long_loop",
+    doc: doc.to_string(),
+    variables: Map::from_iter([("items".to_string(), Value::Array(items))]),
+    files: Vec::new(),
+  }
+}
+
+/// Paragraphs of many distinct expressions.
+fn many_expressions() -> Document {
+  let mut doc = String::from("<poml>");
+  for i in 0..500 {
+    doc += &format!(
+      "<p>{{{{ user.name }}}} has {{{{ user.points + {i} }}}} points, \
+       {{{{ user.points > {i} ? \"above\" : \"below\" }}}} {i}</p>"
+    );
+  }
+  doc += "</poml>";
+  Document {
+    name: "many_expressions",
+    doc,
+    variables: Map::from_iter([("user".to_string(), json!({"name": "Ada", "points": 250}))]),
+    files: Vec::new(),
+  }
+}
+
+/// Big documents included many times.
+fn big_includes() -> Document {
+  let mut doc = String::from("<poml>");
+  let mut files = Vec::new();
+  for i in 0..10 {
+    let mut included = String::from("<poml>");
+    for j in 0..100 {
+      included += &format!("<cp caption=\"Part {j}\"><p>Paragraph {j} of {{{{ topic }}}}</p></cp>");
+    }
+    included += "</poml>";
+    files.push((format!("part{i}.poml"), included));
+    doc += &format!("<include src=\"part{i}.poml\" />");
+  }
+  doc += "</poml>";
+  Document {
+    name: "big_includes",
+    doc,
+    variables: Map::from_iter([("topic".to_string(), json!("benchmarks"))]),
+    files,
+  }
+}
+
+fn documents() -> Vec<Document> {
+  vec![
+    deep_nesting(),
+    long_loop(),
+    many_expressions(),
+    big_includes(),
+  ]
+}
+
+fn renderer(document: &Document) -> MarkdownPomlRenderer<'_> {
+  let mut renderer =
+    MarkdownPomlRenderer::create_from_doc_and_variables(&document.doc, document.variables.clone());
+  renderer
+    .context
+    .set_file_loader(InMemoryLoader::from_iter(document.files.clone()));
+  renderer
+}
+
+fn bench_parse(c: &mut Criterion) {
+  let mut group = c.benchmark_group("parse");
+  for document in documents().iter() {
+    group.bench_function(document.name, |b| {
+      b.iter(|| {
+        PomlParser::from_poml_str(black_box(&document.doc))
+          .parse_as_node()
+          .unwrap()
+      })
+    });
+  }
+  group.finish();
+}
+
+#[cfg(feature = "arena")]
+fn bench_parse_in_arena(c: &mut Criterion) {
+  let mut group = c.benchmark_group("parse_in_arena");
+  for document in documents().iter() {
+    group.bench_function(document.name, |b| {
+      b.iter(|| {
+        let arena = bumpalo::Bump::new();
+        PomlParser::from_poml_str(black_box(&document.doc))
+          .parse_in_arena(&arena)
+          .map(|root| root.children.len())
+          .unwrap()
+      })
+    });
+  }
+  group.finish();
+}
+
+fn bench_render(c: &mut Criterion) {
+  let mut group = c.benchmark_group("render");
+  for document in documents().iter() {
+    let mut renderer = renderer(document);
+    renderer.parse().unwrap();
+    group.bench_function(document.name, |b| b.iter(|| renderer.render().unwrap()));
+  }
+  group.finish();
+}
+
+#[cfg(feature = "arena")]
+criterion_group!(benches, bench_parse, bench_parse_in_arena, bench_render);
+#[cfg(not(feature = "arena"))]
+criterion_group!(benches, bench_parse, bench_render);
+criterion_main!(benches);
@@ -0,0 +1,28 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mini_poml_rs::render::expression::evaluate_expression;
+use mini_poml_rs::render::render_context::RenderContext;
+use serde_json::json;
+
+fuzz_target!(|data: &[u8]| {
+  let Ok(expression) = str::from_utf8(data) else {
+    return;
+  };
+  let serde_json::Value::Object(variables) = json!({
+    "a": 1,
+    "s": "text",
+    "arr": [1, 2, 3],
+    "obj": {"x": {"y": true}}
+  }) else {
+    unreachable!();
+  };
+  let context = RenderContext::from(variables);
+  let _ = evaluate_expression(expression, &context);
+});
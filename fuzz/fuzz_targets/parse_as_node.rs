@@ -0,0 +1,17 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mini_poml_rs::parser::PomlParser;
+
+fuzz_target!(|data: &[u8]| {
+  let Ok(doc) = str::from_utf8(data) else {
+    return;
+  };
+  let _ = PomlParser::from_poml_str(doc).parse_as_node();
+});
@@ -0,0 +1,66 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use mini_poml_rs::MarkdownPomlRenderer;
+use mini_poml_rs::render::file_loader::InMemoryLoader;
+use mini_poml_rs::render::render_context::RenderContext;
+use serde_json::{Map, Value};
+
+/// A JSON value of the context, generated from the fuzzer input.
+#[derive(Arbitrary, Debug)]
+enum ContextValue {
+  Null,
+  Bool(bool),
+  Integer(i64),
+  Float(f64),
+  String(String),
+  Array(Vec<ContextValue>),
+  Object(Vec<(String, ContextValue)>),
+}
+
+impl From<ContextValue> for Value {
+  fn from(value: ContextValue) -> Value {
+    match value {
+      ContextValue::Null => Value::Null,
+      ContextValue::Bool(b) => Value::Bool(b),
+      ContextValue::Integer(i) => Value::from(i),
+      ContextValue::Float(f) => Value::from(f),
+      ContextValue::String(s) => Value::String(s),
+      ContextValue::Array(values) => Value::Array(values.into_iter().map(Value::from).collect()),
+      ContextValue::Object(fields) => Value::Object(
+        fields
+          .into_iter()
+          .map(|(k, v)| (k, Value::from(v)))
+          .collect(),
+      ),
+    }
+  }
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+  doc: String,
+  variables: Vec<(String, ContextValue)>,
+}
+
+fuzz_target!(|input: Input| {
+  let variables: Map<String, Value> = input
+    .variables
+    .into_iter()
+    .map(|(name, value)| (name, Value::from(value)))
+    .collect();
+  let mut context = RenderContext::from_iter(variables);
+  // Includes and `src` attributes load in-memory files, never the files of the machine
+  context.set_file_loader(InMemoryLoader::from_iter([
+    ("part.poml", "<poml><p>{{ 1 + 1 }}</p></poml>"),
+    ("data.csv", "name,age\nAda,36"),
+  ]));
+  let _ = MarkdownPomlRenderer::create_from_doc_and_context(&input.doc, context).render();
+});
@@ -0,0 +1,29 @@
+#ifndef MINI_POML_H
+#define MINI_POML_H
+
+/* Generated by cbindgen from src/capi.rs, which documents the functions. Do not edit. */
+
+typedef enum PomlStatus {
+  POML_OK = 0,
+  POML_ERROR = 1,
+  POML_INVALID_ARGUMENT = 2,
+  POML_PANIC = 3,
+} PomlStatus;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+enum PomlStatus poml_render(const char *doc, const char *context_json, char **out);
+
+const char *poml_last_error(void);
+
+const char *poml_last_error_code(void);
+
+void poml_string_free(char *s);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MINI_POML_H */
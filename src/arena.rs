@@ -0,0 +1,162 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Trees of documents allocated in a `bumpalo` arena, behind the `arena` feature.
+ *
+ * The nodes and their children lists are bump allocated and freed at once with the arena,
+ * instead of one heap allocation each. Like [`PomlNode`], the texts and attributes borrow
+ * the document without copying. The renderer takes [`PomlNode`] trees, so rendering copies
+ * the tree out of the arena with `to_node`, which doesn't save memory over `parse_as_node`.
+ */
+
+use crate::error::Result;
+use crate::parser::{PomlParser, TreeBuilder};
+use crate::{AttributeValue, PomlNode, PomlNodePosition, PomlTagNode};
+use bumpalo::Bump;
+use bumpalo::collections::Vec as BumpVec;
+
+/**
+ * A node of POML document in an arena, with the same variants as [`PomlNode`].
+ */
+#[derive(Debug, PartialEq)]
+pub enum ArenaNode<'b, 'a> {
+  Tag(ArenaTagNode<'b, 'a>),
+  Text(&'a str, PomlNodePosition),
+  Whitespace(PomlNodePosition),
+  CData(&'a str, PomlNodePosition),
+}
+
+/**
+ * A tag node in an arena, with the same fields as [`PomlTagNode`].
+ */
+#[derive(Debug, PartialEq)]
+pub struct ArenaTagNode<'b, 'a> {
+  pub name: &'a str,
+  pub attributes: BumpVec<'b, (&'a str, AttributeValue<'a>)>,
+  pub children: BumpVec<'b, ArenaNode<'b, 'a>>,
+  pub original_pos: PomlNodePosition,
+}
+
+impl<'a> ArenaNode<'_, 'a> {
+  pub fn is_tag(&self) -> bool {
+    matches!(self, ArenaNode::Tag(_))
+  }
+
+  pub fn is_whitespace(&self) -> bool {
+    matches!(self, ArenaNode::Whitespace(_))
+  }
+
+  /**
+   * Copy the node out of the arena, e.g. to render it.
+   */
+  pub fn to_node(&self) -> PomlNode<'a> {
+    match self {
+      ArenaNode::Tag(tag) => PomlNode::Tag(tag.to_node()),
+      ArenaNode::Text(text, pos) => PomlNode::Text(text, pos.clone()),
+      ArenaNode::Whitespace(pos) => PomlNode::Whitespace(pos.clone()),
+      ArenaNode::CData(text, pos) => PomlNode::CData(text, pos.clone()),
+    }
+  }
+}
+
+impl<'a> ArenaTagNode<'_, 'a> {
+  /**
+   * Copy the tag node out of the arena, e.g. to render it.
+   */
+  pub fn to_node(&self) -> PomlTagNode<'a> {
+    PomlTagNode {
+      name: self.name,
+      attributes: self.attributes.to_vec(),
+      children: self.children.iter().map(ArenaNode::to_node).collect(),
+      original_pos: self.original_pos.clone(),
+    }
+  }
+}
+
+impl<'a> PomlParser<'a> {
+  /**
+   * Parse the document like `parse_as_node`, but allocate the tree in the arena.
+   */
+  pub fn parse_in_arena<'b>(&mut self, arena: &'b Bump) -> Result<ArenaTagNode<'b, 'a>>
+  where
+    'a: 'b,
+  {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("poml_parse_in_arena", bytes = self.buf.len()).entered();
+    self.build_tree(&ArenaTreeBuilder { arena })
+  }
+}
+
+/**
+ * Builder of the `ArenaTagNode` tree returned by `PomlParser::parse_in_arena`.
+ */
+struct ArenaTreeBuilder<'b> {
+  arena: &'b Bump,
+}
+
+impl<'b, 'a: 'b> TreeBuilder<'a> for ArenaTreeBuilder<'b> {
+  type Tag = ArenaTagNode<'b, 'a>;
+
+  fn tag(&self, tag: PomlTagNode<'a>) -> ArenaTagNode<'b, 'a> {
+    ArenaTagNode {
+      name: tag.name,
+      attributes: BumpVec::from_iter_in(tag.attributes, self.arena),
+      children: BumpVec::new_in(self.arena),
+      original_pos: tag.original_pos,
+    }
+  }
+
+  fn tag_name(tag: &ArenaTagNode<'b, 'a>) -> &'a str {
+    tag.name
+  }
+
+  fn tag_start(tag: &ArenaTagNode<'b, 'a>) -> usize {
+    tag.original_pos.start
+  }
+
+  fn set_tag_end(tag: &mut ArenaTagNode<'b, 'a>, end: usize) {
+    tag.original_pos.end = end;
+  }
+
+  fn push_tag(&self, parent: &mut ArenaTagNode<'b, 'a>, tag: ArenaTagNode<'b, 'a>) {
+    parent.children.push(ArenaNode::Tag(tag));
+  }
+
+  fn push_content(&self, parent: &mut ArenaTagNode<'b, 'a>, content: PomlNode<'a>) {
+    parent.children.push(match content {
+      PomlNode::Text(text, pos) => ArenaNode::Text(text, pos),
+      PomlNode::Whitespace(pos) => ArenaNode::Whitespace(pos),
+      PomlNode::CData(text, pos) => ArenaNode::CData(text, pos),
+      PomlNode::Tag(_) => unreachable!(),
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_in_arena_as_parse_as_node() {
+    let doc = "<poml>\n  <p speaker=\"human\">Hello <b>{{ name }}</b>!</p>\n  <br />\
+      <![CDATA[<raw>]]><!-- comment --></poml>";
+    let arena = Bump::new();
+    let root = PomlParser::from_poml_str(doc)
+      .parse_in_arena(&arena)
+      .unwrap();
+    assert_eq!(root.children.len(), 5);
+    assert!(root.children[0].is_whitespace());
+    assert!(root.children[1].is_tag());
+    let expected = PomlParser::from_poml_str(doc).parse_as_node().unwrap();
+    assert_eq!(root.to_node(), expected);
+
+    let error = PomlParser::from_poml_str("<poml><p>Hi</poml>")
+      .parse_in_arena(&arena)
+      .unwrap_err();
+    assert!(error.message.contains("the open tag is <p>"));
+  }
+}
@@ -0,0 +1,536 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use clap::{Args, Parser, Subcommand};
+use mini_poml_rs::MarkdownPomlRenderer;
+use mini_poml_rs::doc::{document_interface, variable_usage};
+use mini_poml_rs::error::{Diagnostic, ErrorPosition, Severity};
+use mini_poml_rs::format::format_document;
+use mini_poml_rs::lint::lint;
+use mini_poml_rs::migrate::migrate_document;
+use mini_poml_rs::parser::PomlParser;
+use mini_poml_rs::render::config::RenderConfig;
+use mini_poml_rs::render::data_format::DataFormat;
+use mini_poml_rs::render::render_context::RenderContext;
+use mini_poml_rs::render::tag_renderer::MarkdownTagRenderer;
+use serde_json::{Map, Value, json};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/** Global allocator counting the allocations, reported by the `bench` subcommand. */
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    unsafe { System.alloc(layout) }
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe { System.dealloc(ptr, layout) }
+  }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Render, check and format POML files.
+#[derive(Parser, Debug)]
+#[command(name = "poml")]
+struct Cli {
+  /// Option to set working directory
+  #[arg(long, global = true)]
+  work_dir: Option<String>,
+  #[command(subcommand)]
+  command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Render the POML file as Markdown
+  Render {
+    poml_filename: String,
+    #[command(flatten)]
+    context: ContextArgs,
+    /// Write the output to the file instead of the standard output
+    #[arg(long, short)]
+    output: Option<String>,
+  },
+  /// Check the POML file for errors and lint warnings, without writing the output
+  Check {
+    poml_filename: String,
+    #[command(flatten)]
+    context: ContextArgs,
+    /// Print the diagnostics as JSON lines
+    #[arg(long)]
+    json: bool,
+  },
+  /// Format the POML files, printing the formatted file if there is only one
+  Fmt {
+    #[arg(required = true)]
+    poml_filenames: Vec<String>,
+    /// Write the formatted files back instead of printing them
+    #[arg(long)]
+    write: bool,
+    /// Only check whether the files are formatted, and fail if not
+    #[arg(long)]
+    check: bool,
+  },
+  /// List the variables the POML file needs, and the ones missing from the context if given
+  Vars {
+    poml_filename: String,
+    #[command(flatten)]
+    context: ContextArgs,
+    /// Print the variables as JSON
+    #[arg(long)]
+    json: bool,
+  },
+  /// Print the documentation of the document interface, as `markdown` or `json`
+  Doc {
+    poml_filename: String,
+    #[arg(long, default_value = "markdown")]
+    format: String,
+  },
+  /// Migrate the POML file written for microsoft/poml, printing the migrated file and the
+  /// issues found. Fails if some issues can't be fixed
+  Migrate {
+    poml_filename: String,
+    /// Write the migrated file back instead of printing it
+    #[arg(long)]
+    write: bool,
+  },
+  /// Measure the mean parse and render time and allocation count of the POML file, or the
+  /// POML files of a directory, printed as JSON
+  Bench {
+    path: String,
+    /// Directory of JSON context files, where `name.json` is the context of `name.poml`
+    #[arg(long)]
+    contexts: Option<String>,
+    /// Number of iterations to run for each file
+    #[arg(long, default_value_t = 100)]
+    iterations: u32,
+  },
+}
+
+#[derive(Args, Debug)]
+struct ContextArgs {
+  /// JSON or YAML files of objects to supply the context, merged in order. `-` reads JSON from
+  /// the standard input
+  #[arg(long = "context", short = 'c', value_name = "FILE")]
+  files: Vec<String>,
+  /// Set a variable to a string, like `--set user.name=Ada`, overriding the context files
+  #[arg(long = "set", value_name = "NAME=VALUE")]
+  sets: Vec<String>,
+  /// Set a variable to a JSON value, like `--set-json 'limits={"max": 3}'`
+  #[arg(long = "set-json", value_name = "NAME=JSON")]
+  json_sets: Vec<String>,
+  /// Expose the environment variables as `env`
+  #[arg(long)]
+  env: bool,
+  /// Let `<let src="stdin">` read the standard input
+  #[arg(long)]
+  stdin: bool,
+  /// Config file of the render options. Defaults to `poml.toml` or `poml.json` in the working
+  /// directory
+  #[arg(long)]
+  config: Option<String>,
+}
+
+fn main() -> ExitCode {
+  let cli = Cli::parse();
+  match run(cli) {
+    Ok(code) => code,
+    Err(message) => {
+      eprintln!("error: {message}");
+      ExitCode::FAILURE
+    }
+  }
+}
+
+fn run(cli: Cli) -> Result<ExitCode, String> {
+  if let Some(work_dir) = &cli.work_dir {
+    std::env::set_current_dir(work_dir).map_err(|e| format!("{work_dir}: {e}"))?;
+  }
+  match cli.command {
+    Command::Render {
+      poml_filename,
+      context,
+      output,
+    } => {
+      let doc = read_file(&poml_filename)?;
+      let mut renderer = create_renderer(&doc, &poml_filename, &context)?;
+      let rendered = renderer.render().map_err(|e| e.to_string())?;
+      match output {
+        // Create the file after the render, keeping it when the render fails
+        Some(output) => fs::write(&output, rendered).map_err(|e| format!("{output}: {e}"))?,
+        None => println!("{rendered}"),
+      }
+      Ok(ExitCode::SUCCESS)
+    }
+    Command::Check {
+      poml_filename,
+      context,
+      json,
+    } => {
+      let doc = read_file(&poml_filename)?;
+      let diagnostics = lint(&doc, &MarkdownTagRenderer::default());
+      let parsed = diagnostics.iter().all(|d| d.severity != Severity::Error);
+      let lint_config = load_config(&context)?
+        .map(|config| config.lint)
+        .unwrap_or_default();
+      let mut diagnostics = lint_config.apply(diagnostics);
+      if parsed {
+        let mut renderer = create_renderer(&doc, &poml_filename, &context)?;
+        let errors = renderer.check();
+        // Drop the lint warnings the render reports as errors, like unknown tags
+        let line = |d: &Diagnostic| d.position.as_ref().map(|p| p.line);
+        diagnostics.retain(|w| {
+          !errors
+            .iter()
+            .any(|e| e.code == w.code && line(e) == line(w))
+        });
+        diagnostics.extend(errors);
+      }
+      print_diagnostics(&poml_filename, &diagnostics, json);
+      match diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        true => Ok(ExitCode::FAILURE),
+        false => Ok(ExitCode::SUCCESS),
+      }
+    }
+    Command::Fmt {
+      poml_filenames,
+      write,
+      check,
+    } => {
+      let mut unformatted = false;
+      for filename in poml_filenames.iter() {
+        let doc = read_file(filename)?;
+        let formatted = format_document(&doc).map_err(|e| format!("{filename}: {e}"))?;
+        if check {
+          if formatted != doc {
+            eprintln!("{filename} is not formatted");
+            unformatted = true;
+          }
+        } else if write || poml_filenames.len() > 1 {
+          if formatted != doc {
+            fs::write(filename, formatted).map_err(|e| format!("{filename}: {e}"))?;
+          }
+        } else {
+          print!("{formatted}");
+        }
+      }
+      match unformatted {
+        true => Ok(ExitCode::FAILURE),
+        false => Ok(ExitCode::SUCCESS),
+      }
+    }
+    Command::Vars {
+      poml_filename,
+      context,
+      json,
+    } => {
+      let doc = read_file(&poml_filename)?;
+      let variables = document_interface(&doc)
+        .map_err(|e| e.to_string())?
+        .variables;
+      let missing = match context.has_variables() {
+        false => None,
+        true => {
+          let context = RenderContext::from(load_context(&context)?);
+          Some(
+            variable_usage(&doc, &context)
+              .map_err(|e| e.to_string())?
+              .missing,
+          )
+        }
+      };
+      if json {
+        let mut output = Map::new();
+        output.insert("variables".to_string(), variables.into());
+        if let Some(missing) = &missing {
+          output.insert("missing".to_string(), missing.clone().into());
+        }
+        println!("{:#}", Value::Object(output));
+      } else {
+        for variable in variables.iter() {
+          match &missing {
+            Some(missing) if missing.contains(variable) => println!("{variable} (missing)"),
+            _ => println!("{variable}"),
+          }
+        }
+      }
+      match missing {
+        Some(missing) if !missing.is_empty() => Ok(ExitCode::FAILURE),
+        _ => Ok(ExitCode::SUCCESS),
+      }
+    }
+    Command::Doc {
+      poml_filename,
+      format,
+    } => {
+      let doc = read_file(&poml_filename)?;
+      let interface = document_interface(&doc).map_err(|e| e.to_string())?;
+      match format.as_str() {
+        "json" => println!("{:#}", interface.to_json()),
+        _ => print!("{}", interface.to_markdown(&poml_filename)),
+      }
+      Ok(ExitCode::SUCCESS)
+    }
+    Command::Migrate {
+      poml_filename,
+      write,
+    } => {
+      let doc = read_file(&poml_filename)?;
+      let report = migrate_document(&doc, &MarkdownTagRenderer::default())
+        .map_err(|e| format!("{poml_filename}: {e}"))?;
+      for issue in report.issues.iter() {
+        let position = ErrorPosition::from_span(doc.as_bytes(), issue.start, issue.end);
+        let status = if issue.fixed { "fixed" } else { "unfixed" };
+        eprintln!(
+          "{poml_filename}:{}:{}: [{status}] {}",
+          position.line, position.col, issue.message
+        );
+      }
+      if write {
+        if report.output != doc {
+          fs::write(&poml_filename, &report.output).map_err(|e| format!("{poml_filename}: {e}"))?;
+        }
+      } else {
+        print!("{}", report.output);
+      }
+      match report.issues.iter().any(|issue| !issue.fixed) {
+        true => Ok(ExitCode::FAILURE),
+        false => Ok(ExitCode::SUCCESS),
+      }
+    }
+    Command::Bench {
+      path,
+      contexts,
+      iterations,
+    } => {
+      let iterations = iterations.max(1);
+      let path = Path::new(&path);
+      let mut files = if path.is_dir() {
+        fs::read_dir(path)
+          .and_then(|entries| entries.map(|entry| entry.map(|e| e.path())).collect())
+          .map_err(|e| format!("{}: {e}", path.display()))?
+      } else {
+        vec![path.to_path_buf()]
+      };
+      files.retain(|file| file == path || file.extension().is_some_and(|ext| ext == "poml"));
+      files.sort();
+      let reports = files
+        .iter()
+        .map(|file| bench_file(file, contexts.as_deref(), iterations))
+        .collect::<Result<Vec<_>, _>>()?;
+      println!("{:#}", json!({"iterations": iterations, "files": reports}));
+      Ok(ExitCode::SUCCESS)
+    }
+  }
+}
+
+/**
+ * Measure the mean parse and render time and allocation count of the file, with the context
+ * found in the context directory. The document is parsed before the render is timed.
+ */
+fn bench_file(poml_path: &Path, contexts: Option<&str>, iterations: u32) -> Result<Value, String> {
+  let filename = poml_path.to_string_lossy();
+  let doc = read_file(&filename)?;
+  let context = match contexts.map(|contexts| {
+    let stem = poml_path.file_stem().unwrap_or_default();
+    Path::new(contexts).join(stem).with_extension("json")
+  }) {
+    Some(context_path) if context_path.is_file() => {
+      let context_filename = context_path.to_string_lossy();
+      match serde_json::from_str(&read_file(&context_filename)?) {
+        Ok(Value::Object(context)) => context,
+        _ => {
+          return Err(format!(
+            "{context_filename}: the context should be a JSON object"
+          ));
+        }
+      }
+    }
+    _ => Map::new(),
+  };
+  let parse = measure(iterations, || {
+    PomlParser::from_poml_str(&doc)
+      .parse_as_node()
+      .map(|_| ())
+      .map_err(|e| e.to_string())
+  });
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(&doc, context);
+  renderer.set_document_path(&filename);
+  let render = renderer.parse().map_err(|e| e.to_string()).and_then(|_| {
+    measure(iterations, || {
+      renderer.render().map(|_| ()).map_err(|e| e.to_string())
+    })
+  });
+  let mut report = json!({"file": filename});
+  for (name, result) in [("parse", parse), ("render", render)] {
+    report[name] = match result {
+      Ok((time, allocations)) => json!({
+        "mean_micros": time.as_secs_f64() * 1e6,
+        "allocations": allocations,
+      }),
+      Err(e) => json!({"error": e}),
+    };
+  }
+  Ok(report)
+}
+
+/** Run the function for the iterations, and return the mean time and allocation count. */
+fn measure(
+  iterations: u32,
+  mut f: impl FnMut() -> Result<(), String>,
+) -> Result<(Duration, usize), String> {
+  let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+  let start = Instant::now();
+  for _ in 0..iterations {
+    f()?;
+  }
+  let elapsed = start.elapsed();
+  let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+  Ok((elapsed / iterations, allocations / iterations as usize))
+}
+
+fn read_file(filename: &str) -> Result<String, String> {
+  fs::read_to_string(filename).map_err(|e| format!("{filename}: {e}"))
+}
+
+/**
+ * Create the renderer of the document with the context files, the config and the
+ * providers of the arguments.
+ */
+fn create_renderer<'a>(
+  doc: &'a str,
+  poml_filename: &str,
+  args: &ContextArgs,
+) -> Result<MarkdownPomlRenderer<'a>, String> {
+  let variables = load_context(args)?;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  renderer.set_document_path(poml_filename);
+  if let Some(config) = load_config(args)? {
+    renderer.options = config.options;
+    renderer.context.set_sandbox(config.sandbox);
+  }
+  if args.env {
+    renderer.context.enable_env();
+  }
+  if args.stdin {
+    renderer.context.enable_stdin();
+  }
+  Ok(renderer)
+}
+
+/**
+ * Load the config file of the arguments, or the default config file in the working
+ * directory if there is one.
+ */
+fn load_config(args: &ContextArgs) -> Result<Option<RenderConfig>, String> {
+  match &args.config {
+    Some(path) => RenderConfig::from_file(path).map(Some),
+    None => RenderConfig::find_in_dir("."),
+  }
+  .map_err(|e| e.to_string())
+}
+
+impl ContextArgs {
+  fn has_variables(&self) -> bool {
+    !self.files.is_empty() || !self.sets.is_empty() || !self.json_sets.is_empty()
+  }
+}
+
+/**
+ * Merge the objects of the context files, where later files override the earlier ones, and
+ * then the variables set by `--set` and `--set-json`.
+ */
+fn load_context(args: &ContextArgs) -> Result<Map<String, Value>, String> {
+  let mut variables = Map::new();
+  for filename in args.files.iter() {
+    let content = match filename.as_str() {
+      "-" => {
+        let mut content = String::new();
+        io::stdin()
+          .read_to_string(&mut content)
+          .map_err(|e| format!("stdin: {e}"))?;
+        content
+      }
+      _ => read_file(filename)?,
+    };
+    let value = match DataFormat::from_src(filename, None).map_err(|e| e.to_string())? {
+      Some(format) => format.parse(&content).map_err(|e| e.to_string()),
+      None => serde_json::from_str(&content).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| format!("{filename}: {e}"))?;
+    let Value::Object(object) = value else {
+      return Err(format!("{filename}: the context should be an object"));
+    };
+    variables.extend(object);
+  }
+  for set in args.sets.iter() {
+    let (name, value) = split_assignment(set)?;
+    set_variable(&mut variables, name, Value::String(value.to_string()))?;
+  }
+  for set in args.json_sets.iter() {
+    let (name, value) = split_assignment(set)?;
+    let value = serde_json::from_str(value).map_err(|e| format!("--set-json {name}: {e}"))?;
+    set_variable(&mut variables, name, value)?;
+  }
+  Ok(variables)
+}
+
+fn split_assignment(assignment: &str) -> Result<(&str, &str), String> {
+  match assignment.split_once('=') {
+    Some((name, value)) if !name.trim().is_empty() => Ok((name.trim(), value)),
+    _ => Err(format!("Expected NAME=VALUE, found `{assignment}`")),
+  }
+}
+
+/**
+ * Set the variable of the dotted name like `user.name`, creating the objects on the path.
+ */
+fn set_variable(
+  variables: &mut Map<String, Value>,
+  name: &str,
+  value: Value,
+) -> Result<(), String> {
+  let (path, last) = match name.rsplit_once('.') {
+    Some((path, last)) => (Some(path), last),
+    None => (None, name),
+  };
+  let mut object = variables;
+  for key in path.into_iter().flat_map(|path| path.split('.')) {
+    let entry = object
+      .entry(key)
+      .or_insert_with(|| Value::Object(Map::new()));
+    let Value::Object(inner) = entry else {
+      return Err(format!("Cannot set `{name}`, `{key}` is not an object"));
+    };
+    object = inner;
+  }
+  object.insert(last.to_string(), value);
+  Ok(())
+}
+
+fn print_diagnostics(filename: &str, diagnostics: &[Diagnostic], json: bool) {
+  for diagnostic in diagnostics.iter() {
+    if json {
+      let mut value = diagnostic.to_json();
+      value["file"] = Value::String(filename.to_string());
+      println!("{value}");
+    } else {
+      eprintln!("{filename}: {diagnostic}");
+    }
+  }
+}
@@ -0,0 +1,314 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Self-contained bundles of a POML document with all the files it includes, so the
+ * document can be shipped to another environment and rendered byte-identically.
+ *
+ * A bundle is a JSON document. File contents are stored once by their SHA-256 hash,
+ * and the paths map to the hashes:
+ *
+ * ```json
+ * {"version": 1, "entry": "main.poml", "files": {"main.poml": "<hash>"}, "blobs": {"<hash>": "..."}}
+ * ```
+ */
+
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use crate::parser::PomlParser;
+use crate::render::file_loader::{FileLoader, is_absolute_path, is_url, normalize_path};
+use crate::{PomlNode, PomlTagNode};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+const BUNDLE_VERSION: u64 = 1;
+
+/**
+ * A POML document with all the files it includes.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bundle {
+  entry: String,
+  /** Normalized paths to the hashes of the contents */
+  files: BTreeMap<String, String>,
+  /** Hashes to the contents */
+  blobs: BTreeMap<String, String>,
+}
+
+impl Bundle {
+  /**
+   * Export the document at `entry` with the files used by `<include>` and `src` attributes,
+   * loaded by the file loader. Paths with expressions can't be resolved without rendering,
+   * so they are rejected. The standard input of `<let src="stdin">` and URLs are read when
+   * rendering, so they are left out.
+   */
+  pub fn export(entry: &str, file_loader: &dyn FileLoader) -> Result<Bundle> {
+    let mut bundle = Bundle {
+      entry: normalize_path(entry),
+      files: BTreeMap::new(),
+      blobs: BTreeMap::new(),
+    };
+    let entry = bundle.entry.clone();
+    bundle.add_document(&entry, file_loader)?;
+    Ok(bundle)
+  }
+
+  /**
+   * Path of the entry document.
+   */
+  pub fn entry(&self) -> &str {
+    &self.entry
+  }
+
+  /**
+   * Content of the entry document.
+   */
+  pub fn entry_content(&self) -> &str {
+    let hash = &self.files[&self.entry];
+    &self.blobs[hash]
+  }
+
+  pub fn to_json(&self) -> String {
+    json!({
+      "version": BUNDLE_VERSION,
+      "entry": self.entry,
+      "files": self.files,
+      "blobs": self.blobs,
+    })
+    .to_string()
+  }
+
+  /**
+   * Load a bundle from its JSON form. The hashes of the contents are verified.
+   */
+  pub fn from_json(s: &str) -> Result<Bundle> {
+    let value: Value =
+      serde_json::from_str(s).map_err(|e| bundle_error("Invalid JSON", Some(e)))?;
+    if value.get("version").and_then(|v| v.as_u64()) != Some(BUNDLE_VERSION) {
+      return Err(bundle_error("Unsupported bundle version", None));
+    }
+    let Some(entry) = value.get("entry").and_then(|v| v.as_str()) else {
+      return Err(bundle_error("`entry` is missing", None));
+    };
+    let files = string_map(value.get("files"), "files")?;
+    let blobs = string_map(value.get("blobs"), "blobs")?;
+    for (hash, content) in blobs.iter() {
+      if sha256_hex(content.as_bytes()) != *hash {
+        return Err(bundle_error(
+          &format!("Content of blob {hash} is corrupted"),
+          None,
+        ));
+      }
+    }
+    for (path, hash) in files.iter() {
+      if !blobs.contains_key(hash) {
+        return Err(bundle_error(&format!("Content of {path} is missing"), None));
+      }
+    }
+    if !files.contains_key(entry) {
+      return Err(bundle_error("Entry document is missing", None));
+    }
+    Ok(Bundle {
+      entry: entry.to_string(),
+      files,
+      blobs,
+    })
+  }
+
+  fn add_file(&mut self, path: &str, file_loader: &dyn FileLoader) -> Result<String> {
+    let content = file_loader.load(path)?;
+    let hash = sha256_hex(content.as_bytes());
+    self.files.insert(path.to_string(), hash.clone());
+    self.blobs.insert(hash, content.clone());
+    Ok(content)
+  }
+
+  fn add_document(&mut self, path: &str, file_loader: &dyn FileLoader) -> Result<()> {
+    if self.files.contains_key(path) {
+      return Ok(());
+    }
+    let content = self.add_file(path, file_loader)?;
+    let mut parser = PomlParser::from_poml_str(&content);
+    let root = parser.parse_as_node().map_err(|e| Error {
+      kind: ErrorKind::RendererError,
+      message: format!("Failed to parse {path} for the bundle"),
+      source: Some(Box::new(e)),
+      position: None,
+      code: None,
+    })?;
+    let base_path = path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("");
+    self.add_referenced_files(&root, base_path, file_loader)
+  }
+
+  fn add_referenced_files(
+    &mut self,
+    tag_node: &PomlTagNode,
+    base_path: &str,
+    file_loader: &dyn FileLoader,
+  ) -> Result<()> {
+    for (key, value) in tag_node.attributes.iter() {
+      let srcs: Vec<&str> = match (tag_node.name, *key) {
+        (_, "src") => vec![value.text()],
+        ("include", "srcset") => value
+          .text()
+          .split(',')
+          .map(|src| src.trim())
+          .filter(|src| !src.is_empty())
+          .collect(),
+        _ => continue,
+      };
+      for src in srcs {
+        if (tag_node.name == "let" && src == "stdin") || is_url(src) {
+          continue;
+        }
+        if src.contains("{{") {
+          return Err(bundle_error(
+            &format!("Path with expressions can't be bundled: {src}"),
+            None,
+          ));
+        }
+        let path = if base_path.is_empty() || is_absolute_path(src) {
+          normalize_path(src)
+        } else {
+          normalize_path(&format!("{base_path}/{src}"))
+        };
+        if tag_node.name == "include" {
+          // Fallbacks of `srcset` may not exist.
+          let result = self.add_document(&path, file_loader);
+          if *key == "src" {
+            result?;
+          }
+        } else {
+          self.add_file(&path, file_loader)?;
+        }
+      }
+    }
+    for child in tag_node.children.iter() {
+      if let PomlNode::Tag(child_tag) = child {
+        self.add_referenced_files(child_tag, base_path, file_loader)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+impl FileLoader for Bundle {
+  fn load(&self, path: &str) -> Result<String> {
+    match self.files.get(path) {
+      Some(hash) => Ok(self.blobs[hash].clone()),
+      None => Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("File not found in the bundle: {path}"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::FileNotFound),
+      }),
+    }
+  }
+}
+
+fn bundle_error(message: &str, source: Option<serde_json::Error>) -> Error {
+  Error {
+    kind: ErrorKind::RendererError,
+    message: format!("Invalid bundle: {message}"),
+    source: source.map(|e| Box::new(e) as _),
+    position: None,
+    code: Some(ErrorCode::InvalidConfig),
+  }
+}
+
+fn string_map(value: Option<&Value>, name: &str) -> Result<BTreeMap<String, String>> {
+  let Some(Value::Object(map)) = value else {
+    return Err(bundle_error(&format!("`{name}` should be an object"), None));
+  };
+  map
+    .iter()
+    .map(|(key, value)| match value {
+      Value::String(s) => Ok((key.clone(), s.clone())),
+      _ => Err(bundle_error(
+        &format!("Values of `{name}` should be strings"),
+        None,
+      )),
+    })
+    .collect()
+}
+
+/**
+ * SHA-256 hash of the data in lowercase hex.
+ */
+fn sha256_hex(data: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::MarkdownPomlRenderer;
+  use crate::render::file_loader::InMemoryLoader;
+  use std::collections::HashMap;
+
+  #[test]
+  fn test_sha256() {
+    assert_eq!(
+      sha256_hex(b""),
+      "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+      sha256_hex(b"abc"),
+      "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+      sha256_hex(&[b'a'; 100]),
+      "2816597888e4a0d3a36b82b83316ab32680eb8f00f8cd3b904d681246d285a0e"
+    );
+  }
+
+  #[test]
+  fn test_export_and_render_bundle() {
+    let loader = InMemoryLoader::from_iter([
+      (
+        "prompts/main.poml",
+        r#"<poml><include src="sub/a.poml" /><let name="d" src="data.json" /><p>{{ d.x }}</p></poml>"#,
+      ),
+      (
+        "prompts/sub/a.poml",
+        r#"<include srcset="missing.poml, b.poml" />"#,
+      ),
+      ("prompts/sub/b.poml", "<p>B</p>"),
+      ("prompts/data.json", r#"{"x": 1}"#),
+    ]);
+    let bundle = Bundle::export("prompts/main.poml", &loader).unwrap();
+    let bundle = Bundle::from_json(&bundle.to_json()).unwrap();
+    assert_eq!(bundle.files.len(), 4);
+
+    let doc = bundle.entry_content().to_string();
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(&doc, HashMap::new());
+    renderer.set_document_path(bundle.entry());
+    renderer.context.set_file_loader(bundle);
+    assert_eq!(renderer.render().unwrap(), "B\n\n1\n\n");
+  }
+
+  #[test]
+  fn test_export_skips_stdin_and_urls() {
+    let loader = InMemoryLoader::from_iter([(
+      "main.poml",
+      r#"<poml><let name="input" src="stdin" /><include src="https://example.com/a.poml" /></poml>"#,
+    )]);
+    let bundle = Bundle::export("main.poml", &loader).unwrap();
+    assert_eq!(Vec::from_iter(bundle.files.keys()), ["main.poml"]);
+  }
+
+  #[test]
+  fn test_invalid_bundles() {
+    let loader = InMemoryLoader::from_iter([("main.poml", r#"<include src="{{ name }}.poml" />"#)]);
+    assert!(Bundle::export("main.poml", &loader).is_err());
+
+    let loader = InMemoryLoader::from_iter([("main.poml", "<p>A</p>")]);
+    let json = Bundle::export("main.poml", &loader).unwrap().to_json();
+    assert!(Bundle::from_json(&json.replace("<p>A</p>", "<p>B</p>")).is_err());
+    assert!(Bundle::from_json(&json.replace("\"version\":1", "\"version\":2")).is_err());
+  }
+}
@@ -0,0 +1,246 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * C API to embed the renderer in hosts written in other languages, behind the `capi` feature.
+ * The header is `include/mini_poml.h`, generated by `cbindgen` with `cbindgen.toml`.
+ *
+ * The strings are UTF-8 and NUL-terminated. Functions return `POML_OK` on success, or another
+ * status with the error kept for `poml_last_error` and `poml_last_error_code` of the thread.
+ */
+
+use crate::MarkdownPomlRenderer;
+use crate::error::{Error, ErrorKind};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::ptr;
+
+/**
+ * Status returned by the functions.
+ */
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PomlStatus {
+  /** The call succeeded. */
+  PomlOk = 0,
+  /** The document or the context failed to parse or render. */
+  PomlError = 1,
+  /** An argument is null or not valid UTF-8. */
+  PomlInvalidArgument = 2,
+  /** The renderer panicked, which is a bug. */
+  PomlPanic = 3,
+}
+
+struct LastError {
+  message: CString,
+  code: CString,
+}
+
+thread_local! {
+  static LAST_ERROR: RefCell<Option<LastError>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String, code: &str) {
+  // Messages have no NUL in practice, which would only cut them short
+  let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+  let code = CString::new(code).unwrap_or_default();
+  LAST_ERROR.with(|last| *last.borrow_mut() = Some(LastError { message, code }));
+}
+
+/**
+ * Read the NUL-terminated UTF-8 string, or `None` if it is null.
+ */
+unsafe fn read_str<'a>(s: *const c_char, name: &str) -> Result<Option<&'a str>, PomlStatus> {
+  if s.is_null() {
+    return Ok(None);
+  }
+  match unsafe { CStr::from_ptr(s) }.to_str() {
+    Ok(s) => Ok(Some(s)),
+    Err(e) => {
+      set_last_error(format!("`{name}` is not valid UTF-8: {e}"), "");
+      Err(PomlStatus::PomlInvalidArgument)
+    }
+  }
+}
+
+fn render(doc: &str, context_json: Option<&str>) -> Result<String, Error> {
+  let variables = match context_json {
+    None => Map::new(),
+    Some(context_json) => match serde_json::from_str(context_json) {
+      Ok(Value::Object(variables)) => variables,
+      Ok(_) => {
+        return Err(Error {
+          kind: ErrorKind::RendererError,
+          message: "The context should be a JSON object".to_string(),
+          source: None,
+          position: None,
+          code: None,
+        });
+      }
+      Err(e) => {
+        return Err(Error {
+          kind: ErrorKind::RendererError,
+          message: "Failed to parse the context JSON".to_string(),
+          source: Some(Box::new(e)),
+          position: None,
+          code: None,
+        });
+      }
+    },
+  };
+  MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables).render()
+}
+
+/**
+ * Render the POML document as Markdown with the context, a JSON object or null for an empty
+ * context. On success, `*out` is set to the output, which must be freed by
+ * `poml_string_free`. Otherwise `*out` is set to null.
+ *
+ * # Safety
+ * `doc` and `context_json` must be null or valid NUL-terminated strings, and `out` must be
+ * null or valid to write a pointer to.
+ */
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn poml_render(
+  doc: *const c_char,
+  context_json: *const c_char,
+  out: *mut *mut c_char,
+) -> PomlStatus {
+  LAST_ERROR.with(|last| *last.borrow_mut() = None);
+  if out.is_null() {
+    set_last_error("`out` is null".to_string(), "");
+    return PomlStatus::PomlInvalidArgument;
+  }
+  unsafe { *out = ptr::null_mut() };
+  let doc = match unsafe { read_str(doc, "doc") } {
+    Ok(Some(doc)) => doc,
+    Ok(None) => {
+      set_last_error("`doc` is null".to_string(), "");
+      return PomlStatus::PomlInvalidArgument;
+    }
+    Err(status) => return status,
+  };
+  let context_json = match unsafe { read_str(context_json, "context_json") } {
+    Ok(context_json) => context_json,
+    Err(status) => return status,
+  };
+  match catch_unwind(AssertUnwindSafe(|| render(doc, context_json))) {
+    Ok(Ok(output)) => {
+      let output = CString::new(output.replace('\0', "")).unwrap_or_default();
+      unsafe { *out = output.into_raw() };
+      PomlStatus::PomlOk
+    }
+    Ok(Err(e)) => {
+      set_last_error(e.to_string(), e.code().as_str());
+      PomlStatus::PomlError
+    }
+    Err(_) => {
+      set_last_error("The renderer panicked".to_string(), "");
+      PomlStatus::PomlPanic
+    }
+  }
+}
+
+/**
+ * Message of the last error on this thread, or null if the last call succeeded. The string is
+ * owned by the library and valid until the next call on this thread.
+ */
+#[unsafe(no_mangle)]
+pub extern "C" fn poml_last_error() -> *const c_char {
+  LAST_ERROR.with(|last| match &*last.borrow() {
+    Some(error) => error.message.as_ptr(),
+    None => ptr::null(),
+  })
+}
+
+/**
+ * Code of the last error on this thread like `E201`, which is empty for invalid arguments, or
+ * null if the last call succeeded. The string is valid until the next call on this thread.
+ */
+#[unsafe(no_mangle)]
+pub extern "C" fn poml_last_error_code() -> *const c_char {
+  LAST_ERROR.with(|last| match &*last.borrow() {
+    Some(error) => error.code.as_ptr(),
+    None => ptr::null(),
+  })
+}
+
+/**
+ * Free a string returned by the library. Freeing null does nothing.
+ *
+ * # Safety
+ * `s` must be null or a string returned by the library, which is not freed yet.
+ */
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn poml_string_free(s: *mut c_char) {
+  if !s.is_null() {
+    drop(unsafe { CString::from_raw(s) });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn call_render(doc: Option<&str>, context_json: Option<&str>) -> (PomlStatus, Option<String>) {
+    let doc = doc.map(|doc| CString::new(doc).unwrap());
+    let context_json = context_json.map(|c| CString::new(c).unwrap());
+    let mut out = ptr::null_mut();
+    let status = unsafe {
+      poml_render(
+        doc.as_ref().map_or(ptr::null(), |d| d.as_ptr()),
+        context_json.as_ref().map_or(ptr::null(), |c| c.as_ptr()),
+        &mut out,
+      )
+    };
+    let output = match out.is_null() {
+      true => None,
+      false => {
+        let output = unsafe { CStr::from_ptr(out) }.to_str().unwrap().to_string();
+        unsafe { poml_string_free(out) };
+        Some(output)
+      }
+    };
+    (status, output)
+  }
+
+  fn last_error() -> (String, String) {
+    let message = unsafe { CStr::from_ptr(poml_last_error()) };
+    let code = unsafe { CStr::from_ptr(poml_last_error_code()) };
+    (
+      message.to_str().unwrap().to_string(),
+      code.to_str().unwrap().to_string(),
+    )
+  }
+
+  #[test]
+  fn test_poml_render() {
+    let (status, output) = call_render(
+      Some("<p>Hello, {{ name }}!</p>"),
+      Some(r#"{"name": "Ada"}"#),
+    );
+    assert_eq!(status, PomlStatus::PomlOk);
+    assert_eq!(output.unwrap(), "Hello, Ada!\n\n");
+    assert!(poml_last_error().is_null());
+
+    let (status, output) = call_render(Some("<p>{{ 1 + }}</p>"), None);
+    assert_eq!(status, PomlStatus::PomlError);
+    assert!(output.is_none());
+    let (message, code) = last_error();
+    assert!(message.contains("Operator +"));
+    assert_eq!(code, "E201");
+
+    let (status, _) = call_render(Some("<p>Hi</p>"), Some("[1]"));
+    assert_eq!(status, PomlStatus::PomlError);
+    assert!(last_error().0.contains("JSON object"));
+
+    let (status, _) = call_render(None, None);
+    assert_eq!(status, PomlStatus::PomlInvalidArgument);
+    assert_eq!(last_error(), ("`doc` is null".to_string(), String::new()));
+  }
+}
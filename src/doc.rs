@@ -0,0 +1,474 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::{ErrorPosition, Result};
+use crate::parser::PomlParser;
+use crate::render::expression::tokenize::{ExpressionToken, tokenize_expression_spans};
+use crate::render::is_attribute_evaluated_as_expression;
+use crate::render::render_context::RenderContext;
+use crate::{PomlNode, PomlTagNode};
+use serde_json::{Map, Value, json};
+use std::collections::BTreeSet;
+
+/**
+ * A template defined by `<template>` in the document.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateInterface {
+  pub name: String,
+  pub params: Vec<String>,
+}
+
+/**
+ * Public interface of a document, which documents how to use it like the API docs of a
+ * library.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DocumentInterface {
+  /** Attributes of the `<meta>` tags, like `title` and `description`. */
+  pub metadata: Map<String, Value>,
+  /** Constants defined by `<meta const>`. */
+  pub constants: Vec<String>,
+  pub templates: Vec<TemplateInterface>,
+  /**
+   * Variables referenced by the document but not defined in it, which should be provided
+   * by the context.
+   */
+  pub variables: Vec<String>,
+  /** Files included by `<include>`. */
+  pub includes: Vec<String>,
+}
+
+/**
+ * A reference to a variable in an expression of the document.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariableReference {
+  pub name: String,
+  /** Where the name is in the document, if the expression is a part of the source. */
+  pub position: Option<ErrorPosition>,
+}
+
+/**
+ * Variables referenced by a document, checked against the variables of a context.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VariableUsage {
+  /**
+   * References in the expressions, `if` and `for` attributes in document order, except
+   * the names bound by loops, templates and `ifPresent`.
+   */
+  pub references: Vec<VariableReference>,
+  /** Variables referenced but neither defined in the document nor provided by the context. */
+  pub missing: Vec<String>,
+}
+
+impl VariableUsage {
+  pub fn to_json(&self) -> Value {
+    json!({
+      "references": self.references.iter().map(|r| json!({
+        "name": r.name,
+        "line": r.position.as_ref().map(|p| p.line),
+        "col": r.position.as_ref().map(|p| p.col),
+      })).collect::<Vec<_>>(),
+      "missing": self.missing,
+    })
+  }
+}
+
+/**
+ * Collect the public interface of a document from its source, without rendering it.
+ */
+pub fn document_interface(doc: &str) -> Result<DocumentInterface> {
+  let collector = collect(doc)?;
+  let mut interface = collector.interface;
+  interface.variables = collector
+    .referenced
+    .into_iter()
+    .filter(|name| !collector.defined.contains(name))
+    .collect();
+  Ok(interface)
+}
+
+/**
+ * Report the variables referenced by the document without rendering it, and which of them
+ * the context doesn't provide, to validate a template against its data ahead of time.
+ */
+pub fn variable_usage(doc: &str, context: &RenderContext) -> Result<VariableUsage> {
+  let collector = collect(doc)?;
+  let missing = collector
+    .referenced
+    .into_iter()
+    .filter(|name| !collector.defined.contains(name) && context.get_value(name).is_none())
+    .collect();
+  Ok(VariableUsage {
+    references: collector.references,
+    missing,
+  })
+}
+
+fn collect(doc: &str) -> Result<InterfaceCollector<'_>> {
+  let mut parser = PomlParser::from_poml_str(doc);
+  let root = parser.parse_as_node()?;
+  let mut collector = InterfaceCollector {
+    doc,
+    ..Default::default()
+  };
+  collector.visit_tag(&root, &mut Vec::new());
+  Ok(collector)
+}
+
+impl DocumentInterface {
+  /**
+   * Format the interface as Markdown, with the title as the top-level heading.
+   */
+  pub fn to_markdown(&self, title: &str) -> String {
+    let mut output = format!("# {title}\n");
+    if let Some(Value::String(description)) = self.metadata.get("description") {
+      output += &format!("\n{description}\n");
+    }
+    let metadata: Vec<String> = self
+      .metadata
+      .iter()
+      .filter(|(key, _)| *key != "description")
+      .map(|(key, value)| match value {
+        Value::String(s) => format!("- {key}: {s}"),
+        _ => format!("- {key}: {value}"),
+      })
+      .collect();
+    push_section(&mut output, "Metadata", metadata);
+    push_section(
+      &mut output,
+      "Variables",
+      self.variables.iter().map(|v| format!("- `{v}`")).collect(),
+    );
+    push_section(
+      &mut output,
+      "Constants",
+      self.constants.iter().map(|c| format!("- `{c}`")).collect(),
+    );
+    push_section(
+      &mut output,
+      "Templates",
+      self
+        .templates
+        .iter()
+        .map(|t| format!("- `<{}>` with params: {}", t.name, t.params.join(", ")))
+        .collect(),
+    );
+    push_section(
+      &mut output,
+      "Includes",
+      self.includes.iter().map(|i| format!("- `{i}`")).collect(),
+    );
+    output
+  }
+
+  pub fn to_json(&self) -> Value {
+    json!({
+      "metadata": self.metadata,
+      "constants": self.constants,
+      "templates": self.templates.iter().map(|t| json!({
+        "name": t.name,
+        "params": t.params,
+      })).collect::<Vec<_>>(),
+      "variables": self.variables,
+      "includes": self.includes,
+    })
+  }
+}
+
+fn push_section(output: &mut String, heading: &str, lines: Vec<String>) {
+  if lines.is_empty() {
+    return;
+  }
+  *output += &format!("\n## {heading}\n\n{}\n", lines.join("\n"));
+}
+
+#[derive(Default)]
+struct InterfaceCollector<'d> {
+  /** Source of the document, where the references are located */
+  doc: &'d str,
+  interface: DocumentInterface,
+  /** Names referenced by the expressions outside of the scope of their bindings. */
+  referenced: BTreeSet<String>,
+  /** Names defined by `<let>` and `<meta const>`. */
+  defined: BTreeSet<String>,
+  /** Locations of the referenced names. */
+  references: Vec<VariableReference>,
+}
+
+impl InterfaceCollector<'_> {
+  /**
+   * Visit the tag, with the names bound by the enclosing loops, templates and `ifPresent`.
+   */
+  fn visit_tag(&mut self, tag: &PomlTagNode, bound: &mut Vec<String>) {
+    let scope_len = bound.len();
+    let mut raw = tag.name == "pre";
+    for (key, value) in tag.attributes.iter() {
+      let value = value.text();
+      match *key {
+        "for" => {
+          if let Some((item, range)) = value.split_once(" in ") {
+            self.collect_expression(range, bound);
+            bound.push(item.trim().to_string());
+            bound.push("loop".to_string());
+          }
+        }
+        "ifPresent" => {
+          let (expression, name) = match value.rsplit_once(" as ") {
+            Some((expression, name)) => (expression, Some(name.trim())),
+            None => (value, None),
+          };
+          self.collect_expression(expression, bound);
+          bound.extend(name.map(|name| name.to_string()));
+        }
+        "raw" => raw = value == "true",
+        "if" => self.collect_expression(value, bound),
+        key if is_attribute_evaluated_as_expression(tag.name, key) => {
+          self.collect_expression(value, bound)
+        }
+        _ => self.collect_text(value, bound),
+      }
+    }
+
+    match tag.name {
+      "meta" => self.visit_meta(tag),
+      "let" => {
+        if let Some(name) = get_raw_attribute(tag, "name") {
+          self.defined.insert(name.to_string());
+        }
+      }
+      "include" => {
+        for key in ["src", "srcset"] {
+          if let Some(src) = get_raw_attribute(tag, key) {
+            self
+              .interface
+              .includes
+              .extend(src.split(',').map(|s| s.trim().to_string()));
+          }
+        }
+      }
+      "template" => {
+        let params: Vec<String> = get_raw_attribute(tag, "params")
+          .map(|params| {
+            params
+              .split(',')
+              .map(|p| p.trim().to_string())
+              .filter(|p| !p.is_empty())
+              .collect()
+          })
+          .unwrap_or_default();
+        if let Some(name) = get_raw_attribute(tag, "name") {
+          self.interface.templates.push(TemplateInterface {
+            name: name.to_string(),
+            params: params.clone(),
+          });
+        }
+        bound.extend(params);
+      }
+      _ => {}
+    }
+
+    for child in tag.children.iter() {
+      match child {
+        PomlNode::Tag(child) => self.visit_tag(child, bound),
+        PomlNode::Text(text, _) if !raw => self.collect_text(text, bound),
+        _ => {}
+      }
+    }
+    bound.truncate(scope_len);
+  }
+
+  fn visit_meta(&mut self, tag: &PomlTagNode) {
+    for (key, value) in tag.attributes.iter() {
+      let value = value.text();
+      if *key == "const" {
+        if let Ok(Value::Object(constants)) = serde_json::from_str::<Value>(value) {
+          for name in constants.keys() {
+            self.interface.constants.push(name.clone());
+            self.defined.insert(name.clone());
+          }
+        }
+      } else if *key != "if" {
+        self
+          .interface
+          .metadata
+          .insert(key.to_string(), Value::String(value.to_string()));
+      }
+    }
+  }
+
+  /**
+   * Collect the references of the `{{ }}` expressions in the text.
+   */
+  fn collect_text(&mut self, text: &str, bound: &[String]) {
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+      let Some(end) = rest[start + 2..].find("}}") else {
+        return;
+      };
+      self.collect_expression(&rest[start + 2..start + 2 + end], bound);
+      rest = &rest[start + 2 + end + 2..];
+    }
+  }
+
+  /**
+   * Collect the variables referenced by the expression. Field names, object keys,
+   * function names and keywords are not variables.
+   */
+  fn collect_expression(&mut self, expression: &str, bound: &[String]) {
+    let expression = expression.trim();
+    let expression = match expression.strip_prefix("{{") {
+      Some(e) => e.strip_suffix("}}").unwrap_or(e),
+      None => expression,
+    };
+    let Ok((tokens, spans)) = tokenize_expression_spans(expression.as_bytes()) else {
+      return;
+    };
+    let offset = offset_in(self.doc.as_bytes(), expression);
+    for (i, token) in tokens.iter().enumerate() {
+      let ExpressionToken::Ref(name) = token else {
+        continue;
+      };
+      let previous = i.checked_sub(1).and_then(|i| tokens.get(i));
+      let next = tokens.get(i + 1);
+      let is_field = previous == Some(&ExpressionToken::Dot);
+      let is_function = next == Some(&ExpressionToken::LeftParenthesis);
+      let is_object_key = next == Some(&ExpressionToken::Colon)
+        && matches!(
+          previous,
+          Some(ExpressionToken::LeftCurly) | Some(ExpressionToken::Comma)
+        );
+      let name = String::from_utf8_lossy(name).to_string();
+      if is_field
+        || is_function
+        || is_object_key
+        || matches!(name.as_str(), "true" | "false" | "null")
+        || bound.contains(&name)
+      {
+        continue;
+      }
+      self.references.push(VariableReference {
+        name: name.clone(),
+        position: offset.map(|offset| {
+          ErrorPosition::from_span(
+            self.doc.as_bytes(),
+            offset + spans[i].start,
+            offset + spans[i].end,
+          )
+        }),
+      });
+      self.referenced.insert(name);
+    }
+  }
+}
+
+/**
+ * Byte offset of the part in the document, if it's a slice of the document like the
+ * attributes and text of the parsed nodes.
+ */
+pub(crate) fn offset_in(doc: &[u8], part: &str) -> Option<usize> {
+  let offset = (part.as_ptr() as usize).wrapping_sub(doc.as_ptr() as usize);
+  (offset <= doc.len() && part.len() <= doc.len() - offset).then_some(offset)
+}
+
+/**
+ * Variables referenced by the expressions of the document outside the scope of their
+ * bindings, including the ones defined by `<let>`.
+ */
+pub(crate) fn referenced_variables(doc: &str) -> Result<BTreeSet<String>> {
+  Ok(collect(doc)?.referenced)
+}
+
+pub(crate) fn get_raw_attribute<'a>(tag: &PomlTagNode<'a>, key: &str) -> Option<&'a str> {
+  tag
+    .attributes
+    .iter()
+    .find(|(k, _)| *k == key)
+    .map(|(_, v)| v.text())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_document_interface() {
+    let doc = r#"
+<poml>
+  <meta title="Reviewer" description="Reviews a pull request." />
+  <meta const='{"MAX_ITEMS": 5}' />
+  <let name="tone" value="style.tone" />
+  <template name="card" params="title, body">
+    <p>{{ title }}: {{ body }} {{ footer }}</p>
+  </template>
+  <p if="user.active">Hello {{ user.name }}, in {{ tone }} tone.</p>
+  <list>
+    <item for="file in files" if="loop.index < MAX_ITEMS">{{ file.path }} {{ format(file) }}</item>
+  </list>
+  <p ifPresent="notes as n">{{ n }} {{ {kind: kind} }}</p>
+  <pre>{{ not_a_variable }}</pre>
+  <include src="footer.poml" />
+</poml>"#;
+    let interface = document_interface(doc).unwrap();
+    assert_eq!(interface.metadata.get("title"), Some(&json!("Reviewer")));
+    assert_eq!(interface.constants, vec!["MAX_ITEMS"]);
+    assert_eq!(
+      interface.templates,
+      vec![TemplateInterface {
+        name: "card".to_string(),
+        params: vec!["title".to_string(), "body".to_string()],
+      }]
+    );
+    assert_eq!(
+      interface.variables,
+      vec!["files", "footer", "kind", "notes", "style", "user"]
+    );
+    assert_eq!(interface.includes, vec!["footer.poml"]);
+
+    let markdown = interface.to_markdown("reviewer.poml");
+    assert!(markdown.starts_with("# reviewer.poml\n\nReviews a pull request.\n"));
+    assert!(markdown.contains("## Variables\n\n- `files`\n"));
+    assert!(markdown.contains("- `<card>` with params: title, body"));
+    assert_eq!(interface.to_json()["templates"][0]["name"], json!("card"));
+  }
+
+  #[test]
+  fn test_variable_usage() {
+    let doc = r#"<poml>
+  <let name="tone" value="style.tone" />
+  <p if="user.active">Hello {{ user.name }}, in {{ tone }} tone.</p>
+  <item for="file in files">{{ file.path }}: {{ summary }}</item>
+</poml>"#;
+    let context = RenderContext::from_iter([
+      ("user".to_string(), json!({"name": "Ada"})),
+      ("files".to_string(), json!([])),
+    ]);
+    let usage = variable_usage(doc, &context).unwrap();
+    let references: Vec<(&str, usize, usize)> = usage
+      .references
+      .iter()
+      .map(|r| {
+        let position = r.position.as_ref().unwrap();
+        (r.name.as_str(), position.line, position.col)
+      })
+      .collect();
+    assert_eq!(
+      references,
+      vec![
+        ("style", 2, 27),
+        ("user", 3, 10),
+        ("user", 3, 32),
+        ("tone", 3, 52),
+        ("files", 4, 22),
+        ("summary", 4, 49),
+      ]
+    );
+    assert_eq!(usage.missing, vec!["style", "summary"]);
+    assert_eq!(usage.to_json()["references"][1]["line"], json!(3));
+  }
+}
@@ -4,13 +4,16 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use serde_json::{Value, json};
 use std::error::Error as StdError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ErrorKind {
   ParserError,
   EvaluatorError,
   RendererError,
+  /** The render is cancelled by a cancellation token. */
+  Cancelled,
 }
 
 impl std::fmt::Display for ErrorKind {
@@ -19,6 +22,7 @@ impl std::fmt::Display for ErrorKind {
       ErrorKind::ParserError => write!(f, "ParserError"),
       ErrorKind::EvaluatorError => write!(f, "EvaluatorError"),
       ErrorKind::RendererError => write!(f, "RendererError"),
+      ErrorKind::Cancelled => write!(f, "Cancelled"),
     }
   }
 }
@@ -27,24 +31,454 @@ impl std::fmt::Display for ErrorKind {
 pub struct Error {
   pub kind: ErrorKind,
   pub message: String,
-  pub source: Option<Box<dyn StdError>>,
+  pub source: Option<Box<dyn StdError + Send + Sync>>,
+  /** Where the error happens in the document, if known */
+  pub position: Option<ErrorPosition>,
+  /** Machine-readable code of the error, if it's more specific than the kind */
+  pub code: Option<ErrorCode>,
+}
+
+/**
+ * Machine-readable codes of the errors, stable across versions so editors and CI can
+ * match them. Codes starting with 0 are parser errors, 1 renderer errors, 2 evaluator
+ * errors, 3 lint warnings and 9 others.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+  /** E000: Other syntax errors of the document */
+  Syntax,
+  /** E001: A tag is not closed at the end of the document */
+  UnclosedTag,
+  /** E002: A close tag doesn't match the open tag */
+  MismatchedCloseTag,
+  /** E003: An attribute is declared more than once on a tag */
+  DuplicateAttribute,
+  /** E004: An attribute is not declared like `key="value"` */
+  InvalidAttributeSyntax,
+  /** E005: The document ends in the middle of a tag, comment or CDATA section */
+  UnexpectedEndOfInput,
+  /** E006: The document has no tags, only whitespace or comments */
+  EmptyDocument,
+  /** E100: Other render errors */
+  Render,
+  /** E101: A field is accessed on an undefined or null variable */
+  UndefinedVariable,
+  /** E102: The tag is not supported by the tag renderer */
+  UnknownTag,
+  /** E103: A `{{` expression in text is not closed by `}}` */
+  UnclosedExpression,
+  /** E104: An attribute has a value not valid for it */
+  InvalidAttributeValue,
+  /** E105: A required attribute is missing */
+  MissingAttribute,
+  /** E106: The value of `<let>` is missing, ambiguous or can't be converted to the type */
+  InvalidLetValue,
+  /** E107: A variable or constant shadows a constant */
+  ConstantShadowed,
+  /** E108: Templates are nested too deeply */
+  TemplateTooDeep,
+  /** E109: A file can't be found or read */
+  FileNotFound,
+  /** E110: The access is denied by the sandbox or the pure mode */
+  AccessDenied,
+  /** E111: The config, stylesheet, bundle or serialized context is invalid */
+  InvalidConfig,
+  /** E112: The document exceeds the nesting depth, loop, output size or include limits */
+  LimitExceeded,
+  /** E200: Other expression evaluation errors */
+  Expression,
+  /** E201: The expression is not valid syntax */
+  ExpressionSyntax,
+  /** E202: The function is not a builtin */
+  UnknownFunction,
+  /** E203: An operator or function is applied on values of unsupported types */
+  TypeMismatch,
+  /** E204: Division or modulo by zero */
+  DivisionByZero,
+  /** E205: The expression exceeds the length, token or nesting limits */
+  ExpressionLimit,
+  /** E300: A node is never rendered because its `if` condition is always false */
+  UnreachableNode,
+  /** E301: A variable is defined more than once by the `<let>` tags of a scope */
+  DuplicateVariable,
+  /** E302: A variable defined by `<let>` is never referenced */
+  UnusedVariable,
+  /** E303: Invisible characters, or whitespace around a name or path */
+  SuspiciousWhitespace,
+  /** E900: The render is cancelled */
+  Cancelled,
+  /** E901: Other errors, e.g. of the render pool */
+  Other,
+}
+
+impl ErrorCode {
+  /**
+   * The code like `E001`.
+   */
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ErrorCode::Syntax => "E000",
+      ErrorCode::UnclosedTag => "E001",
+      ErrorCode::MismatchedCloseTag => "E002",
+      ErrorCode::DuplicateAttribute => "E003",
+      ErrorCode::InvalidAttributeSyntax => "E004",
+      ErrorCode::UnexpectedEndOfInput => "E005",
+      ErrorCode::EmptyDocument => "E006",
+      ErrorCode::Render => "E100",
+      ErrorCode::UndefinedVariable => "E101",
+      ErrorCode::UnknownTag => "E102",
+      ErrorCode::UnclosedExpression => "E103",
+      ErrorCode::InvalidAttributeValue => "E104",
+      ErrorCode::MissingAttribute => "E105",
+      ErrorCode::InvalidLetValue => "E106",
+      ErrorCode::ConstantShadowed => "E107",
+      ErrorCode::TemplateTooDeep => "E108",
+      ErrorCode::FileNotFound => "E109",
+      ErrorCode::AccessDenied => "E110",
+      ErrorCode::InvalidConfig => "E111",
+      ErrorCode::LimitExceeded => "E112",
+      ErrorCode::Expression => "E200",
+      ErrorCode::ExpressionSyntax => "E201",
+      ErrorCode::UnknownFunction => "E202",
+      ErrorCode::TypeMismatch => "E203",
+      ErrorCode::DivisionByZero => "E204",
+      ErrorCode::ExpressionLimit => "E205",
+      ErrorCode::UnreachableNode => "E300",
+      ErrorCode::DuplicateVariable => "E301",
+      ErrorCode::UnusedVariable => "E302",
+      ErrorCode::SuspiciousWhitespace => "E303",
+      ErrorCode::Cancelled => "E900",
+      ErrorCode::Other => "E901",
+    }
+  }
+
+  /**
+   * The default code of the errors of the kind.
+   */
+  pub fn of_kind(kind: ErrorKind) -> ErrorCode {
+    match kind {
+      ErrorKind::ParserError => ErrorCode::Syntax,
+      ErrorKind::RendererError => ErrorCode::Render,
+      ErrorKind::EvaluatorError => ErrorCode::Expression,
+      ErrorKind::Cancelled => ErrorCode::Cancelled,
+    }
+  }
+
+  /**
+   * Hint to fix the errors of the code.
+   */
+  pub fn help(&self) -> Option<&'static str> {
+    match self {
+      ErrorCode::UnclosedTag => Some("add the close tag, or close the tag itself like `<tag />`"),
+      ErrorCode::MismatchedCloseTag => Some("close the tags in the reverse order they are opened"),
+      ErrorCode::DuplicateAttribute => Some("remove the duplicated attribute"),
+      ErrorCode::InvalidAttributeSyntax => Some("declare attributes like `key=\"value\"`"),
+      ErrorCode::EmptyDocument => Some("write the prompt in the document, like `<poml>...</poml>`"),
+      ErrorCode::UndefinedVariable => {
+        Some("provide the variable in the context, or guard it with `ifPresent`")
+      }
+      ErrorCode::UnknownTag => Some("check the spelling of the tag, or define it by <template>"),
+      ErrorCode::UnclosedExpression => {
+        Some("close the expression with `}}`, or escape `{` as `#lbrace;`")
+      }
+      ErrorCode::ConstantShadowed => Some("rename the variable, constants can't be redefined"),
+      ErrorCode::AccessDenied => Some("check the sandbox and pure mode of the render options"),
+      ErrorCode::DivisionByZero => Some("guard the divisor with a condition"),
+      ErrorCode::LimitExceeded => Some("simplify the document, or raise `RenderOptions::limits`"),
+      ErrorCode::UnreachableNode => Some("remove the node, or fix its condition"),
+      ErrorCode::DuplicateVariable => Some("rename the variable, or remove the first definition"),
+      ErrorCode::UnusedVariable => Some("remove the `<let>`, or reference the variable"),
+      ErrorCode::SuspiciousWhitespace => Some("remove the invisible characters or whitespace"),
+      _ => None,
+    }
+  }
+}
+
+impl std::fmt::Display for ErrorCode {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.as_str())
+  }
+}
+
+/**
+ * Severity of a diagnostic.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+  Error,
+  Warning,
+}
+
+impl std::fmt::Display for Severity {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Severity::Error => write!(f, "error"),
+      Severity::Warning => write!(f, "warning"),
+    }
+  }
+}
+
+/**
+ * Byte offsets of the start and the end of a range in the document.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize,
+}
+
+/**
+ * A structured problem of a document, to be consumed programmatically by editors and CI.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+  pub code: ErrorCode,
+  pub severity: Severity,
+  pub message: String,
+  pub span: Option<Span>,
+  pub position: Option<ErrorPosition>,
+  pub help: Option<String>,
+}
+
+impl Diagnostic {
+  pub fn to_json(&self) -> Value {
+    json!({
+      "code": self.code.as_str(),
+      "severity": self.severity.to_string(),
+      "message": self.message,
+      "span": self.span.map(|span| json!({"start": span.start, "end": span.end})),
+      "line": self.position.as_ref().map(|p| p.line),
+      "col": self.position.as_ref().map(|p| p.col),
+      "help": self.help,
+    })
+  }
+}
+
+impl std::fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}[{}]: {}", self.severity, self.code, self.message)?;
+    if let Some(position) = &self.position {
+      write!(
+        f,
+        "\n --> line {}, column {}\n  {}\n  {}",
+        position.line,
+        position.col,
+        position.snippet,
+        position.caret()
+      )?;
+    }
+    if let Some(help) = &self.help {
+      write!(f, "\n  help: {help}")?;
+    }
+    Ok(())
+  }
+}
+
+/**
+ * Position of an error in the document. Lines and columns are indexed from 1, and
+ * columns are counted in characters.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorPosition {
+  /** Byte offset in the document */
+  pub offset: usize,
+  /** Byte offset of the end of the range of the error, which is `offset` if it's a point */
+  pub end: usize,
+  pub line: usize,
+  pub col: usize,
+  /** The line of the document containing the position */
+  pub snippet: String,
+}
+
+impl ErrorPosition {
+  /**
+   * Locate the byte offset in the document.
+   */
+  pub fn from_offset(doc: &[u8], offset: usize) -> ErrorPosition {
+    ErrorPosition::from_span(doc, offset, offset)
+  }
+
+  /**
+   * Locate the range of the byte offsets in the document.
+   */
+  pub fn from_span(doc: &[u8], offset: usize, end: usize) -> ErrorPosition {
+    let offset = offset.min(doc.len());
+    let end = end.clamp(offset, doc.len());
+    let line_start = doc[..offset]
+      .iter()
+      .rposition(|c| *c == b'\n')
+      .map_or(0, |p| p + 1);
+    let line_end = doc[offset..]
+      .iter()
+      .position(|c| *c == b'\n')
+      .map_or(doc.len(), |p| offset + p);
+    let line = doc[..line_start].iter().filter(|c| **c == b'\n').count() + 1;
+    let col = String::from_utf8_lossy(&doc[line_start..offset])
+      .chars()
+      .count()
+      + 1;
+    let snippet = String::from_utf8_lossy(&doc[line_start..line_end])
+      .trim_end_matches('\r')
+      .to_string();
+    ErrorPosition {
+      offset,
+      end,
+      line,
+      col,
+      snippet,
+    }
+  }
+
+  /**
+   * The line marking the range of the error under the snippet, like `      ^^^`. Tabs of the
+   * snippet are kept so the marks line up.
+   */
+  pub fn caret(&self) -> String {
+    let mut chars = self.snippet.char_indices().skip(self.col.saturating_sub(1));
+    let mut caret: String = self
+      .snippet
+      .chars()
+      .take(self.col.saturating_sub(1))
+      .map(|c| if c == '\t' { '\t' } else { ' ' })
+      .collect();
+    let start = chars.next().map_or(self.snippet.len(), |(i, _)| i);
+    let width = self.snippet[start..]
+      .char_indices()
+      .take_while(|(i, _)| *i < self.end - self.offset)
+      .count();
+    caret.push_str(&"^".repeat(width.max(1)));
+    caret
+  }
+}
+
+impl Error {
+  /**
+   * Position of the error, or of the error causing it if the position of this error
+   * is unknown.
+   */
+  pub fn position(&self) -> Option<&ErrorPosition> {
+    self.position.as_ref().or_else(|| {
+      self
+        .source
+        .as_ref()
+        .and_then(|source| source.downcast_ref::<Error>())
+        .and_then(Error::position)
+    })
+  }
+
+  /**
+   * Set the position of the error to the range of byte offsets in the document, unless
+   * the error or its cause is already located.
+   */
+  pub(crate) fn with_span(mut self, doc: &[u8], start: usize, end: usize) -> Error {
+    if self.position().is_none() {
+      self.position = Some(ErrorPosition::from_span(doc, start, end));
+    }
+    self
+  }
+
+  /**
+   * Move the position of an error of the expression into the document, if the expression is
+   * a part of the document. Otherwise the position in the expression is dropped, so the
+   * error is located at its node instead.
+   */
+  pub(crate) fn in_document(mut self, doc: &[u8], expression: &str) -> Error {
+    let offset = (expression.as_ptr() as usize).wrapping_sub(doc.as_ptr() as usize);
+    self.position = match self.position.take() {
+      Some(position) if offset <= doc.len() && expression.len() <= doc.len() - offset => Some(
+        ErrorPosition::from_span(doc, offset + position.offset, offset + position.end),
+      ),
+      _ => None,
+    };
+    self
+  }
+
+  /**
+   * Code of the error, or of the error causing it if this error has no specific code.
+   * Without any specific code, it's the code of the kind of the innermost error, which is
+   * the one describing what went wrong.
+   */
+  pub fn code(&self) -> ErrorCode {
+    self.specific_code().unwrap_or_else(|| {
+      let mut innermost = self;
+      while let Some(source) = innermost
+        .source
+        .as_ref()
+        .and_then(|source| source.downcast_ref::<Error>())
+      {
+        innermost = source;
+      }
+      ErrorCode::of_kind(innermost.kind)
+    })
+  }
+
+  fn specific_code(&self) -> Option<ErrorCode> {
+    self.code.or_else(|| {
+      self
+        .source
+        .as_ref()
+        .and_then(|source| source.downcast_ref::<Error>())
+        .and_then(Error::specific_code)
+    })
+  }
+
+  /**
+   * Convert the error into a diagnostic, with the messages of the causing errors.
+   */
+  pub fn to_diagnostic(&self) -> Diagnostic {
+    let mut message = self.message.clone();
+    let mut source = self
+      .source
+      .as_deref()
+      .map(|e| e as &(dyn StdError + 'static));
+    while let Some(e) = source {
+      match e.downcast_ref::<Error>() {
+        Some(error) => message += &format!(": {}", error.message),
+        None => message += &format!(": {e}"),
+      }
+      source = e.source();
+    }
+    let code = self.code();
+    let position = self.position().cloned();
+    Diagnostic {
+      code,
+      severity: Severity::Error,
+      message,
+      span: position.as_ref().map(|p| Span {
+        start: p.offset,
+        end: p.end,
+      }),
+      position,
+      help: code.help().map(|help| help.to_string()),
+    }
+  }
 }
 
 impl StdError for Error {
   fn source(&self) -> Option<&(dyn StdError + 'static)> {
-    self.source.as_ref().map(|e| e.as_ref())
+    self
+      .source
+      .as_ref()
+      .map(|e| e.as_ref() as &(dyn StdError + 'static))
   }
 }
 
 impl std::fmt::Display for Error {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}[{}]: {}", self.kind, self.code(), self.message)?;
+    if let Some(position) = &self.position {
+      write!(
+        f,
+        " (line {}, column {})\n  {}\n  {}",
+        position.line,
+        position.col,
+        position.snippet,
+        position.caret()
+      )?;
+    }
     match &self.source {
-      None => {
-        write!(f, "{}: {}", self.kind, self.message)
-      }
-      Some(source) => {
-        write!(f, "{}: {}\ncaused by {}", self.kind, self.message, source)
-      }
+      None => Ok(()),
+      Some(source) => write!(f, "\ncaused by {}", source),
     }
   }
 }
@@ -0,0 +1,271 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Formatter of POML documents, which indents the lines by the nesting of the tags and
+ * removes trailing whitespace.
+ *
+ * Whitespace that is rendered is kept as it is: the contents of `<pre>`, `<code>`, `<let>`
+ * and the tags with `whiteSpace="pre"` or `whiteSpace="trim"`, CDATA sections, and the lines
+ * of the tags and comments spanning multiple lines. So the formatted document renders the
+ * same output with the default whitespace mode.
+ */
+
+use crate::error::Result;
+use crate::parser::PomlParser;
+use crate::{PomlNode, PomlTagNode};
+
+/**
+ * Spaces to indent a line per level of nesting.
+ */
+const INDENT: &str = "  ";
+
+/**
+ * Tags whose contents are kept as they are, since their whitespace is significant.
+ */
+const PRESERVED_TAGS: [&str; 3] = ["pre", "code", "let"];
+
+/**
+ * Format the document. Documents that fail to parse are not formatted.
+ */
+pub fn format_document(doc: &str) -> Result<String> {
+  let mut parser = PomlParser::from_poml_str(doc);
+  let root = parser.parse_as_node()?;
+  let mut layout = Layout::default();
+  layout.visit_tag(doc, &root, false);
+  layout.kept.extend(comment_ranges(doc));
+
+  let mut output = String::with_capacity(doc.len());
+  let mut line_start = 0;
+  for line in doc.split_inclusive('\n') {
+    let line_end = line_start + line.len();
+    let newline = &line[line.trim_end_matches(['\r', '\n']).len()..];
+    let body = &line[..line.len() - newline.len()];
+    let content = body.trim_start_matches([' ', '\t']);
+    let first = line_start + body.len() - content.len();
+    let content_end = first + content.trim_end_matches([' ', '\t']).len();
+
+    if layout.is_kept(line_start, first) {
+      output += &body[..first - line_start];
+    } else if content_end > first {
+      output += &INDENT.repeat(layout.depth(first));
+    }
+    output += &doc[first..content_end];
+    if layout.is_kept(content_end, line_start + body.len()) {
+      output += &body[content_end - line_start..];
+    }
+    output += newline;
+    line_start = line_end;
+  }
+  if !doc.trim_end().is_empty() && !layout.is_kept(doc.trim_end().len(), doc.len()) {
+    output.truncate(output.trim_end().len());
+    output.push('\n');
+  }
+  Ok(output)
+}
+
+#[derive(Default)]
+struct Layout {
+  /** Byte ranges of the contents of the tags, between their open and close tags. */
+  contents: Vec<(usize, usize)>,
+  /** Byte ranges where the whitespace is kept. */
+  kept: Vec<(usize, usize)>,
+}
+
+impl Layout {
+  fn visit_tag(&mut self, doc: &str, tag: &PomlTagNode, preserved: bool) {
+    let start = tag.original_pos.start;
+    let end = tag.original_pos.end;
+    // The `<poml>` root added around documents without it is not in the source
+    let in_source = doc[start..].starts_with('<') && doc[start + 1..].starts_with(tag.name);
+    if in_source {
+      let open_end = open_tag_end(doc, start);
+      self.kept.push((start, open_end));
+      if !doc[..open_end].ends_with("/>") {
+        let close_start = doc[open_end..end].rfind("</").map_or(end, |i| open_end + i);
+        self.contents.push((open_end, close_start));
+        if preserved || is_preserved(tag) {
+          // Including the position before the close tag
+          self.kept.push((open_end, close_start + 1));
+          return;
+        }
+      }
+    }
+    for child in tag.children.iter() {
+      match child {
+        PomlNode::Tag(child) => self.visit_tag(doc, child, preserved),
+        PomlNode::CData(_, pos) => self.kept.push((pos.start, pos.end)),
+        _ => {}
+      }
+    }
+  }
+
+  /**
+   * Whether the whitespace between the byte offsets, or the whitespace inserted at the
+   * offset if they are equal, is in a range to keep.
+   */
+  fn is_kept(&self, start: usize, end: usize) -> bool {
+    self
+      .kept
+      .iter()
+      .any(|&(kept_start, kept_end)| kept_start < end && start < kept_end)
+  }
+
+  /** Number of the tags whose contents have the byte offset. */
+  fn depth(&self, offset: usize) -> usize {
+    self
+      .contents
+      .iter()
+      .filter(|&&(start, end)| start <= offset && offset < end)
+      .count()
+  }
+}
+
+fn is_preserved(tag: &PomlTagNode) -> bool {
+  PRESERVED_TAGS.contains(&tag.name)
+    || tag
+      .attributes
+      .iter()
+      .any(|(key, value)| *key == "whiteSpace" && matches!(value.text(), "pre" | "trim"))
+}
+
+/**
+ * Byte offset after the `>` of the open tag starting at the offset.
+ */
+fn open_tag_end(doc: &str, start: usize) -> usize {
+  let bytes = doc.as_bytes();
+  let mut quote = None;
+  let mut pos = start;
+  while pos < bytes.len() {
+    match (quote, bytes[pos]) {
+      (Some(q), c) if c == q => quote = None,
+      (None, b'"' | b'\'') => quote = Some(bytes[pos]),
+      (None, b'>') => return pos + 1,
+      // Skip unquoted expression values like `if={{ a > b }}`
+      (None, b'{') if bytes[pos..].starts_with(b"{{") => match doc[pos..].find("}}") {
+        Some(close) => pos += close + 1,
+        None => return doc.len(),
+      },
+      _ => {}
+    }
+    pos += 1;
+  }
+  doc.len()
+}
+
+/**
+ * Byte ranges of the comments, which the parser drops.
+ */
+fn comment_ranges(doc: &str) -> Vec<(usize, usize)> {
+  let mut ranges = Vec::new();
+  let mut rest = 0;
+  while let Some(start) = doc[rest..].find("<!--") {
+    let start = rest + start;
+    let end = doc[start..]
+      .find("-->")
+      .map_or(doc.len(), |i| start + i + 3);
+    ranges.push((start, end));
+    rest = end;
+  }
+  ranges
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{AttributeValue, MarkdownPomlRenderer};
+  use proptest::prelude::*;
+  use serde_json::json;
+  use std::collections::HashMap;
+
+  #[test]
+  fn test_format_document() {
+    let doc = "<poml>  \n<p>Hello,\n      {{ name }}!</p>\n        <list>\n<item>A</item>   \n    <!-- a\n        comment -->\n<item>B</item>\n</list>\n<pre>  keep\n      this  \n</pre>\n<p\n   if=\"true\">C</p>\n</poml>\n\n\n";
+    let formatted = format_document(doc).unwrap();
+    assert_eq!(
+      formatted,
+      "<poml>\n  <p>Hello,\n    {{ name }}!</p>\n  <list>\n    <item>A</item>\n    <!-- a\n        comment -->\n    <item>B</item>\n  </list>\n  <pre>  keep\n      this  \n</pre>\n  <p\n   if=\"true\">C</p>\n</poml>\n"
+    );
+    assert_eq!(format_document(&formatted).unwrap(), formatted);
+
+    let render = |doc: &str| {
+      let variables = HashMap::from([("name".to_owned(), json!("Ada"))]);
+      MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables)
+        .render()
+        .unwrap()
+    };
+    assert_eq!(render(&formatted), render(doc));
+    assert!(format_document("<poml><p></poml>").is_err());
+    assert_eq!(
+      format_document("<poml>\n<p if={{ a > 1 }}>\n<b>A</b></p></poml>").unwrap(),
+      "<poml>\n  <p if={{ a > 1 }}>\n    <b>A</b></p></poml>\n"
+    );
+  }
+
+  /**
+   * The tree of a document without its whitespace and positions, which formatting keeps.
+   */
+  #[derive(Debug, PartialEq)]
+  enum Shape<'a> {
+    Tag(&'a str, Vec<(&'a str, AttributeValue<'a>)>, Vec<Shape<'a>>),
+    Text(&'a str),
+  }
+
+  fn shape<'a>(tag: &PomlTagNode<'a>) -> Shape<'a> {
+    let children = tag
+      .children
+      .iter()
+      .filter_map(|child| match child {
+        PomlNode::Tag(tag) => Some(shape(tag)),
+        PomlNode::Text(text, _) | PomlNode::CData(text, _) => Some(Shape::Text(text.trim())),
+        PomlNode::Whitespace(_) => None,
+      })
+      .collect();
+    Shape::Tag(tag.name, tag.attributes.clone(), children)
+  }
+
+  /**
+   * Documents of nested tags and texts, laid out with random indentation and line breaks.
+   */
+  fn document() -> impl Strategy<Value = String> {
+    let space = prop_oneof!["", "\n", "\n    ", " \t\n\t", "\n\n  "];
+    let text = "[a-zA-Z0-9,.!?]([a-zA-Z0-9 ,.!?]{0,10}[a-zA-Z0-9,.!?])?";
+    let leaf = text.prop_map(String::from);
+    let node = leaf.prop_recursive(4, 32, 4, move |inner| {
+      (
+        prop_oneof!["p", "b", "i", "section", "u"],
+        proptest::option::of("[a-z ]{0,8}"),
+        proptest::collection::vec((space.clone(), inner), 0..4),
+        space.clone(),
+      )
+        .prop_map(|(name, class, children, end_space)| {
+          let class = class.map_or(String::new(), |c| format!(" class=\"{c}\""));
+          let children: String = children.into_iter().map(|(s, c)| s + &c).collect();
+          format!("<{name}{class}>{children}{end_space}</{name}>")
+        })
+    });
+    proptest::collection::vec(node, 0..4)
+      .prop_map(|nodes| format!("<poml>\n{}\n</poml>\n", nodes.join("\n")))
+  }
+
+  proptest! {
+    #[test]
+    fn prop_format_keeps_tree_and_output(doc in document()) {
+      let formatted = format_document(&doc).unwrap();
+      let original = PomlParser::from_poml_str(&doc).parse_as_node().unwrap();
+      let parsed = PomlParser::from_poml_str(&formatted).parse_as_node().unwrap();
+      prop_assert_eq!(shape(&parsed), shape(&original));
+      prop_assert_eq!(format_document(&formatted).unwrap(), formatted.clone());
+
+      let render = |doc: &str| {
+        MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::<String, _>::new())
+          .render()
+          .unwrap()
+      };
+      prop_assert_eq!(render(&formatted), render(&doc));
+    }
+  }
+}
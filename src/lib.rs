@@ -4,17 +4,30 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+#[cfg(feature = "arena")]
+pub mod arena;
+#[cfg(feature = "bundle")]
+pub mod bundle;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod doc;
 pub mod error;
+pub mod format;
+pub mod lint;
+pub mod migrate;
+pub mod owned;
 pub mod parser;
 pub mod render;
 
+pub use owned::{PomlNodeOwned, PomlTagNodeOwned};
 use parser::PomlParser;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 /**
  * Data structure that represents a node in POML document.
  */
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum PomlNode<'a> {
   /** A tag node. */
   Tag(PomlTagNode<'a>),
@@ -22,6 +35,8 @@ pub enum PomlNode<'a> {
   Text(&'a str, PomlNodePosition),
   /** Whitespace content, which could be ignore but plays the role of separators. */
   Whitespace(PomlNodePosition),
+  /** Content of a `<![CDATA[ ... ]]>` section, which is rendered verbatim. */
+  CData(&'a str, PomlNodePosition),
 }
 
 impl<'a> PomlNode<'a> {
@@ -37,7 +52,7 @@ impl<'a> PomlNode<'a> {
 /**
  * Original position of a node in the original document.
  */
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PomlNodePosition {
   pub start: usize,
   pub end: usize,
@@ -46,20 +61,91 @@ pub struct PomlNodePosition {
 /**
  * Data structure to represent a POML Tag Node.
  */
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PomlTagNode<'a> {
   pub name: &'a str,
-  pub attributes: Vec<(&'a str, &'a str)>,
+  pub attributes: Vec<(&'a str, AttributeValue<'a>)>,
   pub children: Vec<PomlNode<'a>>,
   pub original_pos: PomlNodePosition,
 }
 
+/**
+ * Value of an attribute, classified by its `{{ }}` interpolations when the document is
+ * parsed. The text is the value without the quotes, sliced from the document, so the
+ * renderer can evaluate it when the attribute is used.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttributeValue<'a> {
+  /** Text without interpolations, like `"intro"`. */
+  Literal(&'a str),
+  /**
+   * A single interpolation making up the value, like `"{{ items }}"` or the unquoted
+   * `{{ items }}`, which evaluates to the value of the expression.
+   */
+  Expression(&'a str),
+  /** Text mixed with interpolations, like `"Hi, {{ name }}"`, which is rendered as text. */
+  Mixed(&'a str),
+}
+
+impl<'a> AttributeValue<'a> {
+  /**
+   * Classify the text of an attribute value, without the quotes.
+   */
+  pub fn parse(text: &'a str) -> AttributeValue<'a> {
+    if !text.contains("{{") {
+      AttributeValue::Literal(text)
+    } else if Self::single_expression(text).is_some() {
+      AttributeValue::Expression(text)
+    } else {
+      AttributeValue::Mixed(text)
+    }
+  }
+
+  /**
+   * Text of the value as written in the document, without the quotes.
+   */
+  pub fn text(&self) -> &'a str {
+    match self {
+      AttributeValue::Literal(text)
+      | AttributeValue::Expression(text)
+      | AttributeValue::Mixed(text) => text,
+    }
+  }
+
+  /**
+   * Expression inside the `{{ }}` of an expression value, like `items` of `{{ items }}`.
+   */
+  pub fn expression(&self) -> Option<&'a str> {
+    match self {
+      AttributeValue::Expression(text) => Self::single_expression(text),
+      _ => None,
+    }
+  }
+
+  fn single_expression(text: &str) -> Option<&str> {
+    let expression = text.trim().strip_prefix("{{")?.strip_suffix("}}")?;
+    if expression.contains("{{") || expression.contains("}}") {
+      None
+    } else {
+      Some(expression)
+    }
+  }
+}
+
 /**
  * Render POML files into Markdown format.
  */
 pub type MarkdownPomlRenderer<'a> = render::Renderer<'a, render::tag_renderer::MarkdownTagRenderer>;
 
 impl<'a> MarkdownPomlRenderer<'a> {
+  /**
+   * Create a builder of Markdown POML Render instances, to configure them before the
+   * document is given.
+   */
+  pub fn builder() -> render::builder::RendererBuilder<render::tag_renderer::MarkdownTagRenderer> {
+    render::builder::RendererBuilder::new(render::tag_renderer::MarkdownTagRenderer::default())
+  }
+
   /**
    * Create a Markdown POML Render instance with the POML document and a context.
    */
@@ -67,13 +153,11 @@ impl<'a> MarkdownPomlRenderer<'a> {
     doc: &'a str,
     context: render::render_context::RenderContext,
   ) -> Self {
-    let parser = PomlParser::from_poml_str(doc);
-    render::Renderer {
-      parser,
+    render::Renderer::new(
+      doc,
       context,
-      tag_renderer: render::tag_renderer::MarkdownTagRenderer {},
-      filename: "<anonymous>".to_string(),
-    }
+      render::tag_renderer::MarkdownTagRenderer::default(),
+    )
   }
 
   /**
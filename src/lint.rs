@@ -0,0 +1,393 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Checks of a document for common issues without rendering it, like unknown tags, nodes
+ * hidden by conditions that are always false and `<let>` variables never used.
+ */
+
+use crate::doc::{get_raw_attribute, offset_in, referenced_variables};
+use crate::error::{Diagnostic, ErrorCode, ErrorPosition, Severity, Span};
+use crate::parser::PomlParser;
+use crate::render::attr::is_truthy;
+use crate::render::expression::tokenize::{ExpressionToken, tokenize_expression};
+use crate::render::options::CONVERSATION_STATE_TAGS;
+use crate::render::render_context::RenderContext;
+use crate::render::tag_renderer::TagRenderer;
+use crate::{PomlNode, PomlTagNode};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/**
+ * Tags handled by the renderer itself, which the tag renderers don't need to support.
+ */
+pub(crate) const RENDERER_TAGS: [&str; 4] = ["let", "include", "template", "summary"];
+
+/**
+ * Attributes naming a variable, file or tag, where surrounding whitespace is likely a typo.
+ */
+const NAME_ATTRIBUTES: [&str; 5] = ["name", "src", "srcset", "params", "syntax"];
+
+/**
+ * Characters rendered as nothing or as a plain space, which are hard to spot in the source.
+ */
+const INVISIBLE_CHARACTERS: [char; 6] = [
+  '\u{00A0}', '\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}',
+];
+
+/**
+ * Names of the lint rules, which the `[lint]` table of the config file accepts besides
+ * the codes.
+ */
+const LINT_RULES: [(&str, ErrorCode); 6] = [
+  ("unknown_tag", ErrorCode::UnknownTag),
+  ("missing_attribute", ErrorCode::MissingAttribute),
+  ("unreachable_node", ErrorCode::UnreachableNode),
+  ("duplicate_variable", ErrorCode::DuplicateVariable),
+  ("unused_variable", ErrorCode::UnusedVariable),
+  ("suspicious_whitespace", ErrorCode::SuspiciousWhitespace),
+];
+
+/**
+ * Level of the lint warnings of a rule.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintLevel {
+  /** The warnings are dropped. */
+  Off,
+  Warning,
+  /** The warnings are reported as errors, e.g. to fail `poml check`. */
+  Error,
+}
+
+impl LintLevel {
+  /**
+   * Parse the level from its name in config files: `off`, `warning` or `error`.
+   */
+  pub fn from_name(name: &str) -> Option<LintLevel> {
+    match name {
+      "off" => Some(LintLevel::Off),
+      "warning" => Some(LintLevel::Warning),
+      "error" => Some(LintLevel::Error),
+      _ => None,
+    }
+  }
+}
+
+/**
+ * Levels of the lint rules, by their codes. Rules without a level are warnings.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LintConfig {
+  pub levels: HashMap<ErrorCode, LintLevel>,
+}
+
+impl LintConfig {
+  /**
+   * Find the code of a lint rule by its code like `E302` or its name like `unused_variable`.
+   */
+  pub fn rule_code(rule: &str) -> Option<ErrorCode> {
+    LINT_RULES
+      .iter()
+      .find(|(name, code)| *name == rule || code.as_str() == rule)
+      .map(|(_, code)| *code)
+  }
+
+  /**
+   * Apply the levels to the warnings. Errors, like the syntax errors, are kept as they are.
+   */
+  pub fn apply(&self, diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+    diagnostics
+      .into_iter()
+      .filter_map(|mut diagnostic| {
+        if diagnostic.severity != Severity::Warning {
+          return Some(diagnostic);
+        }
+        match self.levels.get(&diagnostic.code) {
+          Some(LintLevel::Off) => None,
+          Some(LintLevel::Error) => {
+            diagnostic.severity = Severity::Error;
+            Some(diagnostic)
+          }
+          Some(LintLevel::Warning) | None => Some(diagnostic),
+        }
+      })
+      .collect()
+  }
+}
+
+/**
+ * Check the document for common issues, with the tags supported by the tag renderer. The
+ * issues are warnings, and a document that fails to parse has the syntax error only.
+ */
+pub fn lint<T: TagRenderer>(doc: &str, tag_renderer: &T) -> Vec<Diagnostic> {
+  lint_with_config(doc, tag_renderer, &LintConfig::default())
+}
+
+/**
+ * Check the document like [`lint`], with the levels of the rules in the config.
+ */
+pub fn lint_with_config<T: TagRenderer>(
+  doc: &str,
+  tag_renderer: &T,
+  config: &LintConfig,
+) -> Vec<Diagnostic> {
+  let mut parser = PomlParser::from_poml_str(doc);
+  let root = match parser.parse_as_node() {
+    Ok(root) => root,
+    Err(e) => return vec![e.to_diagnostic()],
+  };
+  let mut linter = Linter {
+    doc,
+    tag_renderer,
+    templates: HashSet::new(),
+    referenced: referenced_variables(doc).unwrap_or_default(),
+    diagnostics: Vec::new(),
+  };
+  linter.collect_templates(&root);
+  linter.visit_tag(&root);
+  config.apply(linter.diagnostics)
+}
+
+struct Linter<'d, T> {
+  doc: &'d str,
+  tag_renderer: &'d T,
+  /** Names of the tags defined by `<template>`. */
+  templates: HashSet<&'d str>,
+  referenced: BTreeSet<String>,
+  diagnostics: Vec<Diagnostic>,
+}
+
+impl<'d, T: TagRenderer> Linter<'d, T> {
+  fn collect_templates(&mut self, tag: &PomlTagNode<'d>) {
+    if tag.name == "template"
+      && let Some(name) = get_raw_attribute(tag, "name")
+    {
+      self.templates.insert(name);
+    }
+    for child in tag.children.iter() {
+      if let PomlNode::Tag(child) = child {
+        self.collect_templates(child);
+      }
+    }
+  }
+
+  fn visit_tag(&mut self, tag: &PomlTagNode<'d>) {
+    if !self.is_known_tag(tag.name) {
+      self.warn(
+        ErrorCode::UnknownTag,
+        format!("Unknown tag <{}> for the tag renderer", tag.name),
+        tag.name,
+      );
+    }
+    if tag.name == "cp" && get_raw_attribute(tag, "caption").is_none() {
+      self.warn(
+        ErrorCode::MissingAttribute,
+        "Missing `caption` attribute for the <cp> tag".to_string(),
+        tag.name,
+      );
+    }
+    for (key, value) in tag.attributes.iter() {
+      let value = value.text();
+      if *key == "if" && is_always_false(value) {
+        self.warn(
+          ErrorCode::UnreachableNode,
+          format!(
+            "<{}> is never rendered, `{value}` is always false",
+            tag.name
+          ),
+          value,
+        );
+      }
+      if NAME_ATTRIBUTES.contains(key) && !value.is_empty() && value.trim() != value {
+        self.warn(
+          ErrorCode::SuspiciousWhitespace,
+          format!("Value of `{key}` has leading or trailing whitespace"),
+          value,
+        );
+      }
+      self.check_invisible_characters(value);
+    }
+    if tag.name == "let"
+      && let Some(name) = get_raw_attribute(tag, "name")
+      && !self.referenced.contains(name.trim())
+    {
+      self.warn(
+        ErrorCode::UnusedVariable,
+        format!("Variable `{name}` is never used"),
+        name,
+      );
+    }
+
+    let mut defined = HashSet::new();
+    for child in tag.children.iter() {
+      match child {
+        PomlNode::Tag(child) => {
+          if child.name == "let"
+            && let Some(name) = get_raw_attribute(child, "name")
+            && !defined.insert(name.trim())
+          {
+            self.warn(
+              ErrorCode::DuplicateVariable,
+              format!("Variable `{name}` is defined more than once"),
+              name,
+            );
+          }
+          self.visit_tag(child);
+        }
+        PomlNode::Text(text, _) | PomlNode::CData(text, _) => self.check_invisible_characters(text),
+        _ => {}
+      }
+    }
+  }
+
+  fn is_known_tag(&self, name: &str) -> bool {
+    RENDERER_TAGS.contains(&name)
+      || CONVERSATION_STATE_TAGS.contains(&name)
+      || self.templates.contains(name)
+      || self.tag_renderer.is_supported_tag(name)
+  }
+
+  fn check_invisible_characters(&mut self, text: &'d str) {
+    for (i, c) in text.char_indices() {
+      if INVISIBLE_CHARACTERS.contains(&c) {
+        self.warn(
+          ErrorCode::SuspiciousWhitespace,
+          format!("Invisible character U+{:04X}", c as u32),
+          &text[i..i + c.len_utf8()],
+        );
+      }
+    }
+  }
+
+  /**
+   * Add a warning located at the part of the document.
+   */
+  fn warn(&mut self, code: ErrorCode, message: String, part: &str) {
+    let position = offset_in(self.doc.as_bytes(), part)
+      .map(|offset| ErrorPosition::from_span(self.doc.as_bytes(), offset, offset + part.len()));
+    self.diagnostics.push(Diagnostic {
+      code,
+      severity: Severity::Warning,
+      message,
+      span: position.as_ref().map(|p| Span {
+        start: p.offset,
+        end: p.end,
+      }),
+      position,
+      help: code.help().map(|help| help.to_string()),
+    });
+  }
+}
+
+/**
+ * Whether the condition is a constant expression evaluated as false, like `false` or `1 > 2`.
+ * Conditions with variables or function calls are never reported.
+ */
+fn is_always_false(condition: &str) -> bool {
+  let Ok(tokens) = tokenize_expression(condition.as_bytes()) else {
+    return false;
+  };
+  let is_constant = tokens.iter().all(|token| match token {
+    ExpressionToken::Ref(name) => matches!(*name, b"true" | b"false" | b"null"),
+    _ => true,
+  });
+  if !is_constant {
+    return false;
+  }
+  let context = RenderContext::from(serde_json::Map::<String, Value>::new());
+  matches!(context.evaluate(condition), Ok(value) if !is_truthy(&value))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::render::tag_renderer::MarkdownTagRenderer;
+
+  #[test]
+  fn test_lint() {
+    let doc = "<poml>
+  <let name=\"tone\" value=\"'formal'\" />
+  <let name=\"unused\" value=\"1\" />
+  <let name=\"tone\" value=\"'casual'\" />
+  <template name=\"card\" params=\"title\"><p>{{ title }}</p></template>
+  <card title=\"A\" />
+  <p if=\"1 > 2\">Never</p>
+  <p if=\"ready\">Maybe in {{ tone }}\u{200B} tone</p>
+  <cp>No caption</cp>
+  <include src=\"footer.poml \" />
+  <history />
+  <unknown>Text</unknown>
+</poml>";
+    let diagnostics = lint(doc, &MarkdownTagRenderer::default());
+    let found: Vec<(&str, usize, usize)> = diagnostics
+      .iter()
+      .map(|d| {
+        let position = d.position.as_ref().unwrap();
+        (d.code.as_str(), position.line, position.col)
+      })
+      .collect();
+    assert_eq!(
+      found,
+      vec![
+        ("E302", 3, 14),
+        ("E301", 4, 14),
+        ("E300", 7, 10),
+        ("E303", 8, 36),
+        ("E105", 9, 4),
+        ("E303", 10, 17),
+        ("E102", 12, 4),
+      ]
+    );
+    assert!(diagnostics.iter().all(|d| d.severity == Severity::Warning));
+    assert_eq!(
+      diagnostics[6].message,
+      "Unknown tag <unknown> for the tag renderer"
+    );
+
+    let config = LintConfig {
+      levels: HashMap::from([
+        (ErrorCode::UnusedVariable, LintLevel::Off),
+        (ErrorCode::SuspiciousWhitespace, LintLevel::Off),
+        (ErrorCode::UnknownTag, LintLevel::Error),
+      ]),
+    };
+    let diagnostics = lint_with_config(doc, &MarkdownTagRenderer::default(), &config);
+    let found: Vec<(&str, Severity)> = diagnostics
+      .iter()
+      .map(|d| (d.code.as_str(), d.severity))
+      .collect();
+    assert_eq!(
+      found,
+      vec![
+        ("E301", Severity::Warning),
+        ("E300", Severity::Warning),
+        ("E105", Severity::Warning),
+        ("E102", Severity::Error),
+      ]
+    );
+    assert_eq!(
+      LintConfig::rule_code("unused_variable"),
+      Some(ErrorCode::UnusedVariable)
+    );
+    assert_eq!(LintConfig::rule_code("E102"), Some(ErrorCode::UnknownTag));
+    assert_eq!(LintConfig::rule_code("E100"), None);
+
+    let diagnostics = lint("<poml><p>Text</poml>", &MarkdownTagRenderer::default());
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].severity, Severity::Error);
+  }
+
+  #[test]
+  fn test_is_always_false() {
+    assert!(is_always_false("false"));
+    assert!(is_always_false("1 > 2"));
+    assert!(is_always_false("null"));
+    assert!(!is_always_false("true"));
+    assert!(!is_always_false("flag"));
+    assert!(!is_always_false("randint(0, 1)"));
+  }
+}
@@ -0,0 +1,296 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::doc::offset_in;
+use crate::error::Result;
+use crate::lint::RENDERER_TAGS;
+use crate::parser::PomlParser;
+use crate::render::options::CONVERSATION_STATE_TAGS;
+use crate::render::tag_renderer::TagRenderer;
+use crate::{PomlNode, PomlTagNode};
+
+/**
+ * An incompatibility found in the document.
+ */
+#[derive(Debug, PartialEq)]
+pub struct MigrationIssue {
+  /** Start offset of the incompatible code in the original document. */
+  pub start: usize,
+  /** End offset of the incompatible code in the original document. */
+  pub end: usize,
+  pub message: String,
+  /** Whether the issue has been rewritten in the migrated document. */
+  pub fixed: bool,
+}
+
+/**
+ * Result of migrating a document.
+ */
+#[derive(Debug)]
+pub struct MigrationReport {
+  /** The migrated document. */
+  pub output: String,
+  pub issues: Vec<MigrationIssue>,
+}
+
+struct Edit {
+  start: usize,
+  end: usize,
+  replacement: String,
+}
+
+/**
+ * Rewrite a POML document written for microsoft/poml into the dialect supported
+ * by this crate, and flag the constructs that can't be rewritten automatically.
+ * Tags are checked against the given tag renderer.
+ */
+pub fn migrate_document<T: TagRenderer>(doc: &str, tag_renderer: &T) -> Result<MigrationReport> {
+  let mut parser = PomlParser::from_poml_str(doc);
+  let root = parser.parse_as_node()?;
+  let mut issues = Vec::new();
+  let mut edits = Vec::new();
+  migrate_tag(doc, &root, tag_renderer, &mut issues, &mut edits);
+
+  edits.sort_by_key(|e| e.start);
+  let mut output = String::with_capacity(doc.len());
+  let mut pos = 0;
+  for edit in edits {
+    output += &doc[pos..edit.start];
+    output += &edit.replacement;
+    pos = edit.end;
+  }
+  output += &doc[pos..];
+  issues.sort_by_key(|i| i.start);
+  Ok(MigrationReport { output, issues })
+}
+
+fn migrate_tag<T: TagRenderer>(
+  doc: &str,
+  tag: &PomlTagNode,
+  tag_renderer: &T,
+  issues: &mut Vec<MigrationIssue>,
+  edits: &mut Vec<Edit>,
+) {
+  if !RENDERER_TAGS.contains(&tag.name)
+    && !CONVERSATION_STATE_TAGS.contains(&tag.name)
+    && !tag_renderer.is_supported_tag(tag.name)
+  {
+    issues.push(MigrationIssue {
+      start: tag.original_pos.start,
+      end: tag.original_pos.end,
+      message: format!("Tag <{}> is not supported.", tag.name),
+      fixed: false,
+    });
+  }
+
+  for (key, value) in tag.attributes.iter() {
+    let Some(key_start) = offset_in(doc.as_bytes(), key) else {
+      continue;
+    };
+    let value = value.text();
+    // Boolean shorthand attributes like `inline` have no value in the document
+    let value_start = offset_in(doc.as_bytes(), value);
+    let value_end = value_start.map_or(key_start + key.len(), |start| {
+      // Quoted values end after the closing quote, unquoted expressions after the braces
+      let quoted = matches!(doc.as_bytes()[start - 1], b'"' | b'\'');
+      start + value.len() + usize::from(quoted)
+    });
+    if *key == "speaker" {
+      // Remove the attribute together with the whitespace before it.
+      let attribute_start = doc[..key_start].trim_end().len();
+      edits.push(Edit {
+        start: attribute_start,
+        end: value_end,
+        replacement: String::new(),
+      });
+      issues.push(MigrationIssue {
+        start: key_start,
+        end: value_end,
+        message: "Attribute `speaker` is not supported and has been removed.".to_string(),
+        fixed: true,
+      });
+      continue;
+    }
+    let Some(value_start) = value_start else {
+      continue;
+    };
+    if is_expression_attribute(tag.name, key) {
+      migrate_expression(value, value_start, issues, edits);
+    } else {
+      migrate_text(value, value_start, issues, edits);
+    }
+  }
+
+  for child in tag.children.iter() {
+    match child {
+      PomlNode::Tag(child_tag) => migrate_tag(doc, child_tag, tag_renderer, issues, edits),
+      PomlNode::Text(text, pos) => migrate_text(text, pos.start, issues, edits),
+      PomlNode::Whitespace(_) | PomlNode::CData(_, _) => {}
+    }
+  }
+}
+
+fn is_expression_attribute(tag_name: &str, key: &str) -> bool {
+  matches!(
+    (tag_name, key),
+    (_, "if") | (_, "for") | ("let", "value") | ("table", "records") | ("summary", "of")
+  )
+}
+
+/**
+ * Migrate the `{{ }}` expressions in a text starting at `offset` of the document.
+ */
+fn migrate_text(
+  text: &str,
+  offset: usize,
+  issues: &mut Vec<MigrationIssue>,
+  edits: &mut Vec<Edit>,
+) {
+  let mut pos = 0;
+  while let Some(start) = text[pos..].find("{{") {
+    let expression_start = pos + start + 2;
+    let Some(len) = text[expression_start..].find("}}") else {
+      break;
+    };
+    let expression = &text[expression_start..expression_start + len];
+    migrate_expression(expression, offset + expression_start, issues, edits);
+    pos = expression_start + len + 2;
+  }
+}
+
+/**
+ * Migrate an expression starting at `offset` of the document.
+ */
+fn migrate_expression(
+  expression: &str,
+  offset: usize,
+  issues: &mut Vec<MigrationIssue>,
+  edits: &mut Vec<Edit>,
+) {
+  let buf = expression.as_bytes();
+  let mut pos = 0;
+  let mut quote: Option<u8> = None;
+  while pos < buf.len() {
+    let c = buf[pos];
+    if let Some(q) = quote {
+      if c == b'\\' {
+        pos += 1;
+      } else if c == q {
+        quote = None;
+      }
+      pos += 1;
+      continue;
+    }
+    match c {
+      b'"' | b'\'' => quote = Some(c),
+      b'=' | b'!' if buf.get(pos + 1) == Some(&b'=') => {
+        if buf.get(pos + 2) == Some(&b'=') {
+          pos += 3;
+          continue;
+        }
+        // Loose equality is not supported, rewrite it to strict equality.
+        let op = if c == b'=' { "==" } else { "!=" };
+        edits.push(Edit {
+          start: offset + pos,
+          end: offset + pos + 2,
+          replacement: format!("{op}="),
+        });
+        issues.push(MigrationIssue {
+          start: offset + pos,
+          end: offset + pos + 2,
+          message: format!("Operator `{op}` is not supported and has been rewritten to `{op}=`."),
+          fixed: true,
+        });
+        pos += 2;
+        continue;
+      }
+      b'.' if pos > 0 && (buf[pos - 1].is_ascii_alphanumeric() || buf[pos - 1] == b')') => {
+        let name_end = pos
+          + 1
+          + buf[pos + 1..]
+            .iter()
+            .take_while(|v| v.is_ascii_alphanumeric() || **v == b'_')
+            .count();
+        let name = &expression[pos + 1..name_end];
+        if buf.get(name_end) == Some(&b'(') {
+          issues.push(MigrationIssue {
+            start: offset + pos,
+            end: offset + name_end,
+            message: format!("JavaScript method call `{name}()` is not supported."),
+            fixed: false,
+          });
+        } else if name == "length" {
+          issues.push(MigrationIssue {
+            start: offset + pos,
+            end: offset + name_end,
+            message: "JavaScript property `length` is not supported.".to_string(),
+            fixed: false,
+          });
+        }
+        pos = name_end;
+        continue;
+      }
+      _ => {}
+    }
+    pos += 1;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::render::tag_renderer::MarkdownTagRenderer;
+
+  #[test]
+  fn test_migrate_speaker_and_equality() {
+    let doc = r#"<poml><p speaker="human" if="a == 1">{{ b != 'x' }}</p></poml>"#;
+    let report = migrate_document(doc, &MarkdownTagRenderer::default()).unwrap();
+    assert_eq!(
+      report.output,
+      r#"<poml><p if="a === 1">{{ b !== 'x' }}</p></poml>"#
+    );
+    assert_eq!(report.issues.len(), 3);
+    assert!(report.issues.iter().all(|i| i.fixed));
+  }
+
+  #[test]
+  fn test_migrate_boolean_shorthand_attributes() {
+    let doc = r#"<poml><code inline speaker>{{ a == 1 }}</code></poml>"#;
+    let report = migrate_document(doc, &MarkdownTagRenderer::default()).unwrap();
+    assert_eq!(
+      report.output,
+      r#"<poml><code inline>{{ a === 1 }}</code></poml>"#
+    );
+  }
+
+  #[test]
+  fn test_migrate_unquoted_expression_attributes() {
+    let doc = r#"<poml><p if={{ a == 1 }} speaker={{ role }}>Hi</p></poml>"#;
+    let report = migrate_document(doc, &MarkdownTagRenderer::default()).unwrap();
+    assert_eq!(report.output, r#"<poml><p if={{ a === 1 }}>Hi</p></poml>"#);
+  }
+
+  #[test]
+  fn test_migrate_flags_unsupported() {
+    let doc =
+      r#"<poml><img src="a.png"/><p>{{ name.toUpperCase() }} {{ items.length }}</p></poml>"#;
+    let report = migrate_document(doc, &MarkdownTagRenderer::default()).unwrap();
+    assert_eq!(report.output, doc);
+    let messages: Vec<&str> = report.issues.iter().map(|i| i.message.as_str()).collect();
+    assert_eq!(
+      messages,
+      [
+        "Tag <img> is not supported.",
+        "JavaScript method call `toUpperCase()` is not supported.",
+        "JavaScript property `length` is not supported.",
+      ]
+    );
+    assert_eq!(
+      &doc[report.issues[1].start..report.issues[1].end],
+      ".toUpperCase"
+    );
+  }
+}
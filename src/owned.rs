@@ -0,0 +1,172 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::{AttributeValue, PomlNode, PomlNodePosition, PomlTagNode};
+use serde::{Deserialize, Serialize};
+
+/**
+ * A node of POML document which owns its content, so it can be stored beyond the source
+ * document and serialized.
+ *
+ * It is serialized as a JSON object with a `type` of `tag`, `text`, `whitespace` or
+ * `cdata`, and the `start` and `end` offsets of the node in the source document.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PomlNodeOwned {
+  Tag(PomlTagNodeOwned),
+  Text {
+    text: String,
+    #[serde(flatten)]
+    pos: PomlNodePosition,
+  },
+  Whitespace(PomlNodePosition),
+  CData {
+    text: String,
+    #[serde(flatten)]
+    pos: PomlNodePosition,
+  },
+}
+
+/**
+ * A tag node which owns its content. See [`PomlNodeOwned`].
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PomlTagNodeOwned {
+  pub name: String,
+  /** Attributes with the text of the values, without the quotes. */
+  pub attributes: Vec<(String, String)>,
+  pub children: Vec<PomlNodeOwned>,
+  #[serde(flatten)]
+  pub original_pos: PomlNodePosition,
+}
+
+impl PomlNode<'_> {
+  /**
+   * Copy the node into a node owning its content.
+   */
+  pub fn to_owned_node(&self) -> PomlNodeOwned {
+    PomlNodeOwned::from(self)
+  }
+}
+
+impl PomlTagNode<'_> {
+  /**
+   * Copy the tag node into a tag node owning its content.
+   */
+  pub fn to_owned_node(&self) -> PomlTagNodeOwned {
+    PomlTagNodeOwned::from(self)
+  }
+}
+
+impl From<&PomlNode<'_>> for PomlNodeOwned {
+  fn from(node: &PomlNode<'_>) -> Self {
+    match node {
+      PomlNode::Tag(tag) => PomlNodeOwned::Tag(tag.into()),
+      PomlNode::Text(text, pos) => PomlNodeOwned::Text {
+        text: text.to_string(),
+        pos: pos.clone(),
+      },
+      PomlNode::Whitespace(pos) => PomlNodeOwned::Whitespace(pos.clone()),
+      PomlNode::CData(text, pos) => PomlNodeOwned::CData {
+        text: text.to_string(),
+        pos: pos.clone(),
+      },
+    }
+  }
+}
+
+impl From<&PomlTagNode<'_>> for PomlTagNodeOwned {
+  fn from(tag: &PomlTagNode<'_>) -> Self {
+    PomlTagNodeOwned {
+      name: tag.name.to_string(),
+      attributes: tag
+        .attributes
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.text().to_string()))
+        .collect(),
+      children: tag.children.iter().map(PomlNodeOwned::from).collect(),
+      original_pos: tag.original_pos.clone(),
+    }
+  }
+}
+
+impl PomlNodeOwned {
+  /**
+   * Borrow the node as a [`PomlNode`], e.g. to analyze a cached node.
+   */
+  pub fn as_node(&self) -> PomlNode<'_> {
+    match self {
+      PomlNodeOwned::Tag(tag) => PomlNode::Tag(tag.as_node()),
+      PomlNodeOwned::Text { text, pos } => PomlNode::Text(text, pos.clone()),
+      PomlNodeOwned::Whitespace(pos) => PomlNode::Whitespace(pos.clone()),
+      PomlNodeOwned::CData { text, pos } => PomlNode::CData(text, pos.clone()),
+    }
+  }
+}
+
+impl PomlTagNodeOwned {
+  /**
+   * Borrow the tag node as a [`PomlTagNode`].
+   */
+  pub fn as_node(&self) -> PomlTagNode<'_> {
+    PomlTagNode {
+      name: &self.name,
+      attributes: self
+        .attributes
+        .iter()
+        .map(|(k, v)| (k.as_str(), AttributeValue::parse(v)))
+        .collect(),
+      children: self.children.iter().map(PomlNodeOwned::as_node).collect(),
+      original_pos: self.original_pos.clone(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::PomlParser;
+
+  #[test]
+  fn test_owned_node_round_trip() {
+    let doc = r#"<poml><p class='a'>Hello, {{ name }}!</p> <![CDATA[x < y]]></poml>"#;
+    let owned = {
+      let mut parser = PomlParser::from_poml_str(doc);
+      parser.parse_as_node().unwrap().to_owned_node()
+    };
+    let json = serde_json::to_string(&owned).unwrap();
+    let deserialized: PomlTagNodeOwned = serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, owned);
+
+    let mut parser = PomlParser::from_poml_str(doc);
+    assert_eq!(owned.as_node(), parser.parse_as_node().unwrap());
+    let PomlNodeOwned::Tag(p) = &owned.children[0] else {
+      panic!()
+    };
+    assert_eq!(p.attributes, vec![("class".to_string(), "a".to_string())]);
+
+    let json = serde_json::to_value(PomlNodeOwned::Tag(owned)).unwrap();
+    assert_eq!(json["type"], "tag");
+    assert_eq!(json["start"], 0);
+    assert_eq!(json["children"][0]["attributes"][0][1], "a");
+    assert_eq!(json["children"][0]["children"][0]["type"], "text");
+    assert_eq!(json["children"][1]["type"], "whitespace");
+    assert_eq!(json["children"][2]["type"], "cdata");
+    assert_eq!(json["children"][2]["text"], "x < y");
+  }
+
+  #[test]
+  fn test_deserialize_invalid_node() {
+    assert!(serde_json::from_str::<PomlNodeOwned>(r#"{"type": "tag"}"#).is_err());
+    assert!(
+      serde_json::from_str::<PomlNodeOwned>(r#"{"type": "text", "start": 0, "end": 1}"#).is_err()
+    );
+    assert!(
+      serde_json::from_str::<PomlNodeOwned>(r#"{"type": "x", "start": 0, "end": 1}"#).is_err()
+    );
+  }
+}
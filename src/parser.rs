@@ -4,8 +4,22 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use crate::error::{Error, ErrorKind, Result};
-use crate::{PomlNode, PomlNodePosition, PomlTagNode};
+use crate::error::{Error, ErrorCode, ErrorKind, ErrorPosition, Result};
+use crate::{AttributeValue, PomlNode, PomlNodePosition, PomlTagNode};
+
+const CDATA_START: &[u8] = b"<![CDATA[";
+const CDATA_END: &[u8] = b"]]>";
+
+/**
+ * Value of the attributes declared without a value like `<code inline>`.
+ * It's not a slice of the document.
+ */
+pub const BOOLEAN_SHORTHAND_VALUE: AttributeValue<'static> = AttributeValue::Literal("true");
+
+/**
+ * Default maximum nesting depth of the tags, which bounds the recursion over the parsed tree.
+ */
+pub const DEFAULT_MAX_DEPTH: usize = 1024;
 
 #[derive(Debug, PartialEq)]
 pub enum PomlElementKind {
@@ -13,6 +27,7 @@ pub enum PomlElementKind {
   Text,
   Whitespace,
   Comment,
+  CData,
 }
 
 #[derive(Debug, PartialEq)]
@@ -22,28 +37,87 @@ pub struct PomlElement {
   pub end_pos: usize,
 }
 
+/**
+ * Builder of the tree of a document from its nodes in the order they appear, which lets
+ * the parser build other trees than `PomlTagNode`, like the trees in an arena.
+ */
+pub(crate) trait TreeBuilder<'a> {
+  type Tag;
+
+  /** Create the tag node of the parsed tag, which has no children yet. */
+  fn tag(&self, tag: PomlTagNode<'a>) -> Self::Tag;
+
+  fn tag_name(tag: &Self::Tag) -> &'a str;
+
+  fn tag_start(tag: &Self::Tag) -> usize;
+
+  fn set_tag_end(tag: &mut Self::Tag, end: usize);
+
+  /** Append the closed tag to the children of the parent. */
+  fn push_tag(&self, parent: &mut Self::Tag, tag: Self::Tag);
+
+  /** Append the text, whitespace or CDATA node to the children of the parent. */
+  fn push_content(&self, parent: &mut Self::Tag, content: PomlNode<'a>);
+}
+
+/**
+ * Builder of the `PomlTagNode` tree returned by `PomlParser::parse_as_node`.
+ */
+struct NodeTreeBuilder {}
+
+impl<'a> TreeBuilder<'a> for NodeTreeBuilder {
+  type Tag = PomlTagNode<'a>;
+
+  fn tag(&self, tag: PomlTagNode<'a>) -> PomlTagNode<'a> {
+    tag
+  }
+
+  fn tag_name(tag: &PomlTagNode<'a>) -> &'a str {
+    tag.name
+  }
+
+  fn tag_start(tag: &PomlTagNode<'a>) -> usize {
+    tag.original_pos.start
+  }
+
+  fn set_tag_end(tag: &mut PomlTagNode<'a>, end: usize) {
+    tag.original_pos.end = end;
+  }
+
+  fn push_tag(&self, parent: &mut PomlTagNode<'a>, tag: PomlTagNode<'a>) {
+    parent.children.push(PomlNode::Tag(tag));
+  }
+
+  fn push_content(&self, parent: &mut PomlTagNode<'a>, content: PomlNode<'a>) {
+    parent.children.push(content);
+  }
+}
+
 #[derive(Debug)]
 pub struct PomlParser<'a> {
   pub buf: &'a [u8],
   pub pos: usize,
   pub line_end_pos: Vec<usize>,
+  /** Maximum nesting depth of the tags, beyond which the parsing fails */
+  pub max_depth: usize,
 }
 
 impl<'a> PomlParser<'a> {
   pub fn from_poml_str(s: &'a str) -> PomlParser<'a> {
     let buf = s.as_bytes();
     let mut line_end_pos = Vec::new();
-    let mut first_not_space = None;
+    // A byte order mark before the root tag is skipped like the leading spaces
+    let first_not_space = s
+      .char_indices()
+      .find(|(_, c)| !c.is_whitespace() && *c != '\u{feff}')
+      .map(|(pos, _)| pos);
     {
       for (pos, item) in buf.iter().enumerate() {
-        if first_not_space.is_none() && !item.is_ascii_whitespace() {
-          first_not_space = Some(pos);
-        }
         if *item == b'\n' {
           line_end_pos.push(pos);
         }
       }
-      if buf[buf.len() - 1] != b'\n' {
+      if buf.last() != Some(&b'\n') {
         line_end_pos.push(buf.len());
       }
     }
@@ -52,15 +126,30 @@ impl<'a> PomlParser<'a> {
       buf,
       pos: first_not_space.unwrap_or(buf.len()),
       line_end_pos,
+      max_depth: DEFAULT_MAX_DEPTH,
     }
   }
 
+  /**
+   * Parse the document as a tree of nodes under the root tag. Any malformed document,
+   * including empty and truncated ones, returns an error instead of panicking.
+   */
   pub fn parse_as_node(&mut self) -> Result<PomlTagNode<'a>> {
-    let elements = self.parse_as_elements()?;
-    let mut node_stack: Vec<PomlTagNode> = Vec::new();
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("poml_parse", bytes = self.buf.len()).entered();
+    self.build_tree(&NodeTreeBuilder {})
+  }
+
+  /**
+   * Build the tree of the document in one pass over its elements, without collecting the
+   * elements first.
+   */
+  pub(crate) fn build_tree<B: TreeBuilder<'a>>(&mut self, builder: &B) -> Result<B::Tag> {
+    let mut node_stack: Vec<B::Tag> = Vec::new();
     let mut added_poml_root = false;
 
-    for element in elements.iter() {
+    while let Some(element) = self.next_element()? {
+      let element = &element;
       match element.kind {
         PomlElementKind::Text => {
           let last_node = match node_stack.last_mut() {
@@ -68,11 +157,10 @@ impl<'a> PomlParser<'a> {
             None => {
               return Err(Error {
                 kind: ErrorKind::ParserError,
-                message: format!(
-                  "Text appears at position {:?} without a node",
-                  self.get_line_and_col_from_pos(element.start_pos)
-                ),
+                message: "Text appears without a node".to_string(),
                 source: None,
+                position: Some(ErrorPosition::from_offset(self.buf, element.start_pos)),
+                code: Some(ErrorCode::Syntax),
               });
             }
           };
@@ -84,7 +172,7 @@ impl<'a> PomlParser<'a> {
               end: element.end_pos,
             },
           );
-          last_node.children.push(text_node);
+          builder.push_content(last_node, text_node);
         }
         PomlElementKind::Whitespace => {
           let last_node = match node_stack.last_mut() {
@@ -92,11 +180,10 @@ impl<'a> PomlParser<'a> {
             None => {
               return Err(Error {
                 kind: ErrorKind::ParserError,
-                message: format!(
-                  "Whitespace appears at position {:?} without a node",
-                  self.get_line_and_col_from_pos(element.start_pos)
-                ),
+                message: "Whitespace appears without a node".to_string(),
                 source: None,
+                position: Some(ErrorPosition::from_offset(self.buf, element.start_pos)),
+                code: Some(ErrorCode::Syntax),
               });
             }
           };
@@ -104,14 +191,14 @@ impl<'a> PomlParser<'a> {
             start: element.start_pos,
             end: element.end_pos,
           });
-          last_node.children.push(whitespace_node);
+          builder.push_content(last_node, whitespace_node);
         }
         PomlElementKind::Tag => {
           if self.is_self_close_tag_element(element) {
             let tag = self.create_tag_from_element(element)?;
             if node_stack.is_empty() {
               if tag.name != "poml" {
-                node_stack.push(PomlTagNode {
+                node_stack.push(builder.tag(PomlTagNode {
                   name: "poml",
                   attributes: vec![],
                   children: vec![],
@@ -119,19 +206,21 @@ impl<'a> PomlParser<'a> {
                     start: element.start_pos,
                     end: element.end_pos,
                   },
-                });
+                }));
                 added_poml_root = true;
               } else {
                 return Err(Error {
                   kind: ErrorKind::ParserError,
                   message: "<poml> tag should not close itself.".to_string(),
                   source: None,
+                  position: Some(ErrorPosition::from_offset(self.buf, element.start_pos)),
+                  code: Some(ErrorCode::Syntax),
                 });
               }
             }
             match node_stack.last_mut() {
               Some(l) => {
-                l.children.push(PomlNode::Tag(tag));
+                builder.push_tag(l, builder.tag(tag));
               }
               None => {
                 unreachable!()
@@ -144,31 +233,31 @@ impl<'a> PomlParser<'a> {
               None => {
                 return Err(Error {
                   kind: ErrorKind::ParserError,
-                  message: format!(
-                    "Close tag appears without an open tag at position {:?}",
-                    self.get_line_and_col_from_pos(element.start_pos)
-                  ),
+                  message: "Close tag appears without an open tag".to_string(),
                   source: None,
+                  position: Some(ErrorPosition::from_offset(self.buf, element.start_pos)),
+                  code: Some(ErrorCode::MismatchedCloseTag),
                 });
               }
             };
             let (tag_name, _) = self.consume_key_str(element.start_pos + 2);
-            if tag_name != node_to_close.name {
+            if tag_name != B::tag_name(&node_to_close) {
               return Err(Error {
                 kind: ErrorKind::ParserError,
                 message: format!(
-                  "Close tag of </{}> appears at position {:?}, but the open tag is <{}>",
+                  "Close tag of </{}> appears, but the open tag is <{}>",
                   tag_name,
-                  self.get_line_and_col_from_pos(element.start_pos),
-                  node_to_close.name
+                  B::tag_name(&node_to_close)
                 ),
                 source: None,
+                position: Some(ErrorPosition::from_offset(self.buf, element.start_pos)),
+                code: Some(ErrorCode::MismatchedCloseTag),
               });
             }
-            node_to_close.original_pos.end = element.end_pos;
+            B::set_tag_end(&mut node_to_close, element.end_pos);
             match node_stack.last_mut() {
               Some(l) => {
-                l.children.push(PomlNode::Tag(node_to_close));
+                builder.push_tag(l, node_to_close);
               }
               None => {
                 return Ok(node_to_close);
@@ -177,7 +266,7 @@ impl<'a> PomlParser<'a> {
           } else {
             let tag = self.create_tag_from_element(element)?;
             if node_stack.is_empty() && tag.name != "poml" {
-              node_stack.push(PomlTagNode {
+              node_stack.push(builder.tag(PomlTagNode {
                 name: "poml",
                 attributes: vec![],
                 children: vec![],
@@ -185,12 +274,52 @@ impl<'a> PomlParser<'a> {
                   start: 0,
                   end: self.buf.len(),
                 },
-              });
+              }));
               added_poml_root = true;
             }
-            node_stack.push(tag);
+            if node_stack.len() >= self.max_depth {
+              return Err(Error {
+                kind: ErrorKind::ParserError,
+                message: format!(
+                  "Tag is nested deeper than the limit of {} tags",
+                  self.max_depth
+                ),
+                source: None,
+                position: Some(ErrorPosition::from_offset(self.buf, element.start_pos)),
+                code: Some(ErrorCode::LimitExceeded),
+              });
+            }
+            node_stack.push(builder.tag(tag));
           }
         }
+        PomlElementKind::CData => {
+          let last_node = match node_stack.last_mut() {
+            Some(l) => l,
+            None => {
+              return Err(Error {
+                kind: ErrorKind::ParserError,
+                message: "CDATA section appears without a node".to_string(),
+                source: None,
+                position: Some(ErrorPosition::from_offset(self.buf, element.start_pos)),
+                code: Some(ErrorCode::Syntax),
+              });
+            }
+          };
+          // Strip the `<![CDATA[` and `]]>` markers.
+          let content_start = element.start_pos + CDATA_START.len();
+          let content_end = element.end_pos - CDATA_END.len();
+          let text = str::from_utf8(&self.buf[content_start..content_end]).unwrap();
+          builder.push_content(
+            last_node,
+            PomlNode::CData(
+              text,
+              PomlNodePosition {
+                start: element.start_pos,
+                end: element.end_pos,
+              },
+            ),
+          );
+        }
         PomlElementKind::Comment => {
           continue;
         }
@@ -199,59 +328,81 @@ impl<'a> PomlParser<'a> {
 
     if node_stack.len() == 1 && added_poml_root {
       Ok(node_stack.pop().unwrap())
+    } else if node_stack.is_empty() {
+      Err(Error {
+        kind: ErrorKind::ParserError,
+        message: "Document is empty".to_owned(),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::EmptyDocument),
+      })
     } else {
       Err(Error {
         kind: ErrorKind::ParserError,
         message: "Document has not finished at the end".to_owned(),
         source: None,
+        position: node_stack
+          .last()
+          .map(|node| ErrorPosition::from_offset(self.buf, B::tag_start(node))),
+        code: Some(ErrorCode::UnclosedTag),
       })
     }
   }
 
   fn create_tag_from_element(&self, element: &PomlElement) -> Result<PomlTagNode<'a>> {
+    // Attributes never extend beyond the element, even if the element is truncated.
+    let end = element.end_pos.min(self.buf.len());
     let (tag_name, mut pos) = self.consume_key_str(element.start_pos + 1);
-    let mut attributes: Vec<(&'a str, &'a str)> = Vec::new();
+    if tag_name.is_empty() {
+      return Err(Error {
+        kind: ErrorKind::ParserError,
+        message: "Expect a tag name, but not found.".to_string(),
+        source: None,
+        position: Some(ErrorPosition::from_offset(self.buf, pos)),
+        code: Some(ErrorCode::Syntax),
+      });
+    }
+    let mut attributes: Vec<(&'a str, AttributeValue<'a>)> = Vec::new();
     loop {
       pos = self.consume_space(pos);
-      if self.buf[pos].is_ascii_alphanumeric() {
+      if self.byte_at(pos, end)?.is_ascii_alphanumeric() {
         let (attribute_name, next_pos) = self.consume_key_str(pos);
         if attributes.iter().any(|v| v.0 == attribute_name) {
           return Err(Error {
             kind: ErrorKind::ParserError,
-            message: format!(
-              "Duplicate attribute key at position {:?}: {}",
-              self.get_line_and_col_from_pos(pos),
-              attribute_name
-            ),
+            message: format!("Duplicate attribute key: {}", attribute_name),
             source: None,
+            position: Some(ErrorPosition::from_offset(self.buf, pos)),
+            code: Some(ErrorCode::DuplicateAttribute),
           });
         }
         pos = self.consume_space(next_pos);
-        // Expect to see '='
-        if self.buf[pos] != b'=' {
-          return Err(Error {
-            kind: ErrorKind::ParserError,
-            message: format!(
-              "Expect '=' for attribute declaration at position {:?}, but not found.",
-              self.get_line_and_col_from_pos(pos)
-            ),
-            source: None,
-          });
+        // Attributes without '=', like `<code inline>`, are shorthands of `="true"`
+        if self.byte_at(pos, end)? != b'=' {
+          attributes.push((attribute_name, BOOLEAN_SHORTHAND_VALUE));
+          continue;
         }
         pos = self.consume_space(pos + 1);
-        // Expect to see '"' as the start of a string literal
-        if self.buf[pos] != b'"' {
+        // Unquoted values are single expressions like `items={{ items }}`
+        if pos < end && self.buf[pos..end].starts_with(b"{{") {
+          let (attribute_value, next_pos) = self.consume_value_expression(pos, end)?;
+          attributes.push((attribute_name, AttributeValue::Expression(attribute_value)));
+          pos = next_pos;
+          continue;
+        }
+        // Expect to see '"' or '\'' as the start of a string literal
+        let quote = self.byte_at(pos, end)?;
+        if quote != b'"' && quote != b'\'' {
           return Err(Error {
             kind: ErrorKind::ParserError,
-            message: format!(
-              "Expect '\"' for attribute value at position {:?}, but not found.",
-              self.get_line_and_col_from_pos(pos)
-            ),
+            message: "Expect '\"', '\'' or '{{' for attribute value, but not found.".to_string(),
             source: None,
+            position: Some(ErrorPosition::from_offset(self.buf, pos)),
+            code: Some(ErrorCode::InvalidAttributeSyntax),
           });
         }
-        let (attribute_value, next_pos) = self.consume_value_str_literal(pos)?;
-        attributes.push((attribute_name, attribute_value));
+        let (attribute_value, next_pos) = self.consume_value_str_literal(pos, end)?;
+        attributes.push((attribute_name, AttributeValue::parse(attribute_value)));
         pos = next_pos
       } else {
         break;
@@ -286,7 +437,8 @@ impl<'a> PomlParser<'a> {
   }
 
   /**
-   * Consume a key (tag name or attribute name) str.
+   * Consume a key (tag name or attribute name) str. Besides alphanumeric characters, `-`
+   * and `_`, keys can contain `:` and `.`, like `xml:lang` and `data.priority`.
    * Return the key str reference and the next position.
    */
   fn consume_key_str(&self, pos: usize) -> (&'a str, usize) {
@@ -294,7 +446,7 @@ impl<'a> PomlParser<'a> {
     let mut next_pos = pos;
     while next_pos < buf.len() {
       let c = char::from(buf[next_pos]);
-      if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+      if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.') {
         next_pos += 1
       } else {
         break;
@@ -304,46 +456,72 @@ impl<'a> PomlParser<'a> {
   }
 
   /**
-   * Consume a value string literal.
+   * Get the byte at the position, which should be before `end`.
+   */
+  fn byte_at(&self, pos: usize, end: usize) -> Result<u8> {
+    if pos < end {
+      Ok(self.buf[pos])
+    } else {
+      Err(self.unexpected_end_of_input(pos))
+    }
+  }
+
+  fn unexpected_end_of_input(&self, pos: usize) -> Error {
+    Error {
+      kind: ErrorKind::ParserError,
+      message: "Unexpected end of input".to_string(),
+      source: None,
+      position: Some(ErrorPosition::from_offset(self.buf, pos)),
+      code: Some(ErrorCode::UnexpectedEndOfInput),
+    }
+  }
+
+  /**
+   * Consume a value string literal, which should end before `end`.
    *
-   * Return the value str reference and the next position after the ending quote.
+   * Return the value str reference without the quotes and the next position after the
+   * ending quote.
    */
-  fn consume_value_str_literal(&self, pos: usize) -> Result<(&'a str, usize)> {
+  fn consume_value_str_literal(&self, pos: usize, end: usize) -> Result<(&'a str, usize)> {
     let buf = self.buf;
-    if buf[pos] != b'"' {
+    let quote = self.byte_at(pos, end)?;
+    if quote != b'"' && quote != b'\'' {
       return Err(Error {
         kind: ErrorKind::ParserError,
         message: format!(
-          "Expect to see '\"' as the start of a literal at position {:?}, but found {}",
-          self.get_line_and_col_from_pos(pos),
+          "Expect to see '\"' or '\'' as the start of a literal, but found {}",
           buf[pos]
         ),
         source: None,
+        position: Some(ErrorPosition::from_offset(self.buf, pos)),
+        code: Some(ErrorCode::InvalidAttributeSyntax),
       });
     }
     let mut next_pos = pos + 1;
-    while next_pos < buf.len() {
+    while next_pos < end {
       match buf[next_pos] {
         b'\\' => {
           next_pos += 2;
         }
-        b'"' => break,
+        c if c == quote => break,
         _ => next_pos += 1,
       }
     }
-    if buf[next_pos] == b'\"' {
+    if next_pos >= end {
+      return Err(self.unexpected_end_of_input(next_pos));
+    }
+    if buf[next_pos] == quote {
       Ok((
-        str::from_utf8(&buf[pos..next_pos + 1]).unwrap(),
+        str::from_utf8(&buf[pos + 1..next_pos]).unwrap(),
         next_pos + 1,
       ))
     } else {
       Err(Error {
         kind: ErrorKind::ParserError,
-        message: format!(
-          "String literal has not reach an end at position {:?}",
-          self.get_line_and_col_from_pos(next_pos)
-        ),
+        message: "String literal has not reach an end".to_string(),
         source: None,
+        position: Some(ErrorPosition::from_offset(self.buf, next_pos)),
+        code: Some(ErrorCode::UnexpectedEndOfInput),
       })
     }
   }
@@ -366,6 +544,40 @@ impl<'a> PomlParser<'a> {
     next_pos
   }
 
+  /**
+   * Consume Unicode whitespaces, like non-breaking and full-width spaces, between the tags
+   * and texts.
+   * Return the first non-space character position at or after the `pos`
+   */
+  fn consume_whitespace(&self, pos: usize) -> usize {
+    let mut next_pos = pos;
+    while let Some(c) = self.char_at(next_pos) {
+      if c.is_whitespace() {
+        next_pos += c.len_utf8();
+      } else {
+        break;
+      }
+    }
+    next_pos
+  }
+
+  /**
+   * Decode the UTF-8 character starting at the `pos`.
+   */
+  fn char_at(&self, pos: usize) -> Option<char> {
+    let width = match *self.buf.get(pos)? {
+      b if b < 0x80 => 1,
+      b if b >= 0xf0 => 4,
+      b if b >= 0xe0 => 3,
+      _ => 2,
+    };
+    str::from_utf8(self.buf.get(pos..pos + width)?)
+      .ok()?
+      .chars()
+      .next()
+  }
+
+  #[cfg(test)]
   pub(crate) fn parse_as_elements(&mut self) -> Result<Vec<PomlElement>> {
     let mut elements = Vec::new();
     while let Some(e) = self.next_element()? {
@@ -376,11 +588,11 @@ impl<'a> PomlParser<'a> {
 
   fn next_element(&mut self) -> Result<Option<PomlElement>> {
     if self.pos < self.buf.len() {
-      if self.pos + 4 < self.buf.len() && self.buf[self.pos..self.pos + 4] == *b"<!--" {
+      if self.buf[self.pos..].starts_with(b"<!--") {
         // Comment element
         self.pos += 4;
         let mut end = self.pos;
-        while end + 3 < self.buf.len() {
+        while end + 3 <= self.buf.len() {
           if self.buf[end..end + 3] == *b"-->" {
             self.pos = end + 3;
             return Ok(Some(PomlElement {
@@ -393,18 +605,41 @@ impl<'a> PomlParser<'a> {
         }
         return Err(Error {
           kind: ErrorKind::ParserError,
-          message: format!(
-            "Comment not terminated at position {:?}",
-            self.get_line_and_col_from_pos(self.pos)
-          ),
+          message: "Comment not terminated".to_string(),
           source: None,
+          position: Some(ErrorPosition::from_offset(self.buf, self.pos)),
+          code: Some(ErrorCode::UnexpectedEndOfInput),
         });
       }
-      let c = char::from(self.buf[self.pos]);
+      if self.buf[self.pos..].starts_with(CDATA_START) {
+        let start_pos = self.pos;
+        let content_start = start_pos + CDATA_START.len();
+        let Some(len) = self.buf[content_start..]
+          .windows(CDATA_END.len())
+          .position(|w| w == CDATA_END)
+        else {
+          return Err(Error {
+            kind: ErrorKind::ParserError,
+            message: "CDATA section not terminated".to_string(),
+            source: None,
+            position: Some(ErrorPosition::from_offset(self.buf, start_pos)),
+            code: Some(ErrorCode::UnexpectedEndOfInput),
+          });
+        };
+        self.pos = content_start + len + CDATA_END.len();
+        return Ok(Some(PomlElement {
+          kind: PomlElementKind::CData,
+          start_pos,
+          end_pos: self.pos,
+        }));
+      }
+      let c = self
+        .char_at(self.pos)
+        .unwrap_or(char::REPLACEMENT_CHARACTER);
       match c {
-        c if c.is_ascii_whitespace() => {
+        c if c.is_whitespace() => {
           let start_pos = self.pos;
-          let end_pos = self.consume_space(self.pos);
+          let end_pos = self.consume_whitespace(self.pos);
           self.pos = end_pos;
           return Ok(Some(PomlElement {
             kind: PomlElementKind::Whitespace,
@@ -419,8 +654,10 @@ impl<'a> PomlParser<'a> {
             None => {
               return Err(Error {
                 kind: ErrorKind::ParserError,
-                message: format!("Tag starting from {start_pos} is not complete"),
+                message: "Unexpected end of input: tag is not complete".to_string(),
                 source: None,
+                position: Some(ErrorPosition::from_offset(self.buf, start_pos)),
+                code: Some(ErrorCode::UnexpectedEndOfInput),
               });
             }
           };
@@ -433,7 +670,13 @@ impl<'a> PomlParser<'a> {
         }
         _ => {
           let start_pos = self.pos;
-          let end_pos = self.seek_end_of_text(start_pos + 1);
+          let mut end_pos = self.seek_end_of_text(start_pos + 1);
+          if matches!(self.buf.get(end_pos), Some(b'\r' | b'\n')) {
+            // Spaces ending the line belong to the whitespace of the line break, so they
+            // are collapsed with it
+            let text = str::from_utf8(&self.buf[start_pos..end_pos]).unwrap();
+            end_pos = start_pos + text.trim_end().len();
+          }
           self.pos = end_pos;
           return Ok(Some(PomlElement {
             kind: PomlElementKind::Text,
@@ -446,24 +689,56 @@ impl<'a> PomlParser<'a> {
     Ok(None)
   }
 
+  /**
+   * Consume an unquoted value of a single `{{ }}` expression, which should end before `end`.
+   *
+   * Return the value str reference with the braces and the next position after them.
+   */
+  fn consume_value_expression(&self, pos: usize, end: usize) -> Result<(&'a str, usize)> {
+    let close = self.buf[pos + 2..end]
+      .windows(2)
+      .position(|w| w == b"}}")
+      .map(|offset| pos + 2 + offset);
+    let Some(close) = close else {
+      return Err(self.unexpected_end_of_input(end));
+    };
+    if self.buf[pos + 2..close].windows(2).any(|w| w == b"{{") {
+      return Err(Error {
+        kind: ErrorKind::ParserError,
+        message: "Unquoted attribute value should be a single expression".to_string(),
+        source: None,
+        position: Some(ErrorPosition::from_offset(self.buf, pos)),
+        code: Some(ErrorCode::InvalidAttributeSyntax),
+      });
+    }
+    Ok((
+      str::from_utf8(&self.buf[pos..close + 2]).unwrap(),
+      close + 2,
+    ))
+  }
+
   fn seek_gt_char(&self, pos: usize) -> Option<usize> {
     let mut pos = pos;
-    let mut in_string = false;
+    let mut quote: Option<u8> = None;
     while pos < self.buf.len() {
       match self.buf[pos] {
-        b'>' => {
-          if !in_string {
-            return Some(pos + 1);
-          }
+        b'>' if quote.is_none() => {
+          return Some(pos + 1);
         }
-        b'"' => {
-          in_string = !in_string;
+        // `>` in unquoted expression values like `if={{ a > b }}` doesn't end the tag
+        b'{' if quote.is_none() && self.buf[pos..].starts_with(b"{{") => {
+          let close = self.buf[pos + 2..].windows(2).position(|w| w == b"}}")?;
+          pos += close + 3;
         }
-        b'\\' => {
-          if in_string {
-            // skip next character due to escape
-            pos += 1;
-          }
+        c @ (b'"' | b'\'') if quote.is_none() => {
+          quote = Some(c);
+        }
+        c if quote == Some(c) => {
+          quote = None;
+        }
+        b'\\' if quote.is_some() => {
+          // skip next character due to escape
+          pos += 1;
         }
         _ => {}
       }
@@ -486,40 +761,12 @@ impl<'a> PomlParser<'a> {
     }
     pos
   }
-
-  /**
-   * Get the line and col number from the postion value for error message.
-   *
-   * All numbers are indexed from 0.
-   */
-  fn get_line_and_col_from_pos(&self, pos: usize) -> (usize, usize) {
-    if pos >= self.buf.len() {
-      return (self.line_end_pos.len(), 0);
-    }
-    let mut l = 0;
-    let mut r = self.line_end_pos.len() - 1;
-    while l < r {
-      let mid = (l + r) / 2;
-      if self.line_end_pos[mid] < pos {
-        l = mid + 1;
-      } else {
-        r = mid;
-      }
-    }
-    let line_number = l;
-    let offset = if line_number != 0 {
-      self.line_end_pos[line_number - 1]
-    } else {
-      0
-    };
-    let col = pos - offset;
-    (line_number, col)
-  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use proptest::prelude::*;
   #[test]
   fn tokenize_simple_poml() {
     let doc = r#"<poml syntax="markdown"><p> Hello, {{ name }}! </p></poml>"#;
@@ -569,7 +816,7 @@ mod tests {
     assert_eq!(node.attributes.len(), 1);
     assert_eq!(
       node.attributes.first().unwrap(),
-      &("syntax", "\"markdown\"")
+      &("syntax", AttributeValue::Literal("markdown"))
     );
     let tag_children: Vec<&PomlNode> = node.children.iter().filter(|v| v.is_tag()).collect();
     assert_eq!(tag_children.len(), 1);
@@ -597,6 +844,59 @@ mod tests {
     assert!(node.is_err());
   }
 
+  #[test]
+  fn parse_truncated_tag_at_eof() {
+    for doc in [
+      "<",
+      "<p",
+      "<p foo",
+      "<p foo=",
+      r#"<p foo="bar"#,
+      r#"<p foo='bar"#,
+      r#"<p foo="bar\"#,
+      r#"<p foo="a\">"#,
+      "<poml><p foo=\"bar\">text</p><p bar=",
+    ] {
+      let mut parser = PomlParser::from_poml_str(doc);
+      let err = parser.parse_as_node().unwrap_err();
+      assert!(
+        err.message.contains("Unexpected end of input"),
+        "{doc}: {}",
+        err.message
+      );
+    }
+  }
+
+  #[test]
+  fn create_tag_from_truncated_element() {
+    // Elements normally end with '>', but the attribute parsing must not read past the
+    // element even if they don't.
+    for doc in [
+      "<p",
+      "<p foo",
+      "<p foo=",
+      r#"<p foo="bar"#,
+      r#"<p foo="bar\"#,
+    ] {
+      let parser = PomlParser::from_poml_str(doc);
+      let element = PomlElement {
+        kind: PomlElementKind::Tag,
+        start_pos: 0,
+        end_pos: doc.len(),
+      };
+      let err = parser.create_tag_from_element(&element).unwrap_err();
+      assert!(err.message.contains("Unexpected end of input"), "{doc}");
+    }
+    let doc = r#"<p foo="bar">"#;
+    let parser = PomlParser::from_poml_str(doc);
+    let element = PomlElement {
+      kind: PomlElementKind::Tag,
+      start_pos: 0,
+      end_pos: 10,
+    };
+    assert!(parser.create_tag_from_element(&element).is_err());
+  }
+
   #[test]
   fn parse_close_tag_without_open() {
     let doc = r#"
@@ -608,7 +908,10 @@ mod tests {
     let node = parser.parse_as_node();
     assert!(node.is_err());
     let err = node.unwrap_err();
-    assert!(err.message.contains("position (2, 37)"))
+    let position = err.position().unwrap();
+    assert_eq!((position.line, position.col), (3, 37));
+    assert!(err.to_string().contains("(line 3, column 37)"), "{err}");
+    assert!(!err.to_string().contains("at position"), "{err}");
   }
 
   #[test]
@@ -622,6 +925,201 @@ mod tests {
     assert_eq!(node.children.iter().filter(|v| v.is_tag()).count(), 2);
   }
 
+  #[test]
+  fn parse_single_quoted_attribute() {
+    let doc = r#"<meta const='{"a": "x>y"}' if="true"/>"#;
+    let mut parser = PomlParser::from_poml_str(doc);
+    let node = parser.parse_as_node().unwrap();
+    let PomlNode::Tag(meta_node) = &node.children[0] else {
+      panic!()
+    };
+    assert_eq!(
+      meta_node.attributes,
+      vec![
+        ("const", AttributeValue::Literal(r#"{"a": "x>y"}"#)),
+        ("if", AttributeValue::Literal("true"))
+      ]
+    );
+  }
+
+  #[test]
+  fn parse_attribute_names_with_colon_and_dot() {
+    let doc = r#"<p xml:lang="en" data.priority="1">Hi</p>"#;
+    let mut parser = PomlParser::from_poml_str(doc);
+    let node = parser.parse_as_node().unwrap();
+    let PomlNode::Tag(p_node) = &node.children[0] else {
+      panic!()
+    };
+    assert_eq!(
+      p_node.attributes,
+      vec![
+        ("xml:lang", AttributeValue::Literal("en")),
+        ("data.priority", AttributeValue::Literal("1"))
+      ]
+    );
+  }
+
+  #[test]
+  fn parse_boolean_shorthand_attributes() {
+    let doc = r#"<p inline raw = "true" checked/><br hidden>x</br>"#;
+    let mut parser = PomlParser::from_poml_str(doc);
+    let node = parser.parse_as_node().unwrap();
+    let PomlNode::Tag(p_node) = &node.children[0] else {
+      panic!()
+    };
+    assert_eq!(
+      p_node.attributes,
+      vec![
+        ("inline", BOOLEAN_SHORTHAND_VALUE),
+        ("raw", AttributeValue::Literal("true")),
+        ("checked", BOOLEAN_SHORTHAND_VALUE)
+      ]
+    );
+    let PomlNode::Tag(br_node) = &node.children[1] else {
+      panic!()
+    };
+    assert_eq!(
+      br_node.attributes,
+      vec![("hidden", BOOLEAN_SHORTHAND_VALUE)]
+    );
+
+    let doc = r#"<p inline inline>Hi</p>"#;
+    assert!(PomlParser::from_poml_str(doc).parse_as_node().is_err());
+  }
+
+  #[test]
+  fn parse_attribute_values_by_kind() {
+    let doc = r#"<p a="intro" b=" {{ items }} " c="Hi, {{ name }}!" d="{{ a }}{{ b }}" e=''/>"#;
+    let mut parser = PomlParser::from_poml_str(doc);
+    let node = parser.parse_as_node().unwrap();
+    let PomlNode::Tag(p_node) = &node.children[0] else {
+      panic!()
+    };
+    assert_eq!(
+      p_node.attributes,
+      vec![
+        ("a", AttributeValue::Literal("intro")),
+        ("b", AttributeValue::Expression(" {{ items }} ")),
+        ("c", AttributeValue::Mixed("Hi, {{ name }}!")),
+        ("d", AttributeValue::Mixed("{{ a }}{{ b }}")),
+        ("e", AttributeValue::Literal("")),
+      ]
+    );
+    assert_eq!(p_node.attributes[1].1.expression(), Some(" items "));
+    assert_eq!(p_node.attributes[2].1.expression(), None);
+  }
+
+  #[test]
+  fn parse_unquoted_expression_attributes() {
+    let doc = r#"<p if={{ a > 'b' }} items={{items}}>Hi</p>"#;
+    let mut parser = PomlParser::from_poml_str(doc);
+    let node = parser.parse_as_node().unwrap();
+    let PomlNode::Tag(p_node) = &node.children[0] else {
+      panic!()
+    };
+    assert_eq!(
+      p_node.attributes,
+      vec![
+        ("if", AttributeValue::Expression("{{ a > 'b' }}")),
+        ("items", AttributeValue::Expression("{{items}}")),
+      ]
+    );
+    assert_eq!(p_node.children.len(), 1);
+
+    for doc in [
+      r#"<p if={{ a >Hi</p>"#,
+      r#"<p if={{ {{ a }} }}>Hi</p>"#,
+      "<p if={{",
+    ] {
+      assert!(PomlParser::from_poml_str(doc).parse_as_node().is_err());
+    }
+  }
+
+  #[test]
+  fn parse_cdata_section() {
+    let doc = r#"<p><![CDATA[a < b && {{ c }}]]></p>"#;
+    let mut parser = PomlParser::from_poml_str(doc);
+    let node = parser.parse_as_node().unwrap();
+    let PomlNode::Tag(p_node) = &node.children[0] else {
+      panic!()
+    };
+    assert_eq!(
+      p_node.children,
+      [PomlNode::CData(
+        "a < b && {{ c }}",
+        PomlNodePosition { start: 3, end: 31 }
+      )]
+    );
+  }
+
+  #[test]
+  fn parse_malformed_documents_without_panic() {
+    let malformed = [
+      "",
+      "   ",
+      "<",
+      "<p",
+      "<p a",
+      "<p a=",
+      "<p a=\"",
+      "<p a=\"\\",
+      "</",
+      "<>",
+      "</>",
+      "<!--",
+      "<![CDATA[",
+      "<p>é<",
+    ];
+    for doc in malformed {
+      assert!(
+        PomlParser::from_poml_str(doc).parse_as_node().is_err(),
+        "{doc:?}"
+      );
+    }
+    // Every truncation of a valid document
+    let doc = "<poml>\n  <p a='1' b=\"x\\\"y\">Héllo <b>{{ name }}</b></p><br/>\
+      <![CDATA[<raw>]]><!-- comment --></poml>";
+    for (end, _) in doc.char_indices() {
+      let _ = PomlParser::from_poml_str(&doc[..end]).parse_as_node();
+    }
+    assert!(PomlParser::from_poml_str(doc).parse_as_node().is_ok());
+  }
+
+  #[test]
+  fn parse_empty_document() {
+    for doc in ["", "   ", "\n\t\n", "\u{feff}", "<!-- comment -->"] {
+      let err = PomlParser::from_poml_str(doc).parse_as_node().unwrap_err();
+      assert_eq!(err.code(), ErrorCode::EmptyDocument, "{doc:?}: {err}");
+      assert_eq!(err.message, "Document is empty");
+    }
+  }
+
+  #[test]
+  fn parse_nested_deeper_than_max_depth() {
+    let doc = "<poml><p><b>x</b><br/></p></poml>";
+    let mut parser = PomlParser::from_poml_str(doc);
+    parser.max_depth = 3;
+    assert!(parser.parse_as_node().is_ok());
+    let mut parser = PomlParser::from_poml_str(doc);
+    parser.max_depth = 2;
+    let err = parser.parse_as_node().unwrap_err();
+    assert_eq!(err.code(), ErrorCode::LimitExceeded);
+  }
+
+  #[test]
+  fn parse_comment_at_end_of_input() {
+    let doc = "<poml><p>Hi</p></poml><!-- end -->";
+    let node = PomlParser::from_poml_str(doc).parse_as_node().unwrap();
+    assert_eq!(node.name, "poml");
+  }
+
+  #[test]
+  fn parse_unterminated_cdata_section() {
+    let doc = r#"<p><![CDATA[a < b</p>"#;
+    let mut parser = PomlParser::from_poml_str(doc);
+    assert!(parser.parse_as_node().is_err());
+  }
+
   #[test]
   fn parse_multiple_same_key_attribute_doc() {
     let doc = r#"
@@ -630,4 +1128,78 @@ mod tests {
     let mut parser = PomlParser::from_poml_str(doc);
     assert!(parser.parse_as_node().is_err());
   }
+
+  #[test]
+  fn parse_error_position() {
+    let doc = "<poml>\n  <p>Text</b>\n</poml>";
+    let mut parser = PomlParser::from_poml_str(doc);
+    let err = parser.parse_as_node().unwrap_err();
+    let position = err.position().unwrap();
+    assert_eq!((position.line, position.col), (2, 10));
+    assert_eq!(position.snippet, "  <p>Text</b>");
+  }
+
+  #[test]
+  fn tokenize_unicode_whitespace() {
+    let doc = "<p>\u{3000}\u{3000}全角テキスト\u{a0}<b>x</b>\u{a0}\n</p>";
+    let mut parser = PomlParser::from_poml_str(doc);
+    let elements = parser.parse_as_elements().unwrap();
+    let kinds: Vec<_> = elements.iter().map(|e| &e.kind).collect();
+    assert_eq!(
+      kinds,
+      vec![
+        &PomlElementKind::Tag,
+        &PomlElementKind::Whitespace,
+        &PomlElementKind::Text,
+        &PomlElementKind::Tag,
+        &PomlElementKind::Text,
+        &PomlElementKind::Tag,
+        &PomlElementKind::Whitespace,
+        &PomlElementKind::Tag,
+      ]
+    );
+    let slice = |i: usize| &doc[elements[i].start_pos..elements[i].end_pos];
+    assert_eq!(slice(1), "\u{3000}\u{3000}");
+    assert_eq!(slice(2), "全角テキスト\u{a0}");
+    assert_eq!(slice(6), "\u{a0}\n");
+  }
+
+  #[test]
+  fn parse_leading_unicode_whitespace_and_bom() {
+    let doc = "\u{feff}\u{a0}\n<poml><p>Hi</p></poml>";
+    let node = PomlParser::from_poml_str(doc).parse_as_node().unwrap();
+    assert_eq!(node.name, "poml");
+    assert_eq!(node.children.len(), 1);
+  }
+
+  #[test]
+  fn parse_error_col_counts_characters() {
+    let doc = "<poml>\n<p>日本語のテキスト</b>\n</poml>";
+    let mut parser = PomlParser::from_poml_str(doc);
+    let err = parser.parse_as_node().unwrap_err();
+    let position = err.position().unwrap();
+    assert_eq!((position.line, position.col), (2, 12));
+  }
+
+  #[test]
+  fn tokenize_trailing_spaces_as_whitespace() {
+    let doc = "<p>Hello \t\n  world</p>";
+    let elements = PomlParser::from_poml_str(doc).parse_as_elements().unwrap();
+    let slice = |i: usize| &doc[elements[i].start_pos..elements[i].end_pos];
+    assert_eq!(slice(1), "Hello");
+    assert_eq!(slice(2), " \t\n  ");
+    assert_eq!(slice(3), "world");
+  }
+
+  proptest! {
+    #[test]
+    fn prop_parse_any_input_without_panic(doc in "(<|>|/|=|\"|'|\\\\|!|-|\\[|\\]|poml|p|a| |\n|é|\u{3000})*") {
+      let _ = PomlParser::from_poml_str(&doc).parse_as_node();
+    }
+
+    #[test]
+    fn prop_parse_any_string_without_panic(doc in any::<String>()) {
+      let _ = PomlParser::from_poml_str(&doc).parse_as_node();
+    }
+  }
 }
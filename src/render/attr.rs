@@ -0,0 +1,169 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Attribute coercion rules shared by the built-in tags. TagRenderer implementors
+ * should use them to interpret attribute values consistently.
+ */
+
+use serde_json::Value;
+use std::borrow::Cow;
+
+pub use super::expression::utils::is_false_json_value;
+
+/**
+ * Whether a text attribute value should be considered false.
+ *
+ * `0`, `false`, `null`, `NaN` and the empty string, ignoring surrounding whitespace,
+ * are false. All other values are true.
+ */
+pub fn is_false_value(value: &str) -> bool {
+  let val = value.trim();
+  matches!(val, "0" | "false" | "" | "null" | "NaN")
+}
+
+/**
+ * Whether an attribute value should be considered true.
+ *
+ * Text values follow [`is_false_value`], and other values, which come from evaluated
+ * expressions, follow [`is_false_json_value`].
+ */
+pub fn is_truthy(value: &Value) -> bool {
+  match value {
+    Value::String(s) => !is_false_value(s),
+    _ => !is_false_json_value(value),
+  }
+}
+
+/**
+ * Parse a text value as a number. Integers are preferred over floats.
+ */
+pub fn parse_number(value: &str) -> Option<Value> {
+  let value = value.trim();
+  if let Ok(int_value) = value.parse::<i64>() {
+    return Some(Value::Number(serde_json::Number::from(int_value)));
+  }
+  match value.parse::<f64>() {
+    Ok(float_value) => serde_json::Number::from_f64(float_value).map(Value::Number),
+    Err(_) => None,
+  }
+}
+
+/**
+ * Find the value of an attribute.
+ */
+pub fn get_attribute<'v>(attribute_values: &'v [(String, Value)], name: &str) -> Option<&'v Value> {
+  attribute_values
+    .iter()
+    .find(|(key, _)| key == name)
+    .map(|(_, value)| value)
+}
+
+/**
+ * Find the value of a text attribute. Returns `None` if the attribute is missing or
+ * its value is not a text.
+ */
+pub fn get_str_attribute<'v>(
+  attribute_values: &'v [(String, Value)],
+  name: &str,
+) -> Option<&'v str> {
+  get_attribute(attribute_values, name).and_then(|v| v.as_str())
+}
+
+/**
+ * Find the value of an attribute as text. Numbers and booleans, which come from
+ * evaluated expressions, are converted to text. Returns `None` if the attribute is
+ * missing or the value is null, an array or an object.
+ */
+pub fn get_text_attribute<'v>(
+  attribute_values: &'v [(String, Value)],
+  name: &str,
+) -> Option<Cow<'v, str>> {
+  match get_attribute(attribute_values, name)? {
+    Value::String(s) => Some(Cow::Borrowed(s)),
+    Value::Number(n) => Some(Cow::Owned(n.to_string())),
+    Value::Bool(b) => Some(Cow::Owned(b.to_string())),
+    _ => None,
+  }
+}
+
+/**
+ * Find the value of an attribute as a boolean following [`is_truthy`].
+ */
+pub fn get_bool_attribute(attribute_values: &[(String, Value)], name: &str) -> Option<bool> {
+  get_attribute(attribute_values, name).map(is_truthy)
+}
+
+/**
+ * Prefix of metadata attributes like `data-cache`. Metadata attributes carry hints for
+ * the pipeline around the renderer and never change the rendered text.
+ */
+pub const DATA_ATTRIBUTE_PREFIX: &str = "data-";
+
+/**
+ * Iterate the metadata attributes, with the `data-` prefix removed from the names.
+ */
+pub fn get_data_attributes(
+  attribute_values: &[(String, Value)],
+) -> impl Iterator<Item = (&str, &Value)> {
+  attribute_values.iter().filter_map(|(key, value)| {
+    key
+      .strip_prefix(DATA_ATTRIBUTE_PREFIX)
+      .map(|name| (name, value))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_is_truthy() {
+    assert!(!is_truthy(&json!("false")));
+    assert!(!is_truthy(&json!(" 0 ")));
+    assert!(!is_truthy(&json!(0)));
+    assert!(!is_truthy(&json!(null)));
+    assert!(is_truthy(&json!("yes")));
+    assert!(is_truthy(&json!([])));
+  }
+
+  #[test]
+  fn test_parse_number() {
+    assert_eq!(parse_number("3"), Some(json!(3)));
+    assert_eq!(parse_number(" 1.5 "), Some(json!(1.5)));
+    assert_eq!(parse_number("three"), None);
+  }
+
+  #[test]
+  fn test_get_attributes() {
+    let attribute_values = vec![
+      ("inline".to_owned(), json!("true")),
+      ("records".to_owned(), json!([1])),
+    ];
+    assert_eq!(get_bool_attribute(&attribute_values, "inline"), Some(true));
+    assert_eq!(get_bool_attribute(&attribute_values, "missing"), None);
+    assert_eq!(get_str_attribute(&attribute_values, "inline"), Some("true"));
+    assert_eq!(get_str_attribute(&attribute_values, "records"), None);
+    assert_eq!(get_text_attribute(&attribute_values, "records"), None);
+    let attribute_values = vec![("level".to_owned(), json!(2))];
+    assert_eq!(get_str_attribute(&attribute_values, "level"), None);
+    assert_eq!(
+      get_text_attribute(&attribute_values, "level"),
+      Some(Cow::Borrowed("2"))
+    );
+  }
+
+  #[test]
+  fn test_get_data_attributes() {
+    let attribute_values = vec![
+      ("data-cache".to_owned(), json!("false")),
+      ("lang".to_owned(), json!("rust")),
+    ];
+    let data: Vec<(&str, &Value)> = get_data_attributes(&attribute_values).collect();
+    assert_eq!(data, [("cache", &json!("false"))]);
+  }
+}
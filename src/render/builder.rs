@@ -0,0 +1,203 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Builder of renderers, collecting the context, the file loader, the options and the tag
+ * renderer settings before the document is given.
+ */
+
+use super::Renderer;
+use super::expression::UndefinedPolicy;
+use super::file_loader::{FileLoader, Sandbox};
+use super::options::{RenderLimits, RenderOptions, UnknownTagPolicy, WhiteSpaceMode};
+use super::render_context::RenderContext;
+use super::stylesheet::Stylesheet;
+use super::tag_renderer::{InlineTagStyles, MarkdownTagRenderer, TagRenderer, WriterOptions};
+use super::token_counter::TokenCounter;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+/**
+ * Builder of a `Renderer`, created by `RendererBuilder::new` with a tag renderer or by
+ * `MarkdownPomlRenderer::builder`. The settings can be given in any order, and the builder
+ * can be cloned to build renderers of many documents with the same settings.
+ */
+#[derive(Clone)]
+pub struct RendererBuilder<T: TagRenderer> {
+  tag_renderer: T,
+  context: Option<RenderContext>,
+  variables: Map<String, Value>,
+  file_loader: Option<Arc<dyn FileLoader>>,
+  sandbox: Option<Sandbox>,
+  document_path: Option<String>,
+  options: RenderOptions,
+}
+
+impl<T: TagRenderer> RendererBuilder<T> {
+  pub fn new(tag_renderer: T) -> Self {
+    RendererBuilder {
+      tag_renderer,
+      context: None,
+      variables: Map::new(),
+      file_loader: None,
+      sandbox: None,
+      document_path: None,
+      options: RenderOptions::default(),
+    }
+  }
+
+  /**
+   * Render with the context, instead of an empty one.
+   */
+  pub fn context(mut self, context: RenderContext) -> Self {
+    self.context = Some(context);
+    self
+  }
+
+  /**
+   * Add the variables to the root scope of the context, overriding the variables of the
+   * context with the same names.
+   */
+  pub fn variables(mut self, variables: impl IntoIterator<Item = (String, Value)>) -> Self {
+    self.variables.extend(variables);
+    self
+  }
+
+  /**
+   * Load the files of `<include>` and `src` attributes with the loader.
+   */
+  pub fn file_loader<L: FileLoader + 'static>(mut self, file_loader: L) -> Self {
+    self.file_loader = Some(Arc::new(file_loader));
+    self
+  }
+
+  /**
+   * Restrict the files to load, e.g. to render untrusted documents.
+   */
+  pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+    self.sandbox = Some(sandbox);
+    self
+  }
+
+  /**
+   * Path of the document, used in errors and to resolve the relative paths of the files.
+   */
+  pub fn document_path(mut self, path: &str) -> Self {
+    self.document_path = Some(path.to_string());
+    self
+  }
+
+  /**
+   * Replace all the render options. The options set before are discarded, so it should be
+   * called before the setters of single options.
+   */
+  pub fn options(mut self, options: RenderOptions) -> Self {
+    self.options = options;
+    self
+  }
+
+  pub fn unknown_tag_policy(mut self, policy: UnknownTagPolicy) -> Self {
+    self.options.unknown_tag_policy = policy;
+    self
+  }
+
+  pub fn undefined_policy(mut self, policy: UndefinedPolicy) -> Self {
+    self.options.undefined_policy = Some(policy);
+    self
+  }
+
+  pub fn white_space(mut self, mode: WhiteSpaceMode) -> Self {
+    self.options.white_space = Some(mode);
+    self
+  }
+
+  pub fn limits(mut self, limits: RenderLimits) -> Self {
+    self.options.limits = limits;
+    self
+  }
+
+  pub fn stylesheet(mut self, stylesheet: Stylesheet) -> Self {
+    self.options.stylesheet = stylesheet;
+    self
+  }
+
+  pub fn token_counter<C: TokenCounter + 'static>(mut self, token_counter: C) -> Self {
+    self.options.token_counter = Arc::new(token_counter);
+    self
+  }
+
+  /**
+   * Build the renderer of the document with the settings.
+   */
+  pub fn build(self, doc: &str) -> Renderer<'_, T> {
+    let mut context = self
+      .context
+      .unwrap_or_else(|| RenderContext::from(Map::new()));
+    for (name, value) in self.variables {
+      context.set_global_value(&name, value);
+    }
+    if let Some(file_loader) = self.file_loader {
+      context.set_shared_file_loader(file_loader);
+    }
+    if let Some(sandbox) = self.sandbox {
+      context.set_sandbox(sandbox);
+    }
+    let mut renderer = Renderer::new(doc, context, self.tag_renderer);
+    renderer.options = self.options;
+    if let Some(path) = &self.document_path {
+      renderer.set_document_path(path);
+    }
+    renderer
+  }
+}
+
+impl RendererBuilder<MarkdownTagRenderer> {
+  /**
+   * Layout of the Markdown output, like the paragraph separator and the line endings.
+   */
+  pub fn writer(mut self, writer: WriterOptions) -> Self {
+    self.tag_renderer.writer = writer;
+    self
+  }
+
+  /**
+   * Styles of the inline tags in the Markdown output.
+   */
+  pub fn inline_styles(mut self, inline_styles: InlineTagStyles) -> Self {
+    self.tag_renderer.inline_styles = inline_styles;
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::MarkdownPomlRenderer;
+  use crate::render::file_loader::InMemoryLoader;
+  use crate::render::tag_renderer::LineEnding;
+  use serde_json::json;
+
+  #[test]
+  fn test_renderer_builder() {
+    let loader = InMemoryLoader::from_iter([("prompts/footer.poml", "<p>Bye, {{ name }}</p>")]);
+    let builder = MarkdownPomlRenderer::builder()
+      .file_loader(loader)
+      .document_path("prompts/main.poml")
+      .variables([("name".to_string(), json!("Ada"))])
+      .unknown_tag_policy(UnknownTagPolicy::PassthroughChildren)
+      .writer(WriterOptions {
+        line_ending: LineEnding::CrLf,
+        ..WriterOptions::default()
+      });
+    let doc = "<poml><p>Hi, {{ name }}</p><unknown>{{ missing }}</unknown><include src=\"footer.poml\" /></poml>";
+    let output = builder.clone().build(doc).render().unwrap();
+    assert_eq!(output, "Hi, Ada\r\n\r\nnullBye, Ada\r\n\r\n");
+
+    let mut renderer = builder.undefined_policy(UndefinedPolicy::Error).build(doc);
+    let error = renderer.render().unwrap_err();
+    assert!(error.to_string().contains("prompts/main.poml"));
+  }
+}
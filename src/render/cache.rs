@@ -0,0 +1,78 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/**
+ * Cache for expensive outputs, like rendered tables and the content of included files.
+ *
+ * Implement this trait to share the cache across processes, e.g. with Redis.
+ */
+pub trait RenderCache: Send + Sync {
+  /**
+   * Get the cached value of the key, or `None` if it is missing or expired.
+   */
+  fn get(&self, key: &str) -> Option<String>;
+
+  /**
+   * Put a value into the cache. If `ttl` is provided, the value expires after it.
+   */
+  fn put(&self, key: &str, value: String, ttl: Option<Duration>);
+}
+
+/**
+ * A render cache that keeps the values in memory.
+ */
+#[derive(Debug, Default)]
+pub struct InMemoryRenderCache {
+  entries: Mutex<HashMap<String, (String, Option<Instant>)>>,
+}
+
+impl InMemoryRenderCache {
+  pub fn new() -> Self {
+    Self::default()
+  }
+}
+
+impl RenderCache for InMemoryRenderCache {
+  fn get(&self, key: &str) -> Option<String> {
+    let mut entries = self.entries.lock().unwrap();
+    match entries.get(key) {
+      Some((_, Some(expire_at))) if *expire_at <= Instant::now() => {
+        entries.remove(key);
+        None
+      }
+      Some((value, _)) => Some(value.clone()),
+      None => None,
+    }
+  }
+
+  fn put(&self, key: &str, value: String, ttl: Option<Duration>) {
+    let expire_at = ttl.map(|ttl| Instant::now() + ttl);
+    self
+      .entries
+      .lock()
+      .unwrap()
+      .insert(key.to_string(), (value, expire_at));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_in_memory_render_cache() {
+    let cache = InMemoryRenderCache::new();
+    assert_eq!(cache.get("a"), None);
+    cache.put("a", "1".to_string(), None);
+    assert_eq!(cache.get("a"), Some("1".to_string()));
+    cache.put("b", "2".to_string(), Some(Duration::ZERO));
+    assert_eq!(cache.get("b"), None);
+  }
+}
@@ -0,0 +1,193 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::expression::Expression;
+use super::filter::split_filters;
+use super::render_context::RenderContext;
+use super::tag_renderer::TagRenderer;
+use super::utils::decode_xml_entities;
+use super::{Renderer, is_attribute_evaluated_as_expression};
+use crate::error::Result;
+use crate::parser::PomlParser;
+use crate::{PomlNode, PomlTagNode};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/**
+ * A document parsed and validated once, which renders many times with different contexts
+ * without parsing the document or its expressions again.
+ */
+#[derive(Debug, Clone)]
+pub struct CompiledTemplate<'a> {
+  /** The root tag of the document, shared by the renders */
+  pub(crate) root: Arc<PomlNode<'a>>,
+  /** Parsed expressions of the document, by the expression text */
+  pub(crate) expressions: Arc<HashMap<&'a str, Expression>>,
+}
+
+impl<'a> CompiledTemplate<'a> {
+  /**
+   * Parse the document and its expressions. Syntax errors of the document and the
+   * expressions are reported here instead of at render time.
+   */
+  pub fn compile(doc: &'a str) -> Result<CompiledTemplate<'a>> {
+    let mut parser = PomlParser::from_poml_str(doc);
+    let root = parser.parse_as_node()?;
+    let mut expressions = HashMap::new();
+    collect_tag_expressions(doc.as_bytes(), &root, false, &mut expressions)?;
+    Ok(CompiledTemplate {
+      root: Arc::new(PomlNode::Tag(root)),
+      expressions: Arc::new(expressions),
+    })
+  }
+
+  /**
+   * Create a renderer of the compiled document with the context and tag renderer.
+   */
+  pub fn renderer<T: TagRenderer>(
+    &self,
+    doc: &'a str,
+    context: RenderContext,
+    tag_renderer: T,
+  ) -> Renderer<'a, T> {
+    let mut renderer = Renderer::new(doc, context, tag_renderer);
+    renderer.compiled = Some(self.clone());
+    renderer
+  }
+
+  /**
+   * Number of distinct expressions parsed in the document.
+   */
+  pub fn expression_count(&self) -> usize {
+    self.expressions.len()
+  }
+}
+
+fn collect_tag_expressions<'a>(
+  doc: &[u8],
+  tag: &PomlTagNode<'a>,
+  raw: bool,
+  expressions: &mut HashMap<&'a str, Expression>,
+) -> Result<()> {
+  let mut raw = raw || tag.name == "pre";
+  for (key, attribute_value) in tag.attributes.iter() {
+    let value = attribute_value.text();
+    match *key {
+      // The loop instruction is not a plain expression.
+      "for" => {}
+      "raw" => raw = raw || value == "true",
+      "if" => add_expression(doc, value, expressions)?,
+      key if is_attribute_evaluated_as_expression(tag.name, key) => {
+        add_expression(doc, value, expressions)?
+      }
+      _ => match attribute_value.expression() {
+        Some(expression) => add_expression(doc, interpolated_expression(expression), expressions)?,
+        None => collect_text_expressions(doc, value, expressions)?,
+      },
+    }
+  }
+  for child in tag.children.iter() {
+    match child {
+      PomlNode::Tag(child) => collect_tag_expressions(doc, child, raw, expressions)?,
+      PomlNode::Text(text, _) if !raw => collect_text_expressions(doc, text, expressions)?,
+      _ => {}
+    }
+  }
+  Ok(())
+}
+
+/**
+ * Collect the `{{ }}` expressions in the text, which are delimited in the same way as
+ * the renderer does.
+ */
+fn collect_text_expressions<'a>(
+  doc: &[u8],
+  text: &'a str,
+  expressions: &mut HashMap<&'a str, Expression>,
+) -> Result<()> {
+  let mut rest = text;
+  while let Some(start) = rest.find("{{") {
+    let expression_start = start + 2;
+    let Some(end) = rest.get(expression_start + 1..).and_then(|s| s.find("}}")) else {
+      // Reported by the renderer, which is the behavior of the uncompiled documents.
+      return Ok(());
+    };
+    let expression_end = expression_start + 1 + end;
+    add_expression(
+      doc,
+      interpolated_expression(&rest[expression_start..expression_end]),
+      expressions,
+    )?;
+    rest = &rest[expression_end + 2..];
+  }
+  Ok(())
+}
+
+/**
+ * The expression of a `{{ }}` interpolation without its filters.
+ */
+fn interpolated_expression(expression: &str) -> &str {
+  split_filters(expression).map_or(expression, |(expression, _)| expression)
+}
+
+/**
+ * Parse the expression into the expressions. Expressions with character references like
+ * `a &lt; b` are only validated, since the renderer evaluates their decoded text.
+ */
+fn add_expression<'a>(
+  doc: &[u8],
+  expression: &'a str,
+  expressions: &mut HashMap<&'a str, Expression>,
+) -> Result<()> {
+  if let Cow::Owned(decoded) = decode_xml_entities(expression) {
+    Expression::parse(&decoded)?;
+  } else if !expressions.contains_key(expression) {
+    let parsed = Expression::parse(expression).map_err(|e| e.in_document(doc, expression))?;
+    expressions.insert(expression, parsed);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::render::tag_renderer::MarkdownTagRenderer;
+  use serde_json::json;
+
+  #[test]
+  fn test_compiled_template() {
+    let doc = r#"
+<poml>
+  <p if="user.active">Hello, {{ user.name }}! {{ user.name }}</p>
+  <list><item for="item in items">{{ item }}</item></list>
+  <pre>{{ not an expression </pre>
+</poml>"#;
+    let compiled = CompiledTemplate::compile(doc).unwrap();
+    assert_eq!(compiled.expression_count(), 3);
+
+    for name in ["Ada", "Bob"] {
+      let context = RenderContext::from_iter([
+        ("user".to_owned(), json!({"active": true, "name": name})),
+        ("items".to_owned(), json!(["a", "b"])),
+      ]);
+      let mut renderer = compiled.renderer(doc, context, MarkdownTagRenderer::default());
+      let output = renderer.render().unwrap();
+      assert!(output.contains(&format!("Hello, {name}! {name}")));
+      assert!(output.contains("- a"));
+    }
+  }
+
+  #[test]
+  fn test_compile_errors() {
+    assert!(CompiledTemplate::compile("<poml><p>Text</poml>").is_err());
+    assert!(CompiledTemplate::compile("<poml><p>{{ a = b }}</p></poml>").is_err());
+    assert!(CompiledTemplate::compile(r#"<poml><p if="a = b">Text</p></poml>"#).is_err());
+    let err = CompiledTemplate::compile("<poml>\n<p>{{ a ! b }}</p></poml>").unwrap_err();
+    let position = err.position().unwrap();
+    assert_eq!((position.line, position.col), (2, 9));
+  }
+}
@@ -0,0 +1,383 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::data_format::parse_toml;
+use super::expression::UndefinedPolicy;
+use super::file_loader::{Sandbox, is_absolute_path, normalize_path};
+use super::options::{FormatVersion, RenderOptions, UnknownTagPolicy, WhiteSpaceMode};
+use super::post_process::PostProcessStep;
+use super::stylesheet::Stylesheet;
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use crate::lint::{LintConfig, LintLevel};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/**
+ * Project-level settings loaded from a config file like `poml.toml` or `poml.json`,
+ * so the same options don't have to be passed to each render.
+ *
+ * The config file is an object with the following keys, which are all optional:
+ *
+ * ```toml
+ * white_space = "trim"          # "pre", "filter" or "trim"
+ * format_version = 2            # 1, 2 or "latest"
+ * auto_fence_code = true
+ * escape_values = false
+ * pure = false
+ * annotate_token_counts = false
+ * unknown_tag_policy = "error"  # "error", "ignore" or "passthrough"
+ * undefined_policy = "null"     # "null", "error" or "warn"
+ * post_process = ["trim_trailing_whitespace", "collapse_blank_lines", "ensure_trailing_newline", "wrap:80"]
+ *
+ * [block_order]
+ * output-format = 100
+ *
+ * [capabilities]
+ * tools = true
+ *
+ * [stylesheet.defaults.cp]      # see `Stylesheet`
+ * captionStyle = "bold"
+ *
+ * [limits]                      # see `RenderLimits`
+ * max_depth = 64
+ * max_loop_iterations = 1000000
+ * max_output_size = 67108864   # in bytes
+ * max_includes = 1024
+ *
+ * [sandbox]
+ * deny_filesystem = false
+ * root = "prompts"              # relative to the directory of the config file
+ *
+ * [lint]                        # "off", "warning" or "error", by rule name or code
+ * unused_variable = "off"
+ * E300 = "error"
+ * ```
+ *
+ * TOML files need the `toml` feature, and the other files are parsed as JSON.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct RenderConfig {
+  pub options: RenderOptions,
+  pub sandbox: Sandbox,
+  /** Levels of the lint rules, applied by `lint_with_config` and `poml check`. */
+  pub lint: LintConfig,
+}
+
+impl RenderConfig {
+  /** Default names of the config file, in the order they are looked up. */
+  pub const DEFAULT_FILENAMES: [&'static str; 2] = ["poml.toml", "poml.json"];
+
+  /**
+   * Load the config from a file. Relative paths in the config are resolved against the
+   * directory of the file.
+   */
+  pub fn from_file<P: AsRef<Path>>(path: P) -> Result<RenderConfig> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path).map_err(|e| Error {
+      kind: ErrorKind::RendererError,
+      message: format!("Failed to read config file: {}", path.display()),
+      source: Some(Box::new(e)),
+      position: None,
+      code: Some(ErrorCode::FileNotFound),
+    })?;
+    let value = if path.extension().is_some_and(|ext| ext == "toml") {
+      parse_toml(&content)?
+    } else {
+      serde_json::from_str(&content).map_err(|e| Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Failed to parse config file: {}", path.display()),
+        source: Some(Box::new(e)),
+        position: None,
+        code: Some(ErrorCode::InvalidConfig),
+      })?
+    };
+    let mut config = RenderConfig::from_value(&value)?;
+    if let (Some(root), Some(dir)) = (&config.sandbox.root, path.parent())
+      && !is_absolute_path(root)
+      && !dir.as_os_str().is_empty()
+    {
+      config.sandbox.root = Some(normalize_path(&format!("{}/{root}", dir.display())));
+    }
+    Ok(config)
+  }
+
+  /**
+   * Find and load the config file with one of [`RenderConfig::DEFAULT_FILENAMES`] in the
+   * directory. Return `None` if there is no such file.
+   */
+  pub fn find_in_dir<P: AsRef<Path>>(dir: P) -> Result<Option<RenderConfig>> {
+    for filename in Self::DEFAULT_FILENAMES {
+      let path = dir.as_ref().join(filename);
+      if path.is_file() {
+        return RenderConfig::from_file(path).map(Some);
+      }
+    }
+    Ok(None)
+  }
+
+  /**
+   * Create the config from a parsed config file. Unknown keys and values of wrong types
+   * are errors, so typos in the config don't go unnoticed.
+   */
+  pub fn from_value(value: &Value) -> Result<RenderConfig> {
+    let mut config = RenderConfig::default();
+    for (key, value) in as_object(value, "config")? {
+      let options = &mut config.options;
+      match key.as_str() {
+        "white_space" => {
+          let mode = as_str(value, key)?;
+          options.white_space = Some(
+            WhiteSpaceMode::from_attribute_value(mode)
+              .ok_or_else(|| config_error(format!("Invalid value of `white_space`: {mode}")))?,
+          );
+        }
+        "format_version" => {
+          options.format_version = match value {
+            Value::Number(n) if n.as_u64() == Some(1) => FormatVersion::V1,
+            Value::Number(n) if n.as_u64() == Some(2) => FormatVersion::V2,
+            Value::String(s) if s == "latest" => FormatVersion::LATEST,
+            _ => {
+              return Err(config_error(format!(
+                "Invalid value of `format_version`: {value}"
+              )));
+            }
+          };
+        }
+        "auto_fence_code" => options.auto_fence_code = as_bool(value, key)?,
+        "escape_values" => options.escape_values = as_bool(value, key)?,
+        "pure" => options.pure = as_bool(value, key)?,
+        "annotate_token_counts" => options.annotate_token_counts = as_bool(value, key)?,
+        "random_seed" => {
+          options.random_seed = Some(
+            value
+              .as_u64()
+              .ok_or_else(|| config_error(format!("`{key}` should be a non-negative integer")))?,
+          );
+        }
+        "block_order" => {
+          for (tag, order) in as_object(value, key)? {
+            let order = order
+              .as_i64()
+              .ok_or_else(|| config_error(format!("Invalid order of `{tag}` in `block_order`")))?;
+            options.block_order.insert(tag.clone(), order);
+          }
+        }
+        "capabilities" => options.capabilities = as_object(value, key)?.clone(),
+        "stylesheet" => options.stylesheet = Stylesheet::from_value(value)?,
+        "unknown_tag_policy" => {
+          let name = as_str(value, key)?;
+          options.unknown_tag_policy = UnknownTagPolicy::from_name(name).ok_or_else(|| {
+            config_error(format!("Invalid value of `unknown_tag_policy`: {name}"))
+          })?;
+        }
+        "undefined_policy" => {
+          let name = as_str(value, key)?;
+          options.undefined_policy =
+            Some(UndefinedPolicy::from_name(name).ok_or_else(|| {
+              config_error(format!("Invalid value of `undefined_policy`: {name}"))
+            })?);
+        }
+        "post_process" => {
+          let Value::Array(steps) = value else {
+            return Err(config_error(format!("`{key}` should be an array")));
+          };
+          for step in steps {
+            let name = as_str(step, key)?;
+            options.post_process.push(
+              PostProcessStep::from_name(name)
+                .ok_or_else(|| config_error(format!("Invalid step of `post_process`: {name}")))?,
+            );
+          }
+        }
+        "limits" => {
+          for (limit_key, value) in as_object(value, key)? {
+            let limit = match limit_key.as_str() {
+              "max_depth" => &mut options.limits.max_depth,
+              "max_loop_iterations" => &mut options.limits.max_loop_iterations,
+              "max_output_size" => &mut options.limits.max_output_size,
+              "max_includes" => &mut options.limits.max_includes,
+              _ => return Err(config_error(format!("Unknown key `limits.{limit_key}`"))),
+            };
+            *limit = value
+              .as_u64()
+              .and_then(|n| usize::try_from(n).ok())
+              .ok_or_else(|| {
+                config_error(format!(
+                  "`limits.{limit_key}` should be a non-negative integer"
+                ))
+              })?;
+          }
+        }
+        "sandbox" => {
+          for (sandbox_key, value) in as_object(value, key)? {
+            match sandbox_key.as_str() {
+              "deny_filesystem" => config.sandbox.deny_filesystem = as_bool(value, sandbox_key)?,
+              "root" => config.sandbox.root = Some(as_str(value, sandbox_key)?.to_string()),
+              _ => return Err(config_error(format!("Unknown key `sandbox.{sandbox_key}`"))),
+            }
+          }
+        }
+        "lint" => {
+          for (rule, level) in as_object(value, key)? {
+            let code = LintConfig::rule_code(rule)
+              .ok_or_else(|| config_error(format!("Unknown lint rule `lint.{rule}`")))?;
+            let name = as_str(level, rule)?;
+            let level = LintLevel::from_name(name)
+              .ok_or_else(|| config_error(format!("Invalid level of `lint.{rule}`: {name}")))?;
+            config.lint.levels.insert(code, level);
+          }
+        }
+        _ => return Err(config_error(format!("Unknown key `{key}`"))),
+      }
+    }
+    Ok(config)
+  }
+}
+
+impl RenderOptions {
+  /**
+   * Load the render options from a config file. See [`RenderConfig`] for the format.
+   */
+  pub fn from_config_file<P: AsRef<Path>>(path: P) -> Result<RenderOptions> {
+    Ok(RenderConfig::from_file(path)?.options)
+  }
+}
+
+fn config_error(message: String) -> Error {
+  Error {
+    kind: ErrorKind::RendererError,
+    message: format!("Invalid config: {message}"),
+    source: None,
+    position: None,
+    code: Some(ErrorCode::InvalidConfig),
+  }
+}
+
+fn as_object<'v>(value: &'v Value, key: &str) -> Result<&'v Map<String, Value>> {
+  value
+    .as_object()
+    .ok_or_else(|| config_error(format!("`{key}` should be an object")))
+}
+
+fn as_str<'v>(value: &'v Value, key: &str) -> Result<&'v str> {
+  value
+    .as_str()
+    .ok_or_else(|| config_error(format!("`{key}` should be a string")))
+}
+
+fn as_bool(value: &Value, key: &str) -> Result<bool> {
+  value
+    .as_bool()
+    .ok_or_else(|| config_error(format!("`{key}` should be a boolean")))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::render::options::RenderLimits;
+  use serde_json::json;
+  use std::collections::HashMap;
+
+  #[test]
+  fn test_from_value() {
+    let config = RenderConfig::from_value(&json!({
+      "white_space": "trim",
+      "format_version": "latest",
+      "auto_fence_code": true,
+      "escape_values": true,
+      "random_seed": 42,
+      "block_order": {"output-format": 100},
+      "capabilities": {"tools": true},
+      "stylesheet": {"defaults": {"cp": {"captionStyle": "bold"}}},
+      "unknown_tag_policy": "passthrough",
+      "undefined_policy": "warn",
+      "post_process": ["collapse_blank_lines", "wrap:80"],
+      "limits": {"max_depth": 32, "max_includes": 8},
+      "sandbox": {"deny_filesystem": true, "root": "prompts"},
+      "lint": {"unused_variable": "off", "E300": "error"}
+    }))
+    .unwrap();
+    assert_eq!(config.options.white_space, Some(WhiteSpaceMode::Trim));
+    assert_eq!(config.options.format_version, FormatVersion::LATEST);
+    assert!(config.options.auto_fence_code);
+    assert!(config.options.escape_values);
+    assert!(!config.options.pure);
+    assert_eq!(config.options.random_seed, Some(42));
+    assert_eq!(config.options.block_order.get("output-format"), Some(&100));
+    assert_eq!(config.options.capabilities.get("tools"), Some(&json!(true)));
+    assert!(config.options.stylesheet.defaults.contains_key("cp"));
+    assert!(matches!(
+      config.options.unknown_tag_policy,
+      UnknownTagPolicy::PassthroughChildren
+    ));
+    assert_eq!(config.options.undefined_policy, Some(UndefinedPolicy::Warn));
+    assert_eq!(
+      config.options.post_process,
+      vec![
+        PostProcessStep::CollapseBlankLines,
+        PostProcessStep::WordWrap(80)
+      ]
+    );
+    assert_eq!(config.options.limits.max_depth, 32);
+    assert_eq!(config.options.limits.max_includes, 8);
+    assert_eq!(
+      config.options.limits.max_loop_iterations,
+      RenderLimits::default().max_loop_iterations
+    );
+    assert!(config.sandbox.deny_filesystem);
+    assert_eq!(config.sandbox.root.as_deref(), Some("prompts"));
+    assert_eq!(
+      config.lint.levels,
+      HashMap::from([
+        (ErrorCode::UnusedVariable, LintLevel::Off),
+        (ErrorCode::UnreachableNode, LintLevel::Error)
+      ])
+    );
+  }
+
+  #[test]
+  fn test_from_value_errors() {
+    assert!(RenderConfig::from_value(&json!([])).is_err());
+    assert!(RenderConfig::from_value(&json!({"whitespace": "trim"})).is_err());
+    assert!(RenderConfig::from_value(&json!({"white_space": "none"})).is_err());
+    assert!(RenderConfig::from_value(&json!({"format_version": 3})).is_err());
+    assert!(RenderConfig::from_value(&json!({"pure": "yes"})).is_err());
+    assert!(RenderConfig::from_value(&json!({"random_seed": -1})).is_err());
+    assert!(RenderConfig::from_value(&json!({"unknown_tag_policy": "skip"})).is_err());
+    assert!(RenderConfig::from_value(&json!({"post_process": ["wrap:none"]})).is_err());
+    assert!(RenderConfig::from_value(&json!({"limits": {"max_depth": -1}})).is_err());
+    assert!(RenderConfig::from_value(&json!({"limits": {"depth": 1}})).is_err());
+    assert!(RenderConfig::from_value(&json!({"sandbox": {"roots": "a"}})).is_err());
+    assert!(RenderConfig::from_value(&json!({"lint": {"E100": "off"}})).is_err());
+    assert!(RenderConfig::from_value(&json!({"lint": {"unused_variable": "ignore"}})).is_err());
+  }
+
+  #[test]
+  fn test_from_file() {
+    let dir = std::env::temp_dir().join(format!("poml_config_test_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    assert!(RenderConfig::find_in_dir(&dir).unwrap().is_none());
+    let path = dir.join("poml.json");
+    std::fs::write(&path, r#"{"pure": true, "sandbox": {"root": "prompts"}}"#).unwrap();
+    let config = RenderConfig::find_in_dir(&dir).unwrap().unwrap();
+    assert!(config.options.pure);
+    assert_eq!(
+      config.sandbox.root,
+      Some(normalize_path(&format!("{}/prompts", dir.display())))
+    );
+    assert!(RenderOptions::from_config_file(&path).unwrap().pure);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[cfg(feature = "toml")]
+  #[test]
+  fn test_parse_toml() {
+    let value = parse_toml("pure = true\n[block_order]\noutput-format = 100\n").unwrap();
+    let config = RenderConfig::from_value(&value).unwrap();
+    assert!(config.options.pure);
+    assert_eq!(config.options.block_order.get("output-format"), Some(&100));
+  }
+}
@@ -0,0 +1,260 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use serde_json::Value;
+
+/**
+ * Structured data formats of the files loaded by `<let src>`, which are parsed into JSON
+ * values. JSON and text files are not listed, as their values are inferred by `<let>`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataFormat {
+  /** YAML, which needs the `yaml` feature. */
+  Yaml,
+  /** TOML, which needs the `toml` feature. */
+  Toml,
+  /** CSV with a header row, parsed into an array of objects keyed by the header. */
+  Csv,
+}
+
+impl DataFormat {
+  /**
+   * Find the format of a `src` file, by the `parser` attribute if provided, or by the file
+   * extension otherwise. Return `None` for JSON and text files.
+   */
+  pub fn from_src(src: &str, parser: Option<&str>) -> Result<Option<DataFormat>> {
+    match parser {
+      Some("yaml") => Ok(Some(DataFormat::Yaml)),
+      Some("toml") => Ok(Some(DataFormat::Toml)),
+      Some("csv") => Ok(Some(DataFormat::Csv)),
+      Some("json") | Some("text") => Ok(None),
+      Some(parser) => Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Unknown parser `{parser}` of <let>"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::InvalidAttributeValue),
+      }),
+      None => {
+        let extension = src
+          .rsplit_once('.')
+          .map(|(_, ext)| ext.to_ascii_lowercase());
+        match extension.as_deref() {
+          Some("yaml") | Some("yml") => Ok(Some(DataFormat::Yaml)),
+          Some("toml") => Ok(Some(DataFormat::Toml)),
+          Some("csv") => Ok(Some(DataFormat::Csv)),
+          _ => Ok(None),
+        }
+      }
+    }
+  }
+
+  /**
+   * Parse the content into a JSON value.
+   */
+  pub fn parse(&self, content: &str) -> Result<Value> {
+    match self {
+      DataFormat::Yaml => parse_yaml(content),
+      DataFormat::Toml => parse_toml(content),
+      DataFormat::Csv => parse_csv(content),
+    }
+  }
+}
+
+/**
+ * Parse CSV into an array of objects, whose keys are the fields of the header row. Fields
+ * can be quoted with `"`, where `""` is an escaped quote. All values are strings.
+ */
+fn parse_csv(content: &str) -> Result<Value> {
+  let mut records = parse_csv_records(content)?.into_iter();
+  let Some(header) = records.next() else {
+    return Ok(Value::Array(Vec::new()));
+  };
+  let mut rows = Vec::new();
+  for (index, record) in records.enumerate() {
+    if record.len() != header.len() {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!(
+          "CSV row {} has {} fields, but the header has {}",
+          index + 1,
+          record.len(),
+          header.len()
+        ),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::InvalidLetValue),
+      });
+    }
+    let row = header
+      .iter()
+      .cloned()
+      .zip(record.into_iter().map(Value::String))
+      .collect();
+    rows.push(Value::Object(row));
+  }
+  Ok(Value::Array(rows))
+}
+
+fn parse_csv_records(content: &str) -> Result<Vec<Vec<String>>> {
+  let mut records = Vec::new();
+  let mut record = Vec::new();
+  let mut field = String::new();
+  let mut in_quotes = false;
+  let mut chars = content.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '"' if in_quotes => {
+        if chars.peek() == Some(&'"') {
+          chars.next();
+          field.push('"');
+        } else {
+          in_quotes = false;
+        }
+      }
+      '"' if field.is_empty() => in_quotes = true,
+      ',' if !in_quotes => record.push(std::mem::take(&mut field)),
+      '\r' if !in_quotes && chars.peek() == Some(&'\n') => {}
+      '\n' if !in_quotes => {
+        record.push(std::mem::take(&mut field));
+        records.push(std::mem::take(&mut record));
+      }
+      c => field.push(c),
+    }
+  }
+  if in_quotes {
+    return Err(Error {
+      kind: ErrorKind::RendererError,
+      message: "Quoted CSV field is not closed".to_string(),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::InvalidLetValue),
+    });
+  }
+  if !field.is_empty() || !record.is_empty() {
+    record.push(field);
+    records.push(record);
+  }
+  // Skip blank lines.
+  records.retain(|record| !(record.len() == 1 && record[0].is_empty()));
+  Ok(records)
+}
+
+#[cfg(feature = "yaml")]
+fn parse_yaml(content: &str) -> Result<Value> {
+  serde_yaml_ng::from_str(content).map_err(|e| Error {
+    kind: ErrorKind::RendererError,
+    message: "Failed to parse YAML".to_string(),
+    source: Some(Box::new(e)),
+    position: None,
+    code: Some(ErrorCode::InvalidLetValue),
+  })
+}
+
+#[cfg(not(feature = "yaml"))]
+fn parse_yaml(_content: &str) -> Result<Value> {
+  Err(Error {
+    kind: ErrorKind::RendererError,
+    message: "Parsing YAML needs the `yaml` feature".to_string(),
+    source: None,
+    position: None,
+    code: None,
+  })
+}
+
+#[cfg(feature = "toml")]
+pub(crate) fn parse_toml(content: &str) -> Result<Value> {
+  toml::from_str(content).map_err(|e| Error {
+    kind: ErrorKind::RendererError,
+    message: "Failed to parse TOML".to_string(),
+    source: Some(Box::new(e)),
+    position: None,
+    code: Some(ErrorCode::InvalidLetValue),
+  })
+}
+
+#[cfg(not(feature = "toml"))]
+pub(crate) fn parse_toml(_content: &str) -> Result<Value> {
+  Err(Error {
+    kind: ErrorKind::RendererError,
+    message: "Parsing TOML needs the `toml` feature".to_string(),
+    source: None,
+    position: None,
+    code: None,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_from_src() {
+    assert_eq!(
+      DataFormat::from_src("a/data.yaml", None).unwrap(),
+      Some(DataFormat::Yaml)
+    );
+    assert_eq!(
+      DataFormat::from_src("data.YML", None).unwrap(),
+      Some(DataFormat::Yaml)
+    );
+    assert_eq!(
+      DataFormat::from_src("data.toml", None).unwrap(),
+      Some(DataFormat::Toml)
+    );
+    assert_eq!(DataFormat::from_src("data.json", None).unwrap(), None);
+    assert_eq!(
+      DataFormat::from_src("data.txt", Some("yaml")).unwrap(),
+      Some(DataFormat::Yaml)
+    );
+    assert_eq!(
+      DataFormat::from_src("data.yaml", Some("text")).unwrap(),
+      None
+    );
+    assert_eq!(
+      DataFormat::from_src("rows.csv", None).unwrap(),
+      Some(DataFormat::Csv)
+    );
+    assert!(DataFormat::from_src("data", Some("xml")).is_err());
+  }
+
+  #[test]
+  fn test_parse_csv() {
+    let value = DataFormat::Csv
+      .parse("name,note\r\nAda,\"math, \"\"code\"\"\"\r\n\nBob,\"two\nlines\"")
+      .unwrap();
+    assert_eq!(
+      value,
+      json!([
+        {"name": "Ada", "note": "math, \"code\""},
+        {"name": "Bob", "note": "two\nlines"}
+      ])
+    );
+    assert_eq!(DataFormat::Csv.parse("").unwrap(), json!([]));
+    assert!(DataFormat::Csv.parse("a,b\n1\n").is_err());
+    assert!(DataFormat::Csv.parse("a\n\"1\n").is_err());
+  }
+
+  #[cfg(feature = "yaml")]
+  #[test]
+  fn test_parse_yaml() {
+    let value = DataFormat::Yaml
+      .parse("name: poml\ntags:\n  - a\n  - b\n")
+      .unwrap();
+    assert_eq!(value, json!({"name": "poml", "tags": ["a", "b"]}));
+  }
+
+  #[cfg(feature = "toml")]
+  #[test]
+  fn test_parse_toml() {
+    let value = DataFormat::Toml
+      .parse("name = \"poml\"\n[limits]\nmax = 3\n")
+      .unwrap();
+    assert_eq!(value, json!({"name": "poml", "limits": {"max": 3}}));
+  }
+}
@@ -0,0 +1,200 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Date and time of the builtin functions, all in UTC. Dates are ISO 8601 strings like
+ * `2025-01-31T08:30:00Z`, or numbers of milliseconds since the Unix epoch like `Date.now()`
+ * of JavaScript.
+ */
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/**
+ * A date and time in UTC.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTime {
+  pub year: i64,
+  pub month: u32,
+  pub day: u32,
+  pub hour: u32,
+  pub minute: u32,
+  pub second: u32,
+}
+
+impl DateTime {
+  pub fn now() -> DateTime {
+    let seconds = match SystemTime::now().duration_since(UNIX_EPOCH) {
+      Ok(d) => d.as_secs() as i64,
+      Err(e) => -(e.duration().as_secs() as i64),
+    };
+    DateTime::from_timestamp(seconds)
+  }
+
+  /**
+   * Create the date from the number of seconds since the Unix epoch.
+   */
+  pub fn from_timestamp(seconds: i64) -> DateTime {
+    let days = seconds.div_euclid(86400);
+    let seconds_of_day = seconds.rem_euclid(86400) as u32;
+    let (year, month, day) = civil_from_days(days);
+    DateTime {
+      year,
+      month,
+      day,
+      hour: seconds_of_day / 3600,
+      minute: seconds_of_day / 60 % 60,
+      second: seconds_of_day % 60,
+    }
+  }
+
+  /**
+   * Parse an ISO 8601 date like `2025-01-31`, optionally with the time like
+   * `2025-01-31T08:30:00Z`. Fractions of seconds are ignored, and the time zone should
+   * be UTC if present.
+   */
+  pub fn parse(s: &str) -> Option<DateTime> {
+    let s = s.trim();
+    let (date, time) = match s.split_once(['T', ' ']) {
+      Some((date, time)) => (date, Some(time)),
+      None => (s, None),
+    };
+    let mut date_parts = date.splitn(3, '-');
+    let year = date_parts.next()?.parse().ok()?;
+    let month = date_parts.next()?.parse().ok()?;
+    let day = date_parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+      return None;
+    }
+    let (hour, minute, second) = match time {
+      None => (0, 0, 0),
+      Some(time) => {
+        let time = time.strip_suffix('Z').unwrap_or(time);
+        let time = time.strip_suffix("+00:00").unwrap_or(time);
+        let time = time.split('.').next()?;
+        let mut time_parts = time.splitn(3, ':');
+        let hour = time_parts.next()?.parse().ok()?;
+        let minute = time_parts.next()?.parse().ok()?;
+        let second = time_parts.next().map_or(Some(0), |s| s.parse().ok())?;
+        if hour > 23 || minute > 59 || second > 59 {
+          return None;
+        }
+        (hour, minute, second)
+      }
+    };
+    Some(DateTime {
+      year,
+      month,
+      day,
+      hour,
+      minute,
+      second,
+    })
+  }
+
+  /**
+   * Format the date with the pattern, where `YYYY`, `YY`, `MM`, `M`, `DD`, `D`, `HH`, `H`,
+   * `mm` and `ss` are replaced by the fields, and the other characters are kept.
+   */
+  pub fn format(&self, pattern: &str) -> String {
+    let fields: [(&str, String); 10] = [
+      ("YYYY", format!("{:04}", self.year)),
+      ("YY", format!("{:02}", self.year.rem_euclid(100))),
+      ("MM", format!("{:02}", self.month)),
+      ("M", self.month.to_string()),
+      ("DD", format!("{:02}", self.day)),
+      ("D", self.day.to_string()),
+      ("HH", format!("{:02}", self.hour)),
+      ("H", self.hour.to_string()),
+      ("mm", format!("{:02}", self.minute)),
+      ("ss", format!("{:02}", self.second)),
+    ];
+    let mut answer = String::new();
+    let mut rest = pattern;
+    'outer: while let Some(c) = rest.chars().next() {
+      for (token, value) in fields.iter() {
+        if let Some(after) = rest.strip_prefix(token) {
+          answer += value;
+          rest = after;
+          continue 'outer;
+        }
+      }
+      answer.push(c);
+      rest = &rest[c.len_utf8()..];
+    }
+    answer
+  }
+
+  /** The ISO 8601 form like `2025-01-31T08:30:00Z`. */
+  pub fn to_iso_string(self) -> String {
+    self.format("YYYY-MM-DDTHH:mm:ssZ")
+  }
+}
+
+fn is_leap_year(year: i64) -> bool {
+  year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+  match month {
+    2 if is_leap_year(year) => 29,
+    2 => 28,
+    4 | 6 | 9 | 11 => 30,
+    _ => 31,
+  }
+}
+
+/**
+ * Year, month and day of the number of days since 1970-01-01, by the algorithm of
+ * Howard Hinnant.
+ */
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+  (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_from_timestamp() {
+    assert_eq!(
+      DateTime::from_timestamp(0).to_iso_string(),
+      "1970-01-01T00:00:00Z"
+    );
+    assert_eq!(
+      DateTime::from_timestamp(1709210096).to_iso_string(),
+      "2024-02-29T12:34:56Z"
+    );
+    assert_eq!(
+      DateTime::from_timestamp(-1).to_iso_string(),
+      "1969-12-31T23:59:59Z"
+    );
+  }
+
+  #[test]
+  fn test_parse_and_format() {
+    let date = DateTime::parse("2025-01-31T08:05:09.123Z").unwrap();
+    assert_eq!(date.format("YYYY/MM/DD HH:mm:ss"), "2025/01/31 08:05:09");
+    assert_eq!(date.format("D.M.YY H"), "31.1.25 8");
+    assert_eq!(
+      DateTime::parse("2025-03-01").unwrap().to_iso_string(),
+      "2025-03-01T00:00:00Z"
+    );
+    assert!(DateTime::parse("2025-02-29").is_none());
+    assert!(DateTime::parse("2025-13-01").is_none());
+    assert!(DateTime::parse("yesterday").is_none());
+  }
+}
@@ -0,0 +1,534 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::cast::{cast_as_f64, cast_as_i64};
+use super::date::DateTime;
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use crate::render::expression::Expression;
+use crate::render::expression::utils::is_false_json_value;
+use crate::render::render_context::RenderContext;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+
+/**
+ * Maximum number of items `range` can generate.
+ */
+const MAX_RANGE_LENGTH: u64 = 1_000_000;
+
+/**
+ * Call a builtin function with evaluated arguments.
+ */
+pub fn call_builtin_function(name: &str, args: &[Value], context: &RenderContext) -> Result<Value> {
+  match name {
+    "range" => builtin_range(args),
+    "shuffle" => {
+      check_argument_count(name, args, 1)?;
+      check_random_allowed(name, context)?;
+      let items = as_array(name, &args[0])?;
+      Ok(Value::Array(sample_items(items, items.len(), context)))
+    }
+    "sample" => {
+      check_random_allowed(name, context)?;
+      builtin_sample(args, context)
+    }
+    "randint" => {
+      check_random_allowed(name, context)?;
+      builtin_randint(args, context)
+    }
+    "now" => {
+      check_argument_count(name, args, 0)?;
+      context.check_not_pure("`now()`")?;
+      Ok(Value::String(DateTime::now().to_iso_string()))
+    }
+    "today" => {
+      check_argument_count(name, args, 0)?;
+      context.check_not_pure("`today()`")?;
+      Ok(Value::String(DateTime::now().format("YYYY-MM-DD")))
+    }
+    "formatDate" => {
+      context.check_not_pure("`formatDate()`")?;
+      builtin_format_date(args)
+    }
+    "keys" | "values" | "entries" => {
+      check_argument_count(name, args, 1)?;
+      let object = as_object(name, &args[0])?;
+      Ok(Value::Array(match name {
+        "keys" => object.keys().map(|k| Value::String(k.clone())).collect(),
+        "values" => object.values().cloned().collect(),
+        _ => object
+          .iter()
+          .map(|(k, v)| Value::Array(vec![Value::String(k.clone()), v.clone()]))
+          .collect(),
+      }))
+    }
+    "merge" => {
+      check_argument_count(name, args, 2)?;
+      as_object(name, &args[0])?;
+      as_object(name, &args[1])?;
+      Ok(deep_merge(args[0].clone(), &args[1]))
+    }
+    "get" => builtin_get(args),
+    #[cfg(feature = "regex")]
+    "test" => super::pattern::builtin_test(args, context),
+    #[cfg(feature = "regex")]
+    "match" => super::pattern::builtin_match(args, context),
+    #[cfg(feature = "regex")]
+    "replaceAll" => super::pattern::builtin_replace_all(args, context),
+    #[cfg(not(feature = "regex"))]
+    "test" | "match" | "replaceAll" => Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!("{name}() needs the `regex` feature"),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::UnknownFunction),
+    }),
+    "sum" | "avg" | "min" | "max" => builtin_aggregate(name, args),
+    "count" => builtin_count(args, context),
+    "sort" => builtin_sort(args, context),
+    "unique" => {
+      check_argument_count(name, args, 1)?;
+      let mut items: Vec<Value> = Vec::new();
+      for item in as_array(name, &args[0])? {
+        if !items.contains(item) {
+          items.push(item.clone());
+        }
+      }
+      Ok(Value::Array(items))
+    }
+    _ => Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!("Unknown function: {name}"),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::UnknownFunction),
+    }),
+  }
+}
+
+/**
+ * `range(end)`, `range(start, end)` or `range(start, end, step)` generates the integers
+ * from `start` (inclusive, default 0) to `end` (exclusive).
+ */
+fn builtin_range(args: &[Value]) -> Result<Value> {
+  let mut int_args = Vec::with_capacity(args.len());
+  for arg in args {
+    let Some(v) = cast_as_i64(arg) else {
+      return Err(Error {
+        kind: ErrorKind::EvaluatorError,
+        message: format!("Arguments of range() should be integers, found {arg}"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::TypeMismatch),
+      });
+    };
+    int_args.push(v);
+  }
+  let (start, end, step) = match int_args.as_slice() {
+    [end] => (0, *end, 1),
+    [start, end] => (*start, *end, 1),
+    [start, end, step] => (*start, *end, *step),
+    _ => {
+      return Err(Error {
+        kind: ErrorKind::EvaluatorError,
+        message: format!("range() takes 1 to 3 arguments, found {}", args.len()),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::TypeMismatch),
+      });
+    }
+  };
+  if step == 0 {
+    return Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: "Step of range() should not be zero".to_string(),
+      source: None,
+      position: None,
+      code: None,
+    });
+  }
+  let distance = if step > 0 {
+    end.saturating_sub(start)
+  } else {
+    start.saturating_sub(end)
+  };
+  let length = (distance.max(0) as u64).div_ceil(step.unsigned_abs());
+  if length > MAX_RANGE_LENGTH {
+    return Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!("range() generates too many items: {length}"),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::ExpressionLimit),
+    });
+  }
+  Ok(Value::Array(
+    (0..length as i64)
+      .map(|i| Value::from(start + i * step))
+      .collect(),
+  ))
+}
+
+/**
+ * Random builtins are only allowed in pure mode with a seed, which makes them reproducible.
+ */
+fn check_random_allowed(name: &str, context: &RenderContext) -> Result<()> {
+  if context.is_random_seeded() {
+    return Ok(());
+  }
+  context.check_not_pure(&format!("`{name}()` without a random seed"))
+}
+
+/**
+ * `sample(array, n)` picks `n` distinct items of the array in random order.
+ */
+fn builtin_sample(args: &[Value], context: &RenderContext) -> Result<Value> {
+  check_argument_count("sample", args, 2)?;
+  let items = as_array("sample", &args[0])?;
+  let count = match cast_as_i64(&args[1]) {
+    Some(n) if n >= 0 && n as usize <= items.len() => n as usize,
+    _ => {
+      return Err(Error {
+        kind: ErrorKind::EvaluatorError,
+        message: format!(
+          "Sample size of sample() should be an integer from 0 to {}, found {}",
+          items.len(),
+          args[1]
+        ),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::TypeMismatch),
+      });
+    }
+  };
+  Ok(Value::Array(sample_items(items, count, context)))
+}
+
+/**
+ * Pick `count` items by the partial Fisher-Yates shuffle.
+ */
+fn sample_items(items: &[Value], count: usize, context: &RenderContext) -> Vec<Value> {
+  let mut items = items.to_vec();
+  for i in 0..count {
+    let j = i + (context.next_random() % (items.len() - i) as u64) as usize;
+    items.swap(i, j);
+  }
+  items.truncate(count);
+  items
+}
+
+/**
+ * `randint(a, b)` generates a random integer from `a` to `b`, both inclusive.
+ */
+fn builtin_randint(args: &[Value], context: &RenderContext) -> Result<Value> {
+  check_argument_count("randint", args, 2)?;
+  let (Some(low), Some(high)) = (cast_as_i64(&args[0]), cast_as_i64(&args[1])) else {
+    return Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!(
+        "Arguments of randint() should be integers, found {} and {}",
+        args[0], args[1]
+      ),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
+    });
+  };
+  if low > high {
+    return Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!("randint() got an empty range from {low} to {high}"),
+      source: None,
+      position: None,
+      code: None,
+    });
+  }
+  let span = high.abs_diff(low).wrapping_add(1);
+  let offset = match span {
+    0 => context.next_random(),
+    _ => context.next_random() % span,
+  };
+  Ok(Value::from(low.wrapping_add(offset as i64)))
+}
+
+/**
+ * `sum(arr)`, `avg(arr)`, `min(arr)` and `max(arr)` of an array of numbers. `sum` keeps
+ * integers as integers, and the others are `null` for empty arrays.
+ */
+fn builtin_aggregate(name: &str, args: &[Value]) -> Result<Value> {
+  check_argument_count(name, args, 1)?;
+  let items = as_array(name, &args[0])?;
+  let mut numbers = Vec::with_capacity(items.len());
+  for item in items {
+    match (item, cast_as_f64(item)) {
+      (Value::Number(_), Some(n)) => numbers.push(n),
+      _ => {
+        return Err(Error {
+          kind: ErrorKind::EvaluatorError,
+          message: format!("{name}() takes an array of numbers, found {item}"),
+          source: None,
+          position: None,
+          code: Some(ErrorCode::TypeMismatch),
+        });
+      }
+    }
+  }
+  let extreme = |better: fn(f64, f64) -> bool| {
+    let mut answer: Option<(&Value, f64)> = None;
+    for (item, n) in items.iter().zip(numbers.iter()) {
+      if answer.is_none_or(|(_, m)| better(*n, m)) {
+        answer = Some((item, *n));
+      }
+    }
+    answer.map_or(Value::Null, |(item, _)| item.clone())
+  };
+  Ok(match name {
+    "sum" => match items
+      .iter()
+      .map(cast_as_i64)
+      .try_fold(0i64, |sum, n| sum.checked_add(n?))
+    {
+      Some(sum) => Value::from(sum),
+      None => Value::from(numbers.iter().sum::<f64>()),
+    },
+    "avg" if numbers.is_empty() => Value::Null,
+    "avg" => Value::from(numbers.iter().sum::<f64>() / numbers.len() as f64),
+    "min" => extreme(|a, b| a < b),
+    _ => extreme(|a, b| a > b),
+  })
+}
+
+/**
+ * `count(arr)` is the length of the array, and `count(arr, predicate)` counts the items
+ * for which the predicate expression like `'item.score > 60'` is truthy, where `item` is
+ * the item.
+ */
+fn builtin_count(args: &[Value], context: &RenderContext) -> Result<Value> {
+  match args {
+    [items] => Ok(Value::from(as_array("count", items)?.len())),
+    [items, predicate] => {
+      let results = evaluate_for_items("count", as_array("count", items)?, predicate, context)?;
+      Ok(Value::from(
+        results.iter().filter(|v| !is_false_json_value(v)).count(),
+      ))
+    }
+    _ => Err(argument_range_error("count", 1, 2, args.len())),
+  }
+}
+
+/**
+ * `sort(arr)` sorts the items, and `sort(arr, key)` sorts them by the key expression like
+ * `'item.age'`, where `item` is the item. The sort is stable, and numbers go before strings.
+ */
+fn builtin_sort(args: &[Value], context: &RenderContext) -> Result<Value> {
+  let (items, keys) = match args {
+    [items] => {
+      let items = as_array("sort", items)?;
+      (items, items.to_vec())
+    }
+    [items, key] => {
+      let items = as_array("sort", items)?;
+      (items, evaluate_for_items("sort", items, key, context)?)
+    }
+    _ => return Err(argument_range_error("sort", 1, 2, args.len())),
+  };
+  let mut indices: Vec<usize> = (0..items.len()).collect();
+  indices.sort_by(|&a, &b| compare_values(&keys[a], &keys[b]));
+  Ok(Value::Array(
+    indices.into_iter().map(|i| items[i].clone()).collect(),
+  ))
+}
+
+/**
+ * Evaluate the expression with each item bound to `item`.
+ */
+fn evaluate_for_items(
+  name: &str,
+  items: &[Value],
+  expression: &Value,
+  context: &RenderContext,
+) -> Result<Vec<Value>> {
+  let Value::String(expression) = expression else {
+    return Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!("{name}() takes an expression string, found {expression}"),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
+    });
+  };
+  let expression = Expression::parse(expression)?;
+  let mut context = context.clone();
+  context.push_scope();
+  items
+    .iter()
+    .map(|item| {
+      context.set_value("item", item.clone());
+      expression.evaluate(&context)
+    })
+    .collect()
+}
+
+/**
+ * Order of the values for sorting: `null`, booleans, numbers, strings, and the others by
+ * their JSON text.
+ */
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+  fn rank(v: &Value) -> u8 {
+    match v {
+      Value::Null => 0,
+      Value::Bool(_) => 1,
+      Value::Number(_) => 2,
+      Value::String(_) => 3,
+      _ => 4,
+    }
+  }
+  match (a, b) {
+    (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+    (Value::Number(_), Value::Number(_)) => match (a.as_i64(), b.as_i64()) {
+      (Some(a), Some(b)) => a.cmp(&b),
+      _ => cast_as_f64(a)
+        .unwrap_or(f64::NAN)
+        .total_cmp(&cast_as_f64(b).unwrap_or(f64::NAN)),
+    },
+    (Value::String(a), Value::String(b)) => a.cmp(b),
+    _ => rank(a)
+      .cmp(&rank(b))
+      .then_with(|| a.to_string().cmp(&b.to_string())),
+  }
+}
+
+fn argument_range_error(name: &str, min: usize, max: usize, found: usize) -> Error {
+  Error {
+    kind: ErrorKind::EvaluatorError,
+    message: format!("{name}() takes {min} to {max} arguments, found {found}"),
+    source: None,
+    position: None,
+    code: Some(ErrorCode::TypeMismatch),
+  }
+}
+
+/**
+ * Merge the object `b` into `a` recursively, where the values of `b` take precedence
+ * except that the objects in both are merged.
+ */
+fn deep_merge(a: Value, b: &Value) -> Value {
+  match (a, b) {
+    (Value::Object(mut a), Value::Object(b)) => {
+      for (key, value) in b {
+        let merged = match a.remove(key) {
+          Some(old) => deep_merge(old, value),
+          None => value.clone(),
+        };
+        a.insert(key.clone(), merged);
+      }
+      Value::Object(a)
+    }
+    (_, b) => b.clone(),
+  }
+}
+
+/**
+ * `get(obj, "a.b.0", default)` looks up the value by the dotted path, where numbers index
+ * the arrays. Return the default, or `null` without it, if the path doesn't exist.
+ */
+fn builtin_get(args: &[Value]) -> Result<Value> {
+  let (value, path, default) = match args {
+    [value, Value::String(path)] => (value, path, Value::Null),
+    [value, Value::String(path), default] => (value, path, default.clone()),
+    _ => {
+      return Err(Error {
+        kind: ErrorKind::EvaluatorError,
+        message: format!(
+          "get() takes a value, a path string and an optional default, found {} arguments",
+          args.len()
+        ),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::TypeMismatch),
+      });
+    }
+  };
+  let mut current = value;
+  for key in path.split('.').filter(|key| !key.is_empty()) {
+    let next = match current {
+      Value::Object(object) => object.get(key),
+      Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+      _ => None,
+    };
+    match next {
+      Some(next) => current = next,
+      None => return Ok(default),
+    }
+  }
+  Ok(current.clone())
+}
+
+fn as_object<'v>(name: &str, value: &'v Value) -> Result<&'v Map<String, Value>> {
+  match value {
+    Value::Object(object) => Ok(object),
+    _ => Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!("{name}() takes an object, found {value}"),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
+    }),
+  }
+}
+
+fn as_array<'v>(name: &str, value: &'v Value) -> Result<&'v [Value]> {
+  match value {
+    Value::Array(items) => Ok(items),
+    _ => Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!("{name}() takes an array, found {value}"),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
+    }),
+  }
+}
+
+pub(super) fn check_argument_count(name: &str, args: &[Value], count: usize) -> Result<()> {
+  if args.len() == count {
+    return Ok(());
+  }
+  Err(Error {
+    kind: ErrorKind::EvaluatorError,
+    message: format!("{name}() takes {count} arguments, found {}", args.len()),
+    source: None,
+    position: None,
+    code: Some(ErrorCode::TypeMismatch),
+  })
+}
+
+/**
+ * `formatDate(date, pattern)` formats an ISO 8601 date string or a timestamp in
+ * milliseconds with the pattern like `YYYY-MM-DD`. See [`DateTime::format`].
+ */
+fn builtin_format_date(args: &[Value]) -> Result<Value> {
+  check_argument_count("formatDate", args, 2)?;
+  let date = match &args[0] {
+    Value::String(s) => DateTime::parse(s),
+    Value::Number(_) => {
+      cast_as_f64(&args[0]).map(|ms| DateTime::from_timestamp((ms / 1000.0).floor() as i64))
+    }
+    _ => None,
+  };
+  let (Some(date), Value::String(pattern)) = (date, &args[1]) else {
+    return Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!(
+        "formatDate() takes a date and a pattern string, found {} and {}",
+        args[0], args[1]
+      ),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
+    });
+  };
+  Ok(Value::String(date.format(pattern)))
+}
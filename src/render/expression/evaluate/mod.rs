@@ -4,1053 +4,500 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use super::UndefinedPolicy;
+use super::parse::{BinaryOperator, ExpressionNode, TokenSource, locate_error, parse_tokens};
 use super::tokenize::ExpressionToken;
 use super::utils::is_false_json_value;
-use crate::error::{Error, ErrorKind, Result};
+use crate::error::{Error, ErrorCode, ErrorKind, Result, Span};
 use crate::render::render_context::RenderContext;
 use serde_json::Value;
+use std::borrow::Cow;
 mod cast;
 use cast::*;
+mod date;
+mod functions;
+#[cfg(feature = "regex")]
+mod pattern;
+use functions::call_builtin_function;
 
 pub fn evaluate_expression_tokens(
   tokens: &[ExpressionToken],
   context: &RenderContext,
 ) -> Result<Value> {
-  let (value, next_pos) = evaluate_expression_value(tokens, 0, context)?;
-
-  if next_pos == tokens.len() {
-    Ok(value)
-  } else {
-    Err(Error {
-      kind: ErrorKind::EvaluatorError,
-      message: "Not implemented".to_string(),
-      source: None,
-    })
-  }
+  evaluate_located_tokens(tokens, TokenSource::UNKNOWN, context)
 }
 
-#[derive(Debug, Clone, PartialEq)]
-enum ExpressionPart<'a> {
-  Value(serde_json::Value),
-  Operator(&'a str),
+/**
+ * Evaluate the tokens whose errors are located in the source expression.
+ */
+pub(crate) fn evaluate_located_tokens(
+  tokens: &[ExpressionToken],
+  source: TokenSource,
+  context: &RenderContext,
+) -> Result<Value> {
+  check_expression_limits(tokens.len(), nesting_depth(tokens), context)?;
+  let node = parse_tokens(tokens, source)?;
+  Ok(evaluate_node(&node, source.text, context)?.into_owned())
 }
 
-fn evaluate_expression_value(
-  tokens: &[ExpressionToken],
-  start_pos: usize,
+/**
+ * Check the token count and the nesting depth against the limits of the context. The
+ * evaluator recurses for each nesting level, so the depth bounds the recursion.
+ */
+pub(crate) fn check_expression_limits(
+  token_count: usize,
+  depth: usize,
   context: &RenderContext,
-) -> Result<(Value, usize)> {
-  let mut pos = start_pos;
-  let mut parts: Vec<ExpressionPart> = vec![];
-  while pos < tokens.len() {
-    match tokens[pos] {
-      // Signals of ending of a (sub) expression: ']', '}', ','
-      ExpressionToken::RightBracket
-      | ExpressionToken::RightCurly
-      | ExpressionToken::Comma
-      | ExpressionToken::RightParenthesis
-      | ExpressionToken::DoubleRightCurly => break,
-      ExpressionToken::LeftParenthesis => {
-        let (value, new_pos) = evaluate_expression_value(tokens, pos + 1, context)?;
-        if new_pos >= tokens.len() || tokens[new_pos] != ExpressionToken::RightParenthesis {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Not paired right parenthesis for a left parenthesis".to_string(),
-            source: None,
-          });
-        }
-        pos = new_pos + 1;
-        parts.push(ExpressionPart::Value(value));
-      }
-      ExpressionToken::DoubleLeftCurly => {
-        let (value, new_pos) = evaluate_expression_value(tokens, pos + 1, context)?;
-        if new_pos >= tokens.len() || tokens[new_pos] != ExpressionToken::DoubleRightCurly {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Not paired double right curly for a double left curly".to_string(),
-            source: None,
-          });
-        }
-        pos = new_pos + 1;
-        parts.push(ExpressionPart::Value(value));
-      }
-      // Arith operator
-      ExpressionToken::ArithOp(op_name_buf) => {
-        let op_name = str::from_utf8(op_name_buf).unwrap();
-        pos += 1;
-        parts.push(ExpressionPart::Operator(op_name))
-      }
-      ExpressionToken::Exclamation => {
-        pos += 1;
-        parts.push(ExpressionPart::Operator("!"));
-      }
-      ExpressionToken::Ref(_) | ExpressionToken::Number(_) | ExpressionToken::String(_) => {
-        let (value, next_pos) = recognize_next_value(tokens, pos, context)?;
-        parts.push(ExpressionPart::Value(value));
-        pos = next_pos;
-      }
-      ExpressionToken::LeftBracket => {
-        let (value, next_pos) = recognize_next_array(tokens, pos, context)?;
-        parts.push(ExpressionPart::Value(value));
-        pos = next_pos;
-      }
-      ExpressionToken::LeftCurly => {
-        let (value, next_pos) = recognize_next_object(tokens, pos, context)?;
-        parts.push(ExpressionPart::Value(value));
-        pos = next_pos;
-      }
-      ExpressionToken::QuestionMark => {
-        parts.push(ExpressionPart::Operator("?"));
-        pos += 1;
-      }
-      ExpressionToken::Colon => {
-        parts.push(ExpressionPart::Operator(":"));
-        pos += 1;
-      }
-      _ => {
-        return Err(Error {
-          kind: ErrorKind::EvaluatorError,
-          message: format!("Not implemented to recognize token: {:?}", tokens[pos]),
-          source: None,
-        });
-      }
-    }
-  }
-  // Process different operators based on the operator precedence in Javascript
-  // Refer to https://developer.mozilla.org/en-US/docs/Web/JavaScript/Reference/Operators/Operator_precedence
-  parts = process_not_operators(parts)?;
-  parts = process_times_divide_mod_operators(parts)?;
-  parts = process_plus_and_minus_operators(parts)?;
-  parts = process_rational_operators(parts)?;
-  parts = process_equality_operators(parts)?;
-  parts = process_and_operators(parts)?;
-  parts = process_or_operators(parts)?;
-  parts = process_ternary_operators(parts)?;
-  if parts.len() > 1 {
+) -> Result<()> {
+  let limits = context.expression_limits();
+  if token_count > limits.max_tokens {
     return Err(Error {
       kind: ErrorKind::EvaluatorError,
-      message: "Some operators remained unprocessed!".to_string(),
+      message: format!(
+        "Expression has too many tokens: {token_count}, the limit is {}",
+        limits.max_tokens
+      ),
       source: None,
+      position: None,
+      code: Some(ErrorCode::ExpressionLimit),
     });
   }
-  let Some(ExpressionPart::Value(ret_value)) = parts.pop() else {
+  if depth > limits.max_depth {
     return Err(Error {
       kind: ErrorKind::EvaluatorError,
-      message: "No value found in the expression".to_string(),
+      message: format!(
+        "Expression is nested too deeply, the limit is {}",
+        limits.max_depth
+      ),
       source: None,
+      position: None,
+      code: Some(ErrorCode::ExpressionLimit),
     });
-  };
-  Ok((ret_value, pos))
-}
-
-fn process_and_operators<'a>(parts: Vec<ExpressionPart<'a>>) -> Result<Vec<ExpressionPart<'a>>> {
-  let mut contain_and = false;
-  for part in &parts {
-    if *part == ExpressionPart::Operator("&&") {
-      contain_and = true;
-      break;
-    }
-  }
-
-  // directly return if there is no and operators in the input
-  if !contain_and {
-    return Ok(parts);
-  }
-
-  let mut new_parts = Vec::new();
-  let mut i = 0;
-  while i < parts.len() {
-    match parts[i] {
-      ExpressionPart::Operator("&&") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator && appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator && appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let mut value = true;
-        if is_false_json_value(&a) {
-          value = false;
-        }
-        if is_false_json_value(b) {
-          value = false;
-        }
-        new_parts.push(ExpressionPart::Value(Value::Bool(value)));
-        i += 2;
-      }
-      _ => {
-        new_parts.push(parts[i].clone());
-        i += 1;
-      }
-    }
   }
-  Ok(new_parts)
+  Ok(())
 }
 
-fn process_or_operators<'a>(parts: Vec<ExpressionPart<'a>>) -> Result<Vec<ExpressionPart<'a>>> {
-  let mut contain_or = false;
-  for part in &parts {
-    if *part == ExpressionPart::Operator("||") {
-      contain_or = true;
-      break;
-    }
-  }
-
-  // directly return if there is no or operators in the input
-  if !contain_or {
-    return Ok(parts);
-  }
-
-  let mut new_parts = Vec::new();
-  let mut i = 0;
-  while i < parts.len() {
-    match parts[i] {
-      ExpressionPart::Operator("||") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator || appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator || appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let mut value = false;
-        if !is_false_json_value(&a) {
-          value = true;
-        }
-        if !is_false_json_value(b) {
-          value = true;
+/**
+ * Maximum nesting depth of the tokens. Parentheses, brackets and curly brackets nest one
+ * level each, and so does each link of a chain of field accesses and indexing like
+ * `a.b[0]`, which the evaluator also recurses into.
+ */
+pub(crate) fn nesting_depth(tokens: &[ExpressionToken]) -> usize {
+  let mut depth: usize = 0;
+  let mut max_depth = 0;
+  // Length of the current chain, and the chains of the enclosing brackets with their sum
+  let mut chain = 0;
+  let mut enclosing_chains = Vec::new();
+  let mut enclosing_chain_sum = 0;
+  let mut previous: Option<&ExpressionToken> = None;
+  for token in tokens {
+    match token {
+      ExpressionToken::LeftParenthesis
+      | ExpressionToken::LeftBracket
+      | ExpressionToken::LeftCurly
+      | ExpressionToken::DoubleLeftCurly => {
+        // Indexing like `a[0]` is a link of the chain of `a`
+        if *token == ExpressionToken::LeftBracket && previous.is_some_and(ends_value) {
+          chain += 1;
         }
-        new_parts.push(ExpressionPart::Value(Value::Bool(value)));
-        i += 2;
+        enclosing_chains.push(chain);
+        enclosing_chain_sum += chain;
+        chain = 0;
+        depth += 1;
       }
-      _ => {
-        new_parts.push(parts[i].clone());
-        i += 1;
+      ExpressionToken::RightParenthesis
+      | ExpressionToken::RightBracket
+      | ExpressionToken::RightCurly
+      | ExpressionToken::DoubleRightCurly => {
+        depth = depth.saturating_sub(1);
+        chain = enclosing_chains.pop().unwrap_or(0);
+        enclosing_chain_sum -= chain;
       }
+      ExpressionToken::Dot => chain += 1,
+      ExpressionToken::ArithOp(_)
+      | ExpressionToken::Comma
+      | ExpressionToken::Colon
+      | ExpressionToken::QuestionMark => chain = 0,
+      _ => {}
     }
+    max_depth = max_depth.max(depth + chain + enclosing_chain_sum);
+    previous = Some(token);
   }
-  Ok(new_parts)
+  max_depth
 }
 
-fn process_ternary_operators<'a>(
-  parts: Vec<ExpressionPart<'a>>,
-) -> Result<Vec<ExpressionPart<'a>>> {
-  let mut contain_ternary = false;
-  for part in &parts {
-    if *part == ExpressionPart::Operator("?") {
-      contain_ternary = true;
-      break;
-    }
-  }
-
-  if !contain_ternary {
-    return Ok(parts);
-  }
-
-  let mut new_parts = Vec::new();
-  // evaluate ternary operators from right to left
-  let mut i_rev = 0;
-  while i_rev < parts.len() {
-    let i = parts.len() - i_rev - 1;
-    match parts[i] {
-      ExpressionPart::Operator("?") => {
-        if i == 0 {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Ternary operator ?: appears without the false branch value after it."
-              .to_string(),
-            source: None,
-          });
-        }
-        let Some(ExpressionPart::Value(true_branch_value)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Ternary operator ? appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Operator(":")) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Ternary operator ? appears without corresponding : operator.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(false_branch_value)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Ternary operator ?: appears without the false branch value after it."
-              .to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(cond_value)) = parts.get(i - 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Ternary operator ? appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        if is_false_json_value(cond_value) {
-          new_parts.push(ExpressionPart::Value(false_branch_value));
-        } else {
-          new_parts.push(ExpressionPart::Value(true_branch_value));
-        }
-        i_rev += 2;
-      }
-      ExpressionPart::Operator(":") => {
-        // ensure that a value (false branch value) has been pushed into the stack.
-        let Some(&ExpressionPart::Value(_)) = new_parts.last() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Ternary operator : appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        new_parts.push(parts[i].clone());
-        i_rev += 1;
-      }
-      _ => {
-        new_parts.push(parts[i].clone());
-        i_rev += 1;
-      }
-    }
-  }
-  // The new_parts is computed from right to left. Have to reverse it to get back to normal order.
-  new_parts.reverse();
-  Ok(new_parts)
+/**
+ * Whether the token is the last token of a value, which indexing can follow.
+ */
+fn ends_value(token: &ExpressionToken) -> bool {
+  matches!(
+    token,
+    ExpressionToken::Ref(_)
+      | ExpressionToken::Number(_)
+      | ExpressionToken::String(_)
+      | ExpressionToken::RightParenthesis
+      | ExpressionToken::RightBracket
+      | ExpressionToken::RightCurly
+  )
 }
 
-fn process_not_operators<'a>(parts: Vec<ExpressionPart<'a>>) -> Result<Vec<ExpressionPart<'a>>> {
-  let mut contain_not = false;
-  for part in &parts {
-    if *part == ExpressionPart::Operator("!") {
-      contain_not = true;
-    }
-  }
-
-  // directly return if there is no not operators in the input
-  if !contain_not {
-    return Ok(parts);
-  }
-
-  let mut new_parts = Vec::new();
-  let mut i = 0;
-  while i < parts.len() {
-    match parts[i] {
-      ExpressionPart::Operator("!") => {
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator ! appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = is_false_json_value(b);
-        new_parts.push(ExpressionPart::Value(Value::Bool(value)));
-        i += 2;
-      }
-      _ => {
-        new_parts.push(parts[i].clone());
-        i += 1;
-      }
-    }
-  }
-  Ok(new_parts)
+/**
+ * Parse and evaluate the expression starting at `start_pos`, which ends at the end of the
+ * tokens or at a closing token of an enclosing expression. Return the value and the
+ * position after the expression.
+ */
+#[cfg(test)]
+fn evaluate_expression_value(
+  tokens: &[ExpressionToken],
+  start_pos: usize,
+  context: &RenderContext,
+) -> Result<(Value, usize)> {
+  let (node, pos) = super::parse::parse_expression_tokens(tokens, TokenSource::UNKNOWN, start_pos)?;
+  Ok((evaluate_node(&node, b"", context)?.into_owned(), pos))
 }
-fn process_plus_and_minus_operators<'a>(
-  parts: Vec<ExpressionPart<'a>>,
-) -> Result<Vec<ExpressionPart<'a>>> {
-  let mut contain_plus_minus = false;
-  for part in &parts {
-    if *part == ExpressionPart::Operator("+") || *part == ExpressionPart::Operator("-") {
-      contain_plus_minus = true;
-      break;
-    }
-  }
 
-  // directly return if there is no +/- operators in the input
-  if !contain_plus_minus {
-    return Ok(parts);
+/**
+ * Evaluate a reference to an undefined variable by the policy of the context.
+ */
+fn evaluate_undefined<'a>(
+  name: &str,
+  source: &[u8],
+  span: Span,
+  context: &RenderContext,
+) -> Result<Cow<'a, Value>> {
+  let error = || {
+    locate_error(
+      Error {
+        kind: ErrorKind::EvaluatorError,
+        message: format!("Variable `{name}` is not defined"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::UndefinedVariable),
+      },
+      source,
+      span,
+    )
+  };
+  match context.undefined_policy() {
+    UndefinedPolicy::Null => {}
+    UndefinedPolicy::Error => return Err(error()),
+    UndefinedPolicy::Warn => context.warn(error()),
   }
+  Ok(Cow::Owned(Value::Null))
+}
 
-  let mut new_parts = Vec::new();
-  let mut i = 0;
-  while i < parts.len() {
-    match parts[i] {
-      ExpressionPart::Operator("+") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator + appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator + appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = handle_plus_operator(&a, b)?;
-        new_parts.push(ExpressionPart::Value(value));
-        i += 2;
-      }
-      ExpressionPart::Operator("-") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator - appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator - appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = handle_minus_operator(&a, b)?;
-        new_parts.push(ExpressionPart::Value(value));
-        i += 2;
-      }
-      _ => {
-        new_parts.push(parts[i].clone());
-        i += 1;
+/**
+ * Evaluate a node of the syntax tree parsed from the source text, where the errors are
+ * located. Variables and their fields are borrowed from the context, so only the final value
+ * is cloned.
+ */
+pub(crate) fn evaluate_node<'a>(
+  node: &'a ExpressionNode,
+  source: &[u8],
+  context: &'a RenderContext,
+) -> Result<Cow<'a, Value>> {
+  match node {
+    ExpressionNode::Literal(value) => Ok(Cow::Borrowed(value)),
+    ExpressionNode::Reference(name, span) => {
+      if context.is_env_reference(name) {
+        context
+          .check_not_pure("`env`")
+          .map_err(|e| locate_error(e, source, *span))?;
+      }
+      match context.get_value(name) {
+        Some(value) => Ok(Cow::Borrowed(value)),
+        None => evaluate_undefined(name, source, *span, context),
       }
     }
-  }
-  Ok(new_parts)
-}
-
-fn process_times_divide_mod_operators<'a>(
-  parts: Vec<ExpressionPart<'a>>,
-) -> Result<Vec<ExpressionPart<'a>>> {
-  let mut contain_times_divide = false;
-  for part in &parts {
-    if *part == ExpressionPart::Operator("*")
-      || *part == ExpressionPart::Operator("/")
-      || *part == ExpressionPart::Operator("%")
-    {
-      contain_times_divide = true;
-      break;
+    ExpressionNode::Member(object, key, span) => {
+      let object = evaluate_node(object, source, context)?;
+      evaluate_member(node, object, key).map_err(|e| locate_error(e, source, *span))
     }
-  }
-
-  // directly return if there is no * or / operators in the input
-  if !contain_times_divide {
-    return Ok(parts);
-  }
-
-  let mut new_parts = Vec::new();
-  let mut i = 0;
-  while i < parts.len() {
-    match parts[i] {
-      ExpressionPart::Operator("*") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator * appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator * appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = handle_times_operator(&a, b)?;
-        new_parts.push(ExpressionPart::Value(value));
-        i += 2;
-      }
-      ExpressionPart::Operator("/") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator / appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator - appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = handle_divide_operator(&a, b)?;
-        new_parts.push(ExpressionPart::Value(value));
-        i += 2;
-      }
-      ExpressionPart::Operator("%") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator % appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator % appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = handle_mod_operator(&a, b)?;
-        new_parts.push(ExpressionPart::Value(value));
-        i += 2;
-      }
-      _ => {
-        new_parts.push(parts[i].clone());
-        i += 1;
-      }
+    ExpressionNode::Index(target, index, span) => {
+      let index = evaluate_node(index, source, context)?;
+      let target = evaluate_node(target, source, context)?;
+      evaluate_index(node, target, &index).map_err(|e| locate_error(e, source, *span))
     }
-  }
-  Ok(new_parts)
-}
-
-fn process_rational_operators<'a>(
-  parts: Vec<ExpressionPart<'a>>,
-) -> Result<Vec<ExpressionPart<'a>>> {
-  let mut contain_rational = false;
-  for part in &parts {
-    if *part == ExpressionPart::Operator("<")
-      || *part == ExpressionPart::Operator("<=")
-      || *part == ExpressionPart::Operator(">")
-      || *part == ExpressionPart::Operator(">=")
-      || *part == ExpressionPart::Operator("in")
-    {
-      contain_rational = true;
-      break;
+    ExpressionNode::Call(name, arguments, span) => {
+      let arguments = evaluate_nodes(arguments, source, context)?;
+      // Errors of the expressions in the arguments, like `count(items, 'item.x')`, are
+      // located in their own text, so they are located at the call instead
+      let locate_at_call = |e: Error| {
+        locate_error(
+          Error {
+            position: None,
+            ..e
+          },
+          source,
+          *span,
+        )
+      };
+      if context.undefined_policy() != UndefinedPolicy::Warn {
+        return call_builtin_function(name, &arguments, context)
+          .map(Cow::Owned)
+          .map_err(locate_at_call);
+      }
+      let warnings = context.warning_count();
+      let result = call_builtin_function(name, &arguments, context);
+      context.map_warnings_since(warnings, locate_at_call);
+      result.map(Cow::Owned).map_err(locate_at_call)
     }
-  }
-
-  // directly return if there is no rational operators in the input
-  if !contain_rational {
-    return Ok(parts);
-  }
-
-  let mut new_parts = Vec::new();
-  let mut i = 0;
-  while i < parts.len() {
-    match parts[i] {
-      ExpressionPart::Operator("<") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator < appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator < appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = handle_less_than_operator(&a, b)?;
-        new_parts.push(ExpressionPart::Value(value));
-        i += 2;
-      }
-      ExpressionPart::Operator("<=") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator <= appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator <= appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = handle_less_than_or_equal_operator(&a, b)?;
-        new_parts.push(ExpressionPart::Value(value));
-        i += 2;
-      }
-      ExpressionPart::Operator(">") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator > appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator > appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = handle_greater_than_operator(&a, b)?;
-        new_parts.push(ExpressionPart::Value(value));
-        i += 2;
-      }
-      ExpressionPart::Operator(">=") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator >= appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator >= appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = handle_greater_than_or_equal_operator(&a, b)?;
-        new_parts.push(ExpressionPart::Value(value));
-        i += 2;
-      }
-      ExpressionPart::Operator("in") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator `in` appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator `in` appears without a value after it.".to_string(),
-            source: None,
-          });
+    ExpressionNode::Array(items) => Ok(Cow::Owned(Value::Array(evaluate_nodes(
+      items, source, context,
+    )?))),
+    ExpressionNode::Object(fields) => evaluate_object(fields, source, context),
+    ExpressionNode::Not(_) => evaluate_not(node, source, context),
+    ExpressionNode::Binary(..) => evaluate_binary_chain(node, source, context),
+    ExpressionNode::Conditional(..) => {
+      let mut branch = node;
+      while let ExpressionNode::Conditional(condition, true_branch, false_branch) = branch {
+        branch = if is_false_json_value(&*evaluate_node(condition, source, context)?) {
+          false_branch
+        } else {
+          true_branch
         };
-        let value = handle_in_operator(&a, b)?;
-        new_parts.push(ExpressionPart::Value(value));
-        i += 2;
-      }
-      _ => {
-        new_parts.push(parts[i].clone());
-        i += 1;
       }
+      evaluate_node(branch, source, context)
     }
   }
-  Ok(new_parts)
 }
 
-fn process_equality_operators<'a>(
-  parts: Vec<ExpressionPart<'a>>,
-) -> Result<Vec<ExpressionPart<'a>>> {
-  let mut contain_equals = false;
-  for part in &parts {
-    if *part == ExpressionPart::Operator("===") || *part == ExpressionPart::Operator("!==") {
-      contain_equals = true;
-      break;
-    }
-  }
+fn evaluate_object<'a>(
+  fields: &'a [(String, ExpressionNode)],
+  source: &[u8],
+  context: &'a RenderContext,
+) -> Result<Cow<'a, Value>> {
+  let mut object = serde_json::Map::new();
+  for (key, value) in fields {
+    object.insert(
+      key.clone(),
+      evaluate_node(value, source, context)?.into_owned(),
+    );
+  }
+  Ok(Cow::Owned(Value::Object(object)))
+}
 
-  // directly return if there is no equality operators in the input
-  if !contain_equals {
-    return Ok(parts);
-  }
+/**
+ * Evaluate a chain of `!` like `!!!a` without recursion.
+ */
+fn evaluate_not<'a>(
+  node: &'a ExpressionNode,
+  source: &[u8],
+  context: &'a RenderContext,
+) -> Result<Cow<'a, Value>> {
+  let mut negations = 0;
+  let mut operand = node;
+  while let ExpressionNode::Not(inner) = operand {
+    negations += 1;
+    operand = inner;
+  }
+  let is_false = is_false_json_value(&*evaluate_node(operand, source, context)?);
+  Ok(Cow::Owned(Value::Bool(is_false == (negations % 2 == 1))))
+}
 
-  let mut new_parts = Vec::new();
-  let mut i = 0;
-  while i < parts.len() {
-    match parts[i] {
-      ExpressionPart::Operator("===") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator === appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator === appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = a == *b;
-        new_parts.push(ExpressionPart::Value(Value::Bool(value)));
-        i += 2;
-      }
-      ExpressionPart::Operator("!==") => {
-        let Some(ExpressionPart::Value(a)) = new_parts.pop() else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator !== appears without a value before it.".to_string(),
-            source: None,
-          });
-        };
-        let Some(ExpressionPart::Value(b)) = parts.get(i + 1) else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator !== appears without a value after it.".to_string(),
-            source: None,
-          });
-        };
-        let value = a != *b;
-        new_parts.push(ExpressionPart::Value(Value::Bool(value)));
-        i += 2;
-      }
-      _ => {
-        new_parts.push(parts[i].clone());
-        i += 1;
-      }
-    }
-  }
-  Ok(new_parts)
+/**
+ * Evaluate a binary operator, walking down the left operands without recursion, since the
+ * left-associative chains like `a + b + c + ...` are as deep as they are long.
+ */
+fn evaluate_binary_chain<'a>(
+  node: &'a ExpressionNode,
+  source: &[u8],
+  context: &'a RenderContext,
+) -> Result<Cow<'a, Value>> {
+  let mut chain = Vec::new();
+  let mut leftmost = node;
+  while let ExpressionNode::Binary(operator, a, b, span) = leftmost {
+    chain.push((*operator, b.as_ref(), *span));
+    leftmost = a;
+  }
+  let mut value = evaluate_node(leftmost, source, context)?;
+  for (operator, b, span) in chain.into_iter().rev() {
+    value = evaluate_binary_operator(operator, value, b, source, context)
+      .map_err(|e| locate_error(e, source, span))?;
+  }
+  Ok(value)
 }
 
-fn recognize_next_array(
-  tokens: &[ExpressionToken],
-  start_pos: usize,
+fn evaluate_nodes(
+  nodes: &[ExpressionNode],
+  source: &[u8],
   context: &RenderContext,
-) -> Result<(Value, usize)> {
-  let mut pos = start_pos + 1;
-  let mut array_value: Vec<Value> = Vec::new();
-  let mut array_finished = false;
-  while pos < tokens.len() {
-    if tokens[pos] == ExpressionToken::RightBracket {
-      pos += 1;
-      array_finished = true;
-      break;
-    } else {
-      // read next sub-expression
-      let (item_value, next_pos) = evaluate_expression_value(tokens, pos, context)?;
-      array_value.push(item_value);
-      match tokens[next_pos] {
-        ExpressionToken::Comma => {
-          pos = next_pos + 1;
-        }
-        ExpressionToken::RightBracket => {
-          pos = next_pos;
-          continue;
-        }
-        _ => {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: format!(
-              "Expect comma ',' or right bracket ']' characters, but found {:?}",
-              tokens[pos]
-            ),
-            source: None,
-          });
-        }
-      };
+) -> Result<Vec<Value>> {
+  nodes
+    .iter()
+    .map(|node| Ok(evaluate_node(node, source, context)?.into_owned()))
+    .collect()
+}
+
+fn evaluate_member<'a>(
+  node: &ExpressionNode,
+  object: Cow<'a, Value>,
+  key: &str,
+) -> Result<Cow<'a, Value>> {
+  match object {
+    Cow::Borrowed(Value::Object(fields)) => Ok(match fields.get(key) {
+      Some(value) => Cow::Borrowed(value),
+      None => Cow::Owned(Value::Null),
+    }),
+    Cow::Owned(Value::Object(mut fields)) => {
+      Ok(Cow::Owned(fields.remove(key).unwrap_or(Value::Null)))
     }
-  }
-  if !array_finished {
-    Err(Error {
+    Cow::Borrowed(Value::Null) | Cow::Owned(Value::Null) => Err(Error {
       kind: ErrorKind::EvaluatorError,
-      message: "Array value has not finished in the expression".to_string(),
+      message: format!(
+        "Tried to access field `{key}` on undefined or null variable `{}`.",
+        path_name(node)
+      ),
       source: None,
-    })
-  } else {
-    Ok((Value::Array(array_value), pos))
+      position: None,
+      code: Some(ErrorCode::UndefinedVariable),
+    }),
+    _ => Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!(
+        "Variable `{}` is not an object and `{key}` is not available on it",
+        path_name(node)
+      ),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
+    }),
   }
 }
 
-fn recognize_next_object(
-  tokens: &[ExpressionToken],
-  start_pos: usize,
-  context: &RenderContext,
-) -> Result<(Value, usize)> {
-  let mut pos = start_pos + 1;
-  let mut object_value = serde_json::Map::new();
-  let mut object_finished = false;
-  while pos < tokens.len() {
-    if tokens[pos] == ExpressionToken::RightCurly {
-      pos += 1;
-      object_finished = true;
-      break;
-    } else {
-      // read key
-      let key = match tokens[pos] {
-        ExpressionToken::Ref(ref_key_buf) => str::from_utf8(ref_key_buf).unwrap(),
-        ExpressionToken::String(str_key_buf) => str::from_utf8(str_key_buf).unwrap(),
-        _ => {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Object key must be a string".to_string(),
-            source: None,
-          });
-        }
-      };
-      pos += 1;
-      // Expect colon
-      if tokens[pos] != ExpressionToken::Colon {
+fn evaluate_binary_operator<'a>(
+  operator: BinaryOperator,
+  a: Cow<'a, Value>,
+  b: &'a ExpressionNode,
+  source: &[u8],
+  context: &'a RenderContext,
+) -> Result<Cow<'a, Value>> {
+  // Like JavaScript, `&&` and `||` short-circuit and return one of the operands
+  match operator {
+    BinaryOperator::And if is_false_json_value(&a) => return Ok(a),
+    BinaryOperator::Or if !is_false_json_value(&a) => return Ok(a),
+    BinaryOperator::And | BinaryOperator::Or => return evaluate_node(b, source, context),
+    _ => {}
+  }
+  let b = evaluate_node(b, source, context)?;
+  Ok(Cow::Owned(apply_binary_operator(operator, &a, &b)?))
+}
+
+fn apply_binary_operator(operator: BinaryOperator, a: &Value, b: &Value) -> Result<Value> {
+  Ok(match operator {
+    BinaryOperator::Times => handle_times_operator(a, b)?,
+    BinaryOperator::Divide => handle_divide_operator(a, b)?,
+    BinaryOperator::Mod => handle_mod_operator(a, b)?,
+    BinaryOperator::Plus => handle_plus_operator(a, b)?,
+    BinaryOperator::Minus => handle_minus_operator(a, b)?,
+    BinaryOperator::Less => handle_less_than_operator(a, b)?,
+    BinaryOperator::LessOrEqual => handle_less_than_or_equal_operator(a, b)?,
+    BinaryOperator::Greater => handle_greater_than_operator(a, b)?,
+    BinaryOperator::GreaterOrEqual => handle_greater_than_or_equal_operator(a, b)?,
+    BinaryOperator::In => handle_in_operator(a, b)?,
+    BinaryOperator::StrictEqual => Value::Bool(a == b),
+    BinaryOperator::StrictNotEqual => Value::Bool(a != b),
+    BinaryOperator::And | BinaryOperator::Or => unreachable!(),
+  })
+}
+
+fn evaluate_index<'a>(
+  node: &ExpressionNode,
+  target: Cow<'a, Value>,
+  index: &Value,
+) -> Result<Cow<'a, Value>> {
+  match index {
+    Value::Number(index_num) => {
+      let Some(index_int) = index_num.as_u64() else {
         return Err(Error {
           kind: ErrorKind::EvaluatorError,
-          message: "Expect colon ':' after object key".to_string(),
+          message: format!("Number index should be an unsigned integer, found {index_num:?}"),
           source: None,
+          position: None,
+          code: Some(ErrorCode::ExpressionSyntax),
         });
-      }
-      pos += 1;
-      // read value
-      let (value_value, next_pos) = evaluate_expression_value(tokens, pos, context)?;
-      object_value.insert(key.to_string(), value_value);
-      pos = next_pos;
-      // expect comma or the end of the object
-      match tokens[pos] {
-        ExpressionToken::Comma => {
-          pos += 1;
-        }
-        ExpressionToken::RightCurly => {
-          continue;
-        }
+      };
+      let length = match target.as_ref() {
+        Value::Array(items) => items.len(),
         _ => {
           return Err(Error {
             kind: ErrorKind::EvaluatorError,
-            message: format!(
-              "Expect comma ',' or right curly '}}' characters, but found {:?}",
-              tokens[pos]
-            ),
+            message: "Number index can only be applied on array.".to_string(),
             source: None,
+            position: None,
+            code: Some(ErrorCode::TypeMismatch),
           });
         }
       };
-    }
-  }
-  if !object_finished {
-    Err(Error {
-      kind: ErrorKind::EvaluatorError,
-      message: "Object value has not finished in the expression".to_string(),
-      source: None,
-    })
-  } else {
-    Ok((Value::Object(object_value), pos))
-  }
-}
-
-fn recognize_next_value(
-  tokens: &[ExpressionToken],
-  pos: usize,
-  context: &RenderContext,
-) -> Result<(Value, usize)> {
-  let mut pos = pos;
-  if pos < tokens.len() {
-    let cur = &tokens[pos];
-    match cur {
-      ExpressionToken::Ref(refc) => {
-        let value = evaluate_reference(refc, context)?;
-        let mut value_ref = &value;
-        let null_value = Value::Null;
-        let mut recognized_name = String::from_utf8(refc.to_vec()).unwrap();
-        pos += 1;
-        while pos < tokens.len() {
-          match tokens[pos] {
-            ExpressionToken::Dot => {
-              let Some(ExpressionToken::Ref(key_bytes)) = tokens.get(pos + 1) else {
-                return Err(Error {
-                  kind: ErrorKind::EvaluatorError,
-                  message: "No reference found after dot.".to_string(),
-                  source: None,
-                });
-              };
-
-              let key_name = str::from_utf8(key_bytes).unwrap();
-              recognized_name = recognized_name + "." + key_name;
-
-              match value_ref {
-                Value::Null => {
-                  return Err(Error {
-                    kind: ErrorKind::EvaluatorError,
-                    message: format!(
-                      "Tried to access field `{key_name}` on undefined or null variable `{recognized_name}`."
-                    ),
-                    source: None,
-                  });
-                }
-
-                Value::Object(obj) => match obj.get(key_name) {
-                  Some(field_ref) => {
-                    value_ref = field_ref;
-                  }
-                  None => {
-                    value_ref = &null_value;
-                  }
-                },
-
-                _ => {
-                  return Err(Error {
-                    kind: ErrorKind::EvaluatorError,
-                    message: format!(
-                      "Variable `{recognized_name}` is not an object and `{key_name}` is not available on it"
-                    ),
-                    source: None,
-                  });
-                }
-              }
-              pos += 2;
-            }
-
-            ExpressionToken::LeftBracket => {
-              let (index_value, new_pos) = evaluate_expression_value(tokens, pos + 1, context)?;
-              if tokens[new_pos] != ExpressionToken::RightBracket {
-                return Err(Error {
-                  kind: ErrorKind::EvaluatorError,
-                  message: "Indexing is not finished with right bracket".to_string(),
-                  source: None,
-                });
-              };
-              pos = new_pos + 1;
-              match index_value {
-                Value::Number(index_num) => {
-                  let Some(index_int) = index_num.as_u64() else {
-                    return Err(Error {
-                      kind: ErrorKind::EvaluatorError,
-                      message: format!(
-                        "Number index should be an unsiged integer, found {index_num:?}"
-                      ),
-                      source: None,
-                    });
-                  };
-                  match value_ref {
-                    Value::Array(arr) => {
-                      let Some(v_ref) = arr.get(index_int as usize) else {
-                        return Err(Error {
-                          kind: ErrorKind::EvaluatorError,
-                          message: format!(
-                            "Out of bound: index {}, array length: {}",
-                            index_int,
-                            arr.len()
-                          ),
-                          source: None,
-                        });
-                      };
-                      value_ref = v_ref;
-                      recognized_name = recognized_name + &format!("[{index_num}]");
-                    }
-                    _ => {
-                      return Err(Error {
-                        kind: ErrorKind::EvaluatorError,
-                        message: "Number index can only be applied on array.".to_string(),
-                        source: None,
-                      });
-                    }
-                  }
-                }
-                Value::String(index_str) => {
-                  match value_ref {
-                    Value::Null => {
-                      return Err(Error {
-                        kind: ErrorKind::EvaluatorError,
-                        message: format!(
-                          "Tried to access field `{index_str}` on undefined or null variable `{recognized_name}`"
-                        ),
-                        source: None,
-                      });
-                    }
-
-                    Value::Object(obj) => match obj.get(&index_str) {
-                      Some(field_ref) => {
-                        value_ref = field_ref;
-                      }
-                      None => {
-                        value_ref = &null_value;
-                      }
-                    },
-
-                    _ => {
-                      return Err(Error {
-                        kind: ErrorKind::EvaluatorError,
-                        message: "String index can only be applied on object.".to_string(),
-                        source: None,
-                      });
-                    }
-                  };
-                  recognized_name = recognized_name + &format!("[\"{index_str}\"]");
-                }
-                _ => {
-                  return Err(Error {
-                    kind: ErrorKind::EvaluatorError,
-                    message: "Invalid index type.".to_string(),
-                    source: None,
-                  });
-                }
-              }
-            }
-
-            _ => break,
-          }
-        }
-        return Ok((value_ref.to_owned(), pos));
-      }
-      ExpressionToken::Number(numc) => {
-        let value = evaluate_number(numc)?;
-        return Ok((value, pos + 1));
-      }
-      ExpressionToken::String(strc) => {
-        let str_val = evaluate_string(strc)?;
-        return Ok((str_val, pos + 1));
-      }
-      _ => {
+      if index_int as usize >= length {
         return Err(Error {
           kind: ErrorKind::EvaluatorError,
-          message: "Expect a value token, but not found".to_string(),
+          message: format!("Out of bound: index {index_int}, array length: {length}"),
           source: None,
+          position: None,
+          code: Some(ErrorCode::ExpressionSyntax),
         });
       }
+      Ok(match target {
+        Cow::Borrowed(Value::Array(items)) => Cow::Borrowed(&items[index_int as usize]),
+        Cow::Owned(Value::Array(mut items)) => Cow::Owned(items.swap_remove(index_int as usize)),
+        _ => unreachable!(),
+      })
     }
+    Value::String(key) => match target {
+      Cow::Borrowed(Value::Object(fields)) => Ok(match fields.get(key) {
+        Some(value) => Cow::Borrowed(value),
+        None => Cow::Owned(Value::Null),
+      }),
+      Cow::Owned(Value::Object(mut fields)) => {
+        Ok(Cow::Owned(fields.remove(key).unwrap_or(Value::Null)))
+      }
+      Cow::Borrowed(Value::Null) | Cow::Owned(Value::Null) => Err(Error {
+        kind: ErrorKind::EvaluatorError,
+        message: format!(
+          "Tried to access field `{key}` on undefined or null variable `{}`",
+          path_name(node)
+        ),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::UndefinedVariable),
+      }),
+      _ => Err(Error {
+        kind: ErrorKind::EvaluatorError,
+        message: "String index can only be applied on object.".to_string(),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::TypeMismatch),
+      }),
+    },
+    _ => Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: "Invalid index type.".to_string(),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
+    }),
   }
-  Err(Error {
-    kind: ErrorKind::EvaluatorError,
-    message: "Not implemented".to_string(),
-    source: None,
-  })
 }
 
-fn evaluate_reference(refc: &[u8], context: &RenderContext) -> Result<Value> {
-  if match_u8_str(refc, "true") {
-    return Ok(Value::Bool(true));
-  }
-  if match_u8_str(refc, "false") {
-    return Ok(Value::Bool(false));
-  }
-  if match_u8_str(refc, "null") {
-    return Ok(Value::Null);
-  }
-  let refs = match str::from_utf8(refc) {
-    Ok(s) => s,
-    Err(e) => {
-      return Err(Error {
-        kind: ErrorKind::EvaluatorError,
-        message: "String decode error".to_string(),
-        source: Some(Box::new(e)),
-      });
-    }
-  };
-  match context.get_value(refs) {
-    Some(r) => Ok(r.clone()),
-    None => Ok(Value::Null),
+/**
+ * Name of a variable path like `users[0].name` for the error messages.
+ */
+fn path_name(node: &ExpressionNode) -> String {
+  match node {
+    ExpressionNode::Reference(name, _) => name.clone(),
+    ExpressionNode::Member(object, key, _) => format!("{}.{key}", path_name(object)),
+    ExpressionNode::Index(target, index, _) => match index.as_ref() {
+      ExpressionNode::Literal(Value::String(key)) => format!("{}[\"{key}\"]", path_name(target)),
+      ExpressionNode::Literal(index) => format!("{}[{index}]", path_name(target)),
+      _ => format!("{}[...]", path_name(target)),
+    },
+    ExpressionNode::Call(name, ..) => format!("{name}(...)"),
+    _ => "(expression)".to_string(),
   }
 }
 
-fn evaluate_number(numc: &[u8]) -> Result<Value> {
+pub(crate) fn evaluate_number(numc: &[u8]) -> Result<Value> {
   let nums = str::from_utf8(numc).unwrap();
 
   if !numc.contains(&b'.') {
@@ -1059,6 +506,8 @@ fn evaluate_number(numc: &[u8]) -> Result<Value> {
         kind: ErrorKind::EvaluatorError,
         message: format!("Failed to parse number: {nums}"),
         source: None,
+        position: None,
+        code: Some(ErrorCode::ExpressionSyntax),
       });
     };
     Ok(Value::Number(
@@ -1070,13 +519,15 @@ fn evaluate_number(numc: &[u8]) -> Result<Value> {
         kind: ErrorKind::EvaluatorError,
         message: format!("Failed to parse number: {nums}"),
         source: None,
+        position: None,
+        code: Some(ErrorCode::ExpressionSyntax),
       });
     };
     Ok(Value::Number(serde_json::Number::from_f64(val).unwrap()))
   }
 }
 
-fn evaluate_string(strc: &[u8]) -> Result<Value> {
+pub(crate) fn evaluate_string(strc: &[u8]) -> Result<Value> {
   let str_val = match str::from_utf8(&strc[1..strc.len() - 1]) {
     Ok(s) => s,
     Err(e) => {
@@ -1084,25 +535,14 @@ fn evaluate_string(strc: &[u8]) -> Result<Value> {
         kind: ErrorKind::EvaluatorError,
         message: "Failed to decode string literal in expression.".to_string(),
         source: Some(Box::new(e)),
+        position: None,
+        code: Some(ErrorCode::ExpressionSyntax),
       });
     }
   };
   Ok(Value::String(str_val.to_string()))
 }
 
-fn match_u8_str(src: &[u8], pat: &str) -> bool {
-  let p = pat.as_bytes();
-  if src.len() != p.len() {
-    return false;
-  }
-  for i in 0..src.len() {
-    if src[i] != p[i] {
-      return false;
-    }
-  }
-  true
-}
-
 fn handle_plus_operator(a: &Value, b: &Value) -> Result<Value> {
   if let Some((int_a, int_b)) = cast_as_i64_pair(a, b) {
     return Ok(Value::Number(
@@ -1114,19 +554,15 @@ fn handle_plus_operator(a: &Value, b: &Value) -> Result<Value> {
       serde_json::Number::from_f64(num_a + num_b).unwrap(),
     ));
   }
-  let str_a = cast_as_string(a);
-  let str_b = cast_as_string(b);
-  if str_a.is_some() && str_b.is_some() {
-    return Ok(Value::String(format!(
-      "{}{}",
-      str_a.unwrap(),
-      str_b.unwrap()
-    )));
+  if let (Some(str_a), Some(str_b)) = (cast_as_string(a), cast_as_string(b)) {
+    return Ok(Value::String(format!("{str_a}{str_b}")));
   }
   Err(Error {
     kind: ErrorKind::EvaluatorError,
     message: format!("Failed to perform plus operator on {a:?} and {b:?}."),
     source: None,
+    position: None,
+    code: Some(ErrorCode::TypeMismatch),
   })
 }
 
@@ -1145,6 +581,8 @@ fn handle_minus_operator(a: &Value, b: &Value) -> Result<Value> {
     kind: ErrorKind::EvaluatorError,
     message: format!("Failed to perform minus operator on {a:?} and {b:?}."),
     source: None,
+    position: None,
+    code: Some(ErrorCode::TypeMismatch),
   })
 }
 
@@ -1163,6 +601,8 @@ fn handle_times_operator(a: &Value, b: &Value) -> Result<Value> {
     kind: ErrorKind::EvaluatorError,
     message: format!("Failed to perform times operator on {a:?} and {b:?}."),
     source: None,
+    position: None,
+    code: Some(ErrorCode::TypeMismatch),
   })
 }
 
@@ -1172,6 +612,8 @@ fn handle_divide_operator(a: &Value, b: &Value) -> Result<Value> {
       kind: ErrorKind::EvaluatorError,
       message: format!("Failed to cast first operand as number for division: {a}",),
       source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
     });
   };
   let Some(num_b) = cast_as_f64(b) else {
@@ -1179,6 +621,8 @@ fn handle_divide_operator(a: &Value, b: &Value) -> Result<Value> {
       kind: ErrorKind::EvaluatorError,
       message: format!("Failed to cast second operand as number for division: {b}",),
       source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
     });
   };
   if num_b == 0.0 {
@@ -1186,6 +630,8 @@ fn handle_divide_operator(a: &Value, b: &Value) -> Result<Value> {
       kind: ErrorKind::EvaluatorError,
       message: "Division by zero".to_string(),
       source: None,
+      position: None,
+      code: Some(ErrorCode::DivisionByZero),
     });
   };
   Ok(Value::Number(
@@ -1199,6 +645,8 @@ fn handle_mod_operator(a: &Value, b: &Value) -> Result<Value> {
       kind: ErrorKind::EvaluatorError,
       message: format!("Failed to cast first operand as integer for modulo: {a}"),
       source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
     });
   };
   let Some(int_b) = cast_as_i64(b) else {
@@ -1206,6 +654,8 @@ fn handle_mod_operator(a: &Value, b: &Value) -> Result<Value> {
       kind: ErrorKind::EvaluatorError,
       message: format!("Failed to cast second operand as integer for modulo: {b}"),
       source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
     });
   };
   if int_b == 0 {
@@ -1213,6 +663,8 @@ fn handle_mod_operator(a: &Value, b: &Value) -> Result<Value> {
       kind: ErrorKind::EvaluatorError,
       message: "Modulo by zero".to_string(),
       source: None,
+      position: None,
+      code: Some(ErrorCode::DivisionByZero),
     });
   };
   Ok(Value::Number(
@@ -1228,6 +680,8 @@ fn handle_less_than_operator(a: &Value, b: &Value) -> Result<Value> {
     kind: ErrorKind::EvaluatorError,
     message: format!("Failed to perform less-than operator on {a:?} and {b:?}."),
     source: None,
+    position: None,
+    code: Some(ErrorCode::TypeMismatch),
   })
 }
 
@@ -1239,6 +693,8 @@ fn handle_less_than_or_equal_operator(a: &Value, b: &Value) -> Result<Value> {
     kind: ErrorKind::EvaluatorError,
     message: format!("Failed to perform less-than operator on {a:?} and {b:?}."),
     source: None,
+    position: None,
+    code: Some(ErrorCode::TypeMismatch),
   })
 }
 
@@ -1250,6 +706,8 @@ fn handle_greater_than_operator(a: &Value, b: &Value) -> Result<Value> {
     kind: ErrorKind::EvaluatorError,
     message: format!("Failed to perform less-than operator on {a:?} and {b:?}."),
     source: None,
+    position: None,
+    code: Some(ErrorCode::TypeMismatch),
   })
 }
 
@@ -1261,6 +719,8 @@ fn handle_greater_than_or_equal_operator(a: &Value, b: &Value) -> Result<Value>
     kind: ErrorKind::EvaluatorError,
     message: format!("Failed to perform greater-than-or-equal operator on {a:?} and {b:?}."),
     source: None,
+    position: None,
+    code: Some(ErrorCode::TypeMismatch),
   })
 }
 
@@ -1276,6 +736,8 @@ fn handle_in_operator(a: &Value, b: &Value) -> Result<Value> {
             "Left-hand side of `in` must be an integer index when right-hand side is an array"
               .to_string(),
           source: None,
+          position: None,
+          code: Some(ErrorCode::TypeMismatch),
         })
       }
     }
@@ -1288,6 +750,8 @@ fn handle_in_operator(a: &Value, b: &Value) -> Result<Value> {
           message: "Left-hand side of `in` must be a string key when right-hand side is an object"
             .to_string(),
           source: None,
+          position: None,
+          code: Some(ErrorCode::TypeMismatch),
         })
       }
     }
@@ -1295,6 +759,8 @@ fn handle_in_operator(a: &Value, b: &Value) -> Result<Value> {
       kind: ErrorKind::EvaluatorError,
       message: "Right-hand side of 'in' must be an array or an object".to_string(),
       source: None,
+      position: None,
+      code: Some(ErrorCode::TypeMismatch),
     }),
   }
 }
@@ -0,0 +1,108 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Regular expression builtins of the `regex` feature, with the syntax of the `regex` crate.
+ */
+
+use super::functions::check_argument_count;
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use crate::render::render_context::RenderContext;
+use regex::Regex;
+use serde_json::Value;
+
+/**
+ * Maximum number of compiled patterns cached during a render. Patterns beyond it, like
+ * ones built from the items of a long loop, are compiled on each call.
+ */
+const MAX_CACHED_PATTERNS: usize = 256;
+
+/**
+ * `test(str, pattern)` is whether the pattern matches a part of the string.
+ */
+pub fn builtin_test(args: &[Value], context: &RenderContext) -> Result<Value> {
+  check_argument_count("test", args, 2)?;
+  let (text, pattern) = text_and_pattern("test", args, context)?;
+  Ok(Value::Bool(pattern.is_match(text)))
+}
+
+/**
+ * `match(str, pattern)` is the first match and its capture groups, like
+ * `["2025-01", "2025", "01"]`, or `null` if the pattern doesn't match. Groups not
+ * participating in the match are `null`.
+ */
+pub fn builtin_match(args: &[Value], context: &RenderContext) -> Result<Value> {
+  check_argument_count("match", args, 2)?;
+  let (text, pattern) = text_and_pattern("match", args, context)?;
+  Ok(match pattern.captures(text) {
+    Some(captures) => Value::Array(
+      captures
+        .iter()
+        .map(|group| group.map_or(Value::Null, |m| Value::String(m.as_str().to_string())))
+        .collect(),
+    ),
+    None => Value::Null,
+  })
+}
+
+/**
+ * `replaceAll(str, pattern, replacement)` replaces all the matches, where `$1` or `${name}`
+ * in the replacement refer to the capture groups.
+ */
+pub fn builtin_replace_all(args: &[Value], context: &RenderContext) -> Result<Value> {
+  check_argument_count("replaceAll", args, 3)?;
+  let (text, pattern) = text_and_pattern("replaceAll", args, context)?;
+  let Value::String(replacement) = &args[2] else {
+    return Err(type_error("replaceAll", &args[2]));
+  };
+  Ok(Value::String(
+    pattern.replace_all(text, replacement.as_str()).into_owned(),
+  ))
+}
+
+/**
+ * The string and the compiled pattern of the arguments, where the pattern is compiled once
+ * per render.
+ */
+fn text_and_pattern<'v>(
+  name: &str,
+  args: &'v [Value],
+  context: &RenderContext,
+) -> Result<(&'v str, Regex)> {
+  let (Value::String(text), Value::String(pattern)) = (&args[0], &args[1]) else {
+    let found = if args[0].is_string() {
+      &args[1]
+    } else {
+      &args[0]
+    };
+    return Err(type_error(name, found));
+  };
+  if let Some(regex) = context.lock_patterns().get(pattern) {
+    return Ok((text, regex.clone()));
+  }
+  let regex = Regex::new(pattern).map_err(|e| Error {
+    kind: ErrorKind::EvaluatorError,
+    message: format!("Invalid regular expression of {name}(): {pattern}"),
+    source: Some(Box::new(e)),
+    position: None,
+    code: Some(ErrorCode::Expression),
+  })?;
+  let mut patterns = context.lock_patterns();
+  if patterns.len() < MAX_CACHED_PATTERNS {
+    patterns.insert(pattern.clone(), regex.clone());
+  }
+  Ok((text, regex))
+}
+
+fn type_error(name: &str, found: &Value) -> Error {
+  Error {
+    kind: ErrorKind::EvaluatorError,
+    message: format!("{name}() takes strings, found {found}"),
+    source: None,
+    position: None,
+    code: Some(ErrorCode::TypeMismatch),
+  }
+}
@@ -16,7 +16,7 @@ fn test_evaluate_reference() {
   };
   let context = RenderContext::from(variables);
   assert_eq!(
-    recognize_next_value(
+    evaluate_expression_value(
       &[
         ExpressionToken::Ref(b"my"),
         ExpressionToken::Dot,
@@ -30,11 +30,13 @@ fn test_evaluate_reference() {
     json!("127.0.0.1")
   );
   assert_eq!(
-    evaluate_reference("count".as_bytes(), &context).unwrap(),
+    evaluate_expression_value(&[ExpressionToken::Ref(b"count")], 0, &context)
+      .unwrap()
+      .0,
     json!(5)
   );
   assert_eq!(
-    recognize_next_value(
+    evaluate_expression_value(
       &[
         ExpressionToken::Ref(b"my"),
         ExpressionToken::Dot,
@@ -48,7 +50,7 @@ fn test_evaluate_reference() {
     Value::Null
   );
   assert!(
-    recognize_next_value(
+    evaluate_expression_value(
       &[
         ExpressionToken::Ref(b"my"),
         ExpressionToken::Dot,
@@ -74,7 +76,7 @@ fn test_evaluate_array_indexing() {
   let context = RenderContext::from(variables);
 
   assert_eq!(
-    recognize_next_value(
+    evaluate_expression_value(
       &[
         ExpressionToken::Ref(b"arr"),
         ExpressionToken::LeftBracket,
@@ -90,7 +92,7 @@ fn test_evaluate_array_indexing() {
   );
 
   assert_eq!(
-    recognize_next_value(
+    evaluate_expression_value(
       &[
         ExpressionToken::Ref(b"arr"),
         ExpressionToken::LeftBracket,
@@ -108,7 +110,7 @@ fn test_evaluate_array_indexing() {
   );
 
   assert!(
-    recognize_next_value(
+    evaluate_expression_value(
       &[
         ExpressionToken::Ref(b"arr"),
         ExpressionToken::LeftBracket,
@@ -135,7 +137,7 @@ fn test_evaluate_array_indexing_with_dot_access() {
   let context = RenderContext::from(variables);
 
   assert_eq!(
-    recognize_next_value(
+    evaluate_expression_value(
       &[
         ExpressionToken::Ref(b"users"),
         ExpressionToken::LeftBracket,
@@ -153,7 +155,7 @@ fn test_evaluate_array_indexing_with_dot_access() {
   );
 
   assert_eq!(
-    recognize_next_value(
+    evaluate_expression_value(
       &[
         ExpressionToken::Ref(b"users"),
         ExpressionToken::LeftBracket,
@@ -188,7 +190,7 @@ fn test_evaluate_object_indexing_access() {
   let context = RenderContext::from(variables);
 
   assert_eq!(
-    recognize_next_value(
+    evaluate_expression_value(
       &[
         ExpressionToken::Ref(b"users"),
         ExpressionToken::LeftBracket,
@@ -207,7 +209,7 @@ fn test_evaluate_object_indexing_access() {
   );
 
   assert_eq!(
-    recognize_next_value(
+    evaluate_expression_value(
       &[
         ExpressionToken::Ref(b"users"),
         ExpressionToken::LeftBracket,
@@ -927,3 +929,362 @@ fn test_ternary_operator_combine() {
   .unwrap();
   assert_eq!(result, json!(2));
 }
+
+#[test]
+fn test_evaluate_range_function() {
+  let Value::Object(variables) = json!({"n": 3}) else {
+    panic!();
+  };
+  let context = RenderContext::from(variables);
+  let evaluate = |expression: &str| {
+    let tokens = crate::render::expression::tokenize::tokenize_expression(expression.as_bytes())?;
+    evaluate_expression_tokens(&tokens, &context)
+  };
+  assert_eq!(evaluate("range(n)").unwrap(), json!([0, 1, 2]));
+  assert_eq!(evaluate("range(1, n + 1)").unwrap(), json!([1, 2, 3]));
+  assert_eq!(evaluate("range(5, 0, 0 - 2)").unwrap(), json!([5, 3, 1]));
+  assert_eq!(evaluate("range(0)").unwrap(), json!([]));
+  assert!(evaluate("range(1, 2, 0)").is_err());
+  assert!(evaluate("range('a')").is_err());
+  assert!(evaluate("unknown(1)").is_err());
+}
+
+#[test]
+fn test_expression_limits() {
+  use crate::render::expression::{ExpressionLimits, evaluate_expression};
+  let mut nested = json!(1);
+  for _ in 0..100 {
+    nested = json!({ "b": nested });
+  }
+  let Value::Object(variables) = json!({ "a": nested }) else {
+    panic!();
+  };
+  let mut context = RenderContext::from(variables);
+  let deep = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+  assert!(evaluate_expression(&deep, &context).is_err());
+  let long = vec!["1"; 3000].join(" + ");
+  assert!(evaluate_expression(&long, &context).is_err());
+  let chain = format!("a{}", ".b".repeat(2000));
+  let err = evaluate_expression(&chain, &context).unwrap_err();
+  assert_eq!(err.code(), ErrorCode::ExpressionLimit);
+  let err = crate::render::expression::Expression::parse(&chain).unwrap_err();
+  assert_eq!(err.code(), ErrorCode::ExpressionLimit);
+  let nested_chain = format!("a{}", "['b']".repeat(50) + &".b".repeat(50));
+  assert!(evaluate_expression(&nested_chain, &context).is_err());
+
+  context.set_expression_limits(ExpressionLimits {
+    max_length: 100_000,
+    max_tokens: 10_000,
+    max_depth: 200,
+  });
+  assert_eq!(evaluate_expression(&deep, &context).unwrap(), json!(1));
+  assert_eq!(evaluate_expression(&long, &context).unwrap(), json!(3000));
+  assert_eq!(
+    evaluate_expression(&nested_chain, &context).unwrap(),
+    json!(1)
+  );
+
+  context.set_expression_limits(ExpressionLimits {
+    max_length: 4,
+    ..ExpressionLimits::default()
+  });
+  assert!(evaluate_expression("1 + 1", &context).is_err());
+}
+
+#[test]
+fn test_evaluate_date_functions() {
+  let Value::Object(variables) = json!({"released": "2025-01-31T08:30:00Z"}) else {
+    panic!();
+  };
+  let context = RenderContext::from(variables);
+  let evaluate = |expression: &str| {
+    let tokens = crate::render::expression::tokenize::tokenize_expression(expression.as_bytes())?;
+    evaluate_expression_tokens(&tokens, &context)
+  };
+  assert_eq!(
+    evaluate("formatDate(released, 'DD/MM/YYYY HH:mm')").unwrap(),
+    json!("31/01/2025 08:30")
+  );
+  assert_eq!(
+    evaluate("formatDate(1709210096000, 'YYYY-MM-DD')").unwrap(),
+    json!("2024-02-29")
+  );
+  let today = evaluate("today()").unwrap();
+  assert_eq!(today.as_str().unwrap().len(), 10);
+  assert_eq!(evaluate("formatDate(now(), 'YYYY-MM-DD')").unwrap(), today);
+  assert!(evaluate("formatDate('tomorrow', 'YYYY')").is_err());
+  assert!(evaluate("formatDate(released)").is_err());
+  assert!(evaluate("now(1)").is_err());
+}
+
+#[test]
+fn test_evaluate_random_functions() {
+  let Value::Object(variables) = json!({"items": [1, 2, 3, 4, 5]}) else {
+    panic!();
+  };
+  let context = RenderContext::from(variables);
+  let evaluate = |expression: &str| {
+    let tokens = crate::render::expression::tokenize::tokenize_expression(expression.as_bytes())?;
+    evaluate_expression_tokens(&tokens, &context)
+  };
+  context.set_random_seed(7);
+  let shuffled = evaluate("shuffle(items)").unwrap();
+  let mut sorted: Vec<i64> = shuffled
+    .as_array()
+    .unwrap()
+    .iter()
+    .map(|v| v.as_i64().unwrap())
+    .collect();
+  sorted.sort();
+  assert_eq!(sorted, vec![1, 2, 3, 4, 5]);
+  context.set_random_seed(7);
+  assert_eq!(evaluate("shuffle(items)").unwrap(), shuffled);
+
+  let sample = evaluate("sample(items, 3)").unwrap();
+  let sample = sample.as_array().unwrap();
+  assert_eq!(sample.len(), 3);
+  assert!(
+    sample
+      .iter()
+      .all(|v| (1..=5).contains(&v.as_i64().unwrap()))
+  );
+  assert_ne!(sample[0], sample[1]);
+  assert_eq!(evaluate("sample(items, 0)").unwrap(), json!([]));
+  for _ in 0..20 {
+    let n = evaluate("randint(1, 5)").unwrap().as_i64().unwrap();
+    assert!((1..=5).contains(&n));
+  }
+  assert_eq!(evaluate("randint(3, 3)").unwrap(), json!(3));
+  assert!(evaluate("randint(3, 2)").is_err());
+  assert!(evaluate("sample(items, 6)").is_err());
+  assert!(evaluate("shuffle('abc')").is_err());
+}
+
+#[test]
+fn test_evaluate_object_functions() {
+  let Value::Object(variables) = json!({
+    "config": {"model": {"name": "gpt", "temperature": 0.5}, "tags": ["a", "b"]},
+    "overrides": {"model": {"temperature": 0}, "stream": true}
+  }) else {
+    panic!();
+  };
+  let context = RenderContext::from(variables);
+  let evaluate = |expression: &str| {
+    let tokens = crate::render::expression::tokenize::tokenize_expression(expression.as_bytes())?;
+    evaluate_expression_tokens(&tokens, &context)
+  };
+  assert_eq!(
+    evaluate("keys(config.model)").unwrap(),
+    json!(["name", "temperature"])
+  );
+  assert_eq!(
+    evaluate("values(config.model)").unwrap(),
+    json!(["gpt", 0.5])
+  );
+  assert_eq!(
+    evaluate("entries(overrides.model)").unwrap(),
+    json!([["temperature", 0]])
+  );
+  assert_eq!(
+    evaluate("merge(config, overrides)").unwrap(),
+    json!({
+      "model": {"name": "gpt", "temperature": 0},
+      "tags": ["a", "b"],
+      "stream": true
+    })
+  );
+  assert_eq!(evaluate("get(config, 'model.name')").unwrap(), json!("gpt"));
+  assert_eq!(evaluate("get(config, 'tags.1')").unwrap(), json!("b"));
+  assert_eq!(evaluate("get(config, 'model.top_p', 1)").unwrap(), json!(1));
+  assert_eq!(evaluate("get(config, 'tags.5')").unwrap(), Value::Null);
+  assert!(evaluate("keys(config.tags)").is_err());
+  assert!(evaluate("merge(config)").is_err());
+  assert!(evaluate("get(config)").is_err());
+}
+
+#[test]
+fn test_evaluate_array_aggregates() {
+  let Value::Object(variables) = json!({
+    "scores": [3, 1, 2.5, 1],
+    "users": [
+      {"name": "bob", "age": 30, "active": true},
+      {"name": "alice", "age": 25, "active": false},
+      {"name": "carol", "age": 30, "active": true}
+    ]
+  }) else {
+    panic!();
+  };
+  let context = RenderContext::from(variables);
+  let evaluate = |expression: &str| {
+    let tokens = crate::render::expression::tokenize::tokenize_expression(expression.as_bytes())?;
+    evaluate_expression_tokens(&tokens, &context)
+  };
+  assert_eq!(evaluate("sum(scores)").unwrap(), json!(7.5));
+  assert_eq!(evaluate("sum([1, 2, 3])").unwrap(), json!(6));
+  assert_eq!(evaluate("avg(scores)").unwrap(), json!(1.875));
+  assert_eq!(evaluate("avg([])").unwrap(), Value::Null);
+  assert_eq!(evaluate("min(scores)").unwrap(), json!(1));
+  assert_eq!(evaluate("max(scores)").unwrap(), json!(3));
+  assert_eq!(evaluate("count(users)").unwrap(), json!(3));
+  assert_eq!(evaluate("count(users, 'item.active')").unwrap(), json!(2));
+  assert_eq!(evaluate("count(scores, 'item > 1')").unwrap(), json!(2));
+  assert_eq!(evaluate("sort(scores)").unwrap(), json!([1, 1, 2.5, 3]));
+  assert_eq!(
+    evaluate("sort(users, 'item.age')")
+      .unwrap()
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|u| u["name"].clone())
+      .collect::<Vec<_>>(),
+    vec![json!("alice"), json!("bob"), json!("carol")]
+  );
+  assert_eq!(
+    evaluate("sort(['b', 2, 'a', 10])").unwrap(),
+    json!([2, 10, "a", "b"])
+  );
+  assert_eq!(evaluate("unique(scores)").unwrap(), json!([3, 1, 2.5]));
+  assert!(evaluate("sum(['a'])").is_err());
+  assert!(evaluate("count(users, 1)").is_err());
+  assert!(evaluate("sort()").is_err());
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_evaluate_regex_functions() {
+  let Value::Object(variables) = json!({"date": "released on 2025-01-31"}) else {
+    panic!();
+  };
+  let context = RenderContext::from(variables);
+  let evaluate = |expression: &str| {
+    let tokens = crate::render::expression::tokenize::tokenize_expression(expression.as_bytes())?;
+    evaluate_expression_tokens(&tokens, &context)
+  };
+  assert_eq!(evaluate(r"test(date, '\d{4}-\d{2}')").unwrap(), json!(true));
+  assert_eq!(evaluate("test(date, '^\\d')").unwrap(), json!(false));
+  assert_eq!(
+    evaluate(r"match(date, '(\d{4})-(\d{2})(-x)?')").unwrap(),
+    json!(["2025-01", "2025", "01", null])
+  );
+  assert_eq!(evaluate("match(date, 'x')").unwrap(), Value::Null);
+  assert_eq!(
+    evaluate(r"replaceAll(date, '(\d+)-(\d+)-(\d+)', '$3/$2/$1')").unwrap(),
+    json!("released on 31/01/2025")
+  );
+  assert_eq!(
+    evaluate("test(date, '(')").unwrap_err().code(),
+    ErrorCode::Expression
+  );
+  assert_eq!(
+    evaluate("test(1, 'a')").unwrap_err().code(),
+    ErrorCode::TypeMismatch
+  );
+  // The valid patterns are compiled once
+  assert_eq!(evaluate(r"test(date, '\d{4}-\d{2}')").unwrap(), json!(true));
+  assert_eq!(context.lock_patterns().len(), 5);
+}
+
+#[test]
+fn test_logical_operator_operand_values() {
+  let Value::Object(variables) = json!({
+    "user": {"name": "Alice", "nickname": ""},
+    "items": [1, 2]
+  }) else {
+    panic!();
+  };
+  let context = RenderContext::from(variables);
+  let evaluate = |expression: &str| {
+    let tokens = crate::render::expression::tokenize::tokenize_expression(expression.as_bytes())?;
+    evaluate_expression_tokens(&tokens, &context)
+  };
+  assert_eq!(
+    evaluate("user.nickname || user.name").unwrap(),
+    json!("Alice")
+  );
+  assert_eq!(evaluate("user.title || 'guest'").unwrap(), json!("guest"));
+  assert_eq!(evaluate("0 || null").unwrap(), Value::Null);
+  assert_eq!(evaluate("items && count(items)").unwrap(), json!(2));
+  assert_eq!(evaluate("user.nickname && user.name").unwrap(), json!(""));
+  assert_eq!(evaluate("1 && 'a' && 0 || 'b'").unwrap(), json!("b"));
+}
+
+#[test]
+fn test_parsed_expression() {
+  use crate::render::expression::{Expression, ExpressionLimits};
+  let expression = Expression::parse("price * count + (extra || 0)").unwrap();
+  for count in 1..=3 {
+    let context = RenderContext::from_iter([
+      ("price".to_string(), json!(5)),
+      ("count".to_string(), json!(count)),
+    ]);
+    assert_eq!(expression.evaluate(&context).unwrap(), json!(5 * count));
+    assert_eq!(
+      context.evaluate_parsed(&expression).unwrap(),
+      json!(5 * count)
+    );
+  }
+  assert!(Expression::parse("price *").is_err());
+  assert!(Expression::parse("a b").is_err());
+
+  let mut context = RenderContext::from(serde_json::Map::new());
+  context.set_expression_limits(ExpressionLimits {
+    max_depth: 1,
+    ..ExpressionLimits::default()
+  });
+  assert_eq!(
+    Expression::parse("(1)")
+      .unwrap()
+      .evaluate(&context)
+      .unwrap(),
+    json!(1)
+  );
+  let nested = Expression::parse("((1))").unwrap();
+  assert!(nested.evaluate(&context).is_err());
+}
+
+#[test]
+fn test_expression_error_positions() {
+  let context = RenderContext::from_iter([("user".to_string(), json!({"age": 3}))]);
+  let position = |expression: &str| {
+    let e = context.evaluate(expression).unwrap_err();
+    let position = e.position.unwrap();
+    (position.offset, position.end, position.snippet)
+  };
+  assert_eq!(
+    position("user.age + [1]"),
+    (9, 10, "user.age + [1]".to_string())
+  );
+  assert_eq!(position("user.name.first").0, 10);
+  assert_eq!(position("range(1, 2, 3, 4)").0, 0);
+  assert_eq!(position("1 + $").0, 4);
+  assert_eq!(position("(1 + 2) / 0").0, 8);
+}
+
+#[test]
+fn test_undefined_policy() {
+  use crate::render::expression::UndefinedPolicy;
+  let mut context = RenderContext::from_iter([("items".to_string(), json!([{"x": 1}]))]);
+  assert_eq!(context.evaluate("1 + missing").unwrap(), json!("1null"));
+
+  context.set_undefined_policy(UndefinedPolicy::Error);
+  let e = context.evaluate("1 + missing").unwrap_err();
+  assert_eq!(e.code(), ErrorCode::UndefinedVariable);
+  assert_eq!(e.message, "Variable `missing` is not defined");
+  assert_eq!(e.position.map(|p| (p.offset, p.end)), Some((4, 11)));
+  assert_eq!(context.evaluate("items[0].x").unwrap(), json!(1));
+  assert_eq!(context.evaluate("items[0].y").unwrap(), Value::Null);
+
+  context.set_undefined_policy(UndefinedPolicy::Warn);
+  assert_eq!(context.evaluate("[missing, 1]").unwrap(), json!([null, 1]));
+  assert_eq!(
+    context.evaluate("count(items, 'item.x && limit')").unwrap(),
+    json!(0)
+  );
+  let warnings = context.take_warnings();
+  assert_eq!(warnings.len(), 2);
+  assert_eq!(warnings[0].position.as_ref().map(|p| p.offset), Some(1));
+  // Warnings of the expressions in the arguments are located at the call
+  assert!(warnings[1].message.contains("`limit`"));
+  assert_eq!(warnings[1].position.as_ref().map(|p| p.offset), Some(0));
+  assert!(context.take_warnings().is_empty());
+}
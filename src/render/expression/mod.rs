@@ -5,13 +5,145 @@
  */
 
 pub(crate) mod evaluate;
-pub(crate) mod tokenize;
+pub(crate) mod parse;
+pub mod tokenize;
 pub(crate) mod utils;
 use super::render_context::RenderContext;
-use crate::error::Result;
+use crate::error::{Error, ErrorCode, ErrorKind, Result, Span};
 use serde_json::Value;
 
+/**
+ * Limits on the expressions to evaluate, which stop pathological inputs from stalling
+ * the evaluator.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExpressionLimits {
+  /** Maximum length of an expression in bytes. */
+  pub max_length: usize,
+  /** Maximum number of tokens of an expression. */
+  pub max_tokens: usize,
+  /**
+   * Maximum nesting depth of parentheses, brackets and curly brackets, where each field
+   * access or indexing of a chain like `a.b[0]` also nests one level.
+   */
+  pub max_depth: usize,
+}
+
+impl Default for ExpressionLimits {
+  fn default() -> Self {
+    ExpressionLimits {
+      max_length: 16 * 1024,
+      max_tokens: 4096,
+      max_depth: 64,
+    }
+  }
+}
+
+/**
+ * How references to variables not defined in the context are evaluated.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UndefinedPolicy {
+  /** Evaluate them to `null`, which is the default. */
+  #[default]
+  Null,
+  /** Fail the evaluation with an error naming the variable. */
+  Error,
+  /** Evaluate them to `null`, and record a warning in the context. */
+  Warn,
+}
+
+impl UndefinedPolicy {
+  /**
+   * Parse the policy from its name in config files: `null`, `error` or `warn`.
+   */
+  pub fn from_name(name: &str) -> Option<UndefinedPolicy> {
+    match name {
+      "null" => Some(UndefinedPolicy::Null),
+      "error" => Some(UndefinedPolicy::Error),
+      "warn" => Some(UndefinedPolicy::Warn),
+      _ => None,
+    }
+  }
+}
+
+/**
+ * An expression parsed in advance, which evaluates many times in different contexts without
+ * being tokenized and parsed again.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+  root: parse::ExpressionNode,
+  /** Text of the expression, where the errors are located */
+  source: String,
+  token_count: usize,
+  depth: usize,
+}
+
+impl Expression {
+  /**
+   * Parse the expression. Syntax errors are reported here, and the limits of the context
+   * are checked when it's evaluated.
+   */
+  pub fn parse(expression: &str) -> Result<Expression> {
+    let (tokens, spans) = tokenize::tokenize_expression_spans(expression.as_bytes())?;
+    Expression::from_tokens(expression, &tokens, &spans)
+  }
+
+  /**
+   * Parse the expression from its tokens, which are located by the spans in the text.
+   */
+  pub(crate) fn from_tokens(
+    text: &str,
+    tokens: &[tokenize::ExpressionToken],
+    spans: &[Span],
+  ) -> Result<Expression> {
+    let source = parse::TokenSource {
+      text: text.as_bytes(),
+      spans,
+    };
+    Ok(Expression {
+      root: parse::parse_tokens(tokens, source)?,
+      source: text.to_string(),
+      token_count: tokens.len(),
+      depth: evaluate::nesting_depth(tokens),
+    })
+  }
+
+  /**
+   * Evaluate the expression in the context.
+   */
+  pub fn evaluate(&self, context: &RenderContext) -> Result<Value> {
+    check_expression_length(self.source.len(), context)?;
+    evaluate::check_expression_limits(self.token_count, self.depth, context)?;
+    Ok(evaluate::evaluate_node(&self.root, self.source.as_bytes(), context)?.into_owned())
+  }
+}
+
+/**
+ * Evaluate the expression in the context. The position of an error is the byte offset in the
+ * expression, with the expression as the snippet.
+ */
 pub fn evaluate_expression(expression: &str, context: &RenderContext) -> Result<Value> {
-  let tokens = tokenize::tokenize_expression(expression.as_bytes())?;
-  evaluate::evaluate_expression_tokens(&tokens, context)
+  check_expression_length(expression.len(), context)?;
+  let (tokens, spans) = tokenize::tokenize_expression_spans(expression.as_bytes())?;
+  let source = parse::TokenSource {
+    text: expression.as_bytes(),
+    spans: &spans,
+  };
+  evaluate::evaluate_located_tokens(&tokens, source, context)
+}
+
+fn check_expression_length(length: usize, context: &RenderContext) -> Result<()> {
+  let max_length = context.expression_limits().max_length;
+  if length > max_length {
+    return Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!("Expression is too long: {length} bytes, the limit is {max_length}"),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::ExpressionLimit),
+    });
+  }
+  Ok(())
 }
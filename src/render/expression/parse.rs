@@ -0,0 +1,685 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Parse the expression tokens into a syntax tree by precedence climbing, with the operator
+ * precedence of JavaScript. Refer to
+ * https://developer.mozilla.org/en-US/docs/Web/JavaScript/Reference/Operators/Operator_precedence
+ */
+
+use super::evaluate::{evaluate_number, evaluate_string};
+use super::tokenize::ExpressionToken;
+use crate::error::{Error, ErrorCode, ErrorKind, ErrorPosition, Result, Span};
+use serde_json::Value;
+
+/**
+ * Maximum recursion depth of the parser. Parentheses and brackets are bounded by
+ * `ExpressionLimits::max_depth` before parsing, and this also bounds the chains of ternary
+ * operators like `a ? b : c ? d : e`, which recurse without any brackets. The chains of
+ * field accesses and indexing count toward the depth, since the evaluator recurses into them.
+ */
+const MAX_PARSE_DEPTH: usize = 256;
+
+/** Binding power of the ternary operator, the lowest of the operators. */
+const TERNARY_BINDING_POWER: u8 = 1;
+
+/**
+ * A node of the syntax tree of an expression.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ExpressionNode {
+  Literal(Value),
+  /** A variable like `name`. */
+  Reference(String, Span),
+  /** A field access like `user.name`, located at the field name. */
+  Member(Box<ExpressionNode>, String, Span),
+  /** An indexing like `items[0]` or `user['name']`, located at the brackets. */
+  Index(Box<ExpressionNode>, Box<ExpressionNode>, Span),
+  /** A builtin function call like `range(3)`, located at the function name. */
+  Call(String, Vec<ExpressionNode>, Span),
+  Array(Vec<ExpressionNode>),
+  Object(Vec<(String, ExpressionNode)>),
+  Not(Box<ExpressionNode>),
+  /** A binary operator, located at the operator. */
+  Binary(
+    BinaryOperator,
+    Box<ExpressionNode>,
+    Box<ExpressionNode>,
+    Span,
+  ),
+  /** The ternary operator `condition ? a : b`. */
+  Conditional(
+    Box<ExpressionNode>,
+    Box<ExpressionNode>,
+    Box<ExpressionNode>,
+  ),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BinaryOperator {
+  Times,
+  Divide,
+  Mod,
+  Plus,
+  Minus,
+  Less,
+  LessOrEqual,
+  Greater,
+  GreaterOrEqual,
+  In,
+  StrictEqual,
+  StrictNotEqual,
+  And,
+  Or,
+}
+
+impl BinaryOperator {
+  fn from_token(op: &[u8]) -> Option<BinaryOperator> {
+    Some(match op {
+      b"*" => BinaryOperator::Times,
+      b"/" => BinaryOperator::Divide,
+      b"%" => BinaryOperator::Mod,
+      b"+" => BinaryOperator::Plus,
+      b"-" => BinaryOperator::Minus,
+      b"<" => BinaryOperator::Less,
+      b"<=" => BinaryOperator::LessOrEqual,
+      b">" => BinaryOperator::Greater,
+      b">=" => BinaryOperator::GreaterOrEqual,
+      b"in" => BinaryOperator::In,
+      b"===" => BinaryOperator::StrictEqual,
+      b"!==" => BinaryOperator::StrictNotEqual,
+      b"&&" => BinaryOperator::And,
+      b"||" => BinaryOperator::Or,
+      _ => return None,
+    })
+  }
+
+  /**
+   * Binding power of the operator, where the higher ones bind tighter. All the binary
+   * operators are left-associative.
+   */
+  fn binding_power(self) -> u8 {
+    match self {
+      BinaryOperator::Or => 3,
+      BinaryOperator::And => 4,
+      BinaryOperator::StrictEqual | BinaryOperator::StrictNotEqual => 5,
+      BinaryOperator::Less
+      | BinaryOperator::LessOrEqual
+      | BinaryOperator::Greater
+      | BinaryOperator::GreaterOrEqual
+      | BinaryOperator::In => 6,
+      BinaryOperator::Plus | BinaryOperator::Minus => 7,
+      BinaryOperator::Times | BinaryOperator::Divide | BinaryOperator::Mod => 8,
+    }
+  }
+}
+
+/**
+ * Source of the tokens to locate the syntax errors and the nodes: the expression text, and
+ * the byte offsets of the tokens in it. Both are empty if the source is unknown.
+ */
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TokenSource<'s> {
+  pub text: &'s [u8],
+  pub spans: &'s [Span],
+}
+
+impl TokenSource<'_> {
+  pub const UNKNOWN: TokenSource<'static> = TokenSource {
+    text: b"",
+    spans: &[],
+  };
+}
+
+/**
+ * Parse an expression from the tokens starting at `start_pos`. The expression ends at the
+ * end of the tokens, or at a closing token like `]` or `,` of an enclosing expression.
+ * Return the syntax tree and the position after the expression.
+ */
+pub(crate) fn parse_expression_tokens(
+  tokens: &[ExpressionToken],
+  source: TokenSource,
+  start_pos: usize,
+) -> Result<(ExpressionNode, usize)> {
+  let mut parser = Parser {
+    tokens,
+    source,
+    pos: start_pos,
+    depth: 0,
+  };
+  let node = parser
+    .parse_expression(0)
+    .map_err(|e| parser.locate_error(e))?;
+  match parser.peek() {
+    None
+    | Some(ExpressionToken::RightBracket)
+    | Some(ExpressionToken::RightCurly)
+    | Some(ExpressionToken::Comma)
+    | Some(ExpressionToken::RightParenthesis)
+    | Some(ExpressionToken::DoubleRightCurly) => Ok((node, parser.pos)),
+    Some(token) => Err(parser.locate_error(syntax_error(format!(
+      "Unexpected token in the expression: {token:?}"
+    )))),
+  }
+}
+
+/**
+ * Parse all the tokens as one expression.
+ */
+pub(crate) fn parse_tokens(
+  tokens: &[ExpressionToken],
+  source: TokenSource,
+) -> Result<ExpressionNode> {
+  let (node, pos) = parse_expression_tokens(tokens, source, 0)?;
+  match tokens.get(pos) {
+    None => Ok(node),
+    Some(token) => Err(locate_error(
+      syntax_error(format!("Unexpected token in the expression: {token:?}")),
+      source.text,
+      token_span(source, pos),
+    )),
+  }
+}
+
+/**
+ * Set the position of the error to the span in the expression, unless the error is already
+ * located or the expression is unknown.
+ */
+pub(crate) fn locate_error(mut e: Error, text: &[u8], span: Span) -> Error {
+  if e.position.is_none() && !text.is_empty() {
+    e.position = Some(ErrorPosition::from_span(text, span.start, span.end));
+  }
+  e
+}
+
+/**
+ * Span of the token at the index, or the end of the expression after the last token.
+ */
+fn token_span(source: TokenSource, index: usize) -> Span {
+  match source.spans.get(index) {
+    Some(span) => *span,
+    None => Span {
+      start: source.text.len(),
+      end: source.text.len(),
+    },
+  }
+}
+
+struct Parser<'t, 'a> {
+  tokens: &'t [ExpressionToken<'a>],
+  source: TokenSource<'t>,
+  pos: usize,
+  depth: usize,
+}
+
+impl<'t, 'a> Parser<'t, 'a> {
+  fn peek(&self) -> Option<&'t ExpressionToken<'a>> {
+    self.tokens.get(self.pos)
+  }
+
+  fn span(&self, index: usize) -> Span {
+    token_span(self.source, index)
+  }
+
+  /**
+   * Locate the error at the current token, where the parser stops at the error.
+   */
+  fn locate_error(&self, e: Error) -> Error {
+    self.locate_error_at(e, self.pos)
+  }
+
+  /**
+   * Locate the error at the token of the index, like the operator missing its operand.
+   */
+  fn locate_error_at(&self, e: Error, index: usize) -> Error {
+    locate_error(e, self.source.text, self.span(index))
+  }
+
+  fn expect(&mut self, token: ExpressionToken, message: &str) -> Result<()> {
+    if self.peek() != Some(&token) {
+      return Err(syntax_error(message.to_string()));
+    }
+    self.pos += 1;
+    Ok(())
+  }
+
+  fn enter(&mut self) -> Result<()> {
+    self.depth += 1;
+    if self.depth > MAX_PARSE_DEPTH {
+      return Err(Error {
+        kind: ErrorKind::EvaluatorError,
+        message: "Expression is nested too deeply".to_string(),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::ExpressionLimit),
+      });
+    }
+    Ok(())
+  }
+
+  fn parse_expression(&mut self, min_binding_power: u8) -> Result<ExpressionNode> {
+    self.enter()?;
+    let mut lhs = self.parse_prefix()?;
+    loop {
+      match self.peek() {
+        Some(ExpressionToken::ArithOp(op)) => {
+          let Some(operator) = BinaryOperator::from_token(op) else {
+            return Err(operator_error(op, "appears after a value."));
+          };
+          let binding_power = operator.binding_power();
+          if binding_power < min_binding_power {
+            break;
+          }
+          let span = self.span(self.pos);
+          self.pos += 1;
+          if self.at_expression_end() {
+            let e = operator_error(op, "appears without a value after it.");
+            return Err(self.locate_error_at(e, self.pos - 1));
+          }
+          let rhs = self.parse_expression(binding_power + 1)?;
+          lhs = ExpressionNode::Binary(operator, Box::new(lhs), Box::new(rhs), span);
+        }
+        Some(ExpressionToken::QuestionMark) if min_binding_power <= TERNARY_BINDING_POWER => {
+          self.pos += 1;
+          lhs = self.parse_conditional(lhs)?;
+        }
+        _ => break,
+      }
+    }
+    self.depth -= 1;
+    Ok(lhs)
+  }
+
+  /**
+   * Parse the branches of the ternary operator after `?`.
+   */
+  fn parse_conditional(&mut self, condition: ExpressionNode) -> Result<ExpressionNode> {
+    let true_branch = self.parse_expression(0)?;
+    self.expect(
+      ExpressionToken::Colon,
+      "Ternary operator ? appears without corresponding : operator.",
+    )?;
+    if self.at_expression_end() {
+      let e = syntax_error(
+        "Ternary operator ?: appears without the false branch value after it.".to_string(),
+      );
+      return Err(self.locate_error_at(e, self.pos - 1));
+    }
+    // Right-associative, so `a ? b : c ? d : e` is `a ? b : (c ? d : e)`
+    let false_branch = self.parse_expression(TERNARY_BINDING_POWER)?;
+    Ok(ExpressionNode::Conditional(
+      Box::new(condition),
+      Box::new(true_branch),
+      Box::new(false_branch),
+    ))
+  }
+
+  fn at_expression_end(&self) -> bool {
+    matches!(
+      self.peek(),
+      None
+        | Some(ExpressionToken::RightBracket)
+        | Some(ExpressionToken::RightCurly)
+        | Some(ExpressionToken::Comma)
+        | Some(ExpressionToken::RightParenthesis)
+        | Some(ExpressionToken::DoubleRightCurly)
+        | Some(ExpressionToken::Colon)
+    )
+  }
+
+  fn parse_prefix(&mut self) -> Result<ExpressionNode> {
+    let depth = self.depth;
+    let negations = self.skip_negations()?;
+    let primary = self.parse_primary()?;
+    let mut node = self.parse_postfix(primary)?;
+    for _ in 0..negations {
+      node = ExpressionNode::Not(Box::new(node));
+    }
+    self.depth = depth;
+    Ok(node)
+  }
+
+  /**
+   * Skip the chain of `!` before a value and return its length, so `!!!a` is parsed
+   * without recursion.
+   */
+  fn skip_negations(&mut self) -> Result<usize> {
+    let mut negations = 0;
+    while let Some(ExpressionToken::Exclamation | ExpressionToken::ArithOp(b"!")) = self.peek() {
+      negations += 1;
+      self.pos += 1;
+    }
+    if negations > 0 && self.at_expression_end() {
+      let e = syntax_error("Operator ! appears without a value after it.".to_string());
+      return Err(self.locate_error_at(e, self.pos - 1));
+    }
+    match self.peek() {
+      Some(ExpressionToken::ArithOp(op)) => {
+        Err(operator_error(op, "appears without a value before it."))
+      }
+      Some(ExpressionToken::QuestionMark) => Err(syntax_error(
+        "Ternary operator ? appears without a value before it.".to_string(),
+      )),
+      _ => Ok(negations),
+    }
+  }
+
+  fn parse_primary(&mut self) -> Result<ExpressionNode> {
+    match self.peek() {
+      Some(ExpressionToken::LeftParenthesis) => self.parse_group(
+        ExpressionToken::RightParenthesis,
+        "Not paired right parenthesis for a left parenthesis",
+      ),
+      Some(ExpressionToken::DoubleLeftCurly) => self.parse_group(
+        ExpressionToken::DoubleRightCurly,
+        "Not paired double right curly for a double left curly",
+      ),
+      Some(ExpressionToken::LeftBracket) => {
+        self.pos += 1;
+        let items = self.parse_list(
+          ExpressionToken::RightBracket,
+          "Array value has not finished in the expression",
+        )?;
+        Ok(ExpressionNode::Array(items))
+      }
+      Some(ExpressionToken::LeftCurly) => {
+        self.pos += 1;
+        self.parse_object()
+      }
+      Some(ExpressionToken::Ref(name))
+        if self.tokens.get(self.pos + 1) == Some(&ExpressionToken::LeftParenthesis) =>
+      {
+        let name = decode_name(name)?;
+        let span = self.span(self.pos);
+        self.pos += 2;
+        let arguments = self.parse_list(
+          ExpressionToken::RightParenthesis,
+          "Function call is not finished with right parenthesis",
+        )?;
+        Ok(ExpressionNode::Call(name, arguments, span))
+      }
+      _ => self.parse_atom(),
+    }
+  }
+
+  /**
+   * Parse the expression in a pair of parentheses or double curly brackets.
+   */
+  fn parse_group(&mut self, end: ExpressionToken, message: &str) -> Result<ExpressionNode> {
+    self.pos += 1;
+    let node = self.parse_expression(0)?;
+    self.expect(end, message)?;
+    Ok(node)
+  }
+
+  /**
+   * Parse a single-token value like a number, a string or a variable reference.
+   */
+  fn parse_atom(&mut self) -> Result<ExpressionNode> {
+    let Some(token) = self.peek() else {
+      return Err(syntax_error("No value found in the expression".to_string()));
+    };
+    let node = match *token {
+      ExpressionToken::Ref(b"true") => ExpressionNode::Literal(Value::Bool(true)),
+      ExpressionToken::Ref(b"false") => ExpressionNode::Literal(Value::Bool(false)),
+      ExpressionToken::Ref(b"null") => ExpressionNode::Literal(Value::Null),
+      ExpressionToken::Ref(name) => {
+        ExpressionNode::Reference(decode_name(name)?, self.span(self.pos))
+      }
+      ExpressionToken::Number(number) => ExpressionNode::Literal(evaluate_number(number)?),
+      ExpressionToken::String(string) => ExpressionNode::Literal(evaluate_string(string)?),
+      ref token => {
+        return Err(syntax_error(format!(
+          "Expect a value token, but found {token:?}"
+        )));
+      }
+    };
+    self.pos += 1;
+    Ok(node)
+  }
+
+  /**
+   * Parse the field accesses and indexing after a value, like `.name` and `[0]`.
+   */
+  fn parse_postfix(&mut self, mut node: ExpressionNode) -> Result<ExpressionNode> {
+    loop {
+      if matches!(
+        self.peek(),
+        Some(ExpressionToken::Dot | ExpressionToken::LeftBracket)
+      ) {
+        self.enter()?;
+      }
+      match self.peek() {
+        Some(ExpressionToken::Dot) => {
+          let Some(ExpressionToken::Ref(key)) = self.tokens.get(self.pos + 1) else {
+            return Err(syntax_error("No reference found after dot.".to_string()));
+          };
+          node = ExpressionNode::Member(Box::new(node), decode_name(key)?, self.span(self.pos + 1));
+          self.pos += 2;
+        }
+        Some(ExpressionToken::LeftBracket) => {
+          let start = self.span(self.pos).start;
+          self.pos += 1;
+          let index = self.parse_expression(0)?;
+          self.expect(
+            ExpressionToken::RightBracket,
+            "Indexing is not finished with right bracket",
+          )?;
+          let end = self.span(self.pos - 1).end;
+          node = ExpressionNode::Index(Box::new(node), Box::new(index), Span { start, end });
+        }
+        _ => return Ok(node),
+      }
+    }
+  }
+
+  /**
+   * Parse the comma-separated expressions until the closing token. A trailing comma is
+   * allowed.
+   */
+  fn parse_list(
+    &mut self,
+    closing: ExpressionToken,
+    unfinished_message: &str,
+  ) -> Result<Vec<ExpressionNode>> {
+    let mut items = Vec::new();
+    loop {
+      if self.peek() == Some(&closing) {
+        self.pos += 1;
+        return Ok(items);
+      }
+      if self.peek().is_none() {
+        return Err(syntax_error(unfinished_message.to_string()));
+      }
+      items.push(self.parse_expression(0)?);
+      match self.peek() {
+        Some(ExpressionToken::Comma) => self.pos += 1,
+        Some(token) if *token == closing => {}
+        Some(token) => {
+          return Err(syntax_error(format!(
+            "Expect comma ',' or {closing:?}, but found {token:?}"
+          )));
+        }
+        None => return Err(syntax_error(unfinished_message.to_string())),
+      }
+    }
+  }
+
+  fn parse_object(&mut self) -> Result<ExpressionNode> {
+    let mut fields = Vec::new();
+    loop {
+      let key = match self.peek() {
+        Some(ExpressionToken::RightCurly) => {
+          self.pos += 1;
+          return Ok(ExpressionNode::Object(fields));
+        }
+        None => {
+          return Err(syntax_error(
+            "Object value has not finished in the expression".to_string(),
+          ));
+        }
+        Some(ExpressionToken::Ref(key)) => decode_name(key)?,
+        Some(ExpressionToken::String(key)) if key.starts_with(b"\"") || key.starts_with(b"'") => {
+          match evaluate_string(key)? {
+            Value::String(key) => key,
+            _ => unreachable!(),
+          }
+        }
+        Some(ExpressionToken::String(key)) => decode_name(key)?,
+        Some(_) => return Err(syntax_error("Object key must be a string".to_string())),
+      };
+      self.pos += 1;
+      self.expect(ExpressionToken::Colon, "Expect colon ':' after object key")?;
+      fields.push((key, self.parse_expression(0)?));
+      match self.peek() {
+        Some(ExpressionToken::Comma) => self.pos += 1,
+        Some(ExpressionToken::RightCurly) => {}
+        Some(token) => {
+          return Err(syntax_error(format!(
+            "Expect comma ',' or right curly '}}' characters, but found {token:?}"
+          )));
+        }
+        None => {
+          return Err(syntax_error(
+            "Object value has not finished in the expression".to_string(),
+          ));
+        }
+      }
+    }
+  }
+}
+
+fn decode_name(name: &[u8]) -> Result<String> {
+  match str::from_utf8(name) {
+    Ok(s) => Ok(s.to_string()),
+    Err(e) => Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: "String decode error".to_string(),
+      source: Some(Box::new(e)),
+      position: None,
+      code: Some(ErrorCode::ExpressionSyntax),
+    }),
+  }
+}
+
+fn operator_error(op: &[u8], problem: &str) -> Error {
+  syntax_error(format!(
+    "Operator {} {problem}",
+    String::from_utf8_lossy(op)
+  ))
+}
+
+fn syntax_error(message: String) -> Error {
+  Error {
+    kind: ErrorKind::EvaluatorError,
+    message,
+    source: None,
+    position: None,
+    code: Some(ErrorCode::ExpressionSyntax),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::render::expression::tokenize::{tokenize_expression, tokenize_expression_spans};
+
+  /** Span of the nodes parsed without the source. */
+  const NOWHERE: Span = Span { start: 0, end: 0 };
+
+  fn parse(expression: &str) -> Result<ExpressionNode> {
+    let tokens = tokenize_expression(expression.as_bytes())?;
+    parse_tokens(&tokens, TokenSource::UNKNOWN)
+  }
+
+  fn reference(name: &str) -> Box<ExpressionNode> {
+    Box::new(ExpressionNode::Reference(name.to_string(), NOWHERE))
+  }
+
+  fn binary(
+    operator: BinaryOperator,
+    a: Box<ExpressionNode>,
+    b: Box<ExpressionNode>,
+  ) -> Box<ExpressionNode> {
+    Box::new(ExpressionNode::Binary(operator, a, b, NOWHERE))
+  }
+
+  #[test]
+  fn test_precedence() {
+    assert_eq!(
+      Box::new(parse("a || b && !c.d").unwrap()),
+      binary(
+        BinaryOperator::Or,
+        reference("a"),
+        binary(
+          BinaryOperator::And,
+          reference("b"),
+          Box::new(ExpressionNode::Not(Box::new(ExpressionNode::Member(
+            reference("c"),
+            "d".to_string(),
+            NOWHERE
+          ))))
+        )
+      )
+    );
+    assert_eq!(
+      Box::new(parse("a - b - c").unwrap()),
+      binary(
+        BinaryOperator::Minus,
+        binary(BinaryOperator::Minus, reference("a"), reference("b")),
+        reference("c")
+      )
+    );
+    assert_eq!(
+      parse("a ? b : c ? d : e").unwrap(),
+      ExpressionNode::Conditional(
+        reference("a"),
+        reference("b"),
+        Box::new(ExpressionNode::Conditional(
+          reference("c"),
+          reference("d"),
+          reference("e")
+        ))
+      )
+    );
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    assert!(parse("a +").is_err());
+    assert!(parse("* a").is_err());
+    assert!(parse("a b").is_err());
+    assert!(parse("a ? b").is_err());
+    assert!(parse("(a").is_err());
+    assert!(parse("[1, 2").is_err());
+    assert!(parse(&format!("{}a", "!".repeat(1000))).is_ok());
+    assert!(parse(&format!("{}a", "a ? b : ".repeat(1000))).is_err());
+  }
+
+  #[test]
+  fn test_error_positions() {
+    let locate = |expression: &str| {
+      let (tokens, spans) = tokenize_expression_spans(expression.as_bytes())?;
+      let source = TokenSource {
+        text: expression.as_bytes(),
+        spans: &spans,
+      };
+      parse_tokens(&tokens, source).map(|_| ())
+    };
+    let position = |expression: &str| {
+      let e = locate(expression).unwrap_err();
+      let position = e.position.unwrap();
+      (position.offset, position.end)
+    };
+    assert_eq!(position("a b"), (2, 3));
+    assert_eq!(position("a + * b"), (4, 5));
+    assert_eq!(position("1 + "), (2, 3));
+    assert_eq!(position("a ? b : "), (6, 7));
+    assert_eq!(position("f(a, b"), (6, 6));
+    assert_eq!(position("a = b"), (2, 3));
+    assert_eq!(position("'abc"), (0, 4));
+  }
+}
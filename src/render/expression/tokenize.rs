@@ -3,7 +3,12 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
-use crate::error::{Error, ErrorKind, Result};
+
+/*!
+ * Tokenizer of the expressions, which splits them into the tokens of the parser.
+ */
+
+use crate::error::{Error, ErrorCode, ErrorKind, ErrorPosition, Result, Span};
 
 #[derive(Debug, PartialEq)]
 pub enum ExpressionToken<'a> {
@@ -44,169 +49,151 @@ pub enum ExpressionToken<'a> {
 }
 
 pub fn tokenize_expression<'a>(buf: &'a [u8]) -> Result<Vec<ExpressionToken<'a>>> {
+  tokenize_expression_spans(buf).map(|(tokens, _)| tokens)
+}
+
+/**
+ * Tokenize the expression, and return the byte offsets of the tokens in the expression
+ * along with them.
+ */
+pub(crate) fn tokenize_expression_spans<'a>(
+  buf: &'a [u8],
+) -> Result<(Vec<ExpressionToken<'a>>, Vec<Span>)> {
   let mut answer = Vec::new();
+  let mut spans = Vec::new();
   let mut pos = 0;
   while pos < buf.len() {
-    let c = u8_as_char(buf[pos])?;
-    match c {
+    let (c, width) = char_at(buf, pos)?;
+    let (token, end) = match c {
       c if c.is_alphabetic() || c == '_' => {
         let ref_end_pos = seek_ref_end(buf, pos)?;
         let ref_name = &buf[pos..ref_end_pos];
         if ref_name == b"in" {
-          answer.push(ExpressionToken::ArithOp(&buf[pos..ref_end_pos]));
+          (ExpressionToken::ArithOp(ref_name), ref_end_pos)
         } else {
-          answer.push(ExpressionToken::Ref(&buf[pos..ref_end_pos]));
+          (ExpressionToken::Ref(ref_name), ref_end_pos)
         }
-        pos = ref_end_pos;
       }
-      c if c.is_numeric() => {
+      c if c.is_ascii_digit() => {
         let num_end_pos = seek_number_end(buf, pos)?;
-        answer.push(ExpressionToken::Number(&buf[pos..num_end_pos]));
-        pos = num_end_pos;
+        (ExpressionToken::Number(&buf[pos..num_end_pos]), num_end_pos)
       }
       '.' => {
         if pos + 1 >= buf.len() {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "No content following dot operator.".to_string(),
-            source: None,
-          });
+          return Err(syntax_error(
+            buf,
+            pos,
+            pos + 1,
+            "No content following dot operator.",
+          ));
         }
-        let nc = u8_as_char(buf[pos + 1])?;
-        if nc.is_numeric() {
+        if buf[pos + 1].is_ascii_digit() {
           let num_end_pos = seek_number_end(buf, pos)?;
-          answer.push(ExpressionToken::Number(&buf[pos..num_end_pos]));
-          pos = num_end_pos;
+          (ExpressionToken::Number(&buf[pos..num_end_pos]), num_end_pos)
         } else {
-          answer.push(ExpressionToken::Dot);
-          pos += 1;
+          (ExpressionToken::Dot, pos + 1)
         }
       }
       '"' | '\'' => {
         let string_end_pos = seek_string_end(buf, pos)?;
-        answer.push(ExpressionToken::String(&buf[pos..string_end_pos]));
-        pos = string_end_pos;
-      }
-      '+' | '-' | '*' | '/' | '%' => {
-        answer.push(ExpressionToken::ArithOp(&buf[pos..pos + 1]));
-        pos += 1
+        (
+          ExpressionToken::String(&buf[pos..string_end_pos]),
+          string_end_pos,
+        )
       }
+      '+' | '-' | '*' | '/' | '%' => (ExpressionToken::ArithOp(&buf[pos..pos + 1]), pos + 1),
       '&' | '|' => {
         if pos + 1 < buf.len() && buf[pos + 1] == buf[pos] {
-          answer.push(ExpressionToken::ArithOp(&buf[pos..pos + 2]));
-          pos += 2;
+          (ExpressionToken::ArithOp(&buf[pos..pos + 2]), pos + 2)
         } else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator has not been supported!".to_string(),
-            source: None,
-          });
+          return Err(syntax_error(
+            buf,
+            pos,
+            pos + 1,
+            "Operator has not been supported!",
+          ));
         }
       }
       '>' | '<' => {
         if pos + 1 < buf.len() && buf[pos + 1] == b'=' {
-          answer.push(ExpressionToken::ArithOp(&buf[pos..pos + 2]));
-          pos += 2;
+          (ExpressionToken::ArithOp(&buf[pos..pos + 2]), pos + 2)
         } else {
-          answer.push(ExpressionToken::ArithOp(&buf[pos..pos + 1]));
-          pos += 1;
+          (ExpressionToken::ArithOp(&buf[pos..pos + 1]), pos + 1)
         }
       }
       '=' => {
         if pos + 2 < buf.len() && buf[pos + 1] == b'=' && buf[pos + 2] == b'=' {
-          answer.push(ExpressionToken::ArithOp(&buf[pos..pos + 3]));
-          pos += 3;
+          (ExpressionToken::ArithOp(&buf[pos..pos + 3]), pos + 3)
         } else {
-          return Err(Error {
-            kind: ErrorKind::EvaluatorError,
-            message: "Operator has not been supported!".to_string(),
-            source: None,
-          });
+          return Err(syntax_error(
+            buf,
+            pos,
+            pos + 1,
+            "Operator has not been supported!",
+          ));
         }
       }
-      '(' => {
-        answer.push(ExpressionToken::LeftParenthesis);
-        pos += 1;
-      }
-      ')' => {
-        answer.push(ExpressionToken::RightParenthesis);
-        pos += 1;
-      }
-      '[' => {
-        answer.push(ExpressionToken::LeftBracket);
-        pos += 1;
-      }
-      ']' => {
-        answer.push(ExpressionToken::RightBracket);
-        pos += 1;
-      }
+      '(' => (ExpressionToken::LeftParenthesis, pos + 1),
+      ')' => (ExpressionToken::RightParenthesis, pos + 1),
+      '[' => (ExpressionToken::LeftBracket, pos + 1),
+      ']' => (ExpressionToken::RightBracket, pos + 1),
       '{' => {
         if pos + 1 < buf.len() && buf[pos + 1] == b'{' {
-          answer.push(ExpressionToken::DoubleLeftCurly);
-          pos += 2;
+          (ExpressionToken::DoubleLeftCurly, pos + 2)
         } else {
-          answer.push(ExpressionToken::LeftCurly);
-          pos += 1;
+          (ExpressionToken::LeftCurly, pos + 1)
         }
       }
       '}' => {
         if pos + 1 < buf.len() && buf[pos + 1] == b'}' {
-          answer.push(ExpressionToken::DoubleRightCurly);
-          pos += 2;
+          (ExpressionToken::DoubleRightCurly, pos + 2)
         } else {
-          answer.push(ExpressionToken::RightCurly);
-          pos += 1;
+          (ExpressionToken::RightCurly, pos + 1)
         }
       }
-      ',' => {
-        answer.push(ExpressionToken::Comma);
-        pos += 1;
-      }
-      ':' => {
-        answer.push(ExpressionToken::Colon);
-        pos += 1;
-      }
-      '?' => {
-        answer.push(ExpressionToken::QuestionMark);
-        pos += 1;
-      }
+      ',' => (ExpressionToken::Comma, pos + 1),
+      ':' => (ExpressionToken::Colon, pos + 1),
+      '?' => (ExpressionToken::QuestionMark, pos + 1),
       '!' => {
         if pos + 2 < buf.len() && buf[pos + 1] == b'=' && buf[pos + 2] == b'=' {
-          answer.push(ExpressionToken::ArithOp(&buf[pos..pos + 3]));
-          pos += 3;
+          (ExpressionToken::ArithOp(&buf[pos..pos + 3]), pos + 3)
         } else {
-          answer.push(ExpressionToken::Exclamation);
-          pos += 1;
+          (ExpressionToken::Exclamation, pos + 1)
         }
       }
       c if c.is_whitespace() => {
-        pos += 1;
+        pos += width;
+        continue;
       }
       _ => {
-        return Err(Error {
-          kind: ErrorKind::EvaluatorError,
-          message: "Invalid char encoutered in expression".to_string(),
-          source: None,
-        });
+        return Err(syntax_error(
+          buf,
+          pos,
+          pos + width,
+          "Invalid char encoutered in expression",
+        ));
       }
-    }
+    };
+    answer.push(token);
+    spans.push(Span { start: pos, end });
+    pos = end;
   }
-  Ok(answer)
+  Ok((answer, spans))
 }
 
 /**
  * Seek the end of the current reference token. Must be called with `buf[pos]` as the start of
- * the reference token.
+ * the reference token. References are made of Unicode letters, digits and `_`.
  *
  * Return the end position.
  */
 fn seek_ref_end(buf: &[u8], pos: usize) -> Result<usize> {
   // reference
-  let mut ref_end_pos = pos + 1;
+  let mut ref_end_pos = pos;
   while ref_end_pos < buf.len() {
-    let nc = u8_as_char(buf[ref_end_pos])?;
+    let (nc, width) = char_at(buf, ref_end_pos)?;
     if nc.is_alphanumeric() || nc == '_' {
-      ref_end_pos += 1;
+      ref_end_pos += width;
     } else {
       break;
     }
@@ -219,19 +206,20 @@ fn seek_number_end(buf: &[u8], pos: usize) -> Result<usize> {
   // number
   let mut num_end_pos = pos;
   while num_end_pos < buf.len() {
-    let nc = u8_as_char(buf[num_end_pos])?;
-    if nc.is_numeric() {
+    let nc = buf[num_end_pos];
+    if nc.is_ascii_digit() {
       num_end_pos += 1;
-    } else if nc == '.' {
+    } else if nc == b'.' {
       if !found_dot {
         found_dot = true;
         num_end_pos += 1;
       } else {
-        return Err(Error {
-          kind: ErrorKind::EvaluatorError,
-          message: "Multiple dots found in a number literal.".to_string(),
-          source: None,
-        });
+        return Err(syntax_error(
+          buf,
+          pos,
+          num_end_pos + 1,
+          "Multiple dots found in a number literal.",
+        ));
       }
     } else {
       break;
@@ -255,27 +243,54 @@ fn seek_string_end(buf: &[u8], pos: usize) -> Result<usize> {
     }
   }
 
-  Err(Error {
-    kind: ErrorKind::EvaluatorError,
-    message: "String literal doesn't end in the expression.".to_string(),
-    source: None,
-  })
+  Err(syntax_error(
+    buf,
+    pos,
+    buf.len(),
+    "String literal doesn't end in the expression.",
+  ))
 }
 
-fn u8_as_char(v: u8) -> Result<char> {
-  let Some(c) = char::from_u32(v.into()) else {
-    return Err(Error {
-      kind: ErrorKind::EvaluatorError,
-      message: "Invalid char encoutered in expression".to_string(),
-      source: None,
-    });
+/**
+ * Decode the UTF-8 char starting at `buf[pos]`, and return it with its length in bytes.
+ */
+fn char_at(buf: &[u8], pos: usize) -> Result<(char, usize)> {
+  let width = match buf[pos] {
+    0x00..=0x7F => 1,
+    0xC0..=0xDF => 2,
+    0xE0..=0xEF => 3,
+    0xF0..=0xF7 => 4,
+    _ => 0,
   };
-  Ok(c)
+  let c = buf
+    .get(pos..pos + width)
+    .and_then(|bytes| str::from_utf8(bytes).ok())
+    .and_then(|s| s.chars().next());
+  match c {
+    Some(c) => Ok((c, width)),
+    None => Err(syntax_error(
+      buf,
+      pos,
+      pos + 1,
+      "Invalid UTF-8 encountered in expression",
+    )),
+  }
+}
+
+fn syntax_error(buf: &[u8], start: usize, end: usize, message: &str) -> Error {
+  Error {
+    kind: ErrorKind::EvaluatorError,
+    message: message.to_string(),
+    source: None,
+    position: Some(ErrorPosition::from_span(buf, start, end)),
+    code: Some(ErrorCode::ExpressionSyntax),
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use proptest::prelude::*;
   #[test]
   fn test_tokenize() {
     let expression = "(name.age + 1.5) * .2";
@@ -347,6 +362,30 @@ mod tests {
     );
   }
 
+  #[test]
+  fn test_tokenize_unicode() {
+    let expression = "名前 + 'héllo' + user.prénom_2";
+    let (tokens, spans) = tokenize_expression_spans(expression.as_bytes()).unwrap();
+    assert_eq!(
+      tokens,
+      [
+        ExpressionToken::Ref("名前".as_bytes()),
+        ExpressionToken::ArithOp(b"+"),
+        ExpressionToken::String("'héllo'".as_bytes()),
+        ExpressionToken::ArithOp(b"+"),
+        ExpressionToken::Ref(b"user"),
+        ExpressionToken::Dot,
+        ExpressionToken::Ref("prénom_2".as_bytes()),
+      ]
+    );
+    assert_eq!(spans[0], Span { start: 0, end: 6 });
+
+    let error = tokenize_expression("a · b".as_bytes()).unwrap_err();
+    let position = error.position.unwrap();
+    assert_eq!((position.offset, position.end), (2, 4));
+    assert!(tokenize_expression(b"a \xff").is_err());
+  }
+
   #[test]
   fn test_tokenize_in_operator() {
     let expression = "a in b";
@@ -360,4 +399,16 @@ mod tests {
       ]
     );
   }
+
+  proptest! {
+    #[test]
+    fn prop_tokenize_any_input_without_panic(expression in any::<String>()) {
+      let _ = tokenize_expression(expression.as_bytes());
+    }
+
+    #[test]
+    fn prop_tokenize_any_bytes_without_panic(buf in proptest::collection::vec(any::<u8>(), 0..64)) {
+      let _ = tokenize_expression(&buf);
+    }
+  }
 }
@@ -0,0 +1,430 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use std::collections::HashMap;
+
+/**
+ * Load the files used by `<include>` and `src` attributes.
+ *
+ * Implement this trait to source the files from databases, bundles or virtual filesystems.
+ */
+pub trait FileLoader: Send + Sync {
+  /**
+   * Load the content of the file at the path, which is already resolved against the
+   * directory of the including document.
+   */
+  fn load(&self, path: &str) -> Result<String>;
+
+  /**
+   * Whether the loader reads the filesystem, which is denied by [`Sandbox::deny_filesystem`].
+   */
+  fn accesses_filesystem(&self) -> bool {
+    false
+  }
+}
+
+/**
+ * Restrictions on the files a document can load, to render untrusted documents safely.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+  /** Deny the loaders reading the filesystem, so only files in memory can be loaded. */
+  pub deny_filesystem: bool,
+  /** Only allow the paths under this directory, after resolving `..` segments. */
+  pub root: Option<String>,
+}
+
+impl Sandbox {
+  /**
+   * Check whether the resolved and normalized path can be loaded with the loader.
+   */
+  pub fn check(&self, path: &str, file_loader: &dyn FileLoader) -> Result<()> {
+    if self.deny_filesystem && file_loader.accesses_filesystem() {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Filesystem access is denied by the sandbox: {path}"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::AccessDenied),
+      });
+    }
+    if let Some(root) = &self.root
+      && !is_path_under(path, &normalize_path(root))
+    {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Path is outside of the sandbox root {root}: {path}"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::AccessDenied),
+      });
+    }
+    Ok(())
+  }
+}
+
+/**
+ * Whether the normalized path is the root or under the root.
+ */
+fn is_path_under(path: &str, root: &str) -> bool {
+  if root == "." {
+    return !is_absolute_path(path) && path != ".." && !path.starts_with("../");
+  }
+  match path.strip_prefix(root) {
+    Some(rest) => rest.is_empty() || root.ends_with('/') || rest.starts_with('/'),
+    None => false,
+  }
+}
+
+impl std::fmt::Debug for dyn FileLoader {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("FileLoader")
+  }
+}
+
+/**
+ * Normalize a path into a platform independent form: separators become `/`, and `.` and
+ * `..` segments are resolved lexically. Leading `..` segments of relative paths are kept,
+ * while `..` segments can't go above the root of absolute paths.
+ *
+ * Unix absolute paths (`/a`), Windows drive paths (`C:\a`), UNC paths (`\\server\share`)
+ * and HTTP(S) URLs (`https://host/a`) are recognized as absolute.
+ */
+pub fn normalize_path(path: &str) -> String {
+  let path = path.replace('\\', "/");
+  let (root, rest) = split_path_root(&path);
+  let mut segments: Vec<&str> = Vec::new();
+  for segment in rest.split('/') {
+    match segment {
+      "" | "." => {}
+      ".." => {
+        if matches!(segments.last(), Some(last) if *last != "..") {
+          segments.pop();
+        } else if root.is_empty() {
+          segments.push("..");
+        }
+      }
+      _ => segments.push(segment),
+    }
+  }
+  let normalized = root.to_string() + &segments.join("/");
+  if normalized.is_empty() {
+    ".".to_string()
+  } else {
+    normalized
+  }
+}
+
+/**
+ * Whether the path is an HTTP(S) URL.
+ */
+pub fn is_url(path: &str) -> bool {
+  path.starts_with("http://") || path.starts_with("https://")
+}
+
+/**
+ * Whether the path, with separators normalized to `/`, is absolute on any platform.
+ */
+pub fn is_absolute_path(path: &str) -> bool {
+  !split_path_root(&path.replace('\\', "/")).0.is_empty()
+}
+
+/**
+ * Split the path, whose separators are `/`, into the root and the rest. The root is empty
+ * for relative paths.
+ */
+fn split_path_root(path: &str) -> (&str, &str) {
+  if is_url(path) {
+    // URL like `https://host/rest`, whose root is `https://host/`.
+    let host_start = path.find("://").unwrap() + 3;
+    let root_len = path[host_start..]
+      .find('/')
+      .map(|i| host_start + i + 1)
+      .unwrap_or(path.len());
+    return path.split_at(root_len);
+  }
+  if let Some(unc) = path.strip_prefix("//") {
+    // UNC path like `//server/share/rest`, whose root is `//server/share/`.
+    let root_len: usize = unc.splitn(3, '/').take(2).map(|s| s.len() + 1).sum();
+    return path.split_at((2 + root_len).min(path.len()));
+  }
+  let bytes = path.as_bytes();
+  if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+    // Windows drive path like `C:/rest`.
+    let end = if bytes.get(2) == Some(&b'/') { 3 } else { 2 };
+    return path.split_at(end);
+  }
+  if path.starts_with('/') {
+    return path.split_at(1);
+  }
+  ("", path)
+}
+
+/**
+ * A file loader that reads the files from the filesystem.
+ */
+#[derive(Debug, Default)]
+pub struct FsLoader {}
+
+impl FileLoader for FsLoader {
+  fn load(&self, path: &str) -> Result<String> {
+    // Paths are normalized with `/` separators, which are not accepted by the verbatim
+    // paths of Windows.
+    #[cfg(windows)]
+    let fs_path = path.replace('/', "\\");
+    #[cfg(not(windows))]
+    let fs_path = path;
+    std::fs::read_to_string(fs_path).map_err(|e| Error {
+      kind: ErrorKind::RendererError,
+      message: format!("Failed to read file included: {path}"),
+      source: Some(Box::new(e)),
+      position: None,
+      code: Some(ErrorCode::FileNotFound),
+    })
+  }
+
+  fn accesses_filesystem(&self) -> bool {
+    true
+  }
+}
+
+/**
+ * A file loader for HTTP(S) URLs, which loads the other paths with a fallback loader.
+ * Responses are cached in memory for the lifetime of the loader.
+ */
+#[cfg(feature = "http")]
+pub struct HttpLoader {
+  fallback: Box<dyn FileLoader>,
+  agent: ureq::Agent,
+  max_bytes: u64,
+  cache: std::sync::Mutex<HashMap<String, String>>,
+}
+
+#[cfg(feature = "http")]
+impl HttpLoader {
+  /** Default maximum size of a response in bytes. */
+  pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+
+  pub fn new<L: FileLoader + 'static>(fallback: L) -> Self {
+    HttpLoader {
+      fallback: Box::new(fallback),
+      agent: ureq::Agent::new_with_defaults(),
+      max_bytes: Self::DEFAULT_MAX_BYTES,
+      cache: std::sync::Mutex::new(HashMap::new()),
+    }
+  }
+
+  /**
+   * Set the maximum size of a response in bytes. Larger responses fail to load.
+   */
+  pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+    self.max_bytes = max_bytes;
+    self
+  }
+
+  fn fetch(&self, url: &str) -> std::result::Result<String, ureq::Error> {
+    self
+      .agent
+      .get(url)
+      .call()?
+      .body_mut()
+      .with_config()
+      .limit(self.max_bytes)
+      .read_to_string()
+  }
+}
+
+#[cfg(feature = "http")]
+impl FileLoader for HttpLoader {
+  fn load(&self, path: &str) -> Result<String> {
+    if !is_url(path) {
+      return self.fallback.load(path);
+    }
+    if let Some(content) = self.cache.lock().unwrap().get(path) {
+      return Ok(content.clone());
+    }
+    let content = self.fetch(path).map_err(|e| Error {
+      kind: ErrorKind::RendererError,
+      message: format!("Failed to fetch file included: {path}"),
+      source: Some(Box::new(e)),
+      position: None,
+      code: Some(ErrorCode::FileNotFound),
+    })?;
+    self
+      .cache
+      .lock()
+      .unwrap()
+      .insert(path.to_string(), content.clone());
+    Ok(content)
+  }
+
+  fn accesses_filesystem(&self) -> bool {
+    self.fallback.accesses_filesystem()
+  }
+}
+
+/**
+ * A file loader that keeps the files in memory, by their paths.
+ */
+#[derive(Debug, Default)]
+pub struct InMemoryLoader {
+  files: HashMap<String, String>,
+}
+
+impl InMemoryLoader {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn insert(&mut self, path: impl Into<String>, content: impl Into<String>) {
+    self.files.insert(path.into(), content.into());
+  }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for InMemoryLoader {
+  fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+    InMemoryLoader {
+      files: iter
+        .into_iter()
+        .map(|(path, content)| (path.into(), content.into()))
+        .collect(),
+    }
+  }
+}
+
+impl FileLoader for InMemoryLoader {
+  fn load(&self, path: &str) -> Result<String> {
+    match self.files.get(path) {
+      Some(content) => Ok(content.clone()),
+      None => Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("File not found: {path}"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::FileNotFound),
+      }),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_in_memory_loader() {
+    let mut loader = InMemoryLoader::from_iter([("a.poml", "<p>A</p>")]);
+    loader.insert("b.poml", "<p>B</p>");
+    assert_eq!(loader.load("a.poml").unwrap(), "<p>A</p>");
+    assert_eq!(loader.load("b.poml").unwrap(), "<p>B</p>");
+    assert!(loader.load("c.poml").is_err());
+  }
+
+  #[test]
+  fn test_normalize_path() {
+    assert_eq!(normalize_path("a/./b/../c.poml"), "a/c.poml");
+    assert_eq!(normalize_path("a\\b\\c.poml"), "a/b/c.poml");
+    assert_eq!(normalize_path("../a//b.poml"), "../a/b.poml");
+    assert_eq!(normalize_path("a/../../b.poml"), "../b.poml");
+    assert_eq!(normalize_path("a/.."), ".");
+    assert_eq!(normalize_path("/a/../../b.poml"), "/b.poml");
+    assert_eq!(normalize_path("C:\\prompts\\..\\a.poml"), "C:/a.poml");
+    assert_eq!(
+      normalize_path("\\\\server\\share\\x\\..\\a.poml"),
+      "//server/share/a.poml"
+    );
+    assert_eq!(
+      normalize_path("//server/share/../a.poml"),
+      "//server/share/a.poml"
+    );
+    assert_eq!(
+      normalize_path("https://example.com/a/../../b.poml"),
+      "https://example.com/b.poml"
+    );
+  }
+
+  #[test]
+  fn test_is_absolute_path() {
+    assert!(is_absolute_path("/a"));
+    assert!(is_absolute_path("C:\\a"));
+    assert!(is_absolute_path("\\\\server\\share"));
+    assert!(!is_absolute_path("a/b"));
+    assert!(!is_absolute_path("../a"));
+    assert!(is_absolute_path("https://example.com/a.poml"));
+  }
+
+  #[test]
+  fn test_sandbox() {
+    let fs_loader = FsLoader {};
+    let memory_loader = InMemoryLoader::new();
+    let sandbox = Sandbox {
+      deny_filesystem: true,
+      root: None,
+    };
+    assert!(sandbox.check("a.poml", &fs_loader).is_err());
+    assert!(sandbox.check("a.poml", &memory_loader).is_ok());
+
+    let sandbox = Sandbox {
+      deny_filesystem: false,
+      root: Some("/srv/prompts/".to_string()),
+    };
+    assert!(sandbox.check("/srv/prompts/a.poml", &fs_loader).is_ok());
+    assert!(
+      sandbox
+        .check("/srv/prompts_old/a.poml", &fs_loader)
+        .is_err()
+    );
+    assert!(sandbox.check("/etc/passwd", &fs_loader).is_err());
+
+    let sandbox = Sandbox {
+      deny_filesystem: false,
+      root: Some(".".to_string()),
+    };
+    assert!(sandbox.check("sub/a.poml", &fs_loader).is_ok());
+    assert!(sandbox.check("../a.poml", &fs_loader).is_err());
+    assert!(sandbox.check("/a.poml", &fs_loader).is_err());
+  }
+
+  #[cfg(feature = "http")]
+  #[test]
+  fn test_http_loader() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = std::thread::spawn(move || {
+      // The second load of the first URL hits the cache, so only two requests are served.
+      for body in ["<p>Remote</p>", "<p>Too long</p>"] {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf).unwrap();
+        let response = format!(
+          "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+          body.len()
+        );
+        stream.write_all(response.as_bytes()).unwrap();
+      }
+    });
+
+    let loader = HttpLoader::new(InMemoryLoader::from_iter([("a.poml", "<p>A</p>")]));
+    let url = format!("http://127.0.0.1:{port}/fragment.poml");
+    assert_eq!(loader.load(&url).unwrap(), "<p>Remote</p>");
+    assert_eq!(loader.load(&url).unwrap(), "<p>Remote</p>");
+    assert_eq!(loader.load("a.poml").unwrap(), "<p>A</p>");
+
+    let loader = HttpLoader::new(InMemoryLoader::new()).with_max_bytes(4);
+    assert!(loader.load(&url).is_err());
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn test_fs_loader_missing_file() {
+    let err = FsLoader {}.load("missing/file.poml").unwrap_err();
+    assert!(err.message.contains("missing/file.poml"));
+  }
+}
@@ -0,0 +1,301 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Filters transforming the values of the `{{ }}` expressions, chained after the expression
+ * like `{{ name | trim | truncate(20) }}`.
+ *
+ * The builtin filters are `upper`, `lower`, `title`, `trim`, `truncate(length, end)`,
+ * `pad(width, fill)`, `replace(from, to)` and `default(value)`, and `raw` which keeps the
+ * value from being escaped. Custom filters are registered by
+ * [`RenderContext::register_filter`](super::render_context::RenderContext::register_filter),
+ * and take precedence over the builtin ones.
+ */
+
+use super::render_context::RenderContext;
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use serde_json::Value;
+
+/**
+ * Maximum width `pad` can pad a value to, in characters.
+ */
+const MAX_PAD_WIDTH: usize = 1_000_000;
+
+/**
+ * A filter called with the value and the evaluated arguments, like `truncate(20)`.
+ */
+pub trait Filter: Send + Sync {
+  fn apply(&self, value: Value, arguments: &[Value]) -> Result<Value>;
+}
+
+impl<F> Filter for F
+where
+  F: Fn(Value, &[Value]) -> Result<Value> + Send + Sync,
+{
+  fn apply(&self, value: Value, arguments: &[Value]) -> Result<Value> {
+    self(value, arguments)
+  }
+}
+
+impl std::fmt::Debug for dyn Filter {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("Filter")
+  }
+}
+
+/**
+ * A filter in the pipeline of an expression.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterCall<'a> {
+  pub name: &'a str,
+  /** Source of the arguments in the parentheses, if any. */
+  pub arguments: Option<&'a str>,
+}
+
+/**
+ * Split the filters like `| upper` from the expression. The filters are separated by single
+ * `|` outside strings and brackets, so `||` is still the logical operator. Return `None` if
+ * the pipeline is not well-formed, which leaves the expression to the evaluator.
+ */
+pub fn split_filters(expression: &str) -> Option<(&str, Vec<FilterCall<'_>>)> {
+  let p = expression.as_bytes();
+  let mut separators = Vec::new();
+  let mut quote = None;
+  let mut depth = 0usize;
+  let mut pos = 0;
+  while pos < p.len() {
+    let c = p[pos];
+    match quote {
+      Some(_) if c == b'\\' => pos += 1,
+      Some(q) if c == q => quote = None,
+      Some(_) => {}
+      None => match c {
+        b'\'' | b'"' => quote = Some(c),
+        b'(' | b'[' | b'{' => depth += 1,
+        b')' | b']' | b'}' => depth = depth.saturating_sub(1),
+        b'|' if p.get(pos + 1) == Some(&b'|') => pos += 1,
+        b'|' if depth == 0 => separators.push(pos),
+        _ => {}
+      },
+    }
+    pos += 1;
+  }
+  let Some(&first) = separators.first() else {
+    return Some((expression, Vec::new()));
+  };
+  let mut filters = Vec::with_capacity(separators.len());
+  for (i, &start) in separators.iter().enumerate() {
+    let end = separators.get(i + 1).copied().unwrap_or(expression.len());
+    filters.push(parse_filter_call(expression[start + 1..end].trim())?);
+  }
+  Some((&expression[..first], filters))
+}
+
+fn parse_filter_call(source: &str) -> Option<FilterCall<'_>> {
+  let name_end = source
+    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+    .unwrap_or(source.len());
+  let (name, rest) = source.split_at(name_end);
+  if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+    return None;
+  }
+  let arguments = match rest.trim_start() {
+    "" => None,
+    rest => Some(rest.strip_prefix('(')?.strip_suffix(')')?),
+  };
+  Some(FilterCall { name, arguments })
+}
+
+/**
+ * Apply the filter with the name to the value. The `raw` filter keeps the value as is.
+ */
+pub fn apply_filter(
+  name: &str,
+  value: Value,
+  arguments: &[Value],
+  context: &RenderContext,
+) -> Result<Value> {
+  if let Some(filter) = context.get_filter(name) {
+    return filter.apply(value, arguments);
+  }
+  let text = || match &value {
+    Value::String(s) => s.clone(),
+    value => value.to_string(),
+  };
+  match name {
+    "raw" => Ok(value),
+    "upper" => Ok(Value::String(text().to_uppercase())),
+    "lower" => Ok(Value::String(text().to_lowercase())),
+    "trim" => Ok(Value::String(text().trim().to_string())),
+    "title" => Ok(Value::String(title_case(&text()))),
+    "truncate" => {
+      let length = usize_argument(name, arguments, 0)?;
+      let end = str_argument(name, arguments, 1)?.unwrap_or("...");
+      let text = text();
+      Ok(Value::String(match text.char_indices().nth(length) {
+        Some((byte_end, _)) => format!("{}{end}", &text[..byte_end]),
+        None => text,
+      }))
+    }
+    "pad" => {
+      let width = usize_argument(name, arguments, 0)?;
+      if width > MAX_PAD_WIDTH {
+        return Err(Error {
+          kind: ErrorKind::EvaluatorError,
+          message: format!("pad() pads to a too large width: {width}"),
+          source: None,
+          position: None,
+          code: Some(ErrorCode::ExpressionLimit),
+        });
+      }
+      let fill = str_argument(name, arguments, 1)?.unwrap_or(" ");
+      let mut text = text();
+      let mut fill_chars = fill.chars().cycle();
+      for _ in text.chars().count()..width {
+        match fill_chars.next() {
+          Some(c) => text.push(c),
+          None => break,
+        }
+      }
+      Ok(Value::String(text))
+    }
+    "replace" => match (
+      str_argument(name, arguments, 0)?,
+      str_argument(name, arguments, 1)?,
+    ) {
+      (Some(from), Some(to)) if !from.is_empty() => Ok(Value::String(text().replace(from, to))),
+      _ => Err(filter_error(
+        "replace() takes a non-empty pattern and a replacement".to_string(),
+      )),
+    },
+    "default" => match value {
+      Value::Null => Ok(arguments.first().cloned().unwrap_or(Value::Null)),
+      Value::String(s) if s.is_empty() => Ok(arguments.first().cloned().unwrap_or_default()),
+      value => Ok(value),
+    },
+    _ => Err(Error {
+      kind: ErrorKind::EvaluatorError,
+      message: format!("Unknown filter: {name}"),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::UnknownFunction),
+    }),
+  }
+}
+
+/**
+ * Capitalize the first letter of each word and lowercase the rest.
+ */
+fn title_case(text: &str) -> String {
+  let mut answer = String::with_capacity(text.len());
+  let mut word_start = true;
+  for c in text.chars() {
+    if word_start {
+      answer.extend(c.to_uppercase());
+    } else {
+      answer.extend(c.to_lowercase());
+    }
+    word_start = c.is_whitespace();
+  }
+  answer
+}
+
+fn usize_argument(name: &str, arguments: &[Value], index: usize) -> Result<usize> {
+  arguments
+    .get(index)
+    .and_then(|v| v.as_u64())
+    .map(|v| v as usize)
+    .ok_or_else(|| {
+      filter_error(format!(
+        "Argument {} of {name}() should be a non-negative integer",
+        index + 1
+      ))
+    })
+}
+
+fn str_argument<'v>(name: &str, arguments: &'v [Value], index: usize) -> Result<Option<&'v str>> {
+  match arguments.get(index) {
+    None => Ok(None),
+    Some(Value::String(s)) => Ok(Some(s)),
+    Some(v) => Err(filter_error(format!(
+      "Argument {} of {name}() should be a string, found {v}",
+      index + 1
+    ))),
+  }
+}
+
+fn filter_error(message: String) -> Error {
+  Error {
+    kind: ErrorKind::EvaluatorError,
+    message,
+    source: None,
+    position: None,
+    code: Some(ErrorCode::TypeMismatch),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use std::collections::HashMap;
+
+  #[test]
+  fn test_split_filters() {
+    assert_eq!(split_filters("a || b"), Some(("a || b", vec![])));
+    let (expression, filters) = split_filters("name | truncate(5, '|') | raw ").unwrap();
+    assert_eq!(expression, "name ");
+    assert_eq!(
+      filters,
+      vec![
+        FilterCall {
+          name: "truncate",
+          arguments: Some("5, '|'")
+        },
+        FilterCall {
+          name: "raw",
+          arguments: None
+        }
+      ]
+    );
+    assert_eq!(split_filters("f(a | b)").unwrap().1.len(), 0);
+    assert_eq!(split_filters("a | 1"), None);
+    assert_eq!(split_filters("a | upper("), None);
+  }
+
+  #[test]
+  fn test_apply_filter() {
+    let context = RenderContext::from_iter(HashMap::<String, Value>::new());
+    let apply = |name, value, arguments: &[Value]| apply_filter(name, value, arguments, &context);
+    assert_eq!(
+      apply("title", json!("hello wORLD"), &[]).unwrap(),
+      json!("Hello World")
+    );
+    assert_eq!(
+      apply("truncate", json!("abcdef"), &[json!(3)]).unwrap(),
+      json!("abc...")
+    );
+    assert_eq!(
+      apply("truncate", json!("abc"), &[json!(3)]).unwrap(),
+      json!("abc")
+    );
+    assert_eq!(
+      apply("pad", json!(42), &[json!(5), json!(".")]).unwrap(),
+      json!("42...")
+    );
+    assert_eq!(
+      apply("default", Value::Null, &[json!("n/a")]).unwrap(),
+      json!("n/a")
+    );
+    assert_eq!(apply("default", json!(0), &[json!(1)]).unwrap(), json!(0));
+    assert!(apply("truncate", json!("abc"), &[]).is_err());
+    assert!(apply("replace", json!("abc"), &[json!(""), json!("x")]).is_err());
+    assert!(apply("unknown", json!("abc"), &[]).is_err());
+    let err = apply("pad", json!("a"), &[json!(1_000_000_000_000u64)]).unwrap_err();
+    assert_eq!(err.code(), ErrorCode::ExpressionLimit);
+  }
+}
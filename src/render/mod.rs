@@ -4,15 +4,43 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+pub mod attr;
+pub mod builder;
+pub mod cache;
+pub mod compiled;
+pub mod config;
+pub mod data_format;
 pub mod expression;
+pub mod file_loader;
+pub mod filter;
+pub mod options;
+#[cfg(feature = "parallel")]
+pub mod parallel;
+pub mod pool;
+pub mod post_process;
+pub mod progress;
 pub mod render_context;
+pub mod source_map;
+pub mod stylesheet;
+pub mod summarizer;
 pub mod tag_renderer;
+pub mod token_counter;
+pub mod trace;
 pub(crate) mod utils;
 
-use crate::error::{Error, ErrorKind, Result};
+use crate::error::{
+  Diagnostic, Error, ErrorCode, ErrorKind, ErrorPosition, Result, Severity, Span,
+};
 use crate::render::expression::tokenize::ExpressionToken;
-use crate::{PomlNode, PomlParser, PomlTagNode};
+use crate::render::expression::{Expression, UndefinedPolicy};
+use crate::render::options::{FormatVersion, WhiteSpaceMode};
+use crate::render::tag_renderer::ChildrenOutput;
+use crate::{AttributeValue, PomlNode, PomlParser, PomlTagNode};
 use serde_json::{Value, json};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
 
 pub struct Renderer<'a, T>
 where
@@ -21,168 +49,1775 @@ where
   pub parser: PomlParser<'a>,
   pub context: render_context::RenderContext,
   pub tag_renderer: T,
+  pub options: options::RenderOptions,
   /** POML filename for error reporting purpose */
   pub(crate) filename: String,
+  /** Whitespace modes set by `whiteSpace` attributes of the ancestor tags */
+  white_space_stack: Vec<Option<WhiteSpaceMode>>,
+  /** Number of ancestor tags whose text content should not be template-expanded */
+  raw_depth: usize,
+  /** Number of ancestor `<code>` tags, whose interpolated values are not escaped */
+  code_depth: usize,
+  /** Number of rendered nodes and parsed nodes, including the included documents */
+  progress: (usize, usize),
+  /** Templates defined by `<template>`, by name */
+  templates: HashMap<String, Template<'a>>,
+  /** Number of template instances being rendered, to stop infinite recursion */
+  template_depth: usize,
+  /** Number of ancestor tags of the node being rendered, including the including documents */
+  tag_depth: usize,
+  /** Number of `for` loop iterations of the render so far, including the included documents */
+  loop_iterations: usize,
+  /** Number of documents included by the render so far */
+  include_count: usize,
+  /** State of the tag being rendered, decided by the enclosing tags */
+  tag_state: tag_renderer::TagRenderState,
+  /** Number of the rendered tags by name, among the children of each ancestor tag */
+  sibling_counts: Vec<HashMap<&'a str, usize>>,
+  /** The parsed document and its parsed expressions, when rendering a compiled template */
+  compiled: Option<compiled::CompiledTemplate<'a>>,
+  /**
+   * Results of the expressions evaluated in the context of a generation, by the expression
+   * text. It's cleared when the generation of the context changes.
+   */
+  expression_cache: RefCell<(u64, HashMap<String, Value>)>,
+  /**
+   * Syntax trees of the expressions parsed so far, by the expression text, which are kept
+   * when the variables change, like in each iteration of a loop.
+   */
+  parsed_expressions: RefCell<HashMap<String, Arc<Expression>>>,
+  /** Item names and range expressions of the `for` attributes parsed so far */
+  parsed_for_loops: RefCell<HashMap<String, (String, Arc<Expression>)>>,
+  /** Errors of the nodes skipped by `check`, which collects them instead of stopping */
+  diagnostics: Option<Vec<Diagnostic>>,
+  /** Warnings of the evaluated expressions, located in the document */
+  warnings: RefCell<Vec<Diagnostic>>,
+  /**
+   * In the source map mode, the rendered nodes of the tags being rendered, from the
+   * outermost tag.
+   */
+  rendered_nodes: Option<Vec<Vec<source_map::RenderedNode>>>,
+  /** Source map of the last render, in the source map mode */
+  source_map: Option<source_map::SourceMap>,
+  /** Steps of the render so far, in the trace mode */
+  trace: RefCell<Vec<trace::TraceEvent>>,
+  /**
+   * Renders the children of the root tag in parallel, or returns `None` if they depend on
+   * each other. It's set by `render_parallel`, which knows the tag renderer can be sent to
+   * other threads.
+   */
+  #[cfg(feature = "parallel")]
+  render_in_parallel: Option<parallel::RenderInParallelFn<'a, T>>,
+}
+
+/**
+ * A reusable fragment defined by `<template name="..." params="...">`.
+ */
+#[derive(Clone)]
+struct Template<'a> {
+  params: Vec<String>,
+  children: Vec<PomlNode<'a>>,
+  /** Document defining the template, which the positions of the children are offsets of */
+  source: &'a [u8],
 }
 
-impl<'a, T> Renderer<'a, T>
-where
-  T: tag_renderer::TagRenderer,
-{
+/**
+ * Maximum nesting depth of template instances.
+ */
+const MAX_TEMPLATE_DEPTH: usize = 32;
+
+/**
+ * Nesting depth counted for an `<include>` besides the tags of the included document, since
+ * rendering a document takes more of the stack than rendering a tag.
+ */
+const INCLUDE_DEPTH: usize = 2;
+
+impl<'a, T> Renderer<'a, T>
+where
+  T: tag_renderer::TagRenderer,
+{
+  /**
+   * Create a renderer for the POML document with the given context and tag renderer.
+   */
+  pub fn new(doc: &'a str, context: render_context::RenderContext, tag_renderer: T) -> Self {
+    Renderer {
+      parser: PomlParser::from_poml_str(doc),
+      context,
+      tag_renderer,
+      options: options::RenderOptions::default(),
+      filename: "<anonymous>".to_string(),
+      white_space_stack: Vec::new(),
+      raw_depth: 0,
+      code_depth: 0,
+      progress: (0, 0),
+      templates: HashMap::new(),
+      template_depth: 0,
+      tag_depth: 0,
+      loop_iterations: 0,
+      include_count: 0,
+      tag_state: tag_renderer::TagRenderState::default(),
+      sibling_counts: Vec::new(),
+      compiled: None,
+      expression_cache: RefCell::new((0, HashMap::new())),
+      parsed_expressions: RefCell::new(HashMap::new()),
+      parsed_for_loops: RefCell::new(HashMap::new()),
+      diagnostics: None,
+      warnings: RefCell::new(Vec::new()),
+      rendered_nodes: None,
+      source_map: None,
+      trace: RefCell::new(Vec::new()),
+      #[cfg(feature = "parallel")]
+      render_in_parallel: None,
+    }
+  }
+
+  /**
+   * Render the provided POML source code into the desired format.
+   */
+  pub fn render(&mut self) -> Result<String> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("poml_render", file = %self.filename).entered();
+    self.begin_render();
+    let output = self.render_document()?;
+    Ok(self.finish_render(output))
+  }
+
+  /**
+   * Reset the state of the last render and apply the options to the context.
+   */
+  fn begin_render(&mut self) {
+    self.expression_cache.get_mut().1.clear();
+    self.warnings.get_mut().clear();
+    self.trace.get_mut().clear();
+    self.loop_iterations = 0;
+    self.include_count = 0;
+    self.progress = (0, 0);
+    if let Some(seed) = self.options.random_seed {
+      self.context.set_random_seed(seed);
+    }
+    if let Some(policy) = self.options.undefined_policy {
+      self.context.set_undefined_policy(policy);
+    }
+    self.context.set_pure(self.options.pure);
+    #[cfg(feature = "regex")]
+    self.context.lock_patterns().clear();
+    self.tag_state.escape_values = self.options.escape_values;
+    self.rendered_nodes = self.options.source_map.then(|| vec![Vec::new()]);
+  }
+
+  /**
+   * Apply the document level formatting to the output of the document.
+   */
+  fn finish_render(&mut self, output: String) -> String {
+    let output = match self.options.format_version {
+      FormatVersion::V1 => output,
+      FormatVersion::V2 => utils::normalize_output_v2(&output),
+    };
+    let output = post_process::post_process(output, &self.options.post_process);
+    self.source_map = self
+      .rendered_nodes
+      .take()
+      .and_then(|mut nodes| nodes.pop()?.pop())
+      .map(|root| source_map::build_source_map(self.parser.buf, &root, &output));
+    output
+  }
+
+  /**
+   * Parse the document ahead of `render`, which then only renders the parsed document and
+   * can be called again, like a compiled template. It separates the time of parsing from
+   * the time of rendering, e.g. in benchmarks.
+   */
+  pub fn parse(&mut self) -> Result<()> {
+    if self.compiled.is_some() {
+      return Ok(());
+    }
+    self.parser.max_depth = self.options.limits.max_depth;
+    let root = self
+      .parser
+      .parse_as_node()
+      .map_err(|e| self.in_document_error(e))?;
+    self.compiled = Some(compiled::CompiledTemplate {
+      root: Arc::new(PomlNode::Tag(root)),
+      expressions: Arc::new(HashMap::new()),
+    });
+    Ok(())
+  }
+
+  /**
+   * Render the document into the sink, like a `String` to reuse its buffer. The top-level
+   * blocks are written as soon as they are rendered, unless the output is formatted as a
+   * whole, like by `FormatVersion::V2`, the post-processing or the source map, which
+   * render the whole output first.
+   */
+  pub fn render_to<W: std::fmt::Write + ?Sized>(&mut self, out: &mut W) -> Result<()> {
+    let filename = self.filename.clone();
+    self.render_streaming(&mut |chunk: &str| {
+      out
+        .write_str(chunk)
+        .map_err(|e| write_error(&filename, Box::new(e)))
+    })
+  }
+
+  /**
+   * Render the document into the writer, like a file or the standard output, writing the
+   * top-level blocks as soon as they are rendered like `render_to`.
+   */
+  pub fn render_to_writer<W: std::io::Write + ?Sized>(&mut self, out: &mut W) -> Result<()> {
+    let filename = self.filename.clone();
+    self.render_streaming(&mut |chunk: &str| {
+      out
+        .write_all(chunk.as_bytes())
+        .map_err(|e| write_error(&filename, Box::new(e)))
+    })
+  }
+
+  /**
+   * Render the document, passing the output to `write` block by block if it can be
+   * streamed, or as a whole otherwise.
+   */
+  fn render_streaming(&mut self, write: &mut dyn FnMut(&str) -> Result<()>) -> Result<()> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("poml_render", file = %self.filename).entered();
+    self.begin_render();
+    let root = self.document_root()?;
+    let PomlNode::Tag(tag_node) = root.as_ref() else {
+      unreachable!("the root of a document is a tag");
+    };
+    if !self.can_stream(tag_node) {
+      let output = self.render_root(&root)?;
+      return write(&self.finish_render(output));
+    }
+    self.progress.1 += count_nodes(tag_node);
+    let reordered = self
+      .check_pure(tag_node)
+      .and_then(|_| self.reorder_blocks(tag_node))
+      .map_err(|e| self.in_document_error(e))?;
+    self.stream_root(reordered.as_ref().unwrap_or(tag_node), write)
+  }
+
+  /**
+   * Whether the output of the document is the outputs of the top-level blocks as they are,
+   * which can be written one by one.
+   */
+  fn can_stream(&self, root: &PomlTagNode) -> bool {
+    let options = &self.options;
+    #[cfg(feature = "parallel")]
+    if self.render_in_parallel.is_some() {
+      return false;
+    }
+    options.format_version == FormatVersion::V1
+      && options.post_process.is_empty()
+      && !options.source_map
+      && self.diagnostics.is_none()
+      && !matches!(
+        options.white_space,
+        Some(WhiteSpaceMode::Filter) | Some(WhiteSpaceMode::Trim)
+      )
+      && root.name == "poml"
+      && root.attributes.is_empty()
+      && self.tag_renderer.is_root_concatenated()
+  }
+
+  /**
+   * Render the children of the root tag one by one, passing their outputs to `write`. The
+   * whitespace between them is left out like the root tag does. Errors of writing the
+   * outputs are returned as they are.
+   */
+  fn stream_root(
+    &mut self,
+    root: &PomlTagNode<'a>,
+    write: &mut dyn FnMut(&str) -> Result<()>,
+  ) -> Result<()> {
+    self
+      .check_limit(1, self.options.limits.max_depth, "nested tags")
+      .map_err(|e| self.in_document_error(e))?;
+    self.white_space_stack.push(self.current_white_space());
+    self.context.push_scope();
+    self.tag_depth += 1;
+    self.sibling_counts.push(HashMap::new());
+    let mut output_length = 0;
+    let mut out = String::new();
+    let mut result = Ok(());
+    for child in root.children.iter() {
+      out.clear();
+      result = self
+        .render_block(child, &mut out)
+        .and_then(|_| {
+          if child.is_whitespace() {
+            out.clear();
+          }
+          output_length += out.len();
+          let limit = self.options.limits.max_output_size;
+          self.check_limit(output_length, limit, "bytes of output")
+        })
+        .map_err(|e| self.document_error(e))
+        .and_then(|_| write(&out));
+      if result.is_err() {
+        break;
+      }
+    }
+    self.sibling_counts.pop();
+    self.tag_depth -= 1;
+    self.context.pop_scope();
+    self.white_space_stack.pop();
+    result?;
+    let pos = &root.original_pos;
+    self.record_trace(
+      || trace::TraceEventKind::Tag {
+        name: root.name.to_string(),
+        output_length,
+        data: Vec::new(),
+      },
+      Some((pos.start, pos.end)),
+    );
+    self.report_progress();
+    Ok(())
+  }
+
+  /**
+   * Steps of the last render explaining its output, if the trace mode is enabled by
+   * `RenderOptions::trace`.
+   */
+  pub fn trace(&self) -> Vec<trace::TraceEvent> {
+    self.trace.borrow().clone()
+  }
+
+  /**
+   * Record the step in the trace mode, located at the byte range of the document.
+   */
+  fn record_trace(
+    &self,
+    kind: impl FnOnce() -> trace::TraceEventKind,
+    span: Option<(usize, usize)>,
+  ) {
+    if !self.options.trace {
+      return;
+    }
+    self.trace.borrow_mut().push(trace::TraceEvent {
+      kind: kind(),
+      file: self.filename.clone(),
+      position: span.map(|(start, end)| ErrorPosition::from_span(self.parser.buf, start, end)),
+    });
+  }
+
+  /**
+   * Byte range of the part in the document, if it's a slice of the document like the
+   * attributes of the parsed nodes.
+   */
+  fn span_of(&self, part: &str) -> Option<(usize, usize)> {
+    crate::doc::offset_in(self.parser.buf, part).map(|offset| (offset, offset + part.len()))
+  }
+
+  /**
+   * Source map of the last render from the spans of the output to the nodes of the
+   * document, if it's enabled by `RenderOptions::source_map`.
+   */
+  pub fn source_map(&self) -> Option<&source_map::SourceMap> {
+    self.source_map.as_ref()
+  }
+
+  /**
+   * Check the document for errors. Unlike `render`, it keeps going after an error in a
+   * node by skipping the node, and returns the diagnostics of all the errors found.
+   * Errors of the document syntax and cancellation still stop the check.
+   */
+  pub fn check(&mut self) -> Vec<Diagnostic> {
+    self.diagnostics = Some(Vec::new());
+    let result = self.render();
+    let mut diagnostics = self.diagnostics.take().unwrap_or_default();
+    if let Err(e) = result {
+      diagnostics.push(e.to_diagnostic());
+    }
+    diagnostics.extend(self.warnings());
+    diagnostics
+  }
+
+  /**
+   * Warnings of the last render, like the references to undefined variables under
+   * [`UndefinedPolicy::Warn`].
+   */
+  pub fn warnings(&self) -> Vec<Diagnostic> {
+    self.warnings.borrow().clone()
+  }
+
+  /**
+   * Render the document without the document level formatting, which is also used
+   * to render included documents.
+   */
+  fn render_document(&mut self) -> Result<String> {
+    let root = self.document_root()?;
+    self.render_root(&root)
+  }
+
+  /**
+   * The root of the parsed document, or of the document parsed now if it's not parsed
+   * ahead. A parsed document is rendered from its shared tree, without copying it.
+   */
+  fn document_root(&mut self) -> Result<Arc<PomlNode<'a>>> {
+    self.parser.max_depth = self.options.limits.max_depth;
+    match &self.compiled {
+      Some(compiled) => Ok(compiled.root.clone()),
+      None => match self.parser.parse_as_node() {
+        Ok(node) => Ok(Arc::new(PomlNode::Tag(node))),
+        Err(e) => Err(self.in_document_error(e)),
+      },
+    }
+  }
+
+  fn render_root(&mut self, root: &Arc<PomlNode<'a>>) -> Result<String> {
+    let PomlNode::Tag(tag_node) = root.as_ref() else {
+      unreachable!("the root of a document is a tag");
+    };
+    self.progress.1 += count_nodes(tag_node);
+    let mut out = String::new();
+    let result = self
+      .check_pure(tag_node)
+      .and_then(|_| self.reorder_blocks(tag_node))
+      .and_then(|reordered| match reordered {
+        Some(reordered) => self.render_into(&PomlNode::Tag(reordered), &mut out),
+        None => self.render_into(root, &mut out),
+      });
+    result.map(|_| out).map_err(|e| self.document_error(e))
+  }
+
+  /**
+   * Wrap the error of rendering the document with its filename, except the cancellation.
+   */
+  fn document_error(&self, e: Error) -> Error {
+    match e.kind {
+      ErrorKind::Cancelled => e,
+      _ => self.in_document_error(e),
+    }
+  }
+
+  /**
+   * Wrap the error of parsing or rendering the document with its filename.
+   */
+  fn in_document_error(&self, e: Error) -> Error {
+    Error {
+      kind: ErrorKind::RendererError,
+      message: format!("Error in render file {}", self.filename),
+      source: Some(Box::new(e)),
+      position: None,
+      code: None,
+    }
+  }
+
+  /**
+   * Fail the render if the count of the resource exceeds its limit in
+   * `RenderOptions::limits`.
+   */
+  fn check_limit(&self, count: usize, limit: usize, resource: &str) -> Result<()> {
+    if count <= limit {
+      return Ok(());
+    }
+    Err(Error {
+      kind: ErrorKind::RendererError,
+      message: format!(
+        "Render of {} exceeds the limit of {limit} {resource}",
+        self.filename
+      ),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::LimitExceeded),
+    })
+  }
+
+  /**
+   * In pure mode, reject the constructs that require IO or have external effects.
+   */
+  fn check_pure(&self, tag_node: &PomlTagNode) -> Result<()> {
+    if !self.options.pure {
+      return Ok(());
+    }
+    let impure_construct = if tag_node.name == "include" {
+      Some("<include>".to_string())
+    } else if tag_node.name == "summary" && self.options.summarizer.is_some() {
+      Some("<summary> with a summarizer".to_string())
+    } else {
+      tag_node
+        .attributes
+        .iter()
+        .find(|(key, _)| *key == "src")
+        .map(|_| format!("`src` attribute of <{}>", tag_node.name))
+    };
+    if let Some(construct) = impure_construct {
+      let pos = &tag_node.original_pos;
+      return Err(
+        Error {
+          kind: ErrorKind::RendererError,
+          message: format!("{construct} is not allowed in pure mode"),
+          source: None,
+          position: None,
+          code: Some(ErrorCode::AccessDenied),
+        }
+        .with_span(self.parser.buf, pos.start, pos.end),
+      );
+    }
+    for child in tag_node.children.iter() {
+      if let PomlNode::Tag(child_tag) = child {
+        self.check_pure(child_tag)?;
+      }
+    }
+    Ok(())
+  }
+
+  /**
+   * Reorder the top-level blocks of the document by their `order` attribute and
+   * the `block_order` option. Text and whitespace move together with the block before them.
+   * Returns a copy of the root with the reordered children, or `None` if the order is
+   * unchanged.
+   */
+  fn reorder_blocks(&self, root: &PomlTagNode<'a>) -> Result<Option<PomlTagNode<'a>>> {
+    let has_order = root.children.iter().any(|child| match child {
+      PomlNode::Tag(tag) => {
+        self.options.block_order.contains_key(tag.name)
+          || tag.attributes.iter().any(|(key, _)| *key == "order")
+      }
+      _ => false,
+    });
+    if !has_order {
+      return Ok(None);
+    }
+
+    let mut segments: Vec<(i64, Vec<&PomlNode<'a>>)> = Vec::new();
+    for child in root.children.iter() {
+      match child {
+        PomlNode::Tag(tag) => {
+          let order = self.get_block_order(tag)?;
+          segments.push((order, vec![child]));
+        }
+        _ => match segments.last_mut() {
+          Some((_, nodes)) => nodes.push(child),
+          None => segments.push((0, vec![child])),
+        },
+      }
+    }
+    if segments.is_sorted_by_key(|(order, _)| *order) {
+      return Ok(None);
+    }
+    segments.sort_by_key(|(order, _)| *order);
+    Ok(Some(PomlTagNode {
+      name: root.name,
+      attributes: root.attributes.clone(),
+      children: segments
+        .into_iter()
+        .flat_map(|(_, nodes)| nodes.into_iter().cloned())
+        .collect(),
+      original_pos: root.original_pos.clone(),
+    }))
+  }
+
+  fn get_block_order(&self, tag: &PomlTagNode) -> Result<i64> {
+    let Some((_, value)) = tag.attributes.iter().find(|(key, _)| *key == "order") else {
+      return Ok(self.options.block_order.get(tag.name).copied().unwrap_or(0));
+    };
+    let value = self.render_attribute_value(tag.name, "order", value)?;
+    let order = match &value {
+      Value::Number(n) => n.as_i64(),
+      Value::String(s) => s.trim().parse::<i64>().ok(),
+      _ => None,
+    };
+    order.ok_or_else(|| Error {
+      kind: ErrorKind::RendererError,
+      message: format!("Invalid `order` attribute value: {value}"),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::InvalidAttributeValue),
+    })
+  }
+
+  /** Set POML filename for error reporting purpose */
+  pub fn set_filename(&mut self, filename: &str) {
+    self.filename = filename.to_string();
+  }
+
+  /**
+   * Set the path of the POML document, which is used for error reporting and
+   * to resolve the relative paths of `src` attributes.
+   */
+  pub fn set_document_path(&mut self, path: &str) {
+    self.set_filename(path);
+    let base_path = std::path::Path::new(path)
+      .parent()
+      .unwrap_or(std::path::Path::new(""));
+    self.context.set_base_path(base_path);
+  }
+
+  /**
+   * Render the node by appending its output to the buffer. Nodes failing to render leave
+   * the buffer as it was.
+   */
+  pub(crate) fn render_into(&mut self, node: &PomlNode<'a>, out: &mut String) -> Result<()> {
+    if let Some(token) = &self.options.cancellation_token
+      && token.is_cancelled()
+    {
+      return Err(Error {
+        kind: ErrorKind::Cancelled,
+        message: format!("Render of {} is cancelled", self.filename),
+        source: None,
+        position: None,
+        code: None,
+      });
+    }
+    #[cfg(feature = "tracing")]
+    let _span = match node {
+      PomlNode::Tag(tag) => Some(tracing::debug_span!("poml_tag", tag = tag.name).entered()),
+      _ => None,
+    };
+    if let Some(rendered_nodes) = &mut self.rendered_nodes {
+      rendered_nodes.push(Vec::new());
+    }
+    let pos = match node {
+      PomlNode::Tag(tag) => &tag.original_pos,
+      PomlNode::Text(_, pos) | PomlNode::Whitespace(pos) | PomlNode::CData(_, pos) => pos,
+    };
+    let start = out.len();
+    let mut data_attributes = Vec::new();
+    let result = self
+      .render_node(node, out)
+      .and_then(|data| {
+        data_attributes = data;
+        let limit = self.options.limits.max_output_size;
+        self.check_limit(out.len(), limit, "bytes of output")
+      })
+      .map_err(|e| e.with_span(self.parser.buf, pos.start, pos.end));
+    if result.is_err() {
+      out.truncate(start);
+    }
+    if let (PomlNode::Tag(tag), Ok(())) = (node, &result) {
+      self.record_trace(
+        || trace::TraceEventKind::Tag {
+          name: tag.name.to_string(),
+          output_length: out.len() - start,
+          data: data_attributes.clone(),
+        },
+        Some((pos.start, pos.end)),
+      );
+    }
+    if let Some(rendered_nodes) = &mut self.rendered_nodes {
+      let children = rendered_nodes.pop().unwrap_or_default();
+      if let (Ok(()), Some(siblings)) = (&result, rendered_nodes.last_mut()) {
+        siblings.push(source_map::RenderedNode {
+          source: Span {
+            start: pos.start,
+            end: pos.end,
+          },
+          tag: match node {
+            PomlNode::Tag(tag) => Some(tag.name.to_string()),
+            _ => None,
+          },
+          output: out[start..].to_string(),
+          data: data_attributes,
+          children,
+        });
+      }
+    }
+    let result = match (result, &mut self.diagnostics) {
+      (Err(e), Some(diagnostics)) if !matches!(e.kind, ErrorKind::Cancelled) => {
+        diagnostics.push(e.to_diagnostic());
+        Ok(())
+      }
+      (result, _) => result,
+    };
+    self.report_progress();
+    result
+  }
+
+  /**
+   * Count a rendered node in the progress, and report the progress to the callback.
+   */
+  fn report_progress(&mut self) {
+    self.progress.0 += 1;
+    self.progress.1 = self.progress.1.max(self.progress.0);
+    if let Some(on_progress) = &self.options.on_progress {
+      on_progress.on_progress(self.progress.0, self.progress.1);
+    }
+  }
+
+  /**
+   * Render a child node. In the token count debug mode, the top-level blocks are
+   * annotated with their token counts.
+   */
+  fn render_block(&mut self, node: &PomlNode<'a>, out: &mut String) -> Result<()> {
+    let start = out.len();
+    self.render_into(node, out)?;
+    if let PomlNode::Tag(tag_node) = node
+      && self.options.annotate_token_counts
+      && self.tag_depth == 1
+      && out.len() > start
+    {
+      let token_count = self.options.token_counter.count_tokens(&out[start..]);
+      // The annotation is on its own line, so it can't break a code fence before it
+      if !out.ends_with('\n') {
+        out.push('\n');
+      }
+      out.push_str(&format!(
+        "<!-- {}: {token_count} tokens -->\n\n",
+        tag_node.name
+      ));
+    }
+    Ok(())
+  }
+
+  /**
+   * Render the node into the buffer. Return the `data-*` metadata attributes of a tag, if
+   * the trace or the source map is recorded.
+   */
+  fn render_node(&mut self, node: &PomlNode<'a>, out: &mut String) -> Result<Vec<(String, Value)>> {
+    match node {
+      PomlNode::Tag(tag_node) => {
+        let mut attribute_values: Vec<(String, Value)> = Vec::new();
+        let mut for_loop_attribute: Option<Cow<str>> = None;
+        let mut if_attribute_evaluated_as_false = false;
+        let mut if_present_binding: Option<(String, Value)> = None;
+        // With a `for` attribute, `if` is evaluated per iteration with the loop item in scope.
+        let has_for_attribute = tag_node.attributes.iter().any(|(key, _)| *key == "for");
+        let mut for_loop_condition: Option<Cow<str>> = None;
+        for (key, attribute_value) in tag_node.attributes.iter() {
+          let value_str = attribute_value.text();
+          if key == &"ifPresent" {
+            let binding = self.evaluate_if_present(&utils::decode_xml_entities(value_str))?;
+            self.record_trace(
+              || trace::TraceEventKind::Condition {
+                tag: tag_node.name.to_string(),
+                expression: value_str.to_string(),
+                value: binding.is_some(),
+              },
+              self.span_of(value_str),
+            );
+            match binding {
+              Some(binding) => if_present_binding = binding,
+              None => return Ok(Vec::new()),
+            }
+            continue;
+          }
+          if key == &"for" {
+            // `for` attribute should be handled in a special way.
+            for_loop_attribute = Some(utils::decode_xml_entities(value_str));
+            continue;
+          }
+          if key == &"if" && has_for_attribute {
+            for_loop_condition = Some(utils::decode_xml_entities(value_str));
+            continue;
+          }
+          let value = self.render_attribute_value(tag_node.name, key, attribute_value)?;
+          if key == &"if" {
+            if_attribute_evaluated_as_false = !attr::is_truthy(&value);
+            self.record_trace(
+              || trace::TraceEventKind::Condition {
+                tag: tag_node.name.to_string(),
+                expression: value_str.to_string(),
+                value: !if_attribute_evaluated_as_false,
+              },
+              self.span_of(value_str),
+            );
+          }
+          attribute_values.push((key.to_string(), value));
+        }
+        if if_attribute_evaluated_as_false {
+          return Ok(Vec::new());
+        }
+        if !self.options.stylesheet.is_empty() {
+          self
+            .options
+            .stylesheet
+            .apply(tag_node.name, &mut attribute_values);
+        }
+        let data_attributes = if self.options.trace || self.rendered_nodes.is_some() {
+          attr::get_data_attributes(&attribute_values)
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+        } else {
+          Vec::new()
+        };
+
+        let result = match if_present_binding {
+          Some((name, value)) => {
+            self.check_not_constant(&name)?;
+            self.context.push_scope();
+            self.context.set_value(&name, value);
+            let result = self.process_tag_node(
+              tag_node,
+              for_loop_attribute.as_deref(),
+              for_loop_condition.as_deref(),
+              attribute_values,
+              out,
+            );
+            self.context.pop_scope();
+            result
+          }
+          None => self.process_tag_node(
+            tag_node,
+            for_loop_attribute.as_deref(),
+            for_loop_condition.as_deref(),
+            attribute_values,
+            out,
+          ),
+        };
+        result.map(|_| data_attributes)
+      }
+      PomlNode::Text(text, pos) => {
+        out.push_str(&self.render_text_content(
+          text,
+          Some(pos.start),
+          self.options.auto_fence_code,
+          // Backslash escapes are shown as is in code
+          self.options.escape_values && self.code_depth == 0,
+        )?);
+        Ok(Vec::new())
+      }
+      PomlNode::CData(text, _) => {
+        out.push_str(text);
+        Ok(Vec::new())
+      }
+      PomlNode::Whitespace(pos) => {
+        match self.current_white_space() {
+          Some(WhiteSpaceMode::Pre) | Some(WhiteSpaceMode::Trim) => {
+            out.push_str(str::from_utf8(&self.parser.buf[pos.start..pos.end]).unwrap())
+          }
+          Some(WhiteSpaceMode::Filter) | None => out.push(' '),
+        }
+        Ok(Vec::new())
+      }
+    }
+  }
+
+  /**
+   * Evaluate the expression in the current context, with the syntax tree of the compiled
+   * template if the expression was parsed at compile time. The results are memoized
+   * until the variables of the context change.
+   */
+  fn evaluate(&self, expression: &str) -> Result<Value> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("poml_expression", expression).entered();
+    let generation = self.context.generation();
+    let mut cache = self.expression_cache.borrow_mut();
+    if cache.0 != generation {
+      *cache = (generation, HashMap::new());
+    } else if let Some(value) = cache.1.get(expression) {
+      self.trace_expression(expression, value);
+      return Ok(value.clone());
+    }
+    let random_state = self.context.random_state();
+    let value = match self
+      .compiled
+      .as_ref()
+      .and_then(|compiled| compiled.expressions.get(expression))
+    {
+      Some(parsed) => parsed.evaluate(&self.context),
+      None => self
+        .parse_expression(expression)
+        .and_then(|parsed| parsed.evaluate(&self.context)),
+    }
+    .map_err(|e| e.in_document(self.parser.buf, expression));
+    self.collect_warnings(expression);
+    let value = value?;
+    // Expressions drawing random numbers, like `shuffle(examples)`, are not memoized
+    if self.context.random_state() == random_state {
+      cache.1.insert(expression.to_string(), value.clone());
+    }
+    self.trace_expression(expression, &value);
+    Ok(value)
+  }
+
+  fn trace_expression(&self, expression: &str, value: &Value) {
+    self.record_trace(
+      || trace::TraceEventKind::Expression {
+        expression: expression.to_string(),
+        value: value.clone(),
+      },
+      self.span_of(expression),
+    );
+  }
+
+  /**
+   * Move the warnings recorded in the context by evaluating the expression to the
+   * renderer, located in the document.
+   */
+  fn collect_warnings(&self, expression: &str) {
+    if self.context.undefined_policy() != UndefinedPolicy::Warn {
+      return;
+    }
+    let warnings = self
+      .context
+      .take_warnings()
+      .into_iter()
+      .map(|warning| Diagnostic {
+        severity: Severity::Warning,
+        ..warning
+          .in_document(self.parser.buf, expression)
+          .to_diagnostic()
+      });
+    self.warnings.borrow_mut().extend(warnings);
+  }
+
+  /**
+   * Parse the expression, or reuse its syntax tree if it was parsed before.
+   */
+  fn parse_expression(&self, expression: &str) -> Result<Arc<Expression>> {
+    if let Some(parsed) = self.parsed_expressions.borrow().get(expression) {
+      return Ok(parsed.clone());
+    }
+    let parsed = Arc::new(Expression::parse(expression)?);
+    self
+      .parsed_expressions
+      .borrow_mut()
+      .insert(expression.to_string(), parsed.clone());
+    Ok(parsed)
+  }
+
+  /**
+   * Evaluate the expression of a `{{ }}` interpolation, and apply its filters like
+   * `{{ name | upper }}` in order. Return the value, and whether the `raw` filter is found.
+   */
+  fn evaluate_interpolation(&self, expression: &str) -> Result<(Value, bool)> {
+    let Some((expression, filters)) = filter::split_filters(expression) else {
+      return Ok((self.evaluate(expression)?, false));
+    };
+    let mut value = self.evaluate(expression)?;
+    let mut raw = false;
+    for call in filters {
+      raw = raw || call.name == "raw";
+      let arguments = match call.arguments {
+        Some(arguments) => match self.evaluate(&format!("[{arguments}]"))? {
+          Value::Array(arguments) => arguments,
+          _ => Vec::new(),
+        },
+        None => Vec::new(),
+      };
+      value = filter::apply_filter(call.name, value, &arguments, &self.context)?;
+    }
+    Ok((value, raw))
+  }
+
+  /**
+   * Render an attribute value into a typed value.
+   *
+   * Expression attributes like `if` and the `value` of `<let>` are evaluated as expressions.
+   * Other attributes consisting of a single `{{ }}` expression evaluate to the value of the
+   * expression, and the rest are rendered as text. Character references like `&lt;` are
+   * decoded before the expressions are evaluated.
+   */
+  fn render_attribute_value(
+    &self,
+    tag_name: &str,
+    key: &str,
+    value: &AttributeValue,
+  ) -> Result<Value> {
+    if key == "if" || is_attribute_evaluated_as_expression(tag_name, key) {
+      return self.evaluate(&utils::decode_xml_entities(value.text()));
+    }
+    if self.raw_depth == 0
+      && let Some(expression) = value.expression()
+    {
+      return Ok(
+        self
+          .evaluate_interpolation(&utils::decode_xml_entities(expression))?
+          .0,
+      );
+    }
+    Ok(Value::String(self.render_text(value.text())?))
+  }
+
+  fn current_white_space(&self) -> Option<WhiteSpaceMode> {
+    match self.white_space_stack.last() {
+      Some(mode) => *mode,
+      None => self.options.white_space,
+    }
+  }
+
+  /**
+   * Read the `whiteSpace` attribute. Tags without the attribute inherit the mode of the parent,
+   * except `<pre>` and `<code>` which preserve whitespace by default.
+   */
+  fn get_white_space_mode(
+    &self,
+    tag_node: &PomlTagNode,
+    attribute_values: &[(String, Value)],
+  ) -> Result<Option<WhiteSpaceMode>> {
+    let Some((_, value)) = attribute_values.iter().find(|v| v.0 == "whiteSpace") else {
+      if tag_node.name == "pre" || tag_node.name == "code" {
+        return Ok(Some(WhiteSpaceMode::Pre));
+      }
+      return Ok(self.current_white_space());
+    };
+    match value
+      .as_str()
+      .and_then(WhiteSpaceMode::from_attribute_value)
+    {
+      Some(mode) => Ok(Some(mode)),
+      None => Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Unknown `whiteSpace` attribute value: {value}"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::InvalidAttributeValue),
+      }),
+    }
+  }
+
+  /**
+   * Process the tag node after the `if` attributes are evaluated. If the node has a `for`
+   * attribute, its `if` attribute is passed as `for_loop_condition` and evaluated per iteration.
+   */
+  fn process_tag_node(
+    &mut self,
+    tag_node: &PomlTagNode<'a>,
+    for_loop_attribute: Option<&str>,
+    for_loop_condition: Option<&str>,
+    attribute_values: Vec<(String, Value)>,
+    out: &mut String,
+  ) -> Result<()> {
+    // Process for loop
+    if let Some(for_loop_instruction) = for_loop_attribute {
+      let (for_item_name, for_range_expression) = self.parse_for_loop(for_loop_instruction)?;
+      let for_range_value = for_range_expression
+        .evaluate(&self.context)
+        .map_err(|e| e.in_document(self.parser.buf, for_loop_instruction));
+      self.collect_warnings(for_loop_instruction);
+      let for_range_value = for_range_value?;
+      self.trace_expression(for_loop_instruction, &for_range_value);
+      let Value::Array(for_range) = for_range_value else {
+        return Err(Error {
+          kind: ErrorKind::RendererError,
+          message: format!("For loop range is not an array: {for_loop_instruction}",),
+          source: None,
+          position: None,
+          code: Some(ErrorCode::TypeMismatch),
+        });
+      };
+
+      self.record_trace(
+        || trace::TraceEventKind::Loop {
+          tag: tag_node.name.to_string(),
+          expression: for_loop_instruction.to_string(),
+          iterations: for_range.len(),
+        },
+        self.span_of(for_loop_instruction),
+      );
+      self.check_not_constant(&for_item_name)?;
+      self.context.push_scope();
+      let result = self.process_loop_iterations(
+        tag_node,
+        &for_item_name,
+        &for_range,
+        for_loop_condition,
+        attribute_values,
+        out,
+      );
+      self.context.pop_scope();
+      result
+    } else {
+      self.process_tag_node_without_for(tag_node, attribute_values, out)
+    }
+  }
+
+  /**
+   * Render the tag once for each item of the loop, in the scope of the loop variables.
+   */
+  fn process_loop_iterations(
+    &mut self,
+    tag_node: &PomlTagNode<'a>,
+    for_item_name: &str,
+    for_range: &[Value],
+    for_loop_condition: Option<&str>,
+    attribute_values: Vec<(String, Value)>,
+    out: &mut String,
+  ) -> Result<()> {
+    for (item_idx, item_value) in for_range.iter().enumerate() {
+      self.loop_iterations += 1;
+      let limit = self.options.limits.max_loop_iterations;
+      self.check_limit(self.loop_iterations, limit, "loop iterations")?;
+      self.context.set_value(for_item_name, item_value.clone());
+      let loop_variable = json!({
+          "index": item_idx,
+          "length": for_range.len(),
+          "first": item_idx == 0,
+          "last": item_idx + 1 == for_range.len()
+      });
+      self.context.set_value("loop", loop_variable);
+      if let Some(condition) = for_loop_condition {
+        let value = attr::is_truthy(&self.evaluate(condition)?);
+        self.record_trace(
+          || trace::TraceEventKind::Condition {
+            tag: tag_node.name.to_string(),
+            expression: condition.to_string(),
+            value,
+          },
+          self.span_of(condition),
+        );
+        if !value {
+          continue;
+        }
+      }
+      self.process_tag_node_without_for(tag_node, attribute_values.clone(), out)?;
+    }
+    Ok(())
+  }
+
+  /**
+   * Parse the `for` attribute like `item in items` into the item name and the expression of
+   * the range, or reuse them if the attribute was parsed before.
+   */
+  fn parse_for_loop(&self, instruction: &str) -> Result<(String, Arc<Expression>)> {
+    if let Some(parsed) = self.parsed_for_loops.borrow().get(instruction) {
+      return Ok(parsed.clone());
+    }
+    let (tokens, spans) = expression::tokenize::tokenize_expression_spans(instruction.as_bytes())
+      .map_err(|e| e.in_document(self.parser.buf, instruction))?;
+    if tokens.len() < 3 || tokens[1] != ExpressionToken::ArithOp(b"in") {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Invalid for-loop attribute value: {instruction}"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::InvalidAttributeValue),
+      });
+    }
+    let ExpressionToken::Ref(item_name) = tokens[0] else {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Invalid valid as for-loop item: {instruction}"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::InvalidAttributeValue),
+      });
+    };
+    let parsed = (
+      str::from_utf8(item_name).unwrap().to_string(),
+      Arc::new(
+        Expression::from_tokens(instruction, &tokens[2..], &spans[2..])
+          .map_err(|e| e.in_document(self.parser.buf, instruction))?,
+      ),
+    );
+    self
+      .parsed_for_loops
+      .borrow_mut()
+      .insert(instruction.to_string(), parsed.clone());
+    Ok(parsed)
+  }
+
+  /**
+   * Evaluate the `ifPresent` attribute, whose value is an expression optionally
+   * followed by `as name`.
+   *
+   * Return `None` if the value is missing or null, which means the node should be
+   * hidden. Otherwise return the name to bind the value, if there is one.
+   */
+  fn evaluate_if_present(&self, attribute_value: &str) -> Result<Option<Option<(String, Value)>>> {
+    let tokens = expression::tokenize::tokenize_expression(attribute_value.as_bytes())?;
+    let (expression_tokens, name) = match tokens.as_slice() {
+      [
+        rest @ ..,
+        ExpressionToken::Ref(b"as"),
+        ExpressionToken::Ref(name),
+      ] if !rest.is_empty() => (rest, Some(str::from_utf8(name).unwrap().to_owned())),
+      _ => (tokens.as_slice(), None),
+    };
+    // A missing variable hides the node, whatever the undefined policy is
+    if self.is_path_absent(expression_tokens) {
+      return Ok(None);
+    }
+    let value = expression::evaluate::evaluate_expression_tokens(expression_tokens, &self.context);
+    self.collect_warnings(attribute_value);
+    let value = value?;
+    match value {
+      Value::Null => Ok(None),
+      _ => Ok(Some(name.map(|name| (name, value)))),
+    }
+  }
+
+  /**
+   * Whether the tokens are a path like `user.profile['city']` or `items[0]` accessed
+   * through a missing or null value. Other expressions are left to the evaluator.
+   */
+  fn is_path_absent(&self, tokens: &[ExpressionToken]) -> bool {
+    let [ExpressionToken::Ref(variable), rest @ ..] = tokens else {
+      return false;
+    };
+    let mut rest = rest;
+    let Some(mut value) = self.context.get_value(str::from_utf8(variable).unwrap()) else {
+      return true;
+    };
+    loop {
+      if value.is_null() {
+        return true;
+      }
+      let (next, tail) = match (rest, value) {
+        ([], _) => return false,
+        ([ExpressionToken::Dot, ExpressionToken::Ref(key), tail @ ..], Value::Object(object)) => {
+          (object.get(str::from_utf8(key).unwrap()), tail)
+        }
+        (
+          [
+            ExpressionToken::LeftBracket,
+            ExpressionToken::String(key),
+            ExpressionToken::RightBracket,
+            tail @ ..,
+          ],
+          Value::Object(object),
+        ) => (
+          object.get(str::from_utf8(&key[1..key.len() - 1]).unwrap()),
+          tail,
+        ),
+        (
+          [
+            ExpressionToken::LeftBracket,
+            ExpressionToken::Number(index),
+            ExpressionToken::RightBracket,
+            tail @ ..,
+          ],
+          Value::Array(array),
+        ) => match str::from_utf8(index).unwrap().parse::<usize>() {
+          Ok(index) => (array.get(index), tail),
+          Err(_) => return false,
+        },
+        _ => return false,
+      };
+      match next {
+        Some(next) => value = next,
+        None => return true,
+      }
+      rest = tail;
+    }
+  }
+
+  /**
+   * The loop attribute `for` should be processed before this function is called.
+   */
+  fn process_tag_node_without_for(
+    &mut self,
+    tag_node: &PomlTagNode<'a>,
+    attribute_values: Vec<(String, Value)>,
+    out: &mut String,
+  ) -> Result<()> {
+    if tag_node.name == "template" {
+      return self.process_template_definition(tag_node, &attribute_values);
+    }
+    if let Some(template) = self.templates.get(tag_node.name).cloned() {
+      return self.process_template_instance(tag_node.name, template, attribute_values, out);
+    }
+
+    let sibling_index = match self.sibling_counts.last_mut() {
+      Some(counts) => {
+        let count = counts.entry(tag_node.name).or_insert(0);
+        *count += 1;
+        *count - 1
+      }
+      None => 0,
+    };
+
+    let mut children = ChildrenOutput {
+      start: out.len(),
+      ends: Vec::new(),
+    };
+    if !tag_node.children.is_empty() {
+      self.check_limit(
+        self.tag_depth + 1,
+        self.options.limits.max_depth,
+        "nested tags",
+      )?;
+      let white_space = self.get_white_space_mode(tag_node, &attribute_values)?;
+      let raw = is_raw_tag(tag_node, &attribute_values);
+      self.white_space_stack.push(white_space);
+      if raw {
+        self.raw_depth += 1;
+      }
+      let code = tag_node.name == "code";
+      if code {
+        self.code_depth += 1;
+      }
+      let parent_state = self.tag_state.clone();
+      if self
+        .tag_renderer
+        .is_heading_scope(tag_node.name, &attribute_values)
+      {
+        self.tag_state.heading_level += 1;
+      }
+      let list_item_indent = self
+        .tag_renderer
+        .list_item_indent(tag_node.name, &attribute_values);
+      if list_item_indent > 0 {
+        self.tag_state.list_depth += 1;
+        self.tag_state.list_indent += list_item_indent;
+        self.tag_state.list_style =
+          attr::get_text_attribute(&attribute_values, "listStyle").map(|s| s.into_owned());
+      }
+      let scope_attributes = self
+        .tag_renderer
+        .scope_attributes(tag_node.name, &attribute_values);
+      self.tag_state.scope_attributes.extend(scope_attributes);
+      self.context.push_scope();
+      self.tag_depth += 1;
+      self.sibling_counts.push(HashMap::new());
+      let children_result_or_err = self.render_children(&tag_node.children, &mut children, out);
+      self.sibling_counts.pop();
+      self.tag_depth -= 1;
+      self.context.pop_scope();
+      self.tag_state = parent_state;
+      if raw {
+        self.raw_depth -= 1;
+      }
+      if code {
+        self.code_depth -= 1;
+      }
+      self.white_space_stack.pop();
+      if let Err(e) = children_result_or_err {
+        out.truncate(children.start);
+        return Err(e);
+      }
+      if matches!(
+        white_space,
+        Some(WhiteSpaceMode::Filter) | Some(WhiteSpaceMode::Trim)
+      ) {
+        trim_children_output(out, &mut children);
+      }
+    }
+    self.tag_state.sibling_index = sibling_index;
+
+    let output = if tag_node.name == "let" {
+      let children_result = children.take(out);
+      self.process_let_node(attribute_values, children_result)?
+    } else if tag_node.name == "include" {
+      out.truncate(children.start);
+      self.process_include_node(attribute_values)?
+    } else if tag_node.name == "meta" && attr::get_attribute(&attribute_values, "const").is_some() {
+      out.truncate(children.start);
+      self.process_meta_const(&attribute_values)?
+    } else if tag_node.name == "summary" {
+      let children_result = children.take(out);
+      self.process_summary_node(attribute_values, children_result)?
+    } else if options::CONVERSATION_STATE_TAGS.contains(&tag_node.name) {
+      out.truncate(children.start);
+      self.process_conversation_state_node(tag_node.name)
+    } else {
+      let token_limit = self.get_token_limit(&attribute_values)?;
+      self.render_tag_with_cache(tag_node, &attribute_values, &children, out)?;
+      if let Some(limit) = token_limit {
+        // Only the content is truncated, and the separator after it is kept.
+        let output = &out[children.start..];
+        let content_len = output.trim_end().len();
+        let truncated_len = self
+          .options
+          .token_counter
+          .truncate(&output[..content_len], limit)
+          .len();
+        if truncated_len < content_len {
+          let separator = output[content_len..].to_owned();
+          out.truncate(children.start + truncated_len);
+          out.push_str(&separator);
+        }
+      }
+      return Ok(());
+    };
+    out.push_str(&output);
+    Ok(())
+  }
+
   /**
-   * Render the provided POML source code into the desired format.
+   * Render the children of a tag one after another into the buffer, recording their ends.
+   * The children of the root tag are rendered in parallel by `render_parallel` if they are
+   * independent.
    */
-  pub fn render(&mut self) -> Result<String> {
-    let node = match self.parser.parse_as_node() {
-      Ok(n) => n,
-      Err(e) => {
-        return Err(Error {
-          kind: ErrorKind::RendererError,
-          message: format!("Error in render file {}", self.filename),
-          source: Some(Box::new(e)),
-        });
+  fn render_children(
+    &mut self,
+    nodes: &[PomlNode<'a>],
+    children: &mut ChildrenOutput,
+    out: &mut String,
+  ) -> Result<()> {
+    #[cfg(feature = "parallel")]
+    if let Some(render_in_parallel) = self.render_in_parallel
+      && self.tag_depth == 1
+      && let Some(outputs) = render_in_parallel(self, nodes)
+    {
+      for output in outputs? {
+        out.push_str(&output);
+        children.ends.push(out.len());
       }
-    };
-    match self.render_impl(&PomlNode::Tag(node)) {
-      Ok(s) => Ok(s),
-      Err(e) => Err(Error {
-        kind: ErrorKind::RendererError,
-        message: format!("Error in render file {}", self.filename),
-        source: Some(Box::new(e)),
-      }),
+      return Ok(());
     }
+    for child in nodes.iter() {
+      self.render_block(child, out)?;
+      children.ends.push(out.len());
+    }
+    Ok(())
   }
 
-  /** Set POML filename for error reporting purpose */
-  pub fn set_filename(&mut self, filename: &str) {
-    self.filename = filename.to_string();
-  }
-
-  pub(crate) fn render_impl(&mut self, node: &PomlNode) -> Result<String> {
-    match node {
-      PomlNode::Tag(tag_node) => {
-        let mut attribute_values: Vec<(String, Value)> = Vec::new();
-        let mut for_loop_attribute: Option<&str> = None;
-        let mut if_attribute_present = false;
-        let mut if_attribute_evaluated_as_false = false;
-        for (key, value_raw) in tag_node.attributes.iter() {
-          if key == &"if" {
-            if_attribute_present = true;
-            // `if` attribute should be recognized as an expression.
-            let if_attribute_value = self.context.evaluate(&value_raw[1..value_raw.len() - 1])?;
-            if_attribute_evaluated_as_false =
-              expression::utils::is_false_json_value(&if_attribute_value);
-          }
-          if key == &"for" {
-            // `for` attribute should be handled in a special way.
-            for_loop_attribute = Some(&value_raw[1..value_raw.len() - 1]);
-          } else if is_attribute_evaluated_as_expression(tag_node.name, key) {
-            // Special treatment for `value` attribute in `<let>` tag
-            // This attribute should be recognized as an expression instead of string.
-            let value = self.context.evaluate(&value_raw[1..value_raw.len() - 1])?;
-            attribute_values.push((key.to_string(), value));
-          } else {
-            // By default, the attribute should be recognize as text
-            let value = self.render_text(&value_raw[1..value_raw.len() - 1])?;
-            attribute_values.push((key.to_string(), Value::String(value)));
+  /**
+   * Render the tag with the tag renderer, or by the unknown tag policy if the tag is not
+   * supported by it. Outputs of the tags in [`CACHEABLE_TAGS`] only depend on the
+   * attributes, so they are cached by the attribute values.
+   */
+  fn render_tag_with_cache(
+    &mut self,
+    tag_node: &PomlTagNode,
+    attribute_values: &[(String, Value)],
+    children: &ChildrenOutput,
+    out: &mut String,
+  ) -> Result<()> {
+    let cache_key = match &self.options.cache {
+      Some(_) if CACHEABLE_TAGS.contains(&tag_node.name) => {
+        // Metadata attributes don't change the output, so they are not part of the key.
+        let key_attributes: Vec<&(String, Value)> = attribute_values
+          .iter()
+          .filter(|(key, _)| !key.starts_with(attr::DATA_ATTRIBUTE_PREFIX))
+          .collect();
+        Some(format!(
+          "tag:{}:{}",
+          tag_node.name,
+          serde_json::to_string(&key_attributes).unwrap()
+        ))
+      }
+      _ => None,
+    };
+    if let (Some(cache), Some(key)) = (&self.options.cache, &cache_key)
+      && let Some(output) = cache.get(key)
+    {
+      out.truncate(children.start);
+      out.push_str(&output);
+      return Ok(());
+    }
+    // Supported tags are rendered by the tag renderer, like the unknown tags failing it.
+    let policy = if self.tag_renderer.is_supported_tag(tag_node.name) {
+      options::UnknownTagPolicy::Error
+    } else {
+      self.options.unknown_tag_policy
+    };
+    match policy {
+      options::UnknownTagPolicy::Error => self.tag_renderer.render_tag_into(
+        tag_node,
+        attribute_values,
+        children,
+        out,
+        self.parser.buf,
+        &self.tag_state,
+      )?,
+      options::UnknownTagPolicy::Ignore => out.truncate(children.start),
+      options::UnknownTagPolicy::PassthroughChildren => {
+        let children_result = children.take(out);
+        for (child, result) in tag_node.children.iter().zip(children_result) {
+          if !child.is_whitespace() {
+            out.push_str(&result);
           }
         }
-        if if_attribute_present && for_loop_attribute.is_some() {
-          return Err(Error {
-            kind: ErrorKind::RendererError,
-            message: "Control flow attributes `if` and `for` on the same node is not supported!"
-              .to_string(),
-            source: None,
-          });
-        }
-        if if_attribute_evaluated_as_false {
-          return Ok("".to_string());
-        }
+      }
+      options::UnknownTagPolicy::Custom(render) => {
+        let children_result = children.take(out);
+        out.push_str(&render(tag_node, attribute_values, children_result)?);
+      }
+    };
+    if let (Some(cache), Some(key)) = (&self.options.cache, cache_key) {
+      cache.put(
+        &key,
+        out[children.start..].to_string(),
+        self.options.cache_ttl,
+      );
+    }
+    Ok(())
+  }
 
-        // Process for loop
-        if let Some(for_loop_instruction) = for_loop_attribute {
-          let for_loop_tokens =
-            expression::tokenize::tokenize_expression(for_loop_instruction.as_bytes())?;
-          if for_loop_tokens.len() < 3 || for_loop_tokens[1] != ExpressionToken::ArithOp(b"in") {
-            return Err(Error {
-              kind: ErrorKind::RendererError,
-              message: format!("Invalid for-loop attribute value: {for_loop_instruction}"),
-              source: None,
-            });
-          }
-          let ExpressionToken::Ref(for_item_name_buf) = for_loop_tokens[0] else {
-            return Err(Error {
-              kind: ErrorKind::RendererError,
-              message: format!("Invalid valid as for-loop item: {for_loop_instruction}"),
-              source: None,
-            });
-          };
-          let for_item_name = str::from_utf8(for_item_name_buf).unwrap();
-          let for_range_value =
-            expression::evaluate::evaluate_expression_tokens(&for_loop_tokens[2..], &self.context)?;
-          let Value::Array(for_range) = for_range_value else {
-            return Err(Error {
-              kind: ErrorKind::RendererError,
-              message: format!("For loop range is not an array: {for_loop_instruction}",),
-              source: None,
-            });
-          };
+  /**
+   * Read the content of a file used by `src` attributes, consulting the render cache first.
+   */
+  fn read_file_content(&self, filename: &str) -> Result<String> {
+    let Some(cache) = &self.options.cache else {
+      return self.context.read_file_content(filename);
+    };
+    // Check the sandbox before the cache, so cached files are not loaded by denied paths.
+    let key = format!("file:{}", self.context.resolve_allowed_path(filename)?);
+    if let Some(content) = cache.get(&key) {
+      return Ok(content);
+    }
+    let content = self.context.read_file_content(filename)?;
+    cache.put(&key, content.clone(), self.options.cache_ttl);
+    Ok(content)
+  }
 
-          self.context.push_scope();
-          let mut answer = String::new();
-          for (item_idx, item_value) in for_range.iter().enumerate() {
-            self.context.set_value(for_item_name, item_value.clone());
-            let loop_variable = json!({
-                "index": item_idx,
-                "length": for_range.len(),
-                "first": item_idx == 0,
-                "last": item_idx + 1 == for_range.len()
-            });
-            self.context.set_value("loop", loop_variable);
-            let item_node_result =
-              self.process_tag_node_without_for(tag_node, attribute_values.clone())?;
-            answer += &item_node_result;
-          }
-          self.context.pop_scope();
-          Ok(answer)
-        } else {
-          self.process_tag_node_without_for(tag_node, attribute_values)
-        }
+  /**
+   * Evaluate the expression against the capabilities in the options. Capabilities
+   * that are missing, including the fields of missing objects, are absent.
+   */
+  fn has_capability(&self, requires: &str) -> bool {
+    let context = render_context::RenderContext::from(self.options.capabilities.clone());
+    match context.evaluate(requires) {
+      Ok(value) => attr::is_truthy(&value),
+      Err(_) => false,
+    }
+  }
+
+  /**
+   * Read the first file that can be read in a comma separated list of files, which is used
+   * by the `srcset` attribute of `<include>` for fallbacks. Return the filename and the content.
+   */
+  fn read_first_file_content<'s>(&self, srcset: &'s str) -> Result<(&'s str, String)> {
+    let mut last_error = None;
+    for src in srcset.split(',').map(|src| src.trim()) {
+      if src.is_empty() {
+        continue;
+      }
+      match self.read_file_content(src) {
+        Ok(content) => return Ok((src, content)),
+        Err(e) => last_error = Some(e),
       }
-      PomlNode::Text(text, _) => self.render_text(text),
-      PomlNode::Whitespace(_) => Ok(" ".to_owned()),
     }
+    Err(Error {
+      kind: ErrorKind::RendererError,
+      message: format!("None of the files in `srcset` can be read: {srcset}"),
+      source: last_error.map(|e| Box::new(e) as _),
+      position: None,
+      code: Some(ErrorCode::FileNotFound),
+    })
   }
 
   /**
-   * The loop attribute `for` should be processed before this function is called.
+   * Read the `tokenLimit` attribute, which limits the number of tokens of the tag output.
    */
-  fn process_tag_node_without_for(
+  fn get_token_limit(&self, attribute_values: &[(String, Value)]) -> Result<Option<usize>> {
+    let Some(value) = attr::get_attribute(attribute_values, "tokenLimit") else {
+      return Ok(None);
+    };
+    match attr::get_text_attribute(attribute_values, "tokenLimit")
+      .map(|v| v.trim().parse::<usize>())
+    {
+      Some(Ok(limit)) => Ok(Some(limit)),
+      _ => Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("`tokenLimit` attribute should be a non-negative integer: {value}"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::InvalidAttributeValue),
+      }),
+    }
+  }
+
+  /**
+   * Render a placeholder tag of the conversation state like `<history/>`, by splicing the
+   * state in the options, or by rendering the placeholder marker if there is no state.
+   */
+  fn process_conversation_state_node(&self, tag_name: &str) -> String {
+    let separator = self.tag_renderer.paragraph_separator();
+    let Some(items) = self.options.conversation_state.get(tag_name) else {
+      return self.options.placeholder_marker.replace("{name}", tag_name) + separator;
+    };
+    let items: Vec<String> = items
+      .iter()
+      .map(|item| match (item.get("role"), item.get("content")) {
+        (Some(Value::String(role)), Some(Value::String(content))) => format!("{role}: {content}"),
+        (None, Some(Value::String(content))) => content.clone(),
+        _ => self.render_value(item.clone()),
+      })
+      .collect();
+    if items.is_empty() {
+      return String::new();
+    }
+    items.join(separator) + separator
+  }
+
+  /**
+   * Render the `<summary>` tag. The content, which is the value of the `of` attribute or
+   * the children, is summarized if it exceeds `maxTokens` tokens.
+   */
+  fn process_summary_node(
     &mut self,
-    tag_node: &PomlTagNode,
     attribute_values: Vec<(String, Value)>,
+    children_result: Vec<String>,
   ) -> Result<String> {
-    let mut children_result = Vec::new();
-    if !tag_node.children.is_empty() {
-      self.context.push_scope();
-      for child in tag_node.children.iter() {
-        children_result.push(self.render_impl(child)?);
+    let content = match attr::get_attribute(&attribute_values, "of") {
+      Some(value) => self.render_value(value.clone()),
+      None => children_result.join(""),
+    };
+    let max_tokens = match attr::get_text_attribute(&attribute_values, "maxTokens")
+      .map(|v| v.trim().parse::<usize>())
+    {
+      Some(Ok(max_tokens)) => max_tokens,
+      _ => {
+        return Err(Error {
+          kind: ErrorKind::RendererError,
+          message: "`maxTokens` attribute of <summary> should be a non-negative integer"
+            .to_string(),
+          source: None,
+          position: None,
+          code: Some(ErrorCode::InvalidAttributeValue),
+        });
       }
-      self.context.pop_scope();
+    };
+    if self.options.token_counter.count_tokens(&content) <= max_tokens {
+      return Ok(content);
     }
+    let Some(summarizer) = &self.options.summarizer else {
+      return Ok(
+        self
+          .options
+          .token_counter
+          .truncate(&content, max_tokens)
+          .to_owned(),
+      );
+    };
 
-    if tag_node.name == "let" {
-      self.process_let_node(attribute_values, children_result)
-    } else if tag_node.name == "include" {
-      self.process_include_node(attribute_values)
+    let cache_key = format!("summary:{max_tokens}:{content}");
+    if let Some(cache) = &self.options.cache
+      && let Some(summary) = cache.get(&cache_key)
+    {
+      return Ok(summary);
+    }
+    let summary = summarizer
+      .summarize(&content, max_tokens)
+      .map_err(|e| Error {
+        kind: ErrorKind::RendererError,
+        message: "Failed to summarize the content of <summary>".to_string(),
+        source: Some(Box::new(e)),
+        position: None,
+        code: None,
+      })?;
+    if let Some(cache) = &self.options.cache {
+      cache.put(&cache_key, summary.clone(), self.options.cache_ttl);
+    }
+    Ok(summary)
+  }
+
+  /**
+   * Define a template with `<template name="card" params="title,body">`. The template
+   * can be used as `<card title="..." body="..." />` in the rest of the document.
+   */
+  fn process_template_definition(
+    &mut self,
+    tag_node: &PomlTagNode<'a>,
+    attribute_values: &[(String, Value)],
+  ) -> Result<()> {
+    let Some(name) = attr::get_str_attribute(attribute_values, "name") else {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: "`name` attribute not found on <template>.".to_string(),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::MissingAttribute),
+      });
+    };
+    if matches!(name, "template" | "let" | "include" | "summary") {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Template name `{name}` is reserved."),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::InvalidAttributeValue),
+      });
+    }
+    let params = attr::get_str_attribute(attribute_values, "params")
+      .map(|params| {
+        params
+          .split(',')
+          .map(|param| param.trim().to_string())
+          .filter(|param| !param.is_empty())
+          .collect()
+      })
+      .unwrap_or_default();
+    self.templates.insert(
+      name.to_string(),
+      Template {
+        params,
+        children: tag_node.children.clone(),
+        source: self.parser.buf,
+      },
+    );
+    Ok(())
+  }
+
+  /**
+   * Render an instance of a template. The template content is rendered in its own scope,
+   * where the parameters are bound to the attribute values, or null if not provided.
+   */
+  fn process_template_instance(
+    &mut self,
+    name: &str,
+    template: Template<'a>,
+    attribute_values: Vec<(String, Value)>,
+    out: &mut String,
+  ) -> Result<()> {
+    if self.template_depth >= MAX_TEMPLATE_DEPTH {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Template <{name}> is nested too deeply."),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::TemplateTooDeep),
+      });
+    }
+    for param in template.params.iter() {
+      self.check_not_constant(param)?;
+    }
+    self.context.push_scope();
+    for param in template.params.iter() {
+      let value = attr::get_attribute(&attribute_values, param)
+        .cloned()
+        .unwrap_or(Value::Null);
+      self.context.set_value(param, value);
+    }
+    self.template_depth += 1;
+    // Templates of the including document are rendered against the including document, so
+    // the raw content and the whitespace of the children are sliced from it.
+    let parent_parser = if std::ptr::eq(template.source, self.parser.buf) {
+      None
     } else {
-      Ok(self.tag_renderer.render_tag(
-        tag_node,
-        &attribute_values,
-        children_result,
-        self.parser.buf,
-      )?)
+      let mut parser = PomlParser::from_poml_str(str::from_utf8(template.source).unwrap());
+      parser.max_depth = self.parser.max_depth;
+      Some(std::mem::replace(&mut self.parser, parser))
+    };
+    let start = out.len();
+    let mut result = Ok(());
+    for child in template.children.iter() {
+      result = self.render_into(child, out);
+      if result.is_err() {
+        out.truncate(start);
+        break;
+      }
+    }
+    if let Some(parser) = parent_parser {
+      self.parser = parser;
+    }
+    self.template_depth -= 1;
+    self.context.pop_scope();
+    result
+  }
+
+  /**
+   * Define the constants in the JSON object of `<meta const='{"MAX_ITEMS": 5}'>`.
+   */
+  fn process_meta_const(&mut self, attribute_values: &[(String, Value)]) -> Result<String> {
+    let constants = match attr::get_attribute(attribute_values, "const") {
+      Some(Value::String(s)) => serde_json::from_str::<Value>(s).ok(),
+      Some(value) => Some(value.clone()),
+      None => None,
+    };
+    let Some(Value::Object(constants)) = constants else {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: "`const` attribute of <meta> should be a JSON object".to_string(),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::InvalidAttributeValue),
+      });
+    };
+    for (name, value) in constants {
+      self.context.set_constant(&name, value)?;
+    }
+    Ok("".to_owned())
+  }
+
+  /**
+   * Variables can't shadow the constants.
+   */
+  fn check_not_constant(&self, name: &str) -> Result<()> {
+    if self.context.is_constant(name) {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Variable `{name}` shadows a constant"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::ConstantShadowed),
+      });
     }
+    Ok(())
   }
 
   fn process_let_node(
@@ -194,6 +1829,7 @@ where
       .iter()
       .find(|v| v.0 == "name")
       .map(|(_, value)| value);
+    let global = attr::get_bool_attribute(&attribute_values, "global").unwrap_or(false);
 
     // Check whether more than one source of value is provided
     let children_value = if !children_result.is_empty() {
@@ -208,12 +1844,19 @@ where
       .map(|(_, value)| value);
 
     let src_value = match attribute_values.iter().find(|v| v.0 == "src") {
-      Some((_, Value::String(src))) => {
-        let file_content_buf = self.context.read_file_content(src)?;
-        Some(file_content_buf)
+      Some((_, Value::String(src))) if src == "stdin" && self.context.is_stdin_enabled() => {
+        self.context.read_stdin().transpose()?
       }
+      Some((_, Value::String(src))) => Some(self.read_file_content(src)?),
       _ => None,
     };
+    let src_format = match attr::get_str_attribute(&attribute_values, "src") {
+      Some(src) => {
+        let parser = attr::get_str_attribute(&attribute_values, "parser");
+        data_format::DataFormat::from_src(src, parser)?
+      }
+      None => None,
+    };
     let mut value_count = 0;
     let mut value_from_attribute = false;
     if children_value.is_some() {
@@ -233,11 +1876,20 @@ where
           kind: ErrorKind::RendererError,
           message: "No value is provided for the <let> node".to_string(),
           source: None,
+          position: None,
+          code: Some(ErrorCode::InvalidLetValue),
         });
       }
       1 => match (children_value, src_value, attribute_value) {
         (Some(v), None, None) => Value::String(v),
-        (None, Some(v), None) => Value::String(v),
+        (None, Some(v), None) => match src_format {
+          Some(format) => {
+            // Structured data is parsed as it is, like the evaluated attribute value.
+            value_from_attribute = true;
+            format.parse(&v)?
+          }
+          None => Value::String(v),
+        },
         (None, None, Some(v)) => v.clone(),
         _ => unreachable!(),
       },
@@ -246,27 +1898,37 @@ where
           kind: ErrorKind::RendererError,
           message: "More than one value is provided for the <let> node.".to_string(),
           source: None,
+          position: None,
+          code: Some(ErrorCode::InvalidLetValue),
         });
       }
     };
 
     let Some(Value::String(name)) = name else {
-      let Ok(Value::Object(value_obj)) = serde_json::from_str(value.as_str().unwrap()) else {
+      let value = match value {
+        Value::String(s) => serde_json::from_str(&s).unwrap_or(Value::Null),
+        value => value,
+      };
+      let Value::Object(value_obj) = value else {
         return Err(Error {
           kind: ErrorKind::RendererError,
           message: "Only object value can be used to set context variables".to_string(),
           source: None,
+          position: None,
+          code: Some(ErrorCode::InvalidLetValue),
         });
       };
       for (key, value) in value_obj.iter() {
-        self.context.set_value(key, value.clone());
+        self.check_not_constant(key)?;
+        self.set_let_value(global, key, value.clone());
       }
       return Ok("".to_owned());
     };
+    self.check_not_constant(name)?;
 
     if value_from_attribute {
       // For attribute value, directly use the value as it is evaluated.
-      self.context.set_value(name, value);
+      self.set_let_value(global, name, value);
       return Ok("".to_owned());
     }
 
@@ -278,25 +1940,13 @@ where
 
         // If it is a boolean value
         if let Ok(bool_value) = value_str.parse::<bool>() {
-          self.context.set_value(name, Value::Bool(bool_value));
-          return Ok("".to_owned());
-        }
-
-        // If it is an integer
-        if let Ok(int_value) = value_str.parse::<i64>() {
-          self.context.set_value(
-            name,
-            Value::Number(serde_json::Number::from_i128(int_value.into()).unwrap()),
-          );
+          self.set_let_value(global, name, Value::Bool(bool_value));
           return Ok("".to_owned());
         }
 
-        // If it is a float
-        if let Ok(float_value) = value_str.parse::<f64>() {
-          self.context.set_value(
-            name,
-            Value::Number(serde_json::Number::from_f64(float_value).unwrap()),
-          );
+        // If it is a number
+        if let Some(number_value) = attr::parse_number(value_str) {
+          self.set_let_value(global, name, number_value);
           return Ok("".to_owned());
         }
 
@@ -304,7 +1954,7 @@ where
         if let Ok(arr_value) = serde_json::from_str::<serde_json::Value>(value_str)
           && let Some(arr) = arr_value.as_array()
         {
-          self.context.set_value(name, Value::Array(arr.clone()));
+          self.set_let_value(global, name, Value::Array(arr.clone()));
           return Ok("".to_owned());
         }
 
@@ -312,7 +1962,7 @@ where
         if let Ok(obj_value) = serde_json::from_str::<serde_json::Value>(value_str)
           && let Some(obj) = obj_value.as_object()
         {
-          self.context.set_value(name, Value::Object(obj.clone()));
+          self.set_let_value(global, name, Value::Object(obj.clone()));
           return Ok("".to_owned());
         }
 
@@ -332,10 +1982,13 @@ where
               kind: ErrorKind::RendererError,
               message: format!("Failed to convert value to integer {value}"),
               source: Some(Box::new(e)),
+              position: None,
+              code: Some(ErrorCode::InvalidLetValue),
             });
           }
         };
-        self.context.set_value(
+        self.set_let_value(
+          global,
           name,
           Value::Number(serde_json::Number::from_i128(int_val.into()).unwrap()),
         );
@@ -349,10 +2002,13 @@ where
                 kind: ErrorKind::RendererError,
                 message: format!("Failed to convert value to number {value}"),
                 source: Some(Box::new(e)),
+                position: None,
+                code: Some(ErrorCode::InvalidLetValue),
               });
             }
           };
-          self.context.set_value(
+          self.set_let_value(
+            global,
             name,
             Value::Number(serde_json::Number::from_f64(fval).unwrap()),
           );
@@ -364,29 +2020,34 @@ where
                 kind: ErrorKind::RendererError,
                 message: format!("Failed to convert value to number {value}"),
                 source: Some(Box::new(e)),
+                position: None,
+                code: Some(ErrorCode::InvalidLetValue),
               });
             }
           };
-          self.context.set_value(
+          self.set_let_value(
+            global,
             name,
             Value::Number(serde_json::Number::from_i128(int_val.into()).unwrap()),
           );
         }
       }
       "boolean" => {
-        let bool_val = !utils::is_false_value(value_str);
-        self.context.set_value(name, Value::Bool(bool_val));
+        let bool_val = !attr::is_false_value(value_str);
+        self.set_let_value(global, name, Value::Bool(bool_val));
       }
       "array" => {
         match serde_json::from_str(value_str) {
           Ok(Value::Array(value_arr)) => {
-            self.context.set_value(name, Value::Array(value_arr));
+            self.set_let_value(global, name, Value::Array(value_arr));
           }
           _ => {
             return Err(Error {
               kind: ErrorKind::RendererError,
               message: format!("Failed to parse value to array: {value}"),
               source: None,
+              position: None,
+              code: Some(ErrorCode::InvalidLetValue),
             });
           }
         };
@@ -394,51 +2055,141 @@ where
       "object" => {
         match serde_json::from_str(value_str) {
           Ok(Value::Object(value_obj)) => {
-            self.context.set_value(name, Value::Object(value_obj));
+            self.set_let_value(global, name, Value::Object(value_obj));
           }
           _ => {
             return Err(Error {
               kind: ErrorKind::RendererError,
               message: format!("Failed to parse value to object: {value}"),
               source: None,
+              position: None,
+              code: Some(ErrorCode::InvalidLetValue),
             });
           }
         };
       }
       "string" => {
-        self.context.set_value(name, value);
+        self.set_let_value(global, name, value);
       }
       _ => {
         return Err(Error {
           kind: ErrorKind::RendererError,
           message: format!("Unknown type for varaible: {type_value}"),
           source: None,
+          position: None,
+          code: Some(ErrorCode::InvalidLetValue),
         });
       }
     }
     Ok("".to_owned())
   }
+  /**
+   * Set a variable of `<let>`, on the current scope or, with `global="true"`, on the root
+   * scope of the document so it outlives the enclosing tags.
+   */
+  fn set_let_value(&mut self, global: bool, name: &str, value: Value) {
+    if global {
+      self.context.set_global_value(name, value);
+    } else {
+      self.context.set_value(name, value);
+    }
+  }
 
   fn process_include_node(&mut self, attribute_values: Vec<(String, Value)>) -> Result<String> {
-    let Some((_, Value::String(src))) = attribute_values.iter().find(|v| v.0 == "src") else {
-      return Err(Error {
-        kind: ErrorKind::RendererError,
-        message: "`src` attribute not found on <include>.".to_string(),
-        source: None,
-      });
-    };
+    if let Some(requires) = attr::get_str_attribute(&attribute_values, "requires")
+      && !self.has_capability(requires)
+    {
+      let src = attr::get_str_attribute(&attribute_values, "src")
+        .or(attr::get_str_attribute(&attribute_values, "srcset"))
+        .unwrap_or_default();
+      self.record_trace(
+        || trace::TraceEventKind::Include {
+          src: src.to_string(),
+          skipped: true,
+        },
+        None,
+      );
+      return Ok("".to_owned());
+    }
+    let (src, file_content_buf) =
+      if let Some(srcset) = attr::get_str_attribute(&attribute_values, "srcset") {
+        self.read_first_file_content(srcset)?
+      } else if let Some(src) = attr::get_str_attribute(&attribute_values, "src") {
+        (src, self.read_file_content(src)?)
+      } else {
+        return Err(Error {
+          kind: ErrorKind::RendererError,
+          message: "`src` attribute not found on <include>.".to_string(),
+          source: None,
+          position: None,
+          code: Some(ErrorCode::MissingAttribute),
+        });
+      };
 
-    let file_content_buf = self.context.read_file_content(src)?;
-    let new_context = self.context.clone();
-    let new_tag_renderer = self.tag_renderer.clone();
-    let parser = PomlParser::from_poml_str(&file_content_buf);
-    let mut renderer = Renderer {
-      context: new_context,
-      tag_renderer: new_tag_renderer,
-      parser,
-      filename: src.to_string(),
+    let bindings = match attr::get_attribute(&attribute_values, "with") {
+      Some(Value::Object(bindings)) => Some(bindings.clone()),
+      Some(value) => {
+        return Err(Error {
+          kind: ErrorKind::RendererError,
+          message: format!("`with` attribute of <include> should be an object, found {value}"),
+          source: None,
+          position: None,
+          code: Some(ErrorCode::InvalidAttributeValue),
+        });
+      }
+      None => None,
+    };
+    for name in bindings.iter().flat_map(|bindings| bindings.keys()) {
+      self.check_not_constant(name)?;
+    }
+    let mut context = if attr::get_bool_attribute(&attribute_values, "isolated").unwrap_or(false) {
+      self.context.isolated(bindings.unwrap_or_default())
+    } else {
+      let mut context = self.context.clone();
+      if let Some(bindings) = bindings {
+        context.push_scope();
+        for (name, value) in bindings {
+          context.set_value(&name, value);
+        }
+      }
+      context
     };
-    renderer.render()
+    // Paths in the included document are relative to the included document.
+    let resolved_src = self.context.resolve_path(src);
+    let base_path = std::path::Path::new(&resolved_src)
+      .parent()
+      .unwrap_or(std::path::Path::new(""));
+    context.set_base_path(base_path);
+    self.include_count += 1;
+    self.check_limit(
+      self.include_count,
+      self.options.limits.max_includes,
+      "included documents",
+    )?;
+    let mut renderer = Renderer::new(&file_content_buf, context, self.tag_renderer.clone());
+    renderer.options = self.options.clone();
+    renderer.progress = self.progress;
+    renderer.loop_iterations = self.loop_iterations;
+    renderer.include_count = self.include_count;
+    renderer.templates = self.templates.clone();
+    renderer.tag_depth = self.tag_depth + INCLUDE_DEPTH;
+    renderer.code_depth = self.code_depth;
+    renderer.tag_state = self.tag_state.clone();
+    renderer.set_filename(src);
+    self.record_trace(
+      || trace::TraceEventKind::Include {
+        src: src.to_string(),
+        skipped: false,
+      },
+      None,
+    );
+    let result = renderer.render_document();
+    self.progress = renderer.progress;
+    self.loop_iterations = renderer.loop_iterations;
+    self.include_count = renderer.include_count;
+    self.trace.get_mut().append(renderer.trace.get_mut());
+    self.warnings.get_mut().append(renderer.warnings.get_mut());
+    result
   }
 
   /**
@@ -446,6 +2197,29 @@ where
    * variable values
    */
   fn render_text(&self, text: &str) -> Result<String> {
+    self.render_text_content(text, None, false, false)
+  }
+
+  /**
+   * Render the text like `render_text`. `start` is the offset of the text in the document,
+   * if the text is a node of it, to locate the errors. If `auto_fence` is true, interpolated
+   * values that look like code are wrapped in a code fence. If `escape` is true, the other
+   * interpolated values are escaped by the tag renderer, unless they end with `| raw`.
+   */
+  fn render_text_content(
+    &self,
+    text: &str,
+    start: Option<usize>,
+    auto_fence: bool,
+    escape: bool,
+  ) -> Result<String> {
+    let locate = |e: Error, pos: usize, end: usize| match start {
+      Some(start) => e.with_span(self.parser.buf, start + pos, start + end),
+      None => e,
+    };
+    if self.raw_depth > 0 {
+      return Ok(text.to_owned());
+    }
     let p = text.as_bytes();
     let mut answer_buf = Vec::with_capacity(p.len());
     let mut pos = 0;
@@ -464,19 +2238,31 @@ where
             }
           }
           if !expression_found {
-            return Err(Error {
-              kind: ErrorKind::RendererError,
-              // TODO add line/col position for the error message.
-              message: "Expression end not found in text content.".to_string(),
-              source: None,
-            });
+            return Err(locate(
+              Error {
+                kind: ErrorKind::RendererError,
+                message: "Expression end not found in text content.".to_string(),
+                source: None,
+                position: None,
+                code: Some(ErrorCode::UnclosedExpression),
+              },
+              pos,
+              p.len(),
+            ));
           }
           t + 1
         };
         pos = expression_end + 2;
         let expression = str::from_utf8(&p[expression_start..expression_end]).unwrap();
-        let result = self.context.evaluate(expression)?;
-        let result_str = self.render_value(result);
+        let (result, raw) = self
+          .evaluate_interpolation(&utils::decode_xml_entities(expression))
+          .map_err(|e| locate(e, expression_start, expression_end))?;
+        let mut result_str = self.render_value(result);
+        if auto_fence && utils::looks_like_code(&result_str) {
+          result_str = utils::fence_code(&result_str);
+        } else if escape && !raw {
+          result_str = self.tag_renderer.escape_text(&result_str);
+        }
         answer_buf.extend(result_str.as_bytes());
       } else if p[pos] == b'#' {
         let escaping_mapping = [
@@ -502,6 +2288,17 @@ where
           answer_buf.push(p[pos]);
           pos += 1;
         }
+      } else if p[pos] == b'&' {
+        match utils::decode_xml_entity(p, pos) {
+          Some((c, next_pos)) => {
+            answer_buf.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+            pos = next_pos;
+          }
+          None => {
+            answer_buf.push(p[pos]);
+            pos += 1;
+          }
+        }
       } else {
         answer_buf.push(p[pos]);
         pos += 1;
@@ -533,12 +2330,95 @@ where
     }
   }
 }
-fn is_attribute_evaluated_as_expression(tag_name: &str, key_name: &str) -> bool {
-  match (tag_name, key_name) {
-    ("let", "value") | ("table", "records") => true,
-    _ => false,
+/**
+ * Tags whose output only depends on the attribute values, so they can be cached.
+ */
+const CACHEABLE_TAGS: &[&str] = &["table"];
+
+/**
+ * Whether the text in the children of the tag should be output without template expansion,
+ * which is the case for `<pre>` and tags with a true `raw` attribute.
+ */
+fn is_raw_tag(tag_node: &PomlTagNode, attribute_values: &[(String, Value)]) -> bool {
+  if tag_node.name == "pre" {
+    return true;
+  }
+  attr::get_bool_attribute(attribute_values, "raw").unwrap_or(false)
+}
+
+/**
+ * Remove the leading whitespace of the children output until the first child with other
+ * content, and the trailing whitespace after the last one.
+ */
+fn trim_children_output(out: &mut String, children: &mut ChildrenOutput) {
+  for index in 0..children.ends.len() {
+    let child = children.get(out, index);
+    let len = child.len() - child.trim_start().len();
+    let is_blank = len == child.len();
+    let at = children.ends[index] - child.len();
+    remove_from_child(out, children, index, at, len);
+    if !is_blank {
+      break;
+    }
+  }
+  for index in (0..children.ends.len()).rev() {
+    let child = children.get(out, index);
+    let len = child.len() - child.trim_end().len();
+    let is_blank = len == child.len();
+    let at = children.ends[index] - len;
+    remove_from_child(out, children, index, at, len);
+    if !is_blank {
+      break;
+    }
+  }
+}
+
+fn remove_from_child(
+  out: &mut String,
+  children: &mut ChildrenOutput,
+  index: usize,
+  at: usize,
+  len: usize,
+) {
+  out.replace_range(at..at + len, "");
+  for end in children.ends[index..].iter_mut() {
+    *end -= len;
+  }
+}
+
+/**
+ * Error of writing the output of the document into a sink.
+ */
+fn write_error(filename: &str, source: Box<dyn std::error::Error + Send + Sync>) -> Error {
+  Error {
+    kind: ErrorKind::RendererError,
+    message: format!("Failed to write the output of {filename}"),
+    source: Some(source),
+    position: None,
+    code: None,
   }
 }
 
+/**
+ * Count the nodes in the tree.
+ */
+fn count_nodes(tag_node: &PomlTagNode) -> usize {
+  1 + tag_node
+    .children
+    .iter()
+    .map(|child| match child {
+      PomlNode::Tag(child_tag) => count_nodes(child_tag),
+      _ => 1,
+    })
+    .sum::<usize>()
+}
+
+pub(crate) fn is_attribute_evaluated_as_expression(tag_name: &str, key_name: &str) -> bool {
+  matches!(
+    (tag_name, key_name),
+    ("let", "value") | ("table", "records") | ("summary", "of")
+  )
+}
+
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,270 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::cache::RenderCache;
+use super::expression::UndefinedPolicy;
+use super::post_process::PostProcessStep;
+use super::progress::{CancellationToken, ProgressCallback};
+use super::stylesheet::Stylesheet;
+use super::summarizer::Summarizer;
+use super::token_counter::{HeuristicTokenCounter, TokenCounter};
+use crate::PomlTagNode;
+use crate::error::Result;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/**
+ * How whitespace in a subtree is handled, set by the `whiteSpace` attribute.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WhiteSpaceMode {
+  /** Preserve all whitespace as it is in the document. */
+  Pre,
+  /** Collapse whitespace to a single space and remove leading and trailing whitespace. */
+  Filter,
+  /** Preserve whitespace but remove leading and trailing whitespace. */
+  Trim,
+}
+
+impl WhiteSpaceMode {
+  pub fn from_attribute_value(value: &str) -> Option<WhiteSpaceMode> {
+    match value {
+      "pre" => Some(WhiteSpaceMode::Pre),
+      "filter" => Some(WhiteSpaceMode::Filter),
+      "trim" => Some(WhiteSpaceMode::Trim),
+      _ => None,
+    }
+  }
+}
+
+/**
+ * Version of the output formatting. Formatting changes only ship in new versions,
+ * so the output of an existing version stays byte-identical across crate upgrades.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum FormatVersion {
+  /** The original formatting. */
+  #[default]
+  V1,
+  /**
+   * Removes trailing whitespace of lines, collapses three or more consecutive newlines
+   * into a blank line, and ends the output with exactly one newline.
+   */
+  V2,
+}
+
+impl FormatVersion {
+  /** The latest format version. */
+  pub const LATEST: FormatVersion = FormatVersion::V2;
+}
+
+/**
+ * Renders an unknown tag with its evaluated attributes and the rendered children.
+ */
+pub type UnknownTagRenderFn = fn(&PomlTagNode, &[(String, Value)], Vec<String>) -> Result<String>;
+
+/**
+ * How the tags not supported by the tag renderer are rendered.
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub enum UnknownTagPolicy {
+  /** Fail the render, which is the default. */
+  #[default]
+  Error,
+  /** Skip the tag and its children. */
+  Ignore,
+  /** Render the children as if they were not wrapped by the tag. */
+  PassthroughChildren,
+  /** Render the tag with the function. */
+  Custom(UnknownTagRenderFn),
+}
+
+impl UnknownTagPolicy {
+  /**
+   * Parse the policy from its name in config files: `error`, `ignore` or `passthrough`.
+   */
+  pub fn from_name(name: &str) -> Option<UnknownTagPolicy> {
+    match name {
+      "error" => Some(UnknownTagPolicy::Error),
+      "ignore" => Some(UnknownTagPolicy::Ignore),
+      "passthrough" => Some(UnknownTagPolicy::PassthroughChildren),
+      _ => None,
+    }
+  }
+}
+
+/**
+ * Limits on the resources used by a render, which stop hostile documents from exhausting
+ * the stack or the memory. The render fails when a limit is exceeded.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderLimits {
+  /** Maximum nesting depth of the tags, including the tags of the included documents. */
+  pub max_depth: usize,
+  /** Maximum number of iterations of all the `for` loops of a render. */
+  pub max_loop_iterations: usize,
+  /** Maximum size of the output in bytes, checked as the output grows. */
+  pub max_output_size: usize,
+  /** Maximum number of documents included by `<include>` tags in a render. */
+  pub max_includes: usize,
+}
+
+impl Default for RenderLimits {
+  fn default() -> Self {
+    RenderLimits {
+      max_depth: 64,
+      max_loop_iterations: 1_000_000,
+      max_output_size: 64 * 1024 * 1024,
+      max_includes: 1024,
+    }
+  }
+}
+
+/**
+ * Options to control the behavior of a renderer.
+ */
+#[derive(Clone)]
+pub struct RenderOptions {
+  /** Token counter used by `tokenLimit` and other budget related features. */
+  pub token_counter: Arc<dyn TokenCounter>,
+  /**
+   * Default whitespace handling of the document. `None` keeps the legacy behavior,
+   * which collapses whitespace to a single space without trimming.
+   */
+  pub white_space: Option<WhiteSpaceMode>,
+  /** Cache for expensive outputs like tables and included files. */
+  pub cache: Option<Arc<dyn RenderCache>>,
+  /** Time to live of the values put into the cache. */
+  pub cache_ttl: Option<Duration>,
+  /** Version of the output formatting. */
+  pub format_version: FormatVersion,
+  /**
+   * Wrap interpolated values that look like code, or contain Markdown code fences,
+   * in a code fence so they can't break the surrounding document structure.
+   */
+  pub auto_fence_code: bool,
+  /**
+   * Escape the interpolated values in the text with the syntax of the tag renderer, like
+   * `\*` for Markdown, so they can't be mistaken for markup. Values with the `raw` filter,
+   * like `{{ snippet | raw }}`, are not escaped.
+   */
+  pub escape_values: bool,
+  /**
+   * Order of the top-level blocks by tag name, e.g. `output-format` => 100 to always put
+   * the output format last. The `order` attribute of a block takes precedence, and blocks
+   * without either are ordered as 0. Blocks with the same order keep the document order.
+   */
+  pub block_order: HashMap<String, i64>,
+  /**
+   * Summarizer used by `<summary>` tags whose content exceeds `maxTokens`. Without it,
+   * the content is truncated instead.
+   */
+  pub summarizer: Option<Arc<dyn Summarizer>>,
+  /**
+   * Reject the documents using constructs with IO or external effects, like `<include>`,
+   * `src` attributes and `<summary>` with a summarizer, before rendering. Expressions
+   * reading the `env` provider, the date builtins like `now()` or the random builtins
+   * without `random_seed` fail when they are evaluated.
+   */
+  pub pure: bool,
+  /** Callback to receive the progress of the render, called after each node. */
+  pub on_progress: Option<Arc<dyn ProgressCallback>>,
+  /** Token to cancel the render, checked before each node. */
+  pub cancellation_token: Option<CancellationToken>,
+  /**
+   * Capabilities of the target model or application, like `{"feature": {"tools": true}}`.
+   * The `requires` attribute of `<include>` is evaluated against it.
+   */
+  pub capabilities: Map<String, Value>,
+  /**
+   * Debug mode which annotates each top-level block with its token count, counted by
+   * `token_counter`, in an HTML comment like `<!-- p: 182 tokens -->`.
+   */
+  pub annotate_token_counts: bool,
+  /** Default attributes of the tags, applied before the attributes set on the tags. */
+  pub stylesheet: Stylesheet,
+  /**
+   * Dynamic turn state of agent frameworks, spliced into the placeholder tags in
+   * [`CONVERSATION_STATE_TAGS`] by tag name, e.g. `history` => the previous messages.
+   * Items are strings, or messages like `{"role": "user", "content": "..."}`.
+   */
+  pub conversation_state: HashMap<String, Vec<Value>>,
+  /**
+   * Marker rendered by the placeholder tags without state in `conversation_state`, where
+   * `{name}` is replaced by the tag name, so the state can be spliced in later.
+   */
+  pub placeholder_marker: String,
+  /** How the tags not supported by the tag renderer are rendered. */
+  pub unknown_tag_policy: UnknownTagPolicy,
+  /** Cleanup steps applied to the final output in order, like collapsing blank lines. */
+  pub post_process: Vec<PostProcessStep>,
+  /**
+   * Seed of the random builtins like `shuffle`, set at the start of each render so the
+   * output is reproducible. Without it, the results differ between runs.
+   */
+  pub random_seed: Option<u64>,
+  /**
+   * How references to undefined variables are evaluated, set on the context at the start
+   * of each render. Without it, the policy of the context is kept.
+   */
+  pub undefined_policy: Option<UndefinedPolicy>,
+  /**
+   * Record the source map from the spans of the output to the nodes of the document,
+   * returned by `Renderer::source_map`.
+   */
+  pub source_map: bool,
+  /**
+   * Record the trace of the tags, conditions, loops, includes and expressions of each
+   * render, returned by `Renderer::trace`.
+   */
+  pub trace: bool,
+  /** Limits on the nesting depth, loops, output size and includes of a render. */
+  pub limits: RenderLimits,
+}
+
+/**
+ * Reserved tags standing for the dynamic turn state of agent frameworks.
+ */
+pub const CONVERSATION_STATE_TAGS: [&str; 3] = ["history", "scratchpad", "tool-results"];
+
+impl Default for RenderOptions {
+  fn default() -> Self {
+    RenderOptions {
+      token_counter: Arc::new(HeuristicTokenCounter {}),
+      white_space: None,
+      cache: None,
+      cache_ttl: None,
+      format_version: FormatVersion::default(),
+      auto_fence_code: false,
+      escape_values: false,
+      block_order: HashMap::new(),
+      summarizer: None,
+      pure: false,
+      on_progress: None,
+      cancellation_token: None,
+      capabilities: Map::new(),
+      annotate_token_counts: false,
+      stylesheet: Stylesheet::default(),
+      conversation_state: HashMap::new(),
+      placeholder_marker: "<!-- poml:{name} -->".to_string(),
+      unknown_tag_policy: UnknownTagPolicy::default(),
+      post_process: Vec::new(),
+      random_seed: None,
+      undefined_policy: None,
+      source_map: false,
+      trace: false,
+      limits: RenderLimits::default(),
+    }
+  }
+}
+
+impl std::fmt::Debug for RenderOptions {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("RenderOptions").finish_non_exhaustive()
+  }
+}
@@ -0,0 +1,190 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Parallel rendering of the top-level children of a document with `rayon`, behind the
+ * `parallel` feature.
+ *
+ * Each child is rendered by a fork of the renderer, with a clone of the context, like an
+ * included document. The children are only rendered in parallel if none of them changes
+ * the state seen by its siblings, like top-level `<let>` variables, templates and constants.
+ * Otherwise, or when the render records a trace, a source map, the diagnostics or the
+ * progress, they are rendered one after another.
+ */
+
+use super::Renderer;
+use super::tag_renderer::TagRenderer;
+use crate::error::Result;
+use crate::{PomlNode, PomlTagNode};
+use rayon::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/**
+ * Renders the children of the root tag in parallel, or returns `None` to render them one
+ * after another.
+ */
+pub(crate) type RenderInParallelFn<'a, T> =
+  fn(&mut Renderer<'a, T>, &[PomlNode<'a>]) -> Option<Result<Vec<String>>>;
+
+impl<'a, T> Renderer<'a, T>
+where
+  T: TagRenderer + Send,
+{
+  /**
+   * Render the document like `render`, but render the top-level children in parallel if
+   * they are independent of each other. The output is the same as `render`.
+   */
+  pub fn render_parallel(&mut self) -> Result<String> {
+    self.render_in_parallel = Some(render_in_parallel::<T>);
+    let result = self.render();
+    self.render_in_parallel = None;
+    result
+  }
+
+  /**
+   * Create a renderer of a child of the tag being rendered, in the same state as this
+   * renderer, where the siblings before the child are counted by `sibling_counts`.
+   */
+  fn fork(&self, sibling_counts: HashMap<&'a str, usize>) -> Renderer<'a, T> {
+    let doc = str::from_utf8(self.parser.buf).unwrap();
+    let mut renderer = Renderer::new(doc, self.context.clone(), self.tag_renderer.clone());
+    renderer.options = self.options.clone();
+    renderer.filename = self.filename.clone();
+    renderer.white_space_stack = self.white_space_stack.clone();
+    renderer.raw_depth = self.raw_depth;
+    renderer.code_depth = self.code_depth;
+    renderer.templates = self.templates.clone();
+    renderer.template_depth = self.template_depth;
+    renderer.tag_depth = self.tag_depth;
+    renderer.loop_iterations = self.loop_iterations;
+    renderer.include_count = self.include_count;
+    renderer.tag_state = self.tag_state.clone();
+    renderer.sibling_counts = self.sibling_counts.clone();
+    if let Some(counts) = renderer.sibling_counts.last_mut() {
+      *counts = sibling_counts;
+    }
+    renderer.compiled = self.compiled.clone();
+    renderer.expression_cache = self.expression_cache.clone();
+    renderer.parsed_expressions = RefCell::new(self.parsed_expressions.borrow().clone());
+    renderer.parsed_for_loops = RefCell::new(self.parsed_for_loops.borrow().clone());
+    renderer
+  }
+
+  /**
+   * Whether the children can be rendered in parallel, without changing the output.
+   */
+  fn can_render_in_parallel(&self, nodes: &[PomlNode<'a>]) -> bool {
+    let options = &self.options;
+    if options.trace
+      || options.on_progress.is_some()
+      || options.random_seed.is_some()
+      || self.rendered_nodes.is_some()
+      || self.diagnostics.is_some()
+    {
+      return false;
+    }
+    let tags: Vec<&PomlTagNode> = nodes
+      .iter()
+      .filter_map(|node| match node {
+        PomlNode::Tag(tag) => Some(tag),
+        _ => None,
+      })
+      .collect();
+    if tags.len() < 2 {
+      return false;
+    }
+    // Conditional and repeated tags render a number of times known only by rendering
+    // them, which decides the sibling indexes of the later tags of the same name
+    let is_counted_once = |index: usize, tag: &PomlTagNode| {
+      !tag
+        .attributes
+        .iter()
+        .any(|(name, _)| matches!(*name, "if" | "ifPresent" | "for"))
+        || tags[index + 1..].iter().all(|later| later.name != tag.name)
+    };
+    tags.iter().enumerate().all(|(index, tag)| {
+      tag.name != "let" && !changes_renderer_state(tag) && is_counted_once(index, tag)
+    })
+  }
+}
+
+/**
+ * Whether rendering the tag changes the state seen outside of it, by defining templates,
+ * constants or global variables.
+ */
+fn changes_renderer_state(tag: &PomlTagNode) -> bool {
+  let has_attribute = |name: &str| tag.attributes.iter().any(|(n, _)| *n == name);
+  match tag.name {
+    "template" => true,
+    "let" if has_attribute("global") => true,
+    "meta" if has_attribute("const") => true,
+    _ => tag.children.iter().any(|child| match child {
+      PomlNode::Tag(child) => changes_renderer_state(child),
+      _ => false,
+    }),
+  }
+}
+
+fn render_in_parallel<'a, T>(
+  renderer: &mut Renderer<'a, T>,
+  nodes: &[PomlNode<'a>],
+) -> Option<Result<Vec<String>>>
+where
+  T: TagRenderer + Send,
+{
+  if !renderer.can_render_in_parallel(nodes) {
+    return None;
+  }
+  let mut sibling_counts = renderer.sibling_counts.last().cloned().unwrap_or_default();
+  let mut forks = Vec::with_capacity(nodes.len());
+  for node in nodes.iter() {
+    forks.push(renderer.fork(sibling_counts.clone()));
+    if let PomlNode::Tag(tag) = node
+      && !renderer.templates.contains_key(tag.name)
+    {
+      *sibling_counts.entry(tag.name).or_insert(0) += 1;
+    }
+  }
+  let results: Vec<(Result<String>, Renderer<'a, T>)> = forks
+    .into_par_iter()
+    .zip(nodes.par_iter())
+    .map(|(mut fork, node)| {
+      let mut out = String::new();
+      let result = fork.render_block(node, &mut out).map(|_| out);
+      (result, fork)
+    })
+    .collect();
+  let mut outputs = Vec::with_capacity(results.len());
+  let (loop_iterations, include_count) = (renderer.loop_iterations, renderer.include_count);
+  for (result, mut fork) in results {
+    renderer.progress.0 += fork.progress.0;
+    renderer.progress.1 += fork.progress.1;
+    renderer.loop_iterations += fork.loop_iterations - loop_iterations;
+    renderer.include_count += fork.include_count - include_count;
+    renderer.warnings.get_mut().append(fork.warnings.get_mut());
+    match result {
+      Ok(output) => outputs.push(output),
+      Err(e) => return Some(Err(e)),
+    }
+  }
+  // Each fork only counted its own loops and includes against the limits
+  let limits = renderer.options.limits;
+  let result = renderer
+    .check_limit(
+      renderer.loop_iterations,
+      limits.max_loop_iterations,
+      "loop iterations",
+    )
+    .and_then(|_| {
+      renderer.check_limit(
+        renderer.include_count,
+        limits.max_includes,
+        "included documents",
+      )
+    });
+  Some(result.map(|_| outputs))
+}
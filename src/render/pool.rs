@@ -0,0 +1,449 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::Renderer;
+use super::file_loader::{FileLoader, Sandbox};
+use super::options::RenderOptions;
+use super::render_context::RenderContext;
+use super::tag_renderer::TagRenderer;
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::pin::Pin;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/**
+ * The result of a render job submitted to a [`RenderPool`].
+ */
+pub type RenderOutput = Result<String>;
+
+/**
+ * Limits applied to every job submitted to a [`RenderPool`], besides the limits of the
+ * render options.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct JobLimits {
+  /**
+   * Fail the job if the rendered output is longer than this many bytes. It lowers the
+   * `max_output_size` of the render limits, so the render stops as soon as it's exceeded.
+   */
+  pub max_output_len: Option<usize>,
+  /** Fail the job without rendering if it waited in the queue longer than this. */
+  pub queue_timeout: Option<Duration>,
+}
+
+/**
+ * Configuration of a [`RenderPool`].
+ */
+#[derive(Clone)]
+pub struct RenderPoolConfig {
+  /** Number of worker threads. */
+  pub workers: usize,
+  /** Maximum number of jobs waiting in the queue before `submit` blocks. */
+  pub queue_capacity: usize,
+  /** Limits applied to each job. */
+  pub limits: JobLimits,
+  /** Options of the render of each job, like the render limits and the pure mode. */
+  pub options: RenderOptions,
+  /** Loader of the files set on the context of each job, instead of the context's own. */
+  pub file_loader: Option<Arc<dyn FileLoader>>,
+  /** Sandbox set on the context of each job, instead of the context's own. */
+  pub sandbox: Option<Sandbox>,
+}
+
+impl Default for RenderPoolConfig {
+  fn default() -> Self {
+    RenderPoolConfig {
+      workers: 4,
+      queue_capacity: 64,
+      limits: JobLimits::default(),
+      options: RenderOptions::default(),
+      file_loader: None,
+      sandbox: None,
+    }
+  }
+}
+
+impl std::fmt::Debug for RenderPoolConfig {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("RenderPoolConfig")
+      .field("workers", &self.workers)
+      .field("queue_capacity", &self.queue_capacity)
+      .field("limits", &self.limits)
+      .field("sandbox", &self.sandbox)
+      .finish_non_exhaustive()
+  }
+}
+
+struct JobState {
+  result: Mutex<Option<RenderOutput>>,
+  waker: Mutex<Option<Waker>>,
+  finished: Condvar,
+}
+
+impl JobState {
+  fn complete(&self, output: RenderOutput) {
+    *self.result.lock().unwrap() = Some(output);
+    self.finished.notify_all();
+    if let Some(waker) = self.waker.lock().unwrap().take() {
+      waker.wake();
+    }
+  }
+}
+
+struct Job {
+  template_id: String,
+  template: Arc<str>,
+  context: RenderContext,
+  submitted_at: Instant,
+  state: Arc<JobState>,
+}
+
+/**
+ * Handle of a submitted render job.
+ *
+ * It can be awaited as a [`Future`] or blocked on with [`RenderJob::wait`].
+ */
+pub struct RenderJob {
+  state: Arc<JobState>,
+}
+
+impl RenderJob {
+  /**
+   * Block the current thread until the job finishes.
+   */
+  pub fn wait(self) -> RenderOutput {
+    let mut result = self.state.result.lock().unwrap();
+    loop {
+      if let Some(output) = result.take() {
+        return output;
+      }
+      result = self.state.finished.wait(result).unwrap();
+    }
+  }
+}
+
+impl Future for RenderJob {
+  type Output = RenderOutput;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    // Register the waker before checking the result so a completion racing with
+    // this poll always observes the waker.
+    *self.state.waker.lock().unwrap() = Some(cx.waker().clone());
+    match self.state.result.lock().unwrap().take() {
+      Some(output) => Poll::Ready(output),
+      None => Poll::Pending,
+    }
+  }
+}
+
+/**
+ * A pool of worker threads rendering templates from a shared registry.
+ *
+ * Jobs are queued in a bounded queue: `submit` blocks when the queue is full,
+ * while `try_submit` returns an error instead, so callers get backpressure.
+ */
+pub struct RenderPool {
+  registry: Arc<RwLock<HashMap<String, Arc<str>>>>,
+  sender: Option<SyncSender<Job>>,
+  workers: Vec<JoinHandle<()>>,
+}
+
+impl RenderPool {
+  /**
+   * Create a render pool whose workers render with clones of `tag_renderer`.
+   */
+  pub fn new<T>(tag_renderer: T, config: RenderPoolConfig) -> Self
+  where
+    T: TagRenderer + Send + Sync + 'static,
+  {
+    let (sender, receiver) = sync_channel::<Job>(config.queue_capacity);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let tag_renderer = Arc::new(tag_renderer);
+    let workers = (0..config.workers.max(1))
+      .map(|_| {
+        let receiver = receiver.clone();
+        let tag_renderer = tag_renderer.clone();
+        let config = config.clone();
+        std::thread::spawn(move || worker_loop(receiver, tag_renderer, config))
+      })
+      .collect();
+    RenderPool {
+      registry: Arc::new(RwLock::new(HashMap::new())),
+      sender: Some(sender),
+      workers,
+    }
+  }
+
+  /**
+   * Register a template source under the given id. Registering an existing id
+   * replaces the template for jobs submitted afterwards.
+   */
+  pub fn register(&self, template_id: &str, doc: &str) {
+    self
+      .registry
+      .write()
+      .unwrap()
+      .insert(template_id.to_string(), Arc::from(doc));
+  }
+
+  /**
+   * Submit a render job, blocking while the queue is full.
+   */
+  pub fn submit(&self, template_id: &str, context: RenderContext) -> Result<RenderJob> {
+    let (job, handle) = self.create_job(template_id, context)?;
+    match self.sender.as_ref().unwrap().send(job) {
+      Ok(_) => Ok(handle),
+      Err(_) => Err(Error {
+        kind: ErrorKind::RendererError,
+        message: "Render pool has been shut down.".to_string(),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::Other),
+      }),
+    }
+  }
+
+  /**
+   * Submit a render job, returning an error immediately if the queue is full.
+   */
+  pub fn try_submit(&self, template_id: &str, context: RenderContext) -> Result<RenderJob> {
+    let (job, handle) = self.create_job(template_id, context)?;
+    match self.sender.as_ref().unwrap().try_send(job) {
+      Ok(_) => Ok(handle),
+      Err(TrySendError::Full(_)) => Err(Error {
+        kind: ErrorKind::RendererError,
+        message: "Render pool queue is full.".to_string(),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::Other),
+      }),
+      Err(TrySendError::Disconnected(_)) => Err(Error {
+        kind: ErrorKind::RendererError,
+        message: "Render pool has been shut down.".to_string(),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::Other),
+      }),
+    }
+  }
+
+  fn create_job(&self, template_id: &str, context: RenderContext) -> Result<(Job, RenderJob)> {
+    let Some(template) = self.registry.read().unwrap().get(template_id).cloned() else {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Template not found in the render pool: {template_id}"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::Other),
+      });
+    };
+    let state = Arc::new(JobState {
+      result: Mutex::new(None),
+      waker: Mutex::new(None),
+      finished: Condvar::new(),
+    });
+    let job = Job {
+      template_id: template_id.to_string(),
+      template,
+      context,
+      submitted_at: Instant::now(),
+      state: state.clone(),
+    };
+    Ok((job, RenderJob { state }))
+  }
+}
+
+impl Drop for RenderPool {
+  fn drop(&mut self) {
+    // Closing the channel lets the workers finish the queued jobs and exit.
+    self.sender.take();
+    for worker in self.workers.drain(..) {
+      let _ = worker.join();
+    }
+  }
+}
+
+fn worker_loop<T>(
+  receiver: Arc<Mutex<Receiver<Job>>>,
+  tag_renderer: Arc<T>,
+  config: RenderPoolConfig,
+) where
+  T: TagRenderer,
+{
+  loop {
+    let job = match receiver.lock().unwrap().recv() {
+      Ok(job) => job,
+      Err(_) => return,
+    };
+    // A panicking render fails its job instead of the worker, so the waiters are woken up.
+    let output = catch_unwind(AssertUnwindSafe(|| {
+      run_job(&job, tag_renderer.as_ref(), &config)
+    }))
+    .unwrap_or_else(|payload| {
+      let reason = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default();
+      Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Render job for {} panicked: {reason}", job.template_id),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::Other),
+      })
+    });
+    job.state.complete(output);
+  }
+}
+
+fn run_job<T>(job: &Job, tag_renderer: &T, config: &RenderPoolConfig) -> RenderOutput
+where
+  T: TagRenderer,
+{
+  let limits = &config.limits;
+  if let Some(timeout) = limits.queue_timeout
+    && job.submitted_at.elapsed() > timeout
+  {
+    return Err(Error {
+      kind: ErrorKind::RendererError,
+      message: format!("Render job for {} timed out in the queue.", job.template_id),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::Other),
+    });
+  }
+  let mut context = job.context.clone();
+  if let Some(file_loader) = &config.file_loader {
+    context.set_shared_file_loader(file_loader.clone());
+  }
+  if let Some(sandbox) = &config.sandbox {
+    context.set_sandbox(sandbox.clone());
+  }
+  let mut renderer = Renderer::new(&job.template, context, tag_renderer.clone());
+  renderer.options = config.options.clone();
+  if let Some(max_output_len) = limits.max_output_len {
+    let max_output_size = &mut renderer.options.limits.max_output_size;
+    *max_output_size = (*max_output_size).min(max_output_len);
+  }
+  renderer.set_filename(&job.template_id);
+  let output = renderer.render()?;
+  if let Some(max_output_len) = limits.max_output_len
+    && output.len() > max_output_len
+  {
+    return Err(Error {
+      kind: ErrorKind::RendererError,
+      message: format!(
+        "Output of {} exceeds the limit of {max_output_len} bytes.",
+        job.template_id
+      ),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::Other),
+    });
+  }
+  Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::render::file_loader::InMemoryLoader;
+  use crate::render::options::{RenderLimits, UnknownTagPolicy};
+  use crate::render::tag_renderer::MarkdownTagRenderer;
+  use serde_json::json;
+  use std::collections::HashMap;
+
+  #[test]
+  fn test_render_pool_submit() {
+    let pool = RenderPool::new(MarkdownTagRenderer::default(), RenderPoolConfig::default());
+    pool.register("greeting", "<p>Hello, {{ name }}!</p>");
+    let jobs: Vec<RenderJob> = ["alice", "bob"]
+      .iter()
+      .map(|name| {
+        let context = RenderContext::from_iter(HashMap::from([("name".to_owned(), json!(name))]));
+        pool.submit("greeting", context).unwrap()
+      })
+      .collect();
+    let outputs: Vec<String> = jobs.into_iter().map(|j| j.wait().unwrap()).collect();
+    assert_eq!(outputs[0].trim(), "Hello, alice!");
+    assert_eq!(outputs[1].trim(), "Hello, bob!");
+  }
+
+  #[test]
+  fn test_render_pool_unknown_template() {
+    let pool = RenderPool::new(MarkdownTagRenderer::default(), RenderPoolConfig::default());
+    let context = RenderContext::from_iter(HashMap::<String, serde_json::Value>::new());
+    assert!(pool.submit("missing", context).is_err());
+  }
+
+  #[test]
+  fn test_render_pool_output_limit() {
+    let config = RenderPoolConfig {
+      limits: JobLimits {
+        max_output_len: Some(4),
+        queue_timeout: None,
+      },
+      ..Default::default()
+    };
+    let pool = RenderPool::new(MarkdownTagRenderer::default(), config);
+    pool.register("long", "<p>This is too long.</p>");
+    let context = RenderContext::from_iter(HashMap::<String, serde_json::Value>::new());
+    assert!(pool.submit("long", context).unwrap().wait().is_err());
+  }
+
+  #[test]
+  fn test_render_pool_options() {
+    let config = RenderPoolConfig {
+      options: RenderOptions {
+        pure: true,
+        limits: RenderLimits {
+          max_loop_iterations: 3,
+          ..RenderLimits::default()
+        },
+        ..RenderOptions::default()
+      },
+      file_loader: Some(Arc::new(InMemoryLoader::from_iter([(
+        "a.poml", "<p>A</p>",
+      )]))),
+      ..Default::default()
+    };
+    let pool = RenderPool::new(MarkdownTagRenderer::default(), config);
+    pool.register("loop", r#"<p for="i in range(5)">{{ i }}</p>"#);
+    pool.register("include", r#"<include src="a.poml"/>"#);
+    let context = RenderContext::from_iter(HashMap::<String, serde_json::Value>::new());
+    let error = pool.submit("loop", context.clone()).unwrap().wait();
+    assert_eq!(error.unwrap_err().code(), ErrorCode::LimitExceeded);
+    let error = pool.submit("include", context).unwrap().wait();
+    assert_eq!(error.unwrap_err().code(), ErrorCode::AccessDenied);
+  }
+
+  #[test]
+  fn test_render_pool_job_panics() {
+    let config = RenderPoolConfig {
+      workers: 1,
+      options: RenderOptions {
+        unknown_tag_policy: UnknownTagPolicy::Custom(|_, _, _| panic!("boom")),
+        ..RenderOptions::default()
+      },
+      ..Default::default()
+    };
+    let pool = RenderPool::new(MarkdownTagRenderer::default(), config);
+    pool.register("panic", "<unknown/>");
+    pool.register("ok", "<p>OK</p>");
+    let context = RenderContext::from_iter(HashMap::<String, serde_json::Value>::new());
+    let error = pool.submit("panic", context.clone()).unwrap().wait();
+    assert!(error.unwrap_err().to_string().contains("boom"));
+    let output = pool.submit("ok", context).unwrap().wait().unwrap();
+    assert_eq!(output.trim(), "OK");
+  }
+}
@@ -0,0 +1,198 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Cleanup steps applied to the final output of a render, configured by
+ * `RenderOptions::post_process`.
+ */
+
+/**
+ * A step of the post-processing of the output.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PostProcessStep {
+  /** Remove the whitespace at the end of each line. */
+  TrimTrailingWhitespace,
+  /** Collapse 3 or more consecutive newlines into 2, leaving at most one blank line. */
+  CollapseBlankLines,
+  /** End the output with exactly one newline, unless it's empty. */
+  EnsureTrailingNewline,
+  /**
+   * Wrap the lines longer than the number of columns at spaces. Lines in code fences and
+   * table rows are kept, and the wrapped lines keep the indentation of the line.
+   */
+  WordWrap(usize),
+}
+
+impl PostProcessStep {
+  /**
+   * Parse the step from its name in config files, like `collapse_blank_lines` or `wrap:80`.
+   */
+  pub fn from_name(name: &str) -> Option<PostProcessStep> {
+    match name {
+      "trim_trailing_whitespace" => Some(PostProcessStep::TrimTrailingWhitespace),
+      "collapse_blank_lines" => Some(PostProcessStep::CollapseBlankLines),
+      "ensure_trailing_newline" => Some(PostProcessStep::EnsureTrailingNewline),
+      _ => match name.strip_prefix("wrap:")?.trim().parse() {
+        Ok(columns) if columns > 0 => Some(PostProcessStep::WordWrap(columns)),
+        _ => None,
+      },
+    }
+  }
+
+  pub fn apply(&self, text: &str) -> String {
+    match self {
+      PostProcessStep::TrimTrailingWhitespace => map_lines(text, |line| line.trim_end().to_owned()),
+      PostProcessStep::CollapseBlankLines => {
+        let mut answer = String::with_capacity(text.len());
+        let mut newlines = 0;
+        for c in text.chars() {
+          if c == '\n' {
+            newlines += 1;
+            if newlines > 2 {
+              continue;
+            }
+          } else {
+            newlines = 0;
+          }
+          answer.push(c);
+        }
+        answer
+      }
+      PostProcessStep::EnsureTrailingNewline => {
+        let content = text.trim_end_matches('\n');
+        if content.is_empty() {
+          String::new()
+        } else {
+          format!("{content}\n")
+        }
+      }
+      PostProcessStep::WordWrap(columns) => {
+        let mut in_fence = false;
+        map_lines(text, |line| {
+          let trimmed = line.trim_start();
+          if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+            return line.to_owned();
+          }
+          if in_fence || trimmed.starts_with('|') {
+            return line.to_owned();
+          }
+          wrap_line(line, *columns)
+        })
+      }
+    }
+  }
+}
+
+/**
+ * Apply the steps to the output in order. Outputs with CRLF line endings are processed
+ * with LF line endings, and converted back after the steps.
+ */
+pub fn post_process(text: String, steps: &[PostProcessStep]) -> String {
+  if steps.is_empty() {
+    return text;
+  }
+  if !text.contains("\r\n") {
+    return steps.iter().fold(text, |text, step| step.apply(&text));
+  }
+  let text = text.replace("\r\n", "\n");
+  let text = steps.iter().fold(text, |text, step| step.apply(&text));
+  text.replace('\n', "\r\n")
+}
+
+fn map_lines<F: FnMut(&str) -> String>(text: &str, mut f: F) -> String {
+  text.split('\n').map(&mut f).collect::<Vec<_>>().join("\n")
+}
+
+fn wrap_line(line: &str, columns: usize) -> String {
+  if line.chars().count() <= columns {
+    return line.to_owned();
+  }
+  let indent = &line[..line.len() - line.trim_start().len()];
+  let mut answer = String::new();
+  let mut current = indent.to_owned();
+  let mut current_width = indent.chars().count();
+  let mut has_word = false;
+  for word in line.split_whitespace() {
+    let width = word.chars().count();
+    if has_word && current_width + 1 + width > columns {
+      answer += &current;
+      answer.push('\n');
+      current = indent.to_owned();
+      current_width = indent.chars().count();
+      has_word = false;
+    }
+    if has_word {
+      current.push(' ');
+      current_width += 1;
+    }
+    current += word;
+    current_width += width;
+    has_word = true;
+  }
+  answer + &current
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_post_process() {
+    let text = "# Title  \n\n\n\nSome text\t\n\n\n".to_string();
+    assert_eq!(
+      post_process(
+        text,
+        &[
+          PostProcessStep::TrimTrailingWhitespace,
+          PostProcessStep::CollapseBlankLines,
+          PostProcessStep::EnsureTrailingNewline,
+        ]
+      ),
+      "# Title\n\nSome text\n"
+    );
+    assert_eq!(
+      post_process(
+        "one two three\r\n\r\n\r\n\r\na  \r\n".to_string(),
+        &[
+          PostProcessStep::WordWrap(8),
+          PostProcessStep::CollapseBlankLines,
+          PostProcessStep::TrimTrailingWhitespace,
+        ]
+      ),
+      "one two\r\nthree\r\n\r\na\r\n"
+    );
+    assert_eq!(PostProcessStep::EnsureTrailingNewline.apply("a"), "a\n");
+    assert_eq!(PostProcessStep::EnsureTrailingNewline.apply("\n\n"), "");
+  }
+
+  #[test]
+  fn test_word_wrap() {
+    let wrap = PostProcessStep::WordWrap(12);
+    assert_eq!(
+      wrap.apply("The quick brown fox jumps\n  - over the lazy dog"),
+      "The quick\nbrown fox\njumps\n  - over the\n  lazy dog"
+    );
+    assert_eq!(wrap.apply("a extraordinarily b"), "a\nextraordinarily\nb");
+    let fenced = "```\nlet a_long_variable = 1;\n```\n| a long table cell |";
+    assert_eq!(wrap.apply(fenced), fenced);
+  }
+
+  #[test]
+  fn test_from_name() {
+    assert_eq!(
+      PostProcessStep::from_name("wrap:80"),
+      Some(PostProcessStep::WordWrap(80))
+    );
+    assert_eq!(
+      PostProcessStep::from_name("collapse_blank_lines"),
+      Some(PostProcessStep::CollapseBlankLines)
+    );
+    assert_eq!(PostProcessStep::from_name("wrap:0"), None);
+    assert_eq!(PostProcessStep::from_name("wrap"), None);
+  }
+}
@@ -0,0 +1,64 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/**
+ * Receive the progress of a render, e.g. to show a progress bar.
+ *
+ * `total_nodes` is the number of nodes in the parsed documents so far. It grows when
+ * included documents are parsed, or when loops render more nodes than in the document.
+ */
+pub trait ProgressCallback: Send + Sync {
+  fn on_progress(&self, done_nodes: usize, total_nodes: usize);
+}
+
+impl<F> ProgressCallback for F
+where
+  F: Fn(usize, usize) + Send + Sync,
+{
+  fn on_progress(&self, done_nodes: usize, total_nodes: usize) {
+    self(done_nodes, total_nodes)
+  }
+}
+
+/**
+ * Token to cancel a render from another thread. The renderer checks it between nodes,
+ * and a cancelled render returns an error of kind `Cancelled`.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+  cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::Relaxed);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::Relaxed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cancellation_token() {
+    let token = CancellationToken::new();
+    let cloned = token.clone();
+    assert!(!cloned.is_cancelled());
+    token.cancel();
+    assert!(cloned.is_cancelled());
+  }
+}
@@ -4,10 +4,17 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use crate::error::{Error, ErrorKind, Result};
+use super::expression::{Expression, ExpressionLimits, UndefinedPolicy};
+use super::file_loader::{FileLoader, FsLoader, Sandbox, is_absolute_path, normalize_path};
+use super::filter::Filter;
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use serde::Serialize;
 use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 
 /**
  * Contains the variables in the current scope.
@@ -23,16 +30,91 @@ pub struct Scope {
 #[derive(Debug, Clone)]
 pub struct RenderContext {
   scope_layers: Vec<Scope>,
-  pub(crate) file_mapping: HashMap<String, String>,
+  file_loader: Arc<dyn FileLoader>,
+  /** Directory to resolve relative file paths, which is the directory of the document */
+  base_path: PathBuf,
+  expression_limits: ExpressionLimits,
+  undefined_policy: UndefinedPolicy,
+  /** Reject the values and builtins with external effects, set by the pure render mode */
+  pure: bool,
+  /** Warnings of the evaluated expressions, shared with the included files */
+  warnings: Arc<Mutex<Vec<Error>>>,
+  /** Frozen constants defined by `<meta const>`, visible in all scopes */
+  constants: Map<String, Value>,
+  sandbox: Sandbox,
+  /** Environment variables visible as `env`, when the environment provider is enabled */
+  env: Option<Value>,
+  /** Content of `<let src="stdin">`, read once when the stdin provider is enabled */
+  stdin: Option<Arc<OnceLock<String>>>,
+  /** Identifier of the visible variables, which changes whenever they may change */
+  generation: u64,
+  /** State of the random number generator, shared with the included files */
+  random: Arc<AtomicU64>,
+  /** Whether the random number generator has been seeded, shared like its state */
+  random_seeded: Arc<AtomicBool>,
+  /** Custom filters of the `{{ value | filter }}` pipelines by name */
+  filters: HashMap<String, Arc<dyn Filter>>,
+  /** Compiled patterns of the regular expression builtins, shared with the included files */
+  #[cfg(feature = "regex")]
+  patterns: Arc<Mutex<HashMap<String, regex::Regex>>>,
+}
+
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn next_generation() -> u64 {
+  NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+const SPLITMIX_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/**
+ * Seed the random number generator of a new context by the current time, so the
+ * results differ between runs unless a seed is set.
+ */
+fn random_state_from_time() -> Arc<AtomicU64> {
+  let nanos = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map_or(0, |d| d.as_nanos() as u64);
+  Arc::new(AtomicU64::new(
+    nanos ^ next_generation().wrapping_mul(SPLITMIX_GAMMA),
+  ))
 }
 
 impl RenderContext {
+  /**
+   * Create the context from a value serialized as an object, like a struct or a map, whose
+   * fields become the variables. Values serialized as anything else are an error.
+   */
+  pub fn from_serialize<T: Serialize + ?Sized>(value: &T) -> Result<RenderContext> {
+    let error = |message: String, source: Option<serde_json::Error>| Error {
+      kind: ErrorKind::RendererError,
+      message,
+      source: source.map(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>),
+      position: None,
+      code: Some(ErrorCode::InvalidConfig),
+    };
+    match serde_json::to_value(value) {
+      Ok(Value::Object(variables)) => Ok(RenderContext::from(variables)),
+      Ok(other) => Err(error(
+        format!("Context should be serialized as an object, found {other}"),
+        None,
+      )),
+      Err(e) => Err(error(
+        "Failed to serialize the context".to_string(),
+        Some(e),
+      )),
+    }
+  }
+
   /**
    * Obtain the value of the given variable name in the current context.
    *
    * If the value doesn't exist, return `None`.
    */
   pub fn get_value(&self, name: &str) -> Option<&Value> {
+    if let Some(v) = self.constants.get(name) {
+      return Some(v);
+    }
     for i in (0..self.scope_layers.len()).rev() {
       match self.scope_layers[i].variables.get(name) {
         Some(v) => {
@@ -41,6 +123,9 @@ impl RenderContext {
         None => continue,
       }
     }
+    if name == "env" {
+      return self.env.as_ref();
+    }
     None
   }
 
@@ -52,16 +137,301 @@ impl RenderContext {
     if let Some(current_scope) = self.scope_layers.last_mut() {
       current_scope.variables.insert(name.to_string(), value);
     }
+    self.generation = next_generation();
+  }
+
+  /**
+   * Set a value on the root scope, which is visible until the end of the document.
+   */
+  pub fn set_global_value(&mut self, name: &str, value: Value) {
+    if let Some(root_scope) = self.scope_layers.first_mut() {
+      root_scope.variables.insert(name.to_string(), value);
+    }
+    self.generation = next_generation();
+  }
+
+  /**
+   * Define a constant, which is visible in all scopes and can't be changed.
+   *
+   * Defining a constant with the name of an existing variable or constant is an error.
+   */
+  pub fn set_constant(&mut self, name: &str, value: Value) -> Result<()> {
+    if self.get_value(name).is_some() {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: format!("Constant `{name}` shadows an existing variable or constant"),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::ConstantShadowed),
+      });
+    }
+    self.constants.insert(name.to_string(), value);
+    self.generation = next_generation();
+    Ok(())
+  }
+
+  pub fn is_constant(&self, name: &str) -> bool {
+    self.constants.contains_key(name)
   }
 
   pub fn push_scope(&mut self) {
     self.scope_layers.push(Scope {
       variables: Map::new(),
     });
+    self.generation = next_generation();
   }
 
   pub fn pop_scope(&mut self) {
     self.scope_layers.pop();
+    self.generation = next_generation();
+  }
+
+  /**
+   * Identifier of the variables visible in the context. Two contexts with the same
+   * generation evaluate an expression to the same value, so it can key the cached results.
+   */
+  pub fn generation(&self) -> u64 {
+    self.generation
+  }
+
+  /**
+   * Create a context with only the given variables, which keeps the other settings
+   * like the file loader.
+   */
+  pub fn isolated(&self, variables: Map<String, Value>) -> RenderContext {
+    RenderContext {
+      scope_layers: vec![Scope { variables }],
+      file_loader: self.file_loader.clone(),
+      base_path: self.base_path.clone(),
+      expression_limits: self.expression_limits,
+      undefined_policy: self.undefined_policy,
+      pure: self.pure,
+      warnings: self.warnings.clone(),
+      constants: self.constants.clone(),
+      sandbox: self.sandbox.clone(),
+      env: self.env.clone(),
+      stdin: self.stdin.clone(),
+      generation: next_generation(),
+      random: self.random.clone(),
+      random_seeded: self.random_seeded.clone(),
+      filters: self.filters.clone(),
+      #[cfg(feature = "regex")]
+      patterns: self.patterns.clone(),
+    }
+  }
+
+  /**
+   * Expose the environment variables of the process as the `env` object, like
+   * `{{ env.HOME }}`. A variable named `env` takes precedence over it. Reading `env` fails
+   * in pure mode.
+   */
+  pub fn enable_env(&mut self) {
+    let variables = std::env::vars().map(|(k, v)| (k, Value::String(v)));
+    self.env = Some(Value::Object(Map::from_iter(variables)));
+    self.generation = next_generation();
+  }
+
+  /**
+   * Make `<let src="stdin">` read the standard input of the process. The standard input
+   * is read at most once, and shared by all the `<let>` nodes and included files. Reading
+   * it fails in pure mode.
+   */
+  pub fn enable_stdin(&mut self) {
+    self.stdin = Some(Arc::new(OnceLock::new()));
+  }
+
+  /**
+   * Make `<let src="stdin">` read the given content instead of the standard input.
+   */
+  pub fn set_stdin_content(&mut self, content: String) {
+    self.stdin = Some(Arc::new(OnceLock::from(content)));
+  }
+
+  pub fn is_stdin_enabled(&self) -> bool {
+    self.stdin.is_some()
+  }
+
+  /**
+   * Read the content of the standard input, which is `None` if the stdin provider is not
+   * enabled.
+   */
+  pub fn read_stdin(&self) -> Option<Result<String>> {
+    let stdin = self.stdin.as_ref()?;
+    if let Err(e) = self.check_not_pure("Reading stdin") {
+      return Some(Err(e));
+    }
+    if let Some(content) = stdin.get() {
+      return Some(Ok(content.clone()));
+    }
+    let mut content = String::new();
+    if let Err(e) = std::io::stdin().read_to_string(&mut content) {
+      return Some(Err(Error {
+        kind: ErrorKind::RendererError,
+        message: "Failed to read stdin".to_string(),
+        source: Some(Box::new(e)),
+        position: None,
+        code: None,
+      }));
+    }
+    Some(Ok(stdin.get_or_init(|| content).clone()))
+  }
+
+  /**
+   * Seed the random number generator of `shuffle`, `sample` and `randint`, so the
+   * results are reproducible. The seed is shared with the clones of the context.
+   */
+  pub fn set_random_seed(&self, seed: u64) {
+    self.random.store(seed, Ordering::Relaxed);
+    self.random_seeded.store(true, Ordering::Relaxed);
+  }
+
+  pub fn is_random_seeded(&self) -> bool {
+    self.random_seeded.load(Ordering::Relaxed)
+  }
+
+  /**
+   * State of the random number generator, which changes whenever a random number is
+   * drawn.
+   */
+  pub(crate) fn random_state(&self) -> u64 {
+    self.random.load(Ordering::Relaxed)
+  }
+
+  /**
+   * Draw the next random number by SplitMix64.
+   */
+  pub(crate) fn next_random(&self) -> u64 {
+    let mut z = self
+      .random
+      .fetch_add(SPLITMIX_GAMMA, Ordering::Relaxed)
+      .wrapping_add(SPLITMIX_GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+  }
+
+  /**
+   * Register a custom filter for the `{{ value | name(arguments) }}` pipelines, which
+   * replaces the builtin filter with the same name.
+   */
+  pub fn register_filter<F: Filter + 'static>(&mut self, name: &str, filter: F) {
+    self.filters.insert(name.to_string(), Arc::new(filter));
+  }
+
+  pub fn get_filter(&self, name: &str) -> Option<&Arc<dyn Filter>> {
+    self.filters.get(name)
+  }
+
+  pub fn expression_limits(&self) -> &ExpressionLimits {
+    &self.expression_limits
+  }
+
+  /**
+   * Set the limits on the expressions evaluated in this context.
+   */
+  pub fn set_expression_limits(&mut self, limits: ExpressionLimits) {
+    self.expression_limits = limits;
+    self.generation = next_generation();
+  }
+
+  pub fn undefined_policy(&self) -> UndefinedPolicy {
+    self.undefined_policy
+  }
+
+  /**
+   * Set how references to undefined variables are evaluated in this context.
+   */
+  pub fn set_undefined_policy(&mut self, policy: UndefinedPolicy) {
+    self.undefined_policy = policy;
+    self.generation = next_generation();
+  }
+
+  pub fn is_pure(&self) -> bool {
+    self.pure
+  }
+
+  /**
+   * Reject the values and builtins depending on the environment, like `env`, in this context.
+   * It's set by the renderer at the start of each render from `RenderOptions::pure`.
+   */
+  pub fn set_pure(&mut self, pure: bool) {
+    self.pure = pure;
+    self.generation = next_generation();
+  }
+
+  /**
+   * Fail if the context is pure, where the construct, like `` `now()` ``, is not allowed.
+   */
+  pub(crate) fn check_not_pure(&self, construct: &str) -> Result<()> {
+    if !self.pure {
+      return Ok(());
+    }
+    Err(Error {
+      kind: ErrorKind::RendererError,
+      message: format!("{construct} is not allowed in pure mode"),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::AccessDenied),
+    })
+  }
+
+  /**
+   * Whether the name refers to the `env` object of the environment provider, instead of
+   * a variable or constant named `env`.
+   */
+  pub(crate) fn is_env_reference(&self, name: &str) -> bool {
+    name == "env"
+      && self.env.is_some()
+      && !self.constants.contains_key(name)
+      && self
+        .scope_layers
+        .iter()
+        .all(|scope| !scope.variables.contains_key(name))
+  }
+
+  /**
+   * Record a warning of an expression, like a reference to an undefined variable under
+   * [`UndefinedPolicy::Warn`].
+   */
+  pub(crate) fn warn(&self, warning: Error) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(message = %warning.message);
+    self.lock_warnings().push(warning);
+  }
+
+  pub(crate) fn warning_count(&self) -> usize {
+    self.lock_warnings().len()
+  }
+
+  /**
+   * Replace the warnings recorded since the first `from` ones with the results of `f`.
+   */
+  pub(crate) fn map_warnings_since<F: Fn(Error) -> Error>(&self, from: usize, f: F) {
+    let mut warnings = self.lock_warnings();
+    let from = from.min(warnings.len());
+    let recent: Vec<Error> = warnings.drain(from..).collect();
+    warnings.extend(recent.into_iter().map(f));
+  }
+
+  /**
+   * Take the warnings recorded by the expressions evaluated so far, in this context and
+   * the contexts cloned from it.
+   */
+  pub fn take_warnings(&self) -> Vec<Error> {
+    std::mem::take(&mut *self.lock_warnings())
+  }
+
+  fn lock_warnings(&self) -> std::sync::MutexGuard<'_, Vec<Error>> {
+    self.warnings.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /**
+   * Compiled patterns of the regular expression builtins by their source, which the
+   * renderer clears before each render.
+   */
+  #[cfg(feature = "regex")]
+  pub(crate) fn lock_patterns(&self) -> std::sync::MutexGuard<'_, HashMap<String, regex::Regex>> {
+    self.patterns.lock().unwrap_or_else(|e| e.into_inner())
   }
 
   /**
@@ -71,33 +441,65 @@ impl RenderContext {
     super::expression::evaluate_expression(expression, self)
   }
 
-  pub fn read_file_content(&self, filename: &str) -> Result<String> {
-    if self.file_mapping.contains_key(filename) {
-      Ok(self.file_mapping.get(filename).unwrap().to_string())
-    } else {
-      let mut file_content_buf = String::new();
-      let mut file = match std::fs::File::open(filename) {
-        Ok(f) => f,
-        Err(e) => {
-          return Err(Error {
-            kind: ErrorKind::RendererError,
-            message: format!("Failed to open file included: {filename}"),
-            source: Some(Box::new(e)),
-          });
-        }
-      };
-      match file.read_to_string(&mut file_content_buf) {
-        Ok(_) => {}
-        Err(e) => {
-          return Err(Error {
-            kind: ErrorKind::RendererError,
-            message: format!("Failed to read file included: {filename}"),
-            source: Some(Box::new(e)),
-          });
-        }
-      };
-      Ok(file_content_buf)
+  /**
+   * Evaluate the value of an expression parsed in advance by [`Expression::parse`].
+   */
+  pub fn evaluate_parsed(&self, expression: &Expression) -> Result<Value> {
+    expression.evaluate(self)
+  }
+
+  /**
+   * Set the directory to resolve relative file paths.
+   */
+  pub fn set_base_path<P: AsRef<Path>>(&mut self, base_path: P) {
+    self.base_path = base_path.as_ref().to_path_buf();
+  }
+
+  /**
+   * Resolve a file path relative to the base path, and normalize it with
+   * [`normalize_path`]. Absolute paths are not relative to the base path.
+   */
+  pub fn resolve_path(&self, filename: &str) -> String {
+    if self.base_path.as_os_str().is_empty() || is_absolute_path(filename) {
+      return normalize_path(filename);
     }
+    normalize_path(&format!("{}/{filename}", self.base_path.to_string_lossy()))
+  }
+
+  /**
+   * Set the loader of the files used by `<include>` and `src` attributes.
+   */
+  pub fn set_file_loader<L: FileLoader + 'static>(&mut self, file_loader: L) {
+    self.file_loader = Arc::new(file_loader);
+  }
+
+  pub(crate) fn set_shared_file_loader(&mut self, file_loader: Arc<dyn FileLoader>) {
+    self.file_loader = file_loader;
+  }
+
+  /**
+   * Set the restrictions on the files to load, e.g. to render untrusted documents.
+   */
+  pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+    self.sandbox = sandbox;
+  }
+
+  /**
+   * Read the content of a file, whose path is resolved relative to the base path.
+   */
+  pub fn read_file_content(&self, filename: &str) -> Result<String> {
+    let path = self.resolve_allowed_path(filename)?;
+    self.file_loader.load(&path)
+  }
+
+  /**
+   * Resolve the path of a file like [`RenderContext::resolve_path`], and check it against
+   * the sandbox.
+   */
+  pub fn resolve_allowed_path(&self, filename: &str) -> Result<String> {
+    let path = self.resolve_path(filename);
+    self.sandbox.check(&path, self.file_loader.as_ref())?;
+    Ok(path)
   }
 }
 
@@ -109,7 +511,22 @@ impl FromIterator<(String, Value)> for RenderContext {
 
     RenderContext {
       scope_layers: vec![base_scope],
-      file_mapping: HashMap::new(),
+      file_loader: Arc::new(FsLoader {}),
+      base_path: PathBuf::new(),
+      expression_limits: ExpressionLimits::default(),
+      undefined_policy: UndefinedPolicy::default(),
+      pure: false,
+      warnings: Arc::new(Mutex::new(Vec::new())),
+      constants: Map::new(),
+      sandbox: Sandbox::default(),
+      env: None,
+      stdin: None,
+      generation: next_generation(),
+      random: random_state_from_time(),
+      random_seeded: Arc::new(AtomicBool::new(false)),
+      filters: HashMap::new(),
+      #[cfg(feature = "regex")]
+      patterns: Arc::default(),
     }
   }
 }
@@ -125,7 +542,22 @@ impl<'a> FromIterator<(&'a String, &'a Value)> for RenderContext {
 
     RenderContext {
       scope_layers: vec![base_scope],
-      file_mapping: HashMap::new(),
+      file_loader: Arc::new(FsLoader {}),
+      base_path: PathBuf::new(),
+      expression_limits: ExpressionLimits::default(),
+      undefined_policy: UndefinedPolicy::default(),
+      pure: false,
+      warnings: Arc::new(Mutex::new(Vec::new())),
+      constants: Map::new(),
+      sandbox: Sandbox::default(),
+      env: None,
+      stdin: None,
+      generation: next_generation(),
+      random: random_state_from_time(),
+      random_seeded: Arc::new(AtomicBool::new(false)),
+      filters: HashMap::new(),
+      #[cfg(feature = "regex")]
+      patterns: Arc::default(),
     }
   }
 }
@@ -135,7 +567,22 @@ impl From<Map<String, Value>> for RenderContext {
     let base_scope = Scope { variables: value };
     RenderContext {
       scope_layers: vec![base_scope],
-      file_mapping: HashMap::new(),
+      file_loader: Arc::new(FsLoader {}),
+      base_path: PathBuf::new(),
+      expression_limits: ExpressionLimits::default(),
+      undefined_policy: UndefinedPolicy::default(),
+      pure: false,
+      warnings: Arc::new(Mutex::new(Vec::new())),
+      constants: Map::new(),
+      sandbox: Sandbox::default(),
+      env: None,
+      stdin: None,
+      generation: next_generation(),
+      random: random_state_from_time(),
+      random_seeded: Arc::new(AtomicBool::new(false)),
+      filters: HashMap::new(),
+      #[cfg(feature = "regex")]
+      patterns: Arc::default(),
     }
   }
 }
@@ -143,6 +590,7 @@ impl From<Map<String, Value>> for RenderContext {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::render::file_loader::InMemoryLoader;
   use serde_json::json;
   use std::collections::HashMap;
 
@@ -166,6 +614,103 @@ mod tests {
     assert_eq!(context.get_value("s"), Some(json!("s")).as_ref());
   }
 
+  #[test]
+  fn test_isolated() {
+    let mut context = RenderContext::from_iter([("a".to_owned(), json!(1))]);
+    context.set_file_loader(InMemoryLoader::from_iter([("a.poml", "<p></p>")]));
+    let Value::Object(variables) = json!({"b": 2}) else {
+      panic!()
+    };
+    let isolated = context.isolated(variables);
+    assert_eq!(isolated.get_value("a"), None);
+    assert_eq!(isolated.get_value("b"), Some(&json!(2)));
+    assert!(isolated.read_file_content("a.poml").is_ok());
+  }
+
+  #[test]
+  fn test_resolve_path() {
+    let mut context = RenderContext::from_iter([("a".to_owned(), json!(1))]);
+    assert_eq!(context.resolve_path("a.poml"), "a.poml");
+    context.set_base_path("sub");
+    assert_eq!(context.resolve_path("a.poml"), "sub/a.poml");
+    assert_eq!(context.resolve_path("../a.poml"), "a.poml");
+    assert_eq!(context.resolve_path("/b/a.poml"), "/b/a.poml");
+    context.set_base_path("C:\\prompts");
+    assert_eq!(context.resolve_path("x\\a.poml"), "C:/prompts/x/a.poml");
+    context.set_base_path("");
+    assert_eq!(context.resolve_path("./a.poml"), "a.poml");
+  }
+
+  #[test]
+  fn test_set_global_value() {
+    let mut context = RenderContext::from_iter([("a".to_owned(), json!(1))]);
+    context.push_scope();
+    context.set_global_value("b", json!(2));
+    context.set_value("c", json!(3));
+    context.pop_scope();
+    assert_eq!(context.get_value("b"), Some(&json!(2)));
+    assert_eq!(context.get_value("c"), None);
+  }
+
+  #[test]
+  fn test_generation() {
+    let mut context = RenderContext::from_iter([("a".to_owned(), json!(1))]);
+    let generation = context.generation();
+    assert_eq!(context.clone().generation(), generation);
+    context.push_scope();
+    let pushed = context.generation();
+    assert_ne!(pushed, generation);
+    context.pop_scope();
+    assert_ne!(context.generation(), pushed);
+    assert_ne!(context.generation(), generation);
+    let generation = context.generation();
+    context.set_value("a", json!(2));
+    assert_ne!(context.generation(), generation);
+    assert_ne!(
+      context.isolated(Map::new()).generation(),
+      context.generation()
+    );
+  }
+
+  #[test]
+  fn test_constants() {
+    let mut context = RenderContext::from_iter([("a".to_owned(), json!(1))]);
+    assert!(context.set_constant("a", json!(2)).is_err());
+    context.set_constant("MAX", json!(5)).unwrap();
+    assert!(context.set_constant("MAX", json!(6)).is_err());
+    context.push_scope();
+    context.set_value("MAX", json!(7));
+    assert_eq!(context.get_value("MAX"), Some(&json!(5)));
+    assert!(context.is_constant("MAX"));
+    assert!(!context.is_constant("a"));
+  }
+
+  #[test]
+  fn test_env() {
+    let mut context = RenderContext::from_iter([("a".to_owned(), json!(1))]);
+    assert_eq!(context.get_value("env"), None);
+    context.enable_env();
+    let path = std::env::var("PATH").unwrap();
+    assert_eq!(context.evaluate("env.PATH").unwrap(), json!(path));
+    context.set_pure(true);
+    assert!(context.evaluate("env.PATH").is_err());
+    context.set_value("env", json!("shadowed"));
+    assert_eq!(context.evaluate("env").unwrap(), json!("shadowed"));
+  }
+
+  #[test]
+  fn test_stdin() {
+    let mut context = RenderContext::from_iter([("a".to_owned(), json!(1))]);
+    assert!(context.read_stdin().is_none());
+    context.set_stdin_content("piped".to_owned());
+    assert!(context.is_stdin_enabled());
+    assert_eq!(context.read_stdin().unwrap().unwrap(), "piped");
+    let isolated = context.isolated(Map::new());
+    assert_eq!(isolated.read_stdin().unwrap().unwrap(), "piped");
+    context.set_pure(true);
+    assert!(context.read_stdin().unwrap().is_err());
+  }
+
   #[test]
   fn test_creation_with_json_map() {
     let Value::Object(variables) = json!({
@@ -178,4 +723,34 @@ mod tests {
     assert_eq!(context.get_value("a"), Some(json!(1)).as_ref());
     assert_eq!(context.get_value("s"), Some(json!("s")).as_ref());
   }
+
+  #[test]
+  fn test_from_serialize() {
+    #[derive(Serialize)]
+    struct User {
+      name: String,
+      tags: Vec<&'static str>,
+    }
+    let context = RenderContext::from_serialize(&HashMap::from([(
+      "user",
+      User {
+        name: "Ada".to_string(),
+        tags: vec!["admin"],
+      },
+    )]))
+    .unwrap();
+    assert_eq!(context.evaluate("user.name").unwrap(), json!("Ada"));
+    assert_eq!(context.evaluate("user.tags[0]").unwrap(), json!("admin"));
+
+    let map = HashMap::from([("a", 1)]);
+    let context = RenderContext::from_serialize(&map).unwrap();
+    assert_eq!(context.get_value("a"), Some(&json!(1)));
+
+    let error = RenderContext::from_serialize(&[1, 2]).unwrap_err();
+    assert_eq!(
+      error.message,
+      "Context should be serialized as an object, found [1,2]"
+    );
+    assert_eq!(error.code(), ErrorCode::InvalidConfig);
+  }
 }
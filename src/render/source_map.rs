@@ -0,0 +1,139 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Source maps from the spans of the rendered output to the nodes of the document they are
+ * rendered from, recorded when `RenderOptions::source_map` is enabled.
+ */
+
+use crate::error::{ErrorPosition, Span};
+use serde_json::{Value, json};
+
+/**
+ * A span of the output rendered from a node of the document.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceMapEntry {
+  /** Byte range in the output, without the leading and trailing whitespace. */
+  pub output: Span,
+  /** Where the node is in the document. */
+  pub source: ErrorPosition,
+  /** Name of the tag, or `None` for text. */
+  pub tag: Option<String>,
+  /** Nesting depth of the node, which is 0 for the root `<poml>` tag. */
+  pub depth: usize,
+  /** `data-*` metadata attributes of the tag, named without the `data-` prefix. */
+  pub data: Vec<(String, Value)>,
+}
+
+/**
+ * Entries of the output spans, in the order of the output with the enclosing nodes first.
+ *
+ * The spans are found by searching the output of each node in the output of its parent, so
+ * the output of a node transformed by its parent, like indented list items, is not mapped.
+ * Nodes of included files are mapped to the `<include>` tag.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+  pub entries: Vec<SourceMapEntry>,
+}
+
+impl SourceMap {
+  /**
+   * The innermost node whose output covers the byte offset of the output.
+   */
+  pub fn lookup(&self, offset: usize) -> Option<&SourceMapEntry> {
+    self
+      .entries
+      .iter()
+      .filter(|entry| entry.output.start <= offset && offset < entry.output.end)
+      .max_by_key(|entry| entry.depth)
+  }
+
+  pub fn to_json(&self) -> Value {
+    Value::Array(
+      self
+        .entries
+        .iter()
+        .map(|entry| {
+          json!({
+            "output": {"start": entry.output.start, "end": entry.output.end},
+            "source": {"start": entry.source.offset, "end": entry.source.end},
+            "line": entry.source.line,
+            "col": entry.source.col,
+            "tag": entry.tag,
+            "data": entry.data.iter().cloned().collect::<serde_json::Map<_, _>>(),
+          })
+        })
+        .collect(),
+    )
+  }
+}
+
+/**
+ * A node rendered in the source map mode, with its output and its rendered children.
+ */
+#[derive(Debug)]
+pub(crate) struct RenderedNode {
+  pub source: Span,
+  pub tag: Option<String>,
+  pub output: String,
+  pub data: Vec<(String, Value)>,
+  pub children: Vec<RenderedNode>,
+}
+
+/**
+ * Build the source map of the final output from the tree of the rendered nodes.
+ */
+pub(crate) fn build_source_map(doc: &[u8], root: &RenderedNode, output: &str) -> SourceMap {
+  let mut map = SourceMap::default();
+  locate(doc, root, output, (0, output.len()), 0, &mut map.entries);
+  map
+}
+
+/**
+ * Find the output of the node in the range of the final output, after the outputs of its
+ * previous siblings. Return the end of the output if found. Otherwise its children are still
+ * searched in the range, since the output may be changed by post-processing as a whole.
+ */
+fn locate(
+  doc: &[u8],
+  node: &RenderedNode,
+  output: &str,
+  (start, end): (usize, usize),
+  depth: usize,
+  entries: &mut Vec<SourceMapEntry>,
+) -> Option<usize> {
+  let text = node.output.trim();
+  let found = if text.is_empty() {
+    None
+  } else {
+    output[start..end].find(text).map(|i| start + i)
+  };
+  let range = match found {
+    Some(found) => {
+      entries.push(SourceMapEntry {
+        output: Span {
+          start: found,
+          end: found + text.len(),
+        },
+        source: ErrorPosition::from_span(doc, node.source.start, node.source.end),
+        tag: node.tag.clone(),
+        depth,
+        data: node.data.clone(),
+      });
+      (found, found + text.len())
+    }
+    None => (start, end),
+  };
+  let mut cursor = range.0;
+  for child in node.children.iter() {
+    if let Some(child_end) = locate(doc, child, output, (cursor, range.1), depth + 1, entries) {
+      cursor = child_end;
+    }
+  }
+  found.map(|_| range.1)
+}
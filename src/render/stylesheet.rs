@@ -0,0 +1,206 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/**
+ * How the default value of an attribute in the stylesheet is combined with the value set
+ * explicitly on the tag.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AttributeMerge {
+  /** The explicit value replaces the default. */
+  #[default]
+  Override,
+  /**
+   * The explicit value is appended to the default, like `className`. Strings are joined by
+   * a space and arrays are concatenated.
+   */
+  Append,
+}
+
+impl AttributeMerge {
+  pub fn from_str_value(value: &str) -> Option<AttributeMerge> {
+    match value {
+      "override" => Some(AttributeMerge::Override),
+      "append" => Some(AttributeMerge::Append),
+      _ => None,
+    }
+  }
+}
+
+/**
+ * Default attributes of the tags, so the style of a document can be set in one place and
+ * overridden locally.
+ *
+ * The stylesheet can be created from a JSON object like:
+ *
+ * ```json
+ * {
+ *   "defaults": {"*": {"className": "prompt"}, "cp": {"captionStyle": "bold"}},
+ *   "merge": {"className": "append"}
+ * }
+ * ```
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Stylesheet {
+  /**
+   * Default attributes by tag name. The defaults of `*` apply to all tags, and are merged
+   * with the defaults of the tag name.
+   */
+  pub defaults: HashMap<String, Map<String, Value>>,
+  /** How the attributes are merged, by attribute name. Attributes not in it are overridden. */
+  pub merge: HashMap<String, AttributeMerge>,
+}
+
+/** Attributes controlling the rendering flow, which can't have defaults. */
+const CONTROL_ATTRIBUTES: [&str; 3] = ["if", "for", "ifPresent"];
+
+impl Stylesheet {
+  /**
+   * Create a stylesheet from a JSON object with `defaults` and `merge` keys.
+   */
+  pub fn from_value(value: &Value) -> Result<Stylesheet> {
+    let mut stylesheet = Stylesheet::default();
+    let Some(object) = value.as_object() else {
+      return Err(stylesheet_error(
+        "the stylesheet should be an object".to_string(),
+      ));
+    };
+    for (key, value) in object {
+      match (key.as_str(), value) {
+        ("defaults", Value::Object(defaults)) => {
+          for (tag_name, attributes) in defaults {
+            let Value::Object(attributes) = attributes else {
+              return Err(stylesheet_error(format!(
+                "defaults of `{tag_name}` should be an object"
+              )));
+            };
+            stylesheet
+              .defaults
+              .insert(tag_name.clone(), attributes.clone());
+          }
+        }
+        ("merge", Value::Object(merge)) => {
+          for (attribute, mode) in merge {
+            let Some(mode) = mode.as_str().and_then(AttributeMerge::from_str_value) else {
+              return Err(stylesheet_error(format!(
+                "merge mode of `{attribute}` should be \"override\" or \"append\""
+              )));
+            };
+            stylesheet.merge.insert(attribute.clone(), mode);
+          }
+        }
+        _ => return Err(stylesheet_error(format!("invalid key `{key}`"))),
+      }
+    }
+    Ok(stylesheet)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.defaults.is_empty()
+  }
+
+  /**
+   * Apply the defaults of the tag to its evaluated attribute values.
+   */
+  pub fn apply(&self, tag_name: &str, attribute_values: &mut Vec<(String, Value)>) {
+    let mut defaults: Vec<(String, Value)> = Vec::new();
+    for selector in ["*", tag_name] {
+      let Some(attributes) = self.defaults.get(selector) else {
+        continue;
+      };
+      for (key, value) in attributes {
+        if !CONTROL_ATTRIBUTES.contains(&key.as_str()) {
+          self.merge_attribute(&mut defaults, key, value.clone());
+        }
+      }
+    }
+    for (key, value) in std::mem::replace(attribute_values, defaults) {
+      self.merge_attribute(attribute_values, &key, value);
+    }
+  }
+
+  fn merge_attribute(&self, attribute_values: &mut Vec<(String, Value)>, key: &str, value: Value) {
+    let Some(existing) = attribute_values.iter_mut().find(|(k, _)| k == key) else {
+      attribute_values.push((key.to_string(), value));
+      return;
+    };
+    let mode = self.merge.get(key).copied().unwrap_or_default();
+    existing.1 = match (mode, &mut existing.1, value) {
+      (AttributeMerge::Append, Value::String(base), Value::String(extra)) => {
+        Value::String(format!("{base} {extra}").trim().to_string())
+      }
+      (AttributeMerge::Append, Value::Array(base), Value::Array(extra)) => {
+        Value::Array(base.drain(..).chain(extra).collect())
+      }
+      (AttributeMerge::Append, Value::Array(base), extra) => {
+        Value::Array(base.drain(..).chain([extra]).collect())
+      }
+      (_, _, value) => value,
+    };
+  }
+}
+
+fn stylesheet_error(message: String) -> Error {
+  Error {
+    kind: ErrorKind::RendererError,
+    message: format!("Invalid stylesheet: {message}"),
+    source: None,
+    position: None,
+    code: Some(ErrorCode::InvalidConfig),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_apply() {
+    let stylesheet = Stylesheet::from_value(&json!({
+      "defaults": {
+        "*": {"className": "prompt", "if": false},
+        "cp": {"captionStyle": "bold", "className": "section", "tags": ["a"]}
+      },
+      "merge": {"className": "append", "tags": "append"}
+    }))
+    .unwrap();
+
+    let mut attribute_values = vec![
+      ("captionStyle".to_string(), json!("plain")),
+      ("className".to_string(), json!("local")),
+      ("tags".to_string(), json!(["b"])),
+    ];
+    stylesheet.apply("cp", &mut attribute_values);
+    assert_eq!(
+      attribute_values,
+      vec![
+        ("className".to_string(), json!("prompt section local")),
+        ("captionStyle".to_string(), json!("plain")),
+        ("tags".to_string(), json!(["a", "b"])),
+      ]
+    );
+
+    let mut attribute_values = vec![];
+    stylesheet.apply("p", &mut attribute_values);
+    assert_eq!(
+      attribute_values,
+      vec![("className".to_string(), json!("prompt"))]
+    );
+  }
+
+  #[test]
+  fn test_from_value_errors() {
+    assert!(Stylesheet::from_value(&json!([])).is_err());
+    assert!(Stylesheet::from_value(&json!({"default": {}})).is_err());
+    assert!(Stylesheet::from_value(&json!({"defaults": {"p": 1}})).is_err());
+    assert!(Stylesheet::from_value(&json!({"merge": {"className": "join"}})).is_err());
+  }
+}
@@ -0,0 +1,30 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::Result;
+
+/**
+ * Summarize content that exceeds the token budget of a `<summary>` tag.
+ *
+ * The renderer handles the budgeting and caching, and the implementor supplies the model
+ * call. Renders are synchronous, so an async model client should block on its future here,
+ * e.g. with the `block_on` of its runtime.
+ */
+pub trait Summarizer: Send + Sync {
+  /**
+   * Summarize the text in about `max_tokens` tokens.
+   */
+  fn summarize(&self, text: &str, max_tokens: usize) -> Result<String>;
+}
+
+impl<F> Summarizer for F
+where
+  F: Fn(&str, usize) -> Result<String> + Send + Sync,
+{
+  fn summarize(&self, text: &str, max_tokens: usize) -> Result<String> {
+    self(text, max_tokens)
+  }
+}
@@ -3,6 +3,16 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
+
+/*!
+ * Caption attributes shared by the captioned components, like `captionStyle` and
+ * `captionColon`. Tag renderers should use them to interpret the attributes consistently.
+ */
+
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use crate::render::attr::{
+  get_attribute, get_bool_attribute, get_str_attribute, get_text_attribute,
+};
 use serde_json::Value;
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -31,12 +41,7 @@ pub fn get_caption_style_and_colon(
  * or invalid, the default one will be used.
  */
 fn get_caption_style(attribute_values: &[(String, Value)], default: CaptionStyle) -> CaptionStyle {
-  let caption_style_str = attribute_values
-    .iter()
-    .find(|v| v.0 == "captionStyle")
-    .map(|(_, value)| value.as_str().unwrap());
-
-  match caption_style_str {
+  match get_str_attribute(attribute_values, "captionStyle") {
     Some("hidden") => CaptionStyle::Hidden,
     Some("bold") => CaptionStyle::Bold,
     Some("header") => CaptionStyle::Header,
@@ -49,19 +54,146 @@ fn get_caption_style(attribute_values: &[(String, Value)], default: CaptionStyle
  * Get caption colon value from the attributes.
  *
  * By default, it will be true if caption style is plain or bold, and false for
- * other caption styles.
+ * other caption styles. The default of a tag could be changed by the stylesheet, like
+ * `{"defaults": {"hint": {"captionColon": false}}}`.
  */
 fn get_caption_colon_value(
   attribute_values: &[(String, Value)],
   caption_style: CaptionStyle,
 ) -> bool {
-  use crate::render::utils::is_false_value;
-  let caption_colon_str = attribute_values
-    .iter()
-    .find(|v| v.0 == "captionColon")
-    .map(|(_, value)| value);
-  match caption_colon_str {
-    Some(Value::String(v)) => !is_false_value(v),
-    _ => matches!(caption_style, CaptionStyle::Plain | CaptionStyle::Bold),
+  match get_bool_attribute(attribute_values, "captionColon") {
+    Some(v) => v,
+    None => matches!(caption_style, CaptionStyle::Plain | CaptionStyle::Bold),
+  }
+}
+
+/**
+ * Transform of the caption text by the `captionTextTransform` attribute.
+ */
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum CaptionTextTransform {
+  None,
+  /** Upper-case the caption. */
+  Upper,
+  /** Upper-case the first letter of each word. */
+  Capitalize,
+  /** Upper-case the captions of level 1 headings and capitalize the deeper ones. */
+  Level,
+}
+
+impl CaptionTextTransform {
+  pub fn apply(&self, caption: &str, heading_level: usize) -> String {
+    match self {
+      CaptionTextTransform::None => caption.to_string(),
+      CaptionTextTransform::Upper => caption.to_uppercase(),
+      CaptionTextTransform::Level if heading_level <= 1 => caption.to_uppercase(),
+      CaptionTextTransform::Capitalize | CaptionTextTransform::Level => caption
+        .split(' ')
+        .map(|word| {
+          let mut chars = word.chars();
+          match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => String::new(),
+          }
+        })
+        .collect::<Vec<_>>()
+        .join(" "),
+    }
+  }
+}
+
+/**
+ * What follows the caption by the `captionEnding` attribute.
+ */
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum CaptionEnding {
+  /** A colon, and the content on the same line. */
+  Colon,
+  /** The content in the next paragraph. */
+  Newline,
+  /** A colon, and the content in the next paragraph. */
+  ColonNewline,
+  /** The content on the same line. */
+  None,
+}
+
+impl CaptionEnding {
+  pub fn has_colon(&self) -> bool {
+    matches!(self, CaptionEnding::Colon | CaptionEnding::ColonNewline)
+  }
+
+  pub fn has_newline(&self) -> bool {
+    matches!(self, CaptionEnding::Newline | CaptionEnding::ColonNewline)
+  }
+}
+
+/**
+ * Formatting options of a captioned component.
+ */
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct CaptionOptions {
+  pub style: CaptionStyle,
+  pub ending: CaptionEnding,
+  pub text_transform: CaptionTextTransform,
+  /** Heading level of the caption set by `captionLevel`, overriding the structural one. */
+  pub level: Option<usize>,
+}
+
+/**
+ * Get the caption options from the attributes: `captionStyle`, `captionEnding`,
+ * `captionTextTransform` and `captionLevel`. Without `captionEnding`, the ending follows
+ * `captionColon`, and headers and plain captions are followed by a new paragraph while
+ * bold captions are followed by the content on the same line.
+ */
+pub fn get_caption_options(
+  attribute_values: &[(String, Value)],
+  default_style: CaptionStyle,
+) -> Result<CaptionOptions> {
+  let (style, colon) = get_caption_style_and_colon(attribute_values, default_style);
+  let ending = match get_str_attribute(attribute_values, "captionEnding") {
+    Some("colon") => CaptionEnding::Colon,
+    Some("newline") => CaptionEnding::Newline,
+    Some("colon-newline") => CaptionEnding::ColonNewline,
+    Some("none") => CaptionEnding::None,
+    _ => match (style, colon) {
+      (CaptionStyle::Bold, true) => CaptionEnding::Colon,
+      (CaptionStyle::Bold, false) => CaptionEnding::None,
+      (_, true) => CaptionEnding::ColonNewline,
+      (_, false) => CaptionEnding::Newline,
+    },
+  };
+  let text_transform = match get_str_attribute(attribute_values, "captionTextTransform") {
+    Some("upper") => CaptionTextTransform::Upper,
+    Some("capitalize") => CaptionTextTransform::Capitalize,
+    Some("level") => CaptionTextTransform::Level,
+    _ => CaptionTextTransform::None,
+  };
+  Ok(CaptionOptions {
+    style,
+    ending,
+    text_transform,
+    level: get_level_attribute(attribute_values, "captionLevel")?,
+  })
+}
+
+/**
+ * Get a heading level attribute like `level` of `<h>`, which should be a positive integer.
+ */
+pub fn get_level_attribute(
+  attribute_values: &[(String, Value)],
+  name: &str,
+) -> Result<Option<usize>> {
+  let Some(value) = get_attribute(attribute_values, name) else {
+    return Ok(None);
+  };
+  match get_text_attribute(attribute_values, name).map(|v| v.trim().parse::<usize>()) {
+    Some(Ok(level)) if level >= 1 => Ok(Some(level)),
+    _ => Err(Error {
+      kind: ErrorKind::RendererError,
+      message: format!("`{name}` attribute should be a positive integer: {value}"),
+      source: None,
+      position: None,
+      code: Some(ErrorCode::InvalidAttributeValue),
+    }),
   }
 }
@@ -4,18 +4,221 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use super::TagRenderer;
-use super::attribute_utils::{CaptionStyle, get_caption_style_and_colon};
-use crate::error::{Error, ErrorKind, Result};
-use crate::render::utils;
+use super::attribute_utils::{
+  CaptionOptions, CaptionStyle, get_caption_options, get_caption_style_and_colon,
+  get_level_attribute,
+};
+use super::{ChildrenOutput, TagRenderState, TagRenderer, render_tag_with_children_taken};
+use crate::error::{Error, ErrorCode, ErrorKind, Result};
+use crate::render::attr;
 use crate::{PomlNode, PomlTagNode};
 use serde_json::Value;
 use std::collections::HashMap;
+/**
+ * Tags supported by the markdown tag renderer.
+ */
+pub const SUPPORTED_TAGS: &[&str] = &[
+  "poml",
+  "p",
+  "pre",
+  "br",
+  "b",
+  "i",
+  "s",
+  "strike",
+  "u",
+  "sub",
+  "sup",
+  "q",
+  "kbd",
+  "code",
+  "h",
+  "section",
+  "cp",
+  "role",
+  "task",
+  "output-format",
+  "examples",
+  "example",
+  "input",
+  "output",
+  "hint",
+  "stepwise-instructions",
+  "meta",
+  "item",
+  "list",
+  "table",
+  "qa",
+];
+
+/**
+ * Title and default caption style of the components rendered as captioned blocks.
+ */
+fn captioned_block(tag_name: &str) -> Option<(&'static str, CaptionStyle)> {
+  match tag_name {
+    "role" => Some(("Role", CaptionStyle::Header)),
+    "task" => Some(("Task", CaptionStyle::Header)),
+    "output-format" => Some(("Output Format", CaptionStyle::Header)),
+    "examples" => Some(("Examples", CaptionStyle::Header)),
+    "stepwise-instructions" => Some(("Stepwise Instructions", CaptionStyle::Header)),
+    "example" => Some(("Example", CaptionStyle::Hidden)),
+    "input" => Some(("Input", CaptionStyle::Hidden)),
+    "output" => Some(("Output", CaptionStyle::Hidden)),
+    "hint" => Some(("Hint", CaptionStyle::Bold)),
+    _ => None,
+  }
+}
+
+/**
+ * How an inline tag like `<b>` is rendered.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum InlineStyle {
+  /** Wrap the content with the prefix and the suffix, like `**` and `**`. */
+  Wrap(String, String),
+  /** Wrap the content in the HTML element with the name, like `<u>...</u>`. */
+  Html(String),
+  /** Render the content only. */
+  Plain,
+}
+
+impl InlineStyle {
+  /** Wrap the content with the same delimiter on both sides. */
+  pub fn delimiter(delimiter: &str) -> InlineStyle {
+    InlineStyle::Wrap(delimiter.to_string(), delimiter.to_string())
+  }
+
+  /** Wrap the content at the end of the buffer, from the byte offset. */
+  fn wrap(&self, out: &mut String, start: usize) {
+    match self {
+      InlineStyle::Wrap(prefix, suffix) => {
+        out.insert_str(start, prefix);
+        out.push_str(suffix);
+      }
+      InlineStyle::Html(name) => {
+        out.insert_str(start, &format!("<{name}>"));
+        out.push_str(&format!("</{name}>"));
+      }
+      InlineStyle::Plain => {}
+    }
+  }
+}
+
+/**
+ * Styles of the inline tags. Markdown has no syntax of underline, subscript, superscript
+ * and keys, so they are HTML elements by default, which could be set to a Markdown
+ * fallback like `InlineStyle::delimiter("*")` for targets without HTML.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlineTagStyles {
+  /** `<b>`, `**` by default. */
+  pub bold: InlineStyle,
+  /** `<i>`, `*` by default. */
+  pub italic: InlineStyle,
+  /** `<s>` and `<strike>`, `~~` by default. */
+  pub strikethrough: InlineStyle,
+  /** `<u>`, `<u>` by default. */
+  pub underline: InlineStyle,
+  /** `<sub>`, `<sub>` by default. */
+  pub subscript: InlineStyle,
+  /** `<sup>`, `<sup>` by default. */
+  pub superscript: InlineStyle,
+  /** `<q>`, smart quotes `“` and `”` by default. */
+  pub quote: InlineStyle,
+  /** `<kbd>`, `<kbd>` by default. */
+  pub keyboard: InlineStyle,
+}
+
+impl Default for InlineTagStyles {
+  fn default() -> Self {
+    InlineTagStyles {
+      bold: InlineStyle::delimiter("**"),
+      italic: InlineStyle::delimiter("*"),
+      strikethrough: InlineStyle::delimiter("~~"),
+      underline: InlineStyle::Html("u".to_string()),
+      subscript: InlineStyle::Html("sub".to_string()),
+      superscript: InlineStyle::Html("sup".to_string()),
+      quote: InlineStyle::Wrap("“".to_string(), "”".to_string()),
+      keyboard: InlineStyle::Html("kbd".to_string()),
+    }
+  }
+}
+
+/**
+ * Line ending of the output.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LineEnding {
+  #[default]
+  Lf,
+  CrLf,
+}
+
+/**
+ * Options of the layout of the output text.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriterOptions {
+  /** Separator after paragraphs, headings, captions and `<br>`, `"\n\n"` by default. */
+  pub paragraph_separator: String,
+  /** Line ending of the output, applied after the whole document is rendered. */
+  pub line_ending: LineEnding,
+  /** Maximum number of consecutive blank lines in the output, unlimited by default. */
+  pub max_blank_lines: Option<usize>,
+}
+
+impl Default for WriterOptions {
+  fn default() -> Self {
+    WriterOptions {
+      paragraph_separator: "\n\n".to_string(),
+      line_ending: LineEnding::Lf,
+      max_blank_lines: None,
+    }
+  }
+}
+
+impl WriterOptions {
+  /**
+   * Apply the options of the whole output to the rendered document. Applying them again
+   * doesn't change the text, so included documents can be rendered with them as well.
+   */
+  fn finish(&self, text: String) -> String {
+    if self.line_ending == LineEnding::Lf && self.max_blank_lines.is_none() {
+      return text;
+    }
+    let mut answer = String::with_capacity(text.len());
+    let mut blank_lines = 0;
+    let mut lines = text.split('\n').peekable();
+    while let Some(line) = lines.next() {
+      let line = line.strip_suffix('\r').unwrap_or(line);
+      if line.is_empty() && lines.peek().is_some() {
+        blank_lines += 1;
+        if self.max_blank_lines.is_some_and(|max| blank_lines > max) {
+          continue;
+        }
+      } else {
+        blank_lines = 0;
+      }
+      answer += line;
+      if lines.peek().is_some() {
+        answer += match self.line_ending {
+          LineEnding::Lf => "\n",
+          LineEnding::CrLf => "\r\n",
+        };
+      }
+    }
+    answer
+  }
+}
+
 /**
  * The default renderer to render markdown content.
  */
-#[derive(Clone)]
-pub struct MarkdownTagRenderer {}
+#[derive(Clone, Default)]
+pub struct MarkdownTagRenderer {
+  pub inline_styles: InlineTagStyles,
+  pub writer: WriterOptions,
+}
 
 impl TagRenderer for MarkdownTagRenderer {
   fn render_tag(
@@ -25,53 +228,210 @@ impl TagRenderer for MarkdownTagRenderer {
     children_result: Vec<String>,
     source_buf: &[u8],
   ) -> Result<String> {
+    self.render_tag_in_state(
+      tag,
+      attribute_values,
+      children_result,
+      source_buf,
+      &TagRenderState::default(),
+    )
+  }
+
+  fn render_tag_in_state(
+    &self,
+    tag: &PomlTagNode,
+    attribute_values: &[(String, Value)],
+    children_result: Vec<String>,
+    source_buf: &[u8],
+    state: &TagRenderState,
+  ) -> Result<String> {
+    if matches!(tag.name, "example" | "input" | "output") {
+      return self.render_example_tag(tag.name, attribute_values, children_result, state);
+    }
+    if let Some((title, default_style)) = captioned_block(tag.name) {
+      return self.render_captioned_block_tag(
+        title,
+        default_style,
+        attribute_values,
+        children_result,
+        state,
+      );
+    }
     match tag.name {
       "poml" => self.render_poml_tag(tag, children_result),
-      "p" => Ok(self.render_p_tag(children_result)),
+      "p" | "pre" => Ok(self.render_p_tag(children_result)),
       "br" => Ok(self.render_br_tag()),
-      "b" => Ok(self.render_bold_tag(children_result)),
-      "i" => Ok(self.render_italic_tag(children_result)),
-      "s" | "strike" => Ok(self.render_strikethrough_tag(children_result)),
-      "code" => Ok(self.render_code_tag(tag, attribute_values, source_buf)),
-      "h" => Ok(self.render_header_tag(children_result)),
-      "section" => Ok(self.render_section_tag(children_result)),
-      "cp" => self.render_captioned_paragraph_tag(attribute_values, children_result),
-      "role" => Ok(self.render_intention_block_tag("Role", attribute_values, children_result)),
-      "task" => Ok(self.render_intention_block_tag("Task", attribute_values, children_result)),
-      "output-format" => {
-        Ok(self.render_intention_block_tag("Output Format", attribute_values, children_result))
-      }
-      "examples" => {
-        Ok(self.render_intention_block_tag("Examples", attribute_values, children_result))
-      }
-      "example" => {
-        Ok(self.render_title_default_hidden_block_tag("Example", attribute_values, children_result))
+      "b" | "i" | "s" | "strike" | "u" | "sub" | "sup" | "q" | "kbd" => {
+        Ok(self.render_inline_tag(tag.name, children_result))
       }
-      "input" => {
-        Ok(self.render_title_default_hidden_block_tag("Input", attribute_values, children_result))
-      }
-      "output" => {
-        Ok(self.render_title_default_hidden_block_tag("Output", attribute_values, children_result))
-      }
-      "hint" => {
-        Ok(self.render_title_default_bold_block_tag("Hint", attribute_values, children_result))
-      }
-      "stepwise-instructions" => Ok(self.render_intention_block_tag(
-        "Stepwise Instructions",
-        attribute_values,
-        children_result,
-      )),
+      "code" => Ok(self.render_code_tag(tag, attribute_values, children_result, source_buf)),
+      "h" => self.render_header_tag(attribute_values, children_result, state),
+      "section" => Ok(children_result.join("")),
+      "cp" => self.render_captioned_paragraph_tag(attribute_values, children_result, state),
       "meta" => Ok("".to_owned()),
-      "item" => Ok(self.render_item_tag(children_result)),
-      "list" => self.render_list_tag(tag, attribute_values, children_result),
-      "table" => self.render_table_tag(attribute_values),
+      "item" => Ok(self.render_item_tag(tag, attribute_values, children_result, state)),
+      "list" => self.render_list_tag(tag, attribute_values, children_result, state),
+      "table" => self.render_table_tag(attribute_values, state),
+      "qa" => self.render_qa_tag(attribute_values, children_result, state),
       _ => Err(Error {
         kind: ErrorKind::RendererError,
         message: format!("Unknown tag: <{}>", tag.name),
         source: None,
+        position: None,
+        code: Some(ErrorCode::UnknownTag),
       }),
     }
   }
+
+  /**
+   * Paragraphs, headings, sections and inline tags wrap the outputs of their children in
+   * the buffer, and the other tags take them out of it.
+   */
+  fn render_tag_into(
+    &self,
+    tag: &PomlTagNode,
+    attribute_values: &[(String, Value)],
+    children: &ChildrenOutput,
+    out: &mut String,
+    source_buf: &[u8],
+    state: &TagRenderState,
+  ) -> Result<()> {
+    match tag.name {
+      "p" | "pre" => out.push_str(&self.writer.paragraph_separator),
+      "section" => {}
+      "h" => {
+        let level = get_level_attribute(attribute_values, "level")?.unwrap_or(state.heading_level);
+        out.insert_str(children.start, &format!("{} ", "#".repeat(level.min(6))));
+        out.push_str(&self.writer.paragraph_separator);
+      }
+      "b" | "i" | "s" | "strike" | "u" | "sub" | "sup" | "q" | "kbd" => {
+        self.inline_style(tag.name).wrap(out, children.start)
+      }
+      _ => {
+        return render_tag_with_children_taken(
+          self,
+          tag,
+          attribute_values,
+          children,
+          out,
+          source_buf,
+          state,
+        );
+      }
+    }
+    Ok(())
+  }
+
+  fn is_supported_tag(&self, tag_name: &str) -> bool {
+    SUPPORTED_TAGS.contains(&tag_name)
+  }
+
+  /**
+   * Children of `<section>` and the components captioned by headers are one level deeper.
+   */
+  fn is_heading_scope(&self, tag_name: &str, attribute_values: &[(String, Value)]) -> bool {
+    let default_style = match tag_name {
+      "section" => return true,
+      "cp" => CaptionStyle::Header,
+      _ => match captioned_block(tag_name) {
+        Some((_, default_style)) => default_style,
+        None => return false,
+      },
+    };
+    get_caption_style_and_colon(attribute_values, default_style).0 == CaptionStyle::Header
+  }
+
+  /**
+   * `<examples>` and `<example>` pass down the numbering, chat mode and captions of the
+   * examples, inputs and outputs in them.
+   */
+  fn scope_attributes(
+    &self,
+    tag_name: &str,
+    attribute_values: &[(String, Value)],
+  ) -> Vec<(String, Value)> {
+    if !matches!(tag_name, "examples" | "example") {
+      return Vec::new();
+    }
+    attribute_values
+      .iter()
+      .filter(|(key, _)| EXAMPLE_SCOPE_ATTRIBUTES.contains(&key.as_str()))
+      .cloned()
+      .collect()
+  }
+
+  /**
+   * Escape the Markdown special characters with backslashes.
+   */
+  fn escape_text(&self, text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+      if MARKDOWN_SPECIAL_CHARS.contains(&c) {
+        escaped.push('\\');
+      }
+      escaped.push(c);
+    }
+    escaped
+  }
+
+  /**
+   * Items of `decimal` lists are indented by 4 spaces to fit the numbers, and the items
+   * of the other lists by 2 spaces.
+   */
+  fn list_item_indent(&self, tag_name: &str, attribute_values: &[(String, Value)]) -> usize {
+    match tag_name {
+      "list" if get_list_style(attribute_values) == "decimal" => 4,
+      "list" => 2,
+      _ => 0,
+    }
+  }
+  fn paragraph_separator(&self) -> &str {
+    &self.writer.paragraph_separator
+  }
+
+  fn is_root_concatenated(&self) -> bool {
+    // Line endings and blank lines are fixed up over the whole output
+    self.writer.line_ending == LineEnding::Lf && self.writer.max_blank_lines.is_none()
+  }
+}
+
+/**
+ * Characters with a meaning in Markdown, including `|` of tables.
+ */
+const MARKDOWN_SPECIAL_CHARS: &[char] = &['\\', '`', '*', '_', '|', '#', '[', ']', '<', '>'];
+
+/**
+ * Attributes of `<examples>` and `<example>` applying to the tags in them.
+ */
+const EXAMPLE_SCOPE_ATTRIBUTES: [&str; 4] = ["numbered", "chat", "inputCaption", "outputCaption"];
+
+/**
+ * Find the attribute of the tag, or the one passed down by the enclosing tags.
+ */
+fn get_scoped_attribute<'v>(
+  attribute_values: &'v [(String, Value)],
+  state: &'v TagRenderState,
+  name: &str,
+) -> Option<&'v Value> {
+  attr::get_attribute(attribute_values, name).or_else(|| state.scope_attribute(name))
+}
+
+/**
+ * Escape the text of a table cell, so `|` doesn't end the cell and line breaks don't end
+ * the row.
+ */
+fn escape_table_cell(text: &str) -> String {
+  text
+    .replace('|', "\\|")
+    .replace("\r\n", "<br>")
+    .replace(['\r', '\n'], "<br>")
+}
+
+fn get_list_style(attribute_values: &[(String, Value)]) -> &str {
+  match attribute_values.iter().find(|v| v.0 == "listStyle") {
+    Some((_, Value::String(v))) => v,
+    _ => "dash",
+  }
 }
 
 impl MarkdownTagRenderer {
@@ -86,6 +446,8 @@ impl MarkdownTagRenderer {
         kind: ErrorKind::RendererError,
         message: "Missing children result in rendering <poml>.".to_string(),
         source: None,
+        position: None,
+        code: None,
       });
     }
 
@@ -96,57 +458,78 @@ impl MarkdownTagRenderer {
       }
     }
 
-    Ok(answer)
+    Ok(self.writer.finish(answer))
   }
 
   fn render_p_tag(&self, children_result: Vec<String>) -> String {
-    format!("{}\n\n", children_result.join(""))
+    children_result.join("") + &self.writer.paragraph_separator
   }
 
   fn render_br_tag(&self) -> String {
-    "\n\n".to_string()
+    self.writer.paragraph_separator.clone()
   }
 
-  fn render_bold_tag(&self, children_result: Vec<String>) -> String {
-    format!("**{}**", children_result.join(""))
+  fn render_inline_tag(&self, tag_name: &str, children_result: Vec<String>) -> String {
+    let mut content = children_result.join("");
+    self.inline_style(tag_name).wrap(&mut content, 0);
+    content
   }
 
-  fn render_italic_tag(&self, children_result: Vec<String>) -> String {
-    format!("*{}*", children_result.join(""))
-  }
-
-  fn render_strikethrough_tag(&self, children_result: Vec<String>) -> String {
-    format!("~~{}~~", children_result.join(""))
+  fn inline_style(&self, tag_name: &str) -> &InlineStyle {
+    let styles = &self.inline_styles;
+    match tag_name {
+      "b" => &styles.bold,
+      "i" => &styles.italic,
+      "s" | "strike" => &styles.strikethrough,
+      "u" => &styles.underline,
+      "sub" => &styles.subscript,
+      "sup" => &styles.superscript,
+      "q" => &styles.quote,
+      _ => &styles.keyboard,
+    }
   }
 
+  /**
+   * Render `<code>` with the rendered children, whose white space is preserved. With the
+   * `raw` attribute, the source of the children is rendered as is, including the tags.
+   */
   fn render_code_tag(
     &self,
     tag: &PomlTagNode,
     attribute_values: &[(String, Value)],
+    children_result: Vec<String>,
     source_buf: &[u8],
   ) -> String {
-    let tag_code =
-      str::from_utf8(&source_buf[tag.original_pos.start..tag.original_pos.end]).unwrap();
-    let code_start = tag_code.find('>').unwrap() + 1;
-    let code_end = tag_code.rfind("</").unwrap();
-    let code_content = &tag_code[code_start..code_end];
-    let mut inline = false;
-    let mut lang: Option<&str> = None;
-    for (attr_key, attr_value) in attribute_values.iter() {
-      match attr_key.as_str() {
-        "inline" => {
-          if !utils::is_false_value(attr_value.as_str().unwrap()) {
-            inline = true;
-          }
-        }
-        "lang" => {
-          lang = Some(attr_value.as_str().unwrap());
-        }
-        _ => {}
-      }
-    }
+    let code_content = if attr::get_bool_attribute(attribute_values, "raw").unwrap_or(false) {
+      let tag_code = &source_buf[tag.original_pos.start..tag.original_pos.end];
+      let code_start = tag_code
+        .iter()
+        .position(|c| *c == b'>')
+        .map_or(0, |p| p + 1);
+      let code_end = tag_code
+        .windows(2)
+        .rposition(|w| w == b"</")
+        .filter(|end| *end >= code_start)
+        .unwrap_or(code_start);
+      String::from_utf8_lossy(&tag_code[code_start..code_end]).into_owned()
+    } else {
+      children_result.join("")
+    };
+    let inline = attr::get_bool_attribute(attribute_values, "inline").unwrap_or(false);
+    let lang = attr::get_text_attribute(attribute_values, "lang");
     if inline {
-      format!("`{code_content}`")
+      // The delimiter is longer than the backtick runs in the code, which would end it
+      let longest_run = code_content
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+      let delimiter = "`".repeat(longest_run + 1);
+      if code_content.starts_with('`') || code_content.ends_with('`') {
+        format!("{delimiter} {code_content} {delimiter}")
+      } else {
+        format!("{delimiter}{code_content}{delimiter}")
+      }
     } else {
       let header = match lang {
         Some(l) => format!("```{l}\n"),
@@ -156,79 +539,176 @@ impl MarkdownTagRenderer {
     }
   }
 
-  fn render_intention_block_tag(
+  fn render_captioned_block_tag(
     &self,
     title: &str,
+    default_style: CaptionStyle,
     attribute_values: &[(String, Value)],
     children_result: Vec<String>,
-  ) -> String {
-    let (caption_style, caption_colon) =
-      get_caption_style_and_colon(attribute_values, CaptionStyle::Header);
-    self.render_captioned_component(caption_style, caption_colon, title, children_result)
+    state: &TagRenderState,
+  ) -> Result<String> {
+    let options = get_caption_options(attribute_values, default_style)?;
+    Ok(self.render_captioned_component(&options, title, children_result, state))
   }
 
-  fn render_title_default_hidden_block_tag(
+  /**
+   * Render `<example>`, `<input>` and `<output>`, whose captions are hidden by default.
+   * Examples are captioned like "Example 2" if they are `numbered`. In `chat` mode, inputs
+   * and outputs are captioned as human and AI messages, and captions set by `inputCaption`
+   * and `outputCaption` are shown too.
+   */
+  fn render_example_tag(
     &self,
-    title: &str,
+    tag_name: &str,
     attribute_values: &[(String, Value)],
     children_result: Vec<String>,
-  ) -> String {
-    let (caption_style, caption_colon) =
-      get_caption_style_and_colon(attribute_values, CaptionStyle::Hidden);
-    self.render_captioned_component(caption_style, caption_colon, title, children_result)
+    state: &TagRenderState,
+  ) -> Result<String> {
+    let is_true =
+      |name: &str| get_scoped_attribute(attribute_values, state, name).is_some_and(attr::is_truthy);
+    let caption_of = |name: &str| {
+      get_scoped_attribute(attribute_values, state, name).and_then(|value| match value {
+        Value::String(s) => Some(s.clone()),
+        _ => None,
+      })
+    };
+    let (title, default_style) = match tag_name {
+      "example" if is_true("numbered") => (
+        format!("Example {}", state.sibling_index + 1),
+        CaptionStyle::Header,
+      ),
+      "example" => ("Example".to_string(), CaptionStyle::Hidden),
+      _ => {
+        let (caption_name, chat_title, title) = match tag_name {
+          "input" => ("inputCaption", "Human", "Input"),
+          _ => ("outputCaption", "AI", "Output"),
+        };
+        match caption_of(caption_name) {
+          Some(caption) => (caption, CaptionStyle::Bold),
+          None if is_true("chat") => (chat_title.to_string(), CaptionStyle::Bold),
+          None => (title.to_string(), CaptionStyle::Hidden),
+        }
+      }
+    };
+    self.render_captioned_block_tag(
+      &title,
+      default_style,
+      attribute_values,
+      children_result,
+      state,
+    )
   }
 
-  fn render_title_default_bold_block_tag(
+  /**
+   * Render `<qa question="...">` with the children as the answer, captioned by `Q` and `A`,
+   * or as human and AI messages in `chat` mode.
+   */
+  fn render_qa_tag(
     &self,
-    title: &str,
     attribute_values: &[(String, Value)],
     children_result: Vec<String>,
-  ) -> String {
-    let (caption_style, caption_colon) =
-      get_caption_style_and_colon(attribute_values, CaptionStyle::Bold);
-    self.render_captioned_component(caption_style, caption_colon, title, children_result)
-  }
-
-  fn render_header_tag(&self, children_result: Vec<String>) -> String {
-    format!("# {}\n\n", children_result.join(""))
+    state: &TagRenderState,
+  ) -> Result<String> {
+    let Some(question) = attr::get_text_attribute(attribute_values, "question") else {
+      return Err(Error {
+        kind: ErrorKind::RendererError,
+        message: "Missing `question` attribute for the <qa> tag.".to_string(),
+        source: None,
+        position: None,
+        code: Some(ErrorCode::MissingAttribute),
+      });
+    };
+    let chat = get_scoped_attribute(attribute_values, state, "chat").is_some_and(attr::is_truthy);
+    let (question_caption, answer_caption) = if chat { ("Human", "AI") } else { ("Q", "A") };
+    let options = get_caption_options(attribute_values, CaptionStyle::Bold)?;
+    let mut answer = self.render_captioned_component(
+      &options,
+      question_caption,
+      vec![question.into_owned()],
+      state,
+    );
+    // Header captions don't end with a newline, so the answer needs a line of its own.
+    if !answer.ends_with('\n') {
+      answer.push('\n');
+    }
+    answer += &self.render_captioned_component(&options, answer_caption, children_result, state);
+    Ok(answer)
   }
 
-  fn render_section_tag(&self, children_result: Vec<String>) -> String {
-    let mut answer = String::new();
-    for child_text in children_result.iter() {
-      if child_text.starts_with("#") {
-        answer += &format!("#{child_text}");
-      } else {
-        answer += child_text;
-      }
-    }
-    answer
+  /**
+   * Render `<h>` at the heading level of the state, or the level set by the `level`
+   * attribute. Markdown only has 6 heading levels, so deeper levels are rendered as 6.
+   */
+  fn render_header_tag(
+    &self,
+    attribute_values: &[(String, Value)],
+    children_result: Vec<String>,
+    state: &TagRenderState,
+  ) -> Result<String> {
+    let level = get_level_attribute(attribute_values, "level")?.unwrap_or(state.heading_level);
+    Ok(format!(
+      "{} {}{}",
+      "#".repeat(level.min(6)),
+      children_result.join(""),
+      self.writer.paragraph_separator
+    ))
   }
 
   fn render_captioned_paragraph_tag(
     &self,
     attribute_values: &[(String, Value)],
     children_result: Vec<String>,
+    state: &TagRenderState,
   ) -> Result<String> {
-    let Some((_, Value::String(caption))) = attribute_values.iter().find(|v| v.0 == "caption")
-    else {
+    let Some(caption) = attr::get_text_attribute(attribute_values, "caption") else {
       return Err(Error {
         kind: ErrorKind::RendererError,
         message: "Missing `caption` attribute for the <cp> tag.".to_string(),
         source: None,
+        position: None,
+        code: Some(ErrorCode::MissingAttribute),
       });
     };
-    let (caption_style, caption_colon) =
-      get_caption_style_and_colon(attribute_values, CaptionStyle::Header);
-    Ok(self.render_captioned_component(caption_style, caption_colon, caption, children_result))
+    let options = get_caption_options(attribute_values, CaptionStyle::Header)?;
+    Ok(self.render_captioned_component(&options, &caption, children_result, state))
   }
 
-  fn render_item_tag(&self, children_result: Vec<String>) -> String {
-    let raw_content = children_result.join("");
-    let lines: Vec<&str> = raw_content.split('\n').collect();
-    let mut new_content = lines.first().map_or("", |v| v).to_string();
-    for l in &lines[1..lines.len()] {
-      new_content += "\n\t";
+  /**
+   * Render the contents of an item. Nested lists start on new lines, and the lines after
+   * the first one are indented to the contents of the item, unless they are already
+   * indented like the nested lists. Items of `checkbox` lists start with `[x]` if they
+   * are `checked`, or `[ ]` otherwise.
+   */
+  fn render_item_tag(
+    &self,
+    tag: &PomlTagNode,
+    attribute_values: &[(String, Value)],
+    children_result: Vec<String>,
+    state: &TagRenderState,
+  ) -> String {
+    let mut raw_content = String::new();
+    for (child, result) in tag.children.iter().zip(children_result) {
+      if matches!(child, PomlNode::Tag(t) if t.name == "list") && !raw_content.ends_with('\n') {
+        raw_content.truncate(raw_content.trim_end().len());
+        raw_content.push('\n');
+      }
+      raw_content += &result;
+    }
+    let indent = " ".repeat(state.list_indent);
+    let mut lines = raw_content.trim().split('\n');
+    let mut new_content = match state.list_style.as_deref() {
+      Some("checkbox") if attr::get_bool_attribute(attribute_values, "checked") == Some(true) => {
+        "[x] ".to_string()
+      }
+      Some("checkbox") => "[ ] ".to_string(),
+      _ => String::new(),
+    };
+    new_content += lines.next().unwrap_or("");
+    for l in lines {
+      new_content += "\n";
+      if !l.is_empty() && !l.starts_with(&indent) {
+        new_content += &indent;
+      }
       new_content += l;
     }
     new_content += "\n";
@@ -240,6 +720,7 @@ impl MarkdownTagRenderer {
     tag: &PomlTagNode,
     attribute_values: &[(String, Value)],
     children_result: Vec<String>,
+    state: &TagRenderState,
   ) -> Result<String> {
     let children_tags = &tag.children;
     if children_tags.len() != children_result.len() {
@@ -247,12 +728,12 @@ impl MarkdownTagRenderer {
         kind: ErrorKind::RendererError,
         message: "Missing children result in rendering <list>.".to_string(),
         source: None,
+        position: None,
+        code: None,
       });
     }
-    let list_style = match attribute_values.iter().find(|v| v.0 == "listStyle") {
-      Some((_, Value::String(v))) => v,
-      _ => "dash",
-    };
+    let list_style = get_list_style(attribute_values);
+    let indent = " ".repeat(state.list_indent);
     let mut answer = String::new();
     let mut item_counter = 0;
     for i in 0..children_tags.len() {
@@ -263,16 +744,22 @@ impl MarkdownTagRenderer {
         // skip non item children
         continue;
       }
+      // Each line not indented starts an item, since the items of a loop are in one result.
+      // Blank lines are only kept inside the items.
+      let mut blank_lines = 0;
       for l in children_result[i].split("\n") {
         if l.is_empty() {
-          answer += "\n";
-        } else if l.starts_with("\t") {
+          blank_lines += 1;
+        } else if l.starts_with(' ') {
+          answer += &"\n".repeat(blank_lines);
+          blank_lines = 0;
           answer += l;
           answer += "\n";
         } else {
+          blank_lines = 0;
           item_counter += 1;
           let item_mark = match list_style {
-            "dash" => "- ".to_owned(),
+            "dash" | "checkbox" => "- ".to_owned(),
             "star" => "* ".to_owned(),
             "plus" => "+ ".to_owned(),
             "decimal" => format!("{item_counter}. "),
@@ -281,16 +768,22 @@ impl MarkdownTagRenderer {
                 kind: ErrorKind::RendererError,
                 message: format!("Unknown list style: {list_style}"),
                 source: None,
+                position: None,
+                code: Some(ErrorCode::InvalidAttributeValue),
               });
             }
           };
 
+          answer += &indent;
           answer += &item_mark;
           answer += l;
           answer += "\n";
         }
       }
     }
+    if state.list_depth == 0 {
+      answer += "\n";
+    }
     Ok(answer)
   }
 
@@ -299,51 +792,53 @@ impl MarkdownTagRenderer {
    */
   fn render_captioned_component(
     &self,
-    style: CaptionStyle,
-    caption_colon: bool,
+    options: &CaptionOptions,
     caption_text: &str,
     children_result: Vec<String>,
+    state: &TagRenderState,
   ) -> String {
-    match style {
+    let level = options.level.unwrap_or(state.heading_level);
+    let caption_text = options.text_transform.apply(caption_text, level);
+    let colon = if options.ending.has_colon() { ":" } else { "" };
+    let separator = if options.ending.has_newline() {
+      self.writer.paragraph_separator.as_str()
+    } else {
+      " "
+    };
+    match options.style {
       CaptionStyle::Header => {
-        let mut answer = if caption_colon {
-          format!("# {caption_text}:\n\n")
-        } else {
-          format!("# {caption_text}\n\n")
-        };
-        for child_text in children_result.iter() {
-          if child_text.starts_with("#") {
-            answer += &format!("#{child_text}");
-          } else {
-            answer += child_text;
-          }
-        }
-        answer
-      }
-      CaptionStyle::Bold => {
-        if caption_colon {
-          format!("**{caption_text}:** {}\n", children_result.join(""))
-        } else {
-          format!("**{caption_text}** {}\n", children_result.join(""))
-        }
-      }
-      CaptionStyle::Plain => {
-        if caption_colon {
-          format!("{caption_text}:\n\n{}\n", children_result.join(""))
-        } else {
-          format!("{caption_text}\n\n{}\n", children_result.join(""))
-        }
+        // Headings always take a line of their own.
+        let prefix = "#".repeat(level.min(6));
+        format!(
+          "{prefix} {caption_text}{colon}{}{}",
+          self.writer.paragraph_separator,
+          children_result.join("")
+        )
       }
+      CaptionStyle::Bold => format!(
+        "**{caption_text}{colon}**{separator}{}\n",
+        children_result.join("")
+      ),
+      CaptionStyle::Plain => format!(
+        "{caption_text}{colon}{separator}{}\n",
+        children_result.join("")
+      ),
       CaptionStyle::Hidden => format!("{}\n", children_result.join("")),
     }
   }
 
-  fn render_table_tag(&self, attribute_values: &[(String, Value)]) -> Result<String> {
+  fn render_table_tag(
+    &self,
+    attribute_values: &[(String, Value)],
+    state: &TagRenderState,
+  ) -> Result<String> {
     let Some((_, records)) = attribute_values.iter().find(|v| v.0 == "records") else {
       return Err(Error {
         kind: ErrorKind::RendererError,
         message: "Missing `records` attribute for the <table> tag.".to_string(),
         source: None,
+        position: None,
+        code: Some(ErrorCode::MissingAttribute),
       });
     };
     let Value::Array(records) = records else {
@@ -351,6 +846,8 @@ impl MarkdownTagRenderer {
         kind: ErrorKind::RendererError,
         message: "`records` attribute must be an array for the <table> tag.".to_string(),
         source: None,
+        position: None,
+        code: Some(ErrorCode::InvalidAttributeValue),
       });
     };
 
@@ -370,11 +867,18 @@ impl MarkdownTagRenderer {
           kind: ErrorKind::RendererError,
           message: "Table records must be objects.".to_string(),
           source: None,
+          position: None,
+          code: Some(ErrorCode::InvalidAttributeValue),
         });
       };
       let mut item_strings: HashMap<String, String> = HashMap::new();
       for (key, value) in obj.iter() {
-        if !headers.iter().any(|v| v == key) {
+        let key = if state.escape_values {
+          escape_table_cell(key)
+        } else {
+          key.clone()
+        };
+        if !headers.contains(&key) {
           headers.push(key.clone());
           column_max_width.insert(key.to_string(), key.len());
         }
@@ -382,10 +886,15 @@ impl MarkdownTagRenderer {
           Value::String(s) => s.to_string(),
           _ => value.to_string(),
         };
-        if value_str.len() > column_max_width[key] {
-          column_max_width.insert(key.to_string(), value_str.len());
+        let value_str = if state.escape_values {
+          escape_table_cell(&value_str)
+        } else {
+          value_str
+        };
+        if value_str.len() > column_max_width[&key] {
+          column_max_width.insert(key.clone(), value_str.len());
         }
-        item_strings.insert(key.to_string(), value_str);
+        item_strings.insert(key, value_str);
       }
       record_strings.push(item_strings);
     }
@@ -0,0 +1,239 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::{TagRenderState, TagRenderer};
+use crate::PomlTagNode;
+use crate::error::{ErrorPosition, Result};
+use serde_json::Value;
+
+/**
+ * Middleware observing or rewriting the rendering of each tag by a tag renderer, without
+ * reimplementing the tags. Middlewares are composed by wrapping a tag renderer with
+ * [`TagRendererExt::with_middleware`] repeatedly, where the last one is the outermost.
+ */
+pub trait TagMiddleware: Clone {
+  /**
+   * Rewrite the rendered children before the tag is rendered.
+   */
+  fn before_render(
+    &self,
+    _tag: &PomlTagNode,
+    _attribute_values: &[(String, Value)],
+    children_result: Vec<String>,
+  ) -> Result<Vec<String>> {
+    Ok(children_result)
+  }
+
+  /**
+   * Rewrite the output of the tag after it's rendered.
+   */
+  fn after_render(
+    &self,
+    _tag: &PomlTagNode,
+    _attribute_values: &[(String, Value)],
+    output: String,
+    _source_buf: &[u8],
+  ) -> Result<String> {
+    Ok(output)
+  }
+}
+
+/**
+ * A tag renderer wrapped by a middleware.
+ */
+#[derive(Debug, Clone)]
+pub struct WithMiddleware<R, M> {
+  pub inner: R,
+  pub middleware: M,
+}
+
+impl<R: TagRenderer, M: TagMiddleware> TagRenderer for WithMiddleware<R, M> {
+  fn render_tag(
+    &self,
+    tag: &PomlTagNode,
+    attribute_values: &[(String, Value)],
+    children_result: Vec<String>,
+    source_buf: &[u8],
+  ) -> Result<String> {
+    self.render_tag_in_state(
+      tag,
+      attribute_values,
+      children_result,
+      source_buf,
+      &TagRenderState::default(),
+    )
+  }
+
+  fn render_tag_in_state(
+    &self,
+    tag: &PomlTagNode,
+    attribute_values: &[(String, Value)],
+    children_result: Vec<String>,
+    source_buf: &[u8],
+    state: &TagRenderState,
+  ) -> Result<String> {
+    let children_result = self
+      .middleware
+      .before_render(tag, attribute_values, children_result)?;
+    let output =
+      self
+        .inner
+        .render_tag_in_state(tag, attribute_values, children_result, source_buf, state)?;
+    self
+      .middleware
+      .after_render(tag, attribute_values, output, source_buf)
+  }
+
+  fn is_supported_tag(&self, tag_name: &str) -> bool {
+    self.inner.is_supported_tag(tag_name)
+  }
+
+  fn is_heading_scope(&self, tag_name: &str, attribute_values: &[(String, Value)]) -> bool {
+    self.inner.is_heading_scope(tag_name, attribute_values)
+  }
+
+  fn scope_attributes(
+    &self,
+    tag_name: &str,
+    attribute_values: &[(String, Value)],
+  ) -> Vec<(String, Value)> {
+    self.inner.scope_attributes(tag_name, attribute_values)
+  }
+
+  fn escape_text(&self, text: &str) -> String {
+    self.inner.escape_text(text)
+  }
+
+  fn list_item_indent(&self, tag_name: &str, attribute_values: &[(String, Value)]) -> usize {
+    self.inner.list_item_indent(tag_name, attribute_values)
+  }
+
+  fn paragraph_separator(&self) -> &str {
+    self.inner.paragraph_separator()
+  }
+}
+
+pub trait TagRendererExt: TagRenderer + Sized {
+  /**
+   * Wrap the tag renderer with the middleware.
+   */
+  fn with_middleware<M: TagMiddleware>(self, middleware: M) -> WithMiddleware<Self, M> {
+    WithMiddleware {
+      inner: self,
+      middleware,
+    }
+  }
+}
+
+impl<R: TagRenderer> TagRendererExt for R {}
+
+/**
+ * Prepend an HTML comment with the tag name and its line and column in the document to
+ * the output of each tag, like `<!-- p at 3:5 -->`, to trace the output back to the source.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct SourcePositionComments {}
+
+impl TagMiddleware for SourcePositionComments {
+  fn after_render(
+    &self,
+    tag: &PomlTagNode,
+    _attribute_values: &[(String, Value)],
+    output: String,
+    source_buf: &[u8],
+  ) -> Result<String> {
+    if tag.name == "poml" || output.is_empty() {
+      return Ok(output);
+    }
+    let position = ErrorPosition::from_offset(source_buf, tag.original_pos.start);
+    Ok(format!(
+      "<!-- {} at {}:{} -->\n{output}",
+      tag.name, position.line, position.col
+    ))
+  }
+}
+
+/**
+ * Truncate the output of the tags to at most `max_chars` characters. If `tags` is not
+ * empty, only the tags with the names are limited.
+ */
+#[derive(Debug, Clone)]
+pub struct MaxTagLength {
+  pub max_chars: usize,
+  pub tags: Vec<String>,
+}
+
+impl TagMiddleware for MaxTagLength {
+  fn after_render(
+    &self,
+    tag: &PomlTagNode,
+    _attribute_values: &[(String, Value)],
+    output: String,
+    _source_buf: &[u8],
+  ) -> Result<String> {
+    if !self.tags.is_empty() && !self.tags.iter().any(|name| name == tag.name) {
+      return Ok(output);
+    }
+    match output.char_indices().nth(self.max_chars) {
+      Some((end, _)) => Ok(output[..end].to_string()),
+      None => Ok(output),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::render::Renderer;
+  use crate::render::render_context::RenderContext;
+  use crate::render::tag_renderer::MarkdownTagRenderer;
+  use std::collections::HashMap;
+
+  /** Middleware upper-casing the children of `<p>` */
+  #[derive(Clone)]
+  struct UpperCaseParagraphs {}
+
+  impl TagMiddleware for UpperCaseParagraphs {
+    fn before_render(
+      &self,
+      tag: &PomlTagNode,
+      _attribute_values: &[(String, Value)],
+      children_result: Vec<String>,
+    ) -> Result<Vec<String>> {
+      if tag.name != "p" {
+        return Ok(children_result);
+      }
+      Ok(children_result.iter().map(|c| c.to_uppercase()).collect())
+    }
+  }
+
+  fn render<T: TagRenderer>(doc: &str, tag_renderer: T) -> String {
+    let context = RenderContext::from_iter(HashMap::<String, Value>::new());
+    Renderer::new(doc, context, tag_renderer).render().unwrap()
+  }
+
+  #[test]
+  fn test_composed_middlewares() {
+    let doc =
+      "<poml>\n  <p>Hello, world!</p>\n  <cp caption=\"Task\">Summarize the text.</cp>\n</poml>";
+    let tag_renderer = MarkdownTagRenderer::default()
+      .with_middleware(UpperCaseParagraphs {})
+      .with_middleware(MaxTagLength {
+        max_chars: 8,
+        tags: vec!["cp".to_string()],
+      })
+      .with_middleware(SourcePositionComments {});
+    assert_eq!(
+      render(doc, tag_renderer),
+      "<!-- p at 2:3 -->\nHELLO, WORLD!\n\n<!-- cp at 3:3 -->\n# Task\n\n"
+    );
+    assert!(
+      !MarkdownTagRenderer::default()
+        .with_middleware(SourcePositionComments {})
+        .is_supported_tag("unknown")
+    );
+  }
+}
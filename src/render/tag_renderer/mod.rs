@@ -8,6 +8,124 @@ use crate::PomlTagNode;
 use crate::error::Result;
 use serde_json::Value;
 
+/**
+ * State of the renderer at the tag being rendered, which is decided by the enclosing tags.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagRenderState {
+  /**
+   * Level of the headings rendered by the tag, which is 1 plus the number of enclosing
+   * heading scopes like `<section>`.
+   */
+  pub heading_level: usize,
+  /**
+   * Number of the enclosing lists, which is 0 outside of any list.
+   */
+  pub list_depth: usize,
+  /**
+   * Indentation in spaces of the list items rendered by the tag, which is the sum of the
+   * item indents of the enclosing lists.
+   */
+  pub list_indent: usize,
+  /**
+   * Value of the `listStyle` attribute of the innermost enclosing list, if any.
+   */
+  pub list_style: Option<String>,
+  /**
+   * Number of the siblings with the same tag name rendered before the tag, counting the
+   * iterations of `for` loops, like 1 for the second `<example>` of `<examples>`.
+   */
+  pub sibling_index: usize,
+  /**
+   * Attributes passed down by the enclosing tags, like `chat` of `<examples>`. The
+   * attributes of the inner tags come later.
+   */
+  pub scope_attributes: Vec<(String, Value)>,
+  /**
+   * Whether the values are escaped, set by `RenderOptions::escape_values`. Tags rendering
+   * the values of their attributes, like `<table>`, escape them too.
+   */
+  pub escape_values: bool,
+}
+
+impl TagRenderState {
+  /**
+   * Find the attribute passed down by the innermost enclosing tag with it.
+   */
+  pub fn scope_attribute(&self, name: &str) -> Option<&Value> {
+    self
+      .scope_attributes
+      .iter()
+      .rev()
+      .find(|(key, _)| key == name)
+      .map(|(_, value)| value)
+  }
+}
+
+impl Default for TagRenderState {
+  fn default() -> Self {
+    TagRenderState {
+      heading_level: 1,
+      list_depth: 0,
+      list_indent: 0,
+      list_style: None,
+      sibling_index: 0,
+      scope_attributes: Vec::new(),
+      escape_values: false,
+    }
+  }
+}
+
+/**
+ * Outputs of the children of a tag, appended one after another to the output buffer of the
+ * renderer, from `start` to the ends of the children in order.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChildrenOutput {
+  pub start: usize,
+  pub ends: Vec<usize>,
+}
+
+impl ChildrenOutput {
+  /** Output of the child at the index in the buffer. */
+  pub fn get<'o>(&self, out: &'o str, index: usize) -> &'o str {
+    let start = match index {
+      0 => self.start,
+      _ => self.ends[index - 1],
+    };
+    &out[start..self.ends[index]]
+  }
+
+  /** Take the outputs of the children out of the buffer, truncating it to `start`. */
+  pub fn take(&self, out: &mut String) -> Vec<String> {
+    let children_result = (0..self.ends.len())
+      .map(|index| self.get(out, index).to_owned())
+      .collect();
+    out.truncate(self.start);
+    children_result
+  }
+}
+
+/**
+ * Render the tag with the outputs of its children taken out of the buffer, which is how
+ * `TagRenderer::render_tag_into` renders the tags by default.
+ */
+pub fn render_tag_with_children_taken<T: TagRenderer>(
+  tag_renderer: &T,
+  tag: &PomlTagNode,
+  attribute_values: &[(String, Value)],
+  children: &ChildrenOutput,
+  out: &mut String,
+  source_buf: &[u8],
+  state: &TagRenderState,
+) -> Result<()> {
+  let children_result = children.take(out);
+  let output =
+    tag_renderer.render_tag_in_state(tag, attribute_values, children_result, source_buf, state)?;
+  out.push_str(&output);
+  Ok(())
+}
+
 pub trait TagRenderer: Clone {
   fn render_tag(
     &self,
@@ -16,8 +134,112 @@ pub trait TagRenderer: Clone {
     children_result: Vec<String>,
     source_buf: &[u8],
   ) -> Result<String>;
+
+  /**
+   * Render the tag in the state decided by the enclosing tags. Tag renderers which don't
+   * depend on the state only need to implement `render_tag`.
+   */
+  fn render_tag_in_state(
+    &self,
+    tag: &PomlTagNode,
+    attribute_values: &[(String, Value)],
+    children_result: Vec<String>,
+    source_buf: &[u8],
+    _state: &TagRenderState,
+  ) -> Result<String> {
+    self.render_tag(tag, attribute_values, children_result, source_buf)
+  }
+
+  /**
+   * Render the tag by appending to the output buffer, where the outputs of its children are
+   * already appended as `children`. The output of the tag replaces them, so the tags wrapping
+   * their children, like paragraphs, don't need to copy them. By default, the children are
+   * taken out of the buffer and the tag is rendered by `render_tag_in_state`.
+   */
+  fn render_tag_into(
+    &self,
+    tag: &PomlTagNode,
+    attribute_values: &[(String, Value)],
+    children: &ChildrenOutput,
+    out: &mut String,
+    source_buf: &[u8],
+    state: &TagRenderState,
+  ) -> Result<()> {
+    render_tag_with_children_taken(
+      self,
+      tag,
+      attribute_values,
+      children,
+      out,
+      source_buf,
+      state,
+    )
+  }
+
+  /**
+   * Whether the headings in the children of the tag are one level deeper than the
+   * headings of the tag, like the children of `<section>`.
+   */
+  fn is_heading_scope(&self, _tag_name: &str, _attribute_values: &[(String, Value)]) -> bool {
+    false
+  }
+
+  /**
+   * Indentation in spaces of the contents of the items if the tag is a list, or 0 if it's
+   * not. Lists nested in the items are indented by it, like 2 for `- ` markers.
+   */
+  fn list_item_indent(&self, _tag_name: &str, _attribute_values: &[(String, Value)]) -> usize {
+    0
+  }
+
+  /**
+   * Attributes of the tag passed down to its descendants, which find them by
+   * [`TagRenderState::scope_attribute`].
+   */
+  fn scope_attributes(
+    &self,
+    _tag_name: &str,
+    _attribute_values: &[(String, Value)],
+  ) -> Vec<(String, Value)> {
+    Vec::new()
+  }
+
+  /**
+   * Escape the interpolated values in the text so they are rendered literally, when
+   * `RenderOptions::escape_values` is enabled.
+   */
+  fn escape_text(&self, text: &str) -> String {
+    text.to_owned()
+  }
+
+  /**
+   * Whether the tag renderer knows how to render the tag. Tags handled by the
+   * renderer itself, like `<let>` and `<include>`, don't need to be covered.
+   */
+  fn is_supported_tag(&self, _tag_name: &str) -> bool {
+    true
+  }
+
+  /**
+   * Separator after the blocks rendered by the renderer itself, like the items of
+   * `<history/>`.
+   */
+  fn paragraph_separator(&self) -> &str {
+    "\n\n"
+  }
+
+  /**
+   * Whether the root `<poml>` tag without attributes renders as the outputs of its children
+   * except the whitespace between them, in the state of the renderer outside of any tag.
+   * The outputs of the top-level blocks are then written out as soon as they are rendered.
+   */
+  fn is_root_concatenated(&self) -> bool {
+    false
+  }
 }
 
-mod markdown;
-pub use markdown::MarkdownTagRenderer;
-pub(crate) mod attribute_utils;
+pub(crate) mod markdown;
+pub use markdown::{InlineStyle, InlineTagStyles, LineEnding, MarkdownTagRenderer, WriterOptions};
+pub mod middleware;
+pub use middleware::{TagMiddleware, TagRendererExt};
+pub mod attribute_utils;
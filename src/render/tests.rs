@@ -5,10 +5,16 @@
  */
 
 use super::*;
-use crate::{PomlParser, PomlTagNode};
-use serde_json::json;
+use crate::error::{Severity, Span};
+use crate::render::file_loader::InMemoryLoader;
+use crate::render::options::UnknownTagPolicy;
+use crate::render::stylesheet::Stylesheet;
+use crate::{MarkdownPomlRenderer, PomlTagNode};
+use serde_json::{Map, json};
 use std::collections::HashMap;
-use tag_renderer::{MarkdownTagRenderer, TagRenderer};
+use tag_renderer::{
+  InlineStyle, InlineTagStyles, LineEnding, MarkdownTagRenderer, TagRenderer, WriterOptions,
+};
 
 /**
  * The tag render that renders nothing except dumping the
@@ -49,13 +55,7 @@ fn test_render_content() {
   let mut variables = HashMap::new();
   variables.insert("name".to_owned(), json!("world"));
   let context = render_context::RenderContext::from_iter(variables);
-  let parser = PomlParser::from_poml_str(doc);
-  let mut renderer = Renderer {
-    parser,
-    context,
-    tag_renderer: TestTagRenderer {},
-    filename: "<anonymous>".to_owned(),
-  };
+  let mut renderer = Renderer::new(doc, context, TestTagRenderer {});
 
   let output = renderer.render().unwrap();
   assert!(output.contains("Hello, world!"));
@@ -71,13 +71,7 @@ fn test_render_markdown() {
         "#;
   let variables: HashMap<String, Value> = HashMap::new();
   let context = render_context::RenderContext::from_iter(variables);
-  let parser = PomlParser::from_poml_str(doc);
-  let mut renderer = Renderer {
-    parser,
-    context,
-    tag_renderer: MarkdownTagRenderer {},
-    filename: "<anonymous>".to_owned(),
-  };
+  let mut renderer = Renderer::new(doc, context, MarkdownTagRenderer::default());
 
   let output = renderer.render().unwrap();
   assert_eq!(
@@ -95,13 +89,7 @@ fn test_let_tag() {
             </poml>
         "#;
   let context = render_context::RenderContext::from_iter(HashMap::<String, Value>::new());
-  let parser = PomlParser::from_poml_str(doc);
-  let mut renderer = Renderer {
-    parser,
-    context,
-    tag_renderer: TestTagRenderer {},
-    filename: "<anonymous>".to_owned(),
-  };
+  let mut renderer = Renderer::new(doc, context, TestTagRenderer {});
 
   let output = renderer.render().unwrap();
   assert!(output.contains("Hello, world!"));
@@ -116,13 +104,7 @@ fn test_let_tag_children_value() {
             </poml>
         "#;
   let context = render_context::RenderContext::from_iter(HashMap::<String, Value>::new());
-  let parser = PomlParser::from_poml_str(doc);
-  let mut renderer = Renderer {
-    parser,
-    context,
-    tag_renderer: TestTagRenderer {},
-    filename: "<anonymous>".to_owned(),
-  };
+  let mut renderer = Renderer::new(doc, context, TestTagRenderer {});
 
   let output = renderer.render().unwrap();
   assert!(output.contains("Hello, world!"));
@@ -137,13 +119,7 @@ fn test_let_tag_duplicated_values() {
             </poml>
         "#;
   let context = render_context::RenderContext::from_iter(HashMap::<String, Value>::new());
-  let parser = PomlParser::from_poml_str(doc);
-  let mut renderer = Renderer {
-    parser,
-    context,
-    tag_renderer: TestTagRenderer {},
-    filename: "<anonymous>".to_owned(),
-  };
+  let mut renderer = Renderer::new(doc, context, TestTagRenderer {});
 
   assert!(renderer.render().is_err());
 }
@@ -158,13 +134,7 @@ fn test_let_tag_with_type() {
             </poml>
         "#;
   let context = render_context::RenderContext::from_iter(HashMap::<String, Value>::new());
-  let parser = PomlParser::from_poml_str(doc);
-  let mut renderer = Renderer {
-    parser,
-    context,
-    tag_renderer: TestTagRenderer {},
-    filename: "<anonymous>".to_owned(),
-  };
+  let mut renderer = Renderer::new(doc, context, TestTagRenderer {});
 
   let output = renderer.render().unwrap();
   assert!(output.contains("Count: 3"));
@@ -180,13 +150,7 @@ fn test_let_tag_with_type_with_invalid_value() {
             </poml>
         "#;
   let context = render_context::RenderContext::from_iter(HashMap::<String, Value>::new());
-  let parser = PomlParser::from_poml_str(doc);
-  let mut renderer = Renderer {
-    parser,
-    context,
-    tag_renderer: TestTagRenderer {},
-    filename: "<anonymous>".to_owned(),
-  };
+  let mut renderer = Renderer::new(doc, context, TestTagRenderer {});
 
   let output = renderer.render();
   assert!(output.is_err());
@@ -204,13 +168,7 @@ fn test_if_attributes() {
             </poml>
         "#;
   let context = render_context::RenderContext::from_iter(HashMap::<String, Value>::new());
-  let parser = PomlParser::from_poml_str(doc);
-  let mut renderer = Renderer {
-    parser,
-    context,
-    tag_renderer: TestTagRenderer {},
-    filename: "<anonymous>".to_owned(),
-  };
+  let mut renderer = Renderer::new(doc, context, TestTagRenderer {});
 
   let output = renderer.render().unwrap();
   assert!(!output.contains("Hello, world!"));
@@ -229,13 +187,7 @@ fn test_for_attributes() {
             </poml>
         "#;
   let context = render_context::RenderContext::from_iter(HashMap::<String, Value>::new());
-  let parser = PomlParser::from_poml_str(doc);
-  let mut renderer = Renderer {
-    parser,
-    context,
-    tag_renderer: TestTagRenderer {},
-    filename: "<anonymous>".to_owned(),
-  };
+  let mut renderer = Renderer::new(doc, context, TestTagRenderer {});
 
   let output = renderer.render().unwrap();
   assert!(output.contains("Hello, apple! 0"));
@@ -258,13 +210,7 @@ fn test_for_in_obj_array() {
             </poml>
         "#;
   let context = render_context::RenderContext::from_iter(HashMap::<String, Value>::new());
-  let parser = PomlParser::from_poml_str(doc);
-  let mut renderer = Renderer {
-    parser,
-    context,
-    tag_renderer: TestTagRenderer {},
-    filename: "<anonymous>".to_owned(),
-  };
+  let mut renderer = Renderer::new(doc, context, TestTagRenderer {});
 
   let output = renderer.render().unwrap();
   println!("{}", output);
@@ -300,6 +246,136 @@ if __name__ == "__main__":
   assert!(output.contains(code_piece))
 }
 
+#[test]
+fn test_inline_tags() {
+  let doc = r#"<p><b>B</b> <i>I</i> <s>S</s> <u>U</u> H<sub>2</sub>O x<sup>2</sup> <q>Q</q> <kbd>Ctrl</kbd></p>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  assert_eq!(
+    renderer.render().unwrap().trim(),
+    "**B** *I* ~~S~~ <u>U</u> H<sub>2</sub>O x<sup>2</sup> “Q” <kbd>Ctrl</kbd>"
+  );
+
+  let tag_renderer = MarkdownTagRenderer {
+    inline_styles: InlineTagStyles {
+      bold: InlineStyle::delimiter("__"),
+      underline: InlineStyle::delimiter("_"),
+      quote: InlineStyle::delimiter("\""),
+      keyboard: InlineStyle::Plain,
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+  let context = render_context::RenderContext::from_iter(HashMap::<String, Value>::new());
+  let mut renderer = Renderer::new(doc, context, tag_renderer);
+  assert_eq!(
+    renderer.render().unwrap().trim(),
+    "__B__ *I* ~~S~~ _U_ H<sub>2</sub>O x<sup>2</sup> \"Q\" Ctrl"
+  );
+}
+
+#[test]
+fn test_writer_options() {
+  let doc = r#"<poml><h>Title</h><p>First</p><br/><br/><p>Second</p></poml>"#;
+  let render = |writer: WriterOptions| {
+    let tag_renderer = MarkdownTagRenderer {
+      writer,
+      ..Default::default()
+    };
+    let context = render_context::RenderContext::from_iter(HashMap::<String, Value>::new());
+    Renderer::new(doc, context, tag_renderer).render().unwrap()
+  };
+  assert_eq!(
+    render(WriterOptions::default()),
+    "# Title\n\nFirst\n\n\n\n\n\nSecond\n\n"
+  );
+  assert_eq!(
+    render(WriterOptions {
+      paragraph_separator: "\n".to_string(),
+      ..Default::default()
+    }),
+    "# Title\nFirst\n\n\nSecond\n"
+  );
+  assert_eq!(
+    render(WriterOptions {
+      line_ending: LineEnding::CrLf,
+      max_blank_lines: Some(1),
+      ..Default::default()
+    }),
+    "# Title\r\n\r\nFirst\r\n\r\nSecond\r\n\r\n"
+  );
+}
+
+#[test]
+fn test_post_process() {
+  let doc = r#"<poml><p>Hello  </p><br/><br/><p>A line to be wrapped</p></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.post_process = vec![
+    post_process::PostProcessStep::TrimTrailingWhitespace,
+    post_process::PostProcessStep::CollapseBlankLines,
+    post_process::PostProcessStep::EnsureTrailingNewline,
+    post_process::PostProcessStep::WordWrap(10),
+  ];
+  assert_eq!(
+    renderer.render().unwrap(),
+    "Hello\n\nA line to\nbe wrapped\n"
+  );
+}
+
+#[test]
+fn test_caption_options() {
+  let render = |doc: &str| {
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+    renderer.render().unwrap().trim().to_string()
+  };
+  assert_eq!(
+    render(r#"<cp caption="output rules" captionTextTransform="upper">Text</cp>"#),
+    "# OUTPUT RULES\n\nText"
+  );
+  assert_eq!(
+    render(r#"<cp caption="output rules" captionTextTransform="capitalize">Text</cp>"#),
+    "# Output Rules\n\nText"
+  );
+  assert_eq!(
+    render(
+      r#"<section><cp caption="a rule" captionTextTransform="level"><cp caption="sub rule" captionTextTransform="level">Text</cp></cp></section>"#
+    ),
+    "## A Rule\n\n### Sub Rule\n\nText"
+  );
+  assert_eq!(
+    render(r#"<cp caption="Rules" captionEnding="colon" captionLevel="3">Text</cp>"#),
+    "### Rules:\n\nText"
+  );
+  assert_eq!(
+    render(r#"<task captionStyle="bold" captionEnding="newline">Do it</task>"#),
+    "**Task**\n\nDo it"
+  );
+  assert_eq!(
+    render(r#"<task captionStyle="plain" captionEnding="colon">Do it</task>"#),
+    "Task: Do it"
+  );
+  assert_eq!(
+    render(r#"<hint captionEnding="none">Be brief</hint>"#),
+    "**Hint** Be brief"
+  );
+
+  let doc = r#"<cp caption="Rules" captionLevel="0">Text</cp>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  assert!(renderer.render().is_err());
+}
+
+#[test]
+fn test_code_children() {
+  let doc = r#"<poml><let name="name" value="'world'" /><code lang="py">
+def greet():
+    print("hello, {{ name }}")
+</code><code inline="true" raw="true">{{ name }} <b>x</b></code><code/></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let output = renderer.render().unwrap();
+  assert!(output.contains("```py\n\ndef greet():\n    print(\"hello, world\")\n\n```"));
+  assert!(output.contains("`{{ name }} <b>x</b>`"));
+  assert!(output.contains("```\n\n```"));
+}
+
 #[test]
 fn test_intentional_blocks() {
   use crate::MarkdownPomlRenderer;
@@ -377,6 +453,57 @@ fn test_header_and_section() {
   assert!(output.contains("# Header 3"));
 }
 
+#[test]
+fn test_heading_levels_are_structural() {
+  let doc = r#"<poml><section whiteSpace="trim"><p>#hashtag</p><role><h>Details</h><code lang="sh"># comment</code></role><task captionStyle="bold">Do it</task></section></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let output = renderer.render().unwrap();
+  assert!(
+    output.starts_with("#hashtag\n\n## Role\n\n### Details\n\n"),
+    "{output}"
+  );
+  assert!(output.contains("```sh\n# comment\n```"), "{output}");
+  assert!(output.contains("**Task:** Do it"), "{output}");
+}
+
+#[test]
+fn test_header_levels() {
+  let doc = r#"
+<poml syntax="markdown">
+  <h>Title</h>
+  <section>
+    <h>Sub</h>
+    <section>
+      <h>Sub sub</h>
+    </section>
+    <h level="5">Explicit</h>
+    <section><section><section><section><section><h>Deep</h></section></section></section></section></section>
+  </section>
+</poml>
+"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let output = renderer.render().unwrap();
+  let headings: Vec<&str> = output
+    .lines()
+    .map(str::trim)
+    .filter(|l| !l.is_empty())
+    .collect();
+  assert_eq!(
+    headings,
+    vec![
+      "# Title",
+      "## Sub",
+      "### Sub sub",
+      "##### Explicit",
+      "###### Deep"
+    ]
+  );
+
+  let doc = r#"<poml><h level="0">Title</h></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  assert!(renderer.render().is_err());
+}
+
 #[test]
 fn test_include() {
   use crate::MarkdownPomlRenderer;
@@ -390,8 +517,7 @@ fn test_include() {
   let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
   renderer
     .context
-    .file_mapping
-    .insert("a.poml".to_owned(), a_doc.to_owned());
+    .set_file_loader(InMemoryLoader::from_iter([("a.poml", a_doc)]));
   let output = renderer.render().unwrap();
   assert!(output.contains("# AAA"));
 }
@@ -410,8 +536,7 @@ fn test_include_error_reporting() {
   renderer.set_filename("main.poml");
   renderer
     .context
-    .file_mapping
-    .insert("a.poml".to_owned(), a_doc.to_owned());
+    .set_file_loader(InMemoryLoader::from_iter([("a.poml", a_doc)]));
   let output_err = renderer.render().unwrap_err();
   let error_message = format!("{}", output_err);
   let error_message_lines: Vec<&str> = error_message.split("\n").collect();
@@ -454,8 +579,42 @@ fn test_nested_list_render() {
 "#;
   let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
   let output = renderer.render().unwrap();
-  assert!(output.contains("-  1. (1, a)\n\t2. (1, b)\n\t3. (1, c)"));
-  assert!(output.contains("-  1. (2, a)\n\t2. (2, b)\n\t3. (2, c)"));
+  assert!(output.contains("- 1. (1, a)\n  2. (1, b)\n  3. (1, c)\n"));
+  assert!(output.contains("- 1. (2, a)\n  2. (2, b)\n  3. (2, c)\n"));
+}
+
+#[test]
+fn test_nested_list_indentation() {
+  let doc = r#"<poml><list listStyle="star">
+  <item>Fruits
+    <list>
+      <item>Apple</item>
+      <item>Banana<list listStyle="decimal"><item>Ripe</item></list></item>
+    </list>
+  </item>
+  <item>Vegetables</item>
+</list><list listStyle="decimal"><item>Steps<list><item>Wash</item></list></item></list></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let output = renderer.render().unwrap();
+  assert!(output.contains("* Fruits\n  - Apple\n  - Banana\n    1. Ripe\n* Vegetables\n"));
+  assert!(output.contains("1. Steps\n    - Wash\n"));
+}
+
+#[test]
+fn test_checkbox_list() {
+  let doc = r#"<poml><list listStyle="checkbox">
+  <item checked="true">Cite the sources</item>
+  <item>Keep it short</item>
+  <item checked="{{ done }}">Use English</item>
+  <item for="c in ['No code', 'No tables']">{{ c }}</item>
+</list></poml>"#;
+  let variables = HashMap::from([("done".to_string(), Value::Bool(true))]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  let output = renderer.render().unwrap();
+  assert_eq!(
+    output.trim(),
+    "- [x] Cite the sources\n- [ ] Keep it short\n- [x] Use English\n- [ ] No code\n- [ ] No tables"
+  );
 }
 
 #[test]
@@ -494,6 +653,63 @@ fn test_captioned_paragraph_with_style() {
   assert!(output.contains("\n - Do not exceed 1000 tokens."));
 }
 
+#[test]
+fn test_stylesheet_defaults() {
+  let doc = r#"
+<poml>
+  <cp caption="Constraints">Be brief.</cp>
+  <cp caption="Output" captionStyle="plain">JSON only.</cp>
+</poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.stylesheet =
+    Stylesheet::from_value(&json!({"defaults": {"cp": {"captionStyle": "bold"}}})).unwrap();
+  let output = renderer.render().unwrap();
+  assert!(output.contains("**Constraints:**"));
+  assert!(!output.contains("**Output"));
+}
+
+#[test]
+fn test_caption_colon() {
+  let render = |doc: &str, stylesheet: Value| {
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+    renderer.options.stylesheet = Stylesheet::from_value(&stylesheet).unwrap();
+    renderer.render().unwrap().trim().to_string()
+  };
+  let no_stylesheet = json!({});
+  for (doc, expected) in [
+    (
+      r#"<task captionColon="true">Do it</task>"#,
+      "# Task:\n\nDo it",
+    ),
+    (r#"<task>Do it</task>"#, "# Task\n\nDo it"),
+    (r#"<hint>Be brief</hint>"#, "**Hint:** Be brief"),
+    (
+      r#"<hint captionColon="false">Be brief</hint>"#,
+      "**Hint** Be brief",
+    ),
+    (
+      r#"<cp caption="Rules" captionStyle="plain" captionColon="false">Text</cp>"#,
+      "Rules\n\nText",
+    ),
+  ] {
+    assert_eq!(render(doc, no_stylesheet.clone()), expected);
+  }
+  let stylesheet =
+    json!({"defaults": {"hint": {"captionColon": false}, "task": {"captionColon": true}}});
+  assert_eq!(
+    render("<task>Do it</task>", stylesheet.clone()),
+    "# Task:\n\nDo it"
+  );
+  assert_eq!(
+    render("<hint>Be brief</hint>", stylesheet),
+    "**Hint** Be brief"
+  );
+
+  let doc = r#"<poml><examples><example><input>a</input><output>b</output></example></examples><p>z</p></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  assert_eq!(renderer.render().unwrap(), "# Examples\n\na\nb\n\nz\n\n");
+}
+
 #[test]
 fn test_let_src_include() {
   use crate::MarkdownPomlRenderer;
@@ -507,12 +723,115 @@ fn test_let_src_include() {
   let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
   renderer
     .context
-    .file_mapping
-    .insert("foo.json".to_owned(), foo_doc.to_owned());
+    .set_file_loader(InMemoryLoader::from_iter([("foo.json", foo_doc)]));
   let output = renderer.render().unwrap();
   assert!(output.contains("fubar"));
 }
 
+#[cfg(all(feature = "yaml", feature = "toml"))]
+#[test]
+fn test_let_src_yaml_and_toml() {
+  let doc = r#"
+<poml syntax="markdown">
+  <let name="config" src="config.yaml" />
+  <let src="limits.toml" />
+  <let name="extra" src="extra.txt" parser="yaml" />
+  <p>{{ config.name }} {{ config.tags[1] }} {{ max }} {{ extra.a }}</p>
+</poml>
+"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.context.set_file_loader(InMemoryLoader::from_iter([
+    ("config.yaml", "name: poml\ntags: [a, b]\n"),
+    ("limits.toml", "max = 3\n"),
+    ("extra.txt", "a: 1\n"),
+  ]));
+  let output = renderer.render().unwrap();
+  assert!(output.contains("poml b 3 1"));
+}
+
+#[test]
+fn test_let_src_csv() {
+  let doc = r#"
+<poml syntax="markdown">
+  <let name="rows" src="rows.csv" />
+  <list>
+    <item for="row in rows">{{ row.name }}: {{ row.score }}</item>
+  </list>
+</poml>
+"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer
+    .context
+    .set_file_loader(InMemoryLoader::from_iter([(
+      "rows.csv",
+      "name,score\nAda,90\nBob,85\n",
+    )]));
+  let output = renderer.render().unwrap();
+  assert!(output.contains("Ada: 90"));
+  assert!(output.contains("Bob: 85"));
+}
+
+#[test]
+fn test_let_src_stdin() {
+  let doc = r#"
+<poml syntax="markdown">
+  <let name="foo" src="stdin" />
+  <p>{{ foo.bar }}</p>
+</poml>
+"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer
+    .context
+    .set_file_loader(InMemoryLoader::from_iter([("stdin", r#"{"bar":"file"}"#)]));
+  assert!(renderer.render().unwrap().contains("file"));
+
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer
+    .context
+    .set_stdin_content(r#"{"bar":"piped"}"#.to_owned());
+  assert!(renderer.render().unwrap().contains("piped"));
+}
+
+#[test]
+fn test_let_global() {
+  let doc = r#"
+<poml syntax="markdown">
+  <section>
+    <let name="tone" global="true">formal</let>
+    <let name="local">x</let>
+  </section>
+  <p>{{ tone }}</p>
+  <p>{{ local }}</p>
+</poml>
+"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let output = renderer.render().unwrap();
+  assert!(output.contains("formal"));
+  assert!(!output.contains('x'));
+}
+
+#[test]
+fn test_expression_cache_follows_variables() {
+  let doc = r#"
+<poml syntax="markdown">
+  <p>{{ name }} {{ name }}</p>
+  <let name="name">Bob</let>
+  <p>{{ name }}</p>
+  <p for="name in names">{{ name }}</p>
+  <p>{{ name }}</p>
+</poml>
+"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(
+    doc,
+    [
+      ("name".to_owned(), json!("Ada")),
+      ("names".to_owned(), json!(["Cy", "Di"])),
+    ],
+  );
+  let output = renderer.render().unwrap();
+  assert_eq!(output, "Ada Ada\n\nBob\n\nCy\n\nDi\n\nBob\n\n");
+}
+
 #[test]
 fn test_let_object() {
   use crate::MarkdownPomlRenderer;
@@ -572,6 +891,60 @@ fn test_examples() {
   assert!(output.contains("**Hint** No need to add any explanation"));
 }
 
+#[test]
+fn test_examples_options() {
+  let render = |doc: &str| {
+    let variables = HashMap::from([("questions".to_owned(), json!(["1+1", "2+2"]))]);
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+    renderer.render().unwrap().trim().to_string()
+  };
+  let output = render(
+    r#"<examples numbered="true"><example><input>Hi</input><output>Hello</output></example><example for="q in questions"><input>{{ q }}</input></example></examples>"#,
+  );
+  assert!(output.starts_with("# Examples\n\n## Example 1\n\nHi\nHello\n"));
+  assert!(output.contains("## Example 2\n\n1+1\n"));
+  assert!(output.ends_with("## Example 3\n\n2+2"));
+
+  let output = render(
+    r#"<examples chat="true"><example><input>Hi</input><output>Hello</output></example></examples>"#,
+  );
+  assert!(output.contains("**Human:** Hi\n**AI:** Hello"));
+
+  let output = render(
+    r#"<examples inputCaption="Question" chat="true"><example outputCaption="Answer"><input>Hi</input><output>Hello</output></example></examples>"#,
+  );
+  assert!(output.contains("**Question:** Hi\n**Answer:** Hello"));
+
+  let output = render(r#"<example><input>Hi</input><output>Hello</output></example>"#);
+  assert_eq!(output, "Hi\nHello");
+}
+
+#[test]
+fn test_qa() {
+  let render = |doc: &str| {
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+    renderer.render().map(|output| output.trim().to_string())
+  };
+  assert_eq!(
+    render(r#"<qa question="What is 1+1?">2</qa>"#).unwrap(),
+    "**Q:** What is 1+1?\n**A:** 2"
+  );
+  assert_eq!(
+    render(
+      r#"<examples chat="true" captionStyle="hidden"><qa question="Hi">Hello</qa></examples>"#
+    )
+    .unwrap(),
+    "**Human:** Hi\n**AI:** Hello"
+  );
+  assert_eq!(
+    render(r#"<qa question="Hi" captionStyle="header">Hello</qa>"#).unwrap(),
+    "# Human\n\nHi\n# AI\n\nHello"
+      .replace("Human", "Q")
+      .replace("AI", "A")
+  );
+  assert!(render("<qa>Hello</qa>").is_err());
+}
+
 #[test]
 fn test_bold_italic_strikethrough() {
   use crate::MarkdownPomlRenderer;
@@ -586,3 +959,1518 @@ fn test_bold_italic_strikethrough() {
   assert!(output.contains("*italic*"));
   assert!(output.contains("~~strikethrough~~"));
 }
+
+#[test]
+fn test_token_limit() {
+  use crate::MarkdownPomlRenderer;
+  use crate::render::token_counter::TokenCounter;
+  let doc = r#"
+<poml syntax="markdown">
+  <p tokenLimit="3">one two three four five six</p>
+</poml>
+"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let output = renderer.render().unwrap();
+  assert_eq!(output, "one two\n\n");
+
+  /** Token counter that counts every char as a token. */
+  struct CharTokenCounter {}
+  impl TokenCounter for CharTokenCounter {
+    fn count_tokens(&self, text: &str) -> usize {
+      text.chars().count()
+    }
+  }
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.token_counter = std::sync::Arc::new(CharTokenCounter {});
+  let output = renderer.render().unwrap();
+  assert_eq!(output, "one\n\n");
+
+  let doc = r#"<poml><p tokenLimit="3">one two three four five six</p><p>next</p></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  assert_eq!(renderer.render().unwrap(), "one two\n\nnext\n\n");
+}
+
+#[test]
+fn test_white_space_modes() {
+  use crate::MarkdownPomlRenderer;
+  let doc = r#"
+<poml syntax="markdown">
+  <p whiteSpace="pre">  a
+    b  </p>
+  <p whiteSpace="trim">  a
+    b  </p>
+  <p whiteSpace="filter">  a
+    b  </p>
+  <p>  a
+    b  </p>
+</poml>
+"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let output = renderer.render().unwrap();
+  assert_eq!(output, "  a\n    b  \n\na\n    b\n\na b\n\n a b  \n\n");
+}
+
+#[test]
+fn test_white_space_option() {
+  use crate::MarkdownPomlRenderer;
+  use crate::render::options::WhiteSpaceMode;
+  let doc = "<p> a\n b </p>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.white_space = Some(WhiteSpaceMode::Pre);
+  assert_eq!(renderer.render().unwrap(), " a\n b \n\n");
+}
+
+#[test]
+fn test_cdata_section() {
+  use crate::MarkdownPomlRenderer;
+  let doc = r#"<p>Use <![CDATA[<b>{{ name }}</b> #lt;]]> literally.</p>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let output = renderer.render().unwrap();
+  assert_eq!(output, "Use <b>{{ name }}</b> #lt; literally.\n\n");
+}
+
+#[test]
+fn test_render_cache() {
+  use crate::MarkdownPomlRenderer;
+  use crate::render::cache::{InMemoryRenderCache, RenderCache};
+  use std::sync::Arc;
+  let doc = r#"
+<poml syntax="markdown">
+  <include src="a.poml"/>
+  <table records="{{ [{a: 1}] }}"/>
+</poml>
+"#;
+  let cache = Arc::new(InMemoryRenderCache::new());
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.cache = Some(cache.clone());
+  renderer
+    .context
+    .set_file_loader(InMemoryLoader::from_iter([("a.poml", "<p>AAA</p>")]));
+  let output = renderer.render().unwrap();
+  assert!(output.contains("AAA"));
+  assert_eq!(cache.get("file:a.poml"), Some("<p>AAA</p>".to_owned()));
+
+  // The second render reads from the cache.
+  cache.put("file:a.poml", "<p>BBB</p>".to_owned(), None);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.cache = Some(cache.clone());
+  let output = renderer.render().unwrap();
+  assert!(output.contains("BBB"));
+  assert!(output.contains("| a |"));
+}
+
+#[test]
+fn test_if_present_attribute() {
+  use crate::MarkdownPomlRenderer;
+  let doc = r#"
+<poml syntax="markdown">
+  <p ifPresent="nickname">Nickname: {{ nickname }}</p>
+  <p ifPresent="missing">Missing</p>
+  <p ifPresent="empty">Empty</p>
+  <p ifPresent="user.profile.city as city">City: {{ city }}</p>
+  <p ifPresent="user.name.first">Name</p>
+  <p ifPresent="missing.name">Missing name</p>
+  <p ifPresent="user['profile'].zip">Zip</p>
+  <p ifPresent="user.tags[3]">Tag</p>
+  <p ifPresent="user.nothing.city">Nothing</p>
+</poml>
+"#;
+  let variables = HashMap::from([
+    ("nickname".to_owned(), json!("Bob")),
+    ("empty".to_owned(), json!("")),
+    (
+      "user".to_owned(),
+      json!({"profile": {"city": "Paris"}, "tags": ["a"], "nothing": null}),
+    ),
+  ]);
+  for policy in [None, Some(UndefinedPolicy::Error)] {
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+    renderer.options.undefined_policy = policy;
+    let output = renderer.render().unwrap();
+    assert_eq!(output, "Nickname: Bob\n\nEmpty\n\nCity: Paris\n\n");
+  }
+}
+
+#[test]
+fn test_raw_text() {
+  use crate::MarkdownPomlRenderer;
+  let doc = r#"
+<poml syntax="markdown">
+  <p raw="true">Hello, {{ name }} #lt;</p>
+  <pre>Hi,
+  {{ name }}</pre>
+  <p>Hello, {{ name }} #lt;</p>
+</poml>
+"#;
+  let variables = HashMap::from([("name".to_owned(), json!("world"))]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  let output = renderer.render().unwrap();
+  assert_eq!(
+    output,
+    "Hello, {{ name }} #lt;\n\nHi,\n  {{ name }}\n\nHello, world <\n\n"
+  );
+}
+
+#[test]
+fn test_xml_entities() {
+  use crate::MarkdownPomlRenderer;
+  let doc = r#"
+<poml syntax="markdown">
+  <cp caption="Q&amp;A">&lt;tag&gt; &quot;quoted&quot; &#39;single&#39; &#x7B;&#x7D; &nbsp;A &amp B</cp>
+</poml>
+"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let output = renderer.render().unwrap();
+  assert!(output.contains("# Q&A"));
+  assert!(output.contains("<tag> \"quoted\" 'single' {} \u{a0}A &amp B"));
+}
+
+#[test]
+fn test_xml_entities_in_attributes() {
+  use crate::render::compiled::CompiledTemplate;
+  let doc = r#"<poml>
+<p if="1 &lt; 2">Shown</p>
+<p if="2 &lt; 1">Hidden</p>
+<let name="a" value="&quot;hi&quot;"/>
+<let name="b" value="{{ &quot;there&quot; }}"/>
+<p for="x in [1, 2, 3]" if="x &gt; 2" title="{{ a + &apos;!&apos; }}">{{ a }} {{ b }} {{ x }}</p>
+<p ifPresent="a &amp;&amp; b as c">{{ c }}</p>
+</poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  assert_eq!(
+    renderer.render().unwrap(),
+    "Shown\n\nhi there 3\n\nthere\n\n"
+  );
+  let compiled = CompiledTemplate::compile(doc).unwrap();
+  let mut renderer = compiled.renderer(
+    doc,
+    render_context::RenderContext::from_iter(HashMap::<String, Value>::new()),
+    MarkdownTagRenderer::default(),
+  );
+  assert_eq!(
+    renderer.render().unwrap(),
+    "Shown\n\nhi there 3\n\nthere\n\n"
+  );
+}
+
+#[test]
+fn test_typed_attribute_values() {
+  let doc = r#"
+<poml syntax="markdown">
+  <p if="{{ count - count }}">Hidden</p>
+  <p data="{{ {a: count} }}" title="Hi {{ count }}" flag="{{ count > 1 }}">Shown</p>
+</poml>
+"#;
+  let context =
+    render_context::RenderContext::from_iter(HashMap::from([("count".to_owned(), json!(3))]));
+  let mut renderer = Renderer::new(doc, context, TestTagRenderer {});
+  let output = renderer.render().unwrap();
+  assert!(!output.contains("Hidden"));
+  assert!(output.contains("  - data: {\"a\":3}\n"));
+  assert!(output.contains("  - title: \"Hi 3\"\n"));
+  assert!(output.contains("  - flag: true\n"));
+}
+
+#[test]
+fn test_format_version() {
+  use crate::MarkdownPomlRenderer;
+  use crate::render::options::FormatVersion;
+  let doc = r#"
+<poml syntax="markdown">
+  <p>Hello </p>
+  <br/>
+  <p>World</p>
+</poml>
+"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  assert_eq!(renderer.render().unwrap(), "Hello \n\n\n\nWorld\n\n");
+
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.format_version = FormatVersion::V2;
+  assert_eq!(renderer.render().unwrap(), "Hello\n\nWorld\n");
+}
+
+#[test]
+fn test_escape_values() {
+  let doc = r#"<p>Name: {{ name }}, raw: {{ name | raw }}, or: {{ a || b }}</p>"#;
+  let variables = HashMap::from([
+    ("name".to_owned(), json!("*a_b* | `c` #1")),
+    ("a".to_owned(), json!(false)),
+    ("b".to_owned(), json!(true)),
+  ]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+  renderer.options.escape_values = true;
+  assert_eq!(
+    renderer.render().unwrap().trim(),
+    r"Name: \*a\_b\* \| \`c\` \#1, raw: *a_b* | `c` #1, or: true"
+  );
+
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+  assert_eq!(
+    renderer.render().unwrap().trim(),
+    "Name: *a_b* | `c` #1, raw: *a_b* | `c` #1, or: true"
+  );
+
+  let doc =
+    r#"<poml><p>Run <code inline="true">{{ name }}</code></p><code>{{ name }}</code></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  renderer.options.escape_values = true;
+  assert_eq!(
+    renderer.render().unwrap(),
+    "Run ``*a_b* | `c` #1``\n\n```\n*a_b* | `c` #1\n```"
+  );
+
+  // Cells of tables escape `|` and line breaks, which would break the table
+  let doc = r#"<table records="{{ records }}" />"#;
+  let variables = HashMap::from([("records".to_owned(), json!([{"a|b": "x|y\nz"}]))]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+  renderer.options.escape_values = true;
+  assert_eq!(
+    renderer.render().unwrap().trim(),
+    "| a\\|b      |\n| --------- |\n| x\\|y<br>z |"
+  );
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  assert!(renderer.render().unwrap().contains("| x|y\nz |"));
+}
+
+#[test]
+fn test_random_seed() {
+  let doc = r#"<poml>
+<list><item for="e in shuffle(examples)">{{ e }}</item></list>
+<p>{{ randint(1, 1000000) }} {{ randint(1, 1000000) }}</p>
+</poml>"#;
+  let variables = HashMap::from([("examples".to_owned(), json!(["a", "b", "c", "d"]))]);
+  let render = |seed| {
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+    renderer.options.random_seed = Some(seed);
+    renderer.render().unwrap()
+  };
+  let output = render(42);
+  assert_eq!(render(42), output);
+  let numbers: Vec<&str> = output
+    .trim_end()
+    .lines()
+    .last()
+    .unwrap()
+    .split(' ')
+    .collect();
+  assert_ne!(numbers[0], numbers[1]);
+  for item in ["- a", "- b", "- c", "- d"] {
+    assert!(output.contains(item));
+  }
+}
+
+#[test]
+fn test_filters() {
+  let doc = r#"<poml>
+<p>{{ name | trim | title }}: {{ bio | truncate(10) }} [{{ code | pad(6, '.') | upper }}]</p>
+<p>{{ nickname | default('none') }}, {{ a || b }}, {{ name | shout }}</p>
+<cp caption="{{ name | trim | replace('alice', 'bob') }}">{{ bio | lower | raw }}</cp>
+</poml>"#;
+  let variables = HashMap::from([
+    ("name".to_owned(), json!("  alice smith ")),
+    ("bio".to_owned(), json!("Writes *Rust* and Python")),
+    ("code".to_owned(), json!("ab")),
+    ("a".to_owned(), json!(false)),
+    ("b".to_owned(), json!(true)),
+  ]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+  renderer
+    .context
+    .register_filter("shout", |value: Value, _: &[Value]| {
+      Ok(json!(format!(
+        "{}!",
+        value.as_str().unwrap_or_default().trim()
+      )))
+    });
+  renderer.options.escape_values = true;
+  assert_eq!(
+    renderer.render().unwrap(),
+    "Alice Smith: Writes \\*Ru... [AB....]\n\nnone, true, alice smith!\n\n# bob smith\n\nwrites *rust* and python"
+  );
+
+  let mut renderer =
+    MarkdownPomlRenderer::create_from_doc_and_variables("<p>{{ name | unknown }}</p>", variables);
+  assert!(renderer.render().is_err());
+}
+
+#[test]
+fn test_auto_fence_code() {
+  use crate::MarkdownPomlRenderer;
+  let doc = r#"<p>Snippet: {{ snippet }}</p>"#;
+  let variables = HashMap::from([(
+    "snippet".to_owned(),
+    json!("fn main() {\n    println!(\"hi\");\n}"),
+  )]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+  renderer.options.auto_fence_code = true;
+  assert_eq!(
+    renderer.render().unwrap(),
+    "Snippet: \n```\nfn main() {\n    println!(\"hi\");\n}\n```\n\n\n"
+  );
+
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  assert!(!renderer.render().unwrap().contains("```"));
+}
+
+#[test]
+fn test_falsy_attributes_do_not_hide_node() {
+  let doc = r#"<poml><let name="x" value="0" /><p>x={{ x }}</p><code lang="" inline="false">a</code><p if="x">hidden</p></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let result = renderer.render().unwrap();
+  assert!(result.contains("x=0"), "{result}");
+  assert!(result.contains("```\na\n```"), "{result}");
+  assert!(!result.contains("hidden"), "{result}");
+}
+
+#[test]
+fn test_if_and_for_on_same_node() {
+  let doc = r#"<poml><p for="x in xs" if="{{ x.visible }}">{{ x.name }}</p></poml>"#;
+  let variables = HashMap::from([(
+    "xs".to_owned(),
+    json!([
+      {"name": "a", "visible": true},
+      {"name": "b", "visible": false},
+      {"name": "c", "visible": true},
+    ]),
+  )]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  assert_eq!(renderer.render().unwrap(), "a\n\nc\n\n");
+}
+
+#[test]
+fn test_data_attributes_are_ignored() {
+  let doc =
+    r#"<poml data-owner="search"><p data-cache="false" data-route="{{ route }}">Hello</p></poml>"#;
+  let variables = HashMap::from([("route".to_owned(), json!("fast"))]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  assert_eq!(renderer.render().unwrap(), "Hello\n\n");
+}
+
+#[test]
+fn test_for_loop_over_range() {
+  let doc = r#"<poml><p for="i in range(3)">Item {{ i + 1 }}</p></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  assert_eq!(renderer.render().unwrap(), "Item 1\n\nItem 2\n\nItem 3\n\n");
+}
+
+#[test]
+fn test_block_order() {
+  let doc =
+    r#"<poml><output-format>JSON</output-format><p order="-1">First</p><p>Second</p></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer
+    .options
+    .block_order
+    .insert("output-format".to_owned(), 10);
+  let result = renderer.render().unwrap();
+  let positions: Vec<usize> = ["First", "Second", "JSON"]
+    .iter()
+    .map(|s| result.find(s).unwrap())
+    .collect();
+  assert!(positions.is_sorted(), "{result}");
+
+  // A parsed document is reordered in each render, keeping its own order
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer
+    .options
+    .block_order
+    .insert("output-format".to_owned(), 10);
+  renderer.parse().unwrap();
+  assert_eq!(renderer.render().unwrap(), result);
+  assert_eq!(renderer.render().unwrap(), result);
+
+  let doc = r#"<poml><p order="two">A</p></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  assert!(renderer.render().is_err());
+}
+
+#[test]
+fn test_conversation_state_placeholders() {
+  let doc = r#"
+<poml>
+  <role>You are a helpful agent.</role>
+  <history/>
+  <scratchpad/>
+  <tool-results/>
+</poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.conversation_state = HashMap::from([
+    (
+      "history".to_owned(),
+      vec![
+        json!({"role": "user", "content": "Hi"}),
+        json!({"role": "assistant", "content": "Hello!"}),
+      ],
+    ),
+    ("scratchpad".to_owned(), vec![json!("Plan: greet back.")]),
+  ]);
+  renderer.options.placeholder_marker = "[[{name}]]".to_owned();
+  let output = renderer.render().unwrap();
+  assert!(output.contains("user: Hi\n\nassistant: Hello!\n\n"));
+  assert!(output.contains("Plan: greet back."));
+  assert!(output.contains("[[tool-results]]"));
+  assert!(!output.contains("[[history]]"));
+
+  let doc = "<poml><history/><scratchpad/><p>End</p></poml>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.tag_renderer = MarkdownTagRenderer {
+    writer: WriterOptions {
+      paragraph_separator: "\n".to_string(),
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+  renderer.options.conversation_state =
+    HashMap::from([("history".to_owned(), vec![json!("Hi"), json!("Hello!")])]);
+  assert_eq!(
+    renderer.render().unwrap(),
+    "Hi\nHello!\n<!-- poml:scratchpad -->\nEnd\n"
+  );
+}
+
+#[test]
+fn test_summary_tag() {
+  use crate::render::cache::InMemoryRenderCache;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  let doc = r#"<poml><p><summary of="article" maxTokens="3" /></p><p><summary maxTokens="10">short</summary></p></poml>"#;
+  let variables = HashMap::from([(
+    "article".to_owned(),
+    json!("a very long article about many things"),
+  )]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+  assert_eq!(renderer.render().unwrap(), "a very long\n\nshort\n\n");
+
+  let calls = Arc::new(AtomicUsize::new(0));
+  let summarizer_calls = calls.clone();
+  let cache = Arc::new(InMemoryRenderCache::new());
+  for _ in 0..2 {
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+    let summarizer_calls = summarizer_calls.clone();
+    renderer.options.summarizer = Some(Arc::new(move |text: &str, max_tokens: usize| {
+      summarizer_calls.fetch_add(1, Ordering::SeqCst);
+      Ok(format!("{} words in {max_tokens}", text.split(' ').count()))
+    }));
+    renderer.options.cache = Some(cache.clone());
+    assert_eq!(renderer.render().unwrap(), "7 words in 3\n\nshort\n\n");
+  }
+  assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_pure_mode() {
+  let doc = r#"<poml><p>{{ name }}</p><p><include src="a.poml" /></p></poml>"#;
+  let variables = HashMap::from([("name".to_owned(), json!("Alice"))]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+  renderer.options.pure = true;
+  let err = renderer.render().unwrap_err();
+  let source = std::error::Error::source(&err).unwrap().to_string();
+  assert!(source.contains("<include>"), "{source}");
+
+  let doc = r#"<poml><p>{{ name }}</p><p><code src="a.rs" /></p></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+  renderer.options.pure = true;
+  assert!(renderer.render().is_err());
+
+  let doc = r#"<poml><p>{{ name }}</p></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  renderer.options.pure = true;
+  assert_eq!(renderer.render().unwrap(), "Alice\n\n");
+}
+
+#[test]
+fn test_pure_mode_expressions() {
+  use crate::error::ErrorCode;
+
+  let render = |doc: &str, pure: bool| {
+    let mut context =
+      render_context::RenderContext::from_iter(HashMap::from([("name".to_owned(), json!("A"))]));
+    context.enable_env();
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_context(doc, context);
+    renderer.options.pure = pure;
+    renderer.render()
+  };
+  let doc = r#"<poml><p>{{ env.HOME }}</p></poml>"#;
+  assert!(render(doc, false).is_ok());
+  assert_eq!(
+    render(doc, true).unwrap_err().code(),
+    ErrorCode::AccessDenied
+  );
+  let doc = r#"<poml><let name="env" value="{{ name }}"/><p>{{ env }}</p></poml>"#;
+  assert_eq!(render(doc, true).unwrap(), "A\n\n");
+  for expression in [
+    "now()",
+    "today()",
+    "formatDate('2025-01-31', 'YYYY')",
+    "shuffle([1, 2])",
+    "sample([1, 2], 1)",
+    "randint(1, 2)",
+  ] {
+    let doc = format!("<poml><p>{{{{ {expression} }}}}</p></poml>");
+    assert!(render(&doc, false).is_ok());
+    assert_eq!(
+      render(&doc, true).unwrap_err().code(),
+      ErrorCode::AccessDenied
+    );
+  }
+  let doc = "<poml><p>{{ randint(1, 1) }}</p></poml>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.pure = true;
+  renderer.options.random_seed = Some(7);
+  assert_eq!(renderer.render().unwrap(), "1\n\n");
+}
+
+#[test]
+fn test_progress_and_cancellation() {
+  use crate::error::ErrorKind;
+  use crate::render::progress::CancellationToken;
+  use std::sync::{Arc, Mutex};
+
+  let doc = r#"<poml><p for="i in range(3)">{{ i }}</p></poml>"#;
+  let reports = Arc::new(Mutex::new(Vec::new()));
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let progress_reports = reports.clone();
+  renderer.options.on_progress = Some(Arc::new(move |done: usize, total: usize| {
+    progress_reports.lock().unwrap().push((done, total));
+  }));
+  renderer.parse().unwrap();
+  renderer.render().unwrap();
+  assert_eq!(reports.lock().unwrap().last(), Some(&(5, 5)));
+  assert!(
+    reports
+      .lock()
+      .unwrap()
+      .iter()
+      .all(|(done, total)| done <= total)
+  );
+  // A second render of the same renderer reports its progress from the start.
+  reports.lock().unwrap().clear();
+  renderer.render().unwrap();
+  let reports = reports.lock().unwrap();
+  assert_eq!(reports.first().map(|(done, _)| *done), Some(1));
+  assert_eq!(reports.last(), Some(&(5, 5)));
+
+  let token = CancellationToken::new();
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let progress_token = token.clone();
+  renderer.options.on_progress = Some(Arc::new(move |done: usize, _| {
+    if done == 2 {
+      progress_token.cancel();
+    }
+  }));
+  renderer.options.cancellation_token = Some(token);
+  let err = renderer.render().unwrap_err();
+  assert!(matches!(err.kind, ErrorKind::Cancelled), "{err}");
+}
+
+#[test]
+fn test_template() {
+  let doc = r#"<poml>
+<template name="card" params="title, body"><p>{{ title }}: {{ body }}</p></template>
+<card title="A" body="{{ 1 + 1 }}" />
+<card title="B" />
+</poml>"#;
+  let variables = HashMap::new();
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  let result = renderer.render().unwrap();
+  assert!(result.contains("A: 2\n\n"), "{result}");
+  assert!(result.contains("B: null\n\n"), "{result}");
+
+  let doc = r#"<poml><template name="loop"><loop /></template><loop /></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  assert!(renderer.render().is_err());
+}
+
+#[test]
+fn test_template_used_by_included_document() {
+  let doc = r#"<poml>
+<template name="card" params="t"><p>{{ t }}:  <b>x</b></p><code raw="true">let a = <b>1</b>;</code></template>
+<include src="inc.poml"/>
+</poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer
+    .context
+    .set_file_loader(InMemoryLoader::from_iter([(
+      "inc.poml",
+      r#"<card t="x"/>"#,
+    )]));
+  let output = renderer.render().unwrap();
+  assert!(output.contains("```\nlet a = <b>1</b>;\n```"), "{output}");
+
+  let inlined = doc.replace(r#"<include src="inc.poml"/>"#, r#"<card t="x"/>"#);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(&inlined, HashMap::new());
+  assert_eq!(renderer.render().unwrap(), output);
+}
+
+#[test]
+fn test_include_srcset() {
+  let doc = r#"<poml><include srcset="override.poml, default.poml"/></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer
+    .context
+    .set_file_loader(InMemoryLoader::from_iter([(
+      "default.poml",
+      "<p>Default</p>",
+    )]));
+  assert_eq!(renderer.render().unwrap(), "Default\n\n");
+
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.context.set_file_loader(InMemoryLoader::from_iter([
+    ("default.poml", "<p>Default</p>"),
+    ("override.poml", "<p>Override</p>"),
+  ]));
+  assert_eq!(renderer.render().unwrap(), "Override\n\n");
+
+  let doc = r#"<poml><include srcset="missing_a.poml, missing_b.poml"/></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let error_message = renderer.render().unwrap_err().to_string();
+  assert!(error_message.contains("missing_b.poml"), "{error_message}");
+}
+
+#[test]
+fn test_include_with_bindings() {
+  let doc = r#"<poml>
+<include src="a.poml" with="{{ {user: name} }}" />
+<include src="a.poml" with="{{ {user: 'Bob'} }}" isolated="true" />
+</poml>"#;
+  let variables = HashMap::from([("name".to_owned(), json!("Alice"))]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  renderer
+    .context
+    .set_file_loader(InMemoryLoader::from_iter([(
+      "a.poml",
+      "<p>{{ user }} {{ name }}</p>",
+    )]));
+  let result = renderer.render().unwrap();
+  assert!(result.contains("Alice Alice"), "{result}");
+  assert!(result.contains("Bob null"), "{result}");
+
+  let doc = r#"<poml><include src="a.poml" with="1" /></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer
+    .context
+    .set_file_loader(InMemoryLoader::from_iter([("a.poml", "<p></p>")]));
+  assert!(renderer.render().is_err());
+}
+
+#[test]
+fn test_include_requires() {
+  let doc = r#"<poml><include src="tools.poml" requires="feature.tools" /></poml>"#;
+  let render_with_capabilities = |capabilities: Value| {
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+    renderer
+      .context
+      .set_file_loader(InMemoryLoader::from_iter([("tools.poml", "<p>Tools</p>")]));
+    let Value::Object(capabilities) = capabilities else {
+      panic!();
+    };
+    renderer.options.capabilities = capabilities;
+    renderer.render().unwrap()
+  };
+  assert_eq!(
+    render_with_capabilities(json!({"feature": {"tools": true}})),
+    "Tools\n\n"
+  );
+  assert_eq!(
+    render_with_capabilities(json!({"feature": {"tools": false}})),
+    ""
+  );
+  assert_eq!(render_with_capabilities(json!({})), "");
+
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.trace = true;
+  renderer.render().unwrap();
+  let trace = renderer.trace();
+  assert_eq!(
+    trace[0].kind,
+    trace::TraceEventKind::Include {
+      src: "tools.poml".to_string(),
+      skipped: true
+    }
+  );
+  assert_eq!(
+    trace[0].to_string(),
+    "<anonymous>: skipped including tools.poml without the required capabilities"
+  );
+  assert_eq!(trace[0].to_json()["skipped"], json!(true));
+}
+
+#[test]
+fn test_annotate_token_counts() {
+  let doc =
+    r#"<poml><p>Hello world</p><let name="x" value="1" /><list><item>a</item></list></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.annotate_token_counts = true;
+  assert_eq!(
+    renderer.render().unwrap(),
+    "Hello world\n\n<!-- p: 4 tokens -->\n\n- a\n\n<!-- list: 2 tokens -->\n\n"
+  );
+
+  let doc = r#"<poml><code>let a = 1;</code><p tokenLimit="2">a b c d</p></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.options.annotate_token_counts = true;
+  let output = renderer.render().unwrap();
+  assert!(output.contains("\n```\n<!-- code: "), "{output}");
+  assert!(output.contains("\n<!-- p: "), "{output}");
+}
+
+#[test]
+fn test_relative_include_path() {
+  let doc = r#"<poml><include src="sub/a.poml" /></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.set_document_path("prompts/main.poml");
+  renderer.context.set_file_loader(InMemoryLoader::from_iter([
+    ("prompts/sub/a.poml", r#"<include src="..\b.poml" />"#),
+    ("prompts/b.poml", "<p>B</p>"),
+  ]));
+  assert_eq!(renderer.render().unwrap(), "B\n\n");
+}
+
+#[test]
+fn test_meta_const() {
+  let doc = r#"<poml>
+<meta const='{"MAX_ITEMS": 2, "MODES": ["fast", "careful"]}' />
+<p for="m in MODES">{{ m }} {{ MAX_ITEMS }}</p>
+<include src="a.poml" />
+</poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer
+    .context
+    .set_file_loader(InMemoryLoader::from_iter([(
+      "a.poml",
+      "<p>{{ MODES[1] }}</p>",
+    )]));
+  let result = renderer.render().unwrap();
+  assert!(result.contains("fast 2"), "{result}");
+  assert!(result.contains("careful\n\n"), "{result}");
+
+  for doc in [
+    r#"<poml><meta const='{"N": 1}' /><let name="N" value="2" /></poml>"#,
+    r#"<poml><meta const='{"N": 1}' /><p for="N in [1]">x</p></poml>"#,
+    r#"<poml><let name="N" value="2" /><meta const='{"N": 1}' /></poml>"#,
+    r#"<poml><meta const='[1]' /></poml>"#,
+  ] {
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+    assert!(renderer.render().is_err(), "{doc}");
+  }
+
+  for isolated in ["false", "true"] {
+    let doc = format!(
+      r#"<poml><meta const='{{"N": 1}}' /><include src="a.poml" with="{{{{ {{N: 2}} }}}}" isolated="{isolated}" /></poml>"#
+    );
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(&doc, HashMap::new());
+    renderer
+      .context
+      .set_file_loader(InMemoryLoader::from_iter([("a.poml", "<p>{{ N }}</p>")]));
+    let err = renderer.render().unwrap_err();
+    assert_eq!(err.code(), ErrorCode::ConstantShadowed, "{doc}");
+  }
+}
+
+#[test]
+fn test_undefined_policy() {
+  let doc =
+    "<poml>\n  <p ifPresent=\"nickname\">{{ nickname }}</p>\n  <p>Hello, {{ name }}!</p>\n</poml>";
+  let render = |policy| {
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+    renderer.options.undefined_policy = policy;
+    (renderer.render(), renderer.warnings())
+  };
+  let (output, warnings) = render(None);
+  assert_eq!(output.unwrap(), "Hello, null!\n\n");
+  assert!(warnings.is_empty());
+
+  let (output, _) = render(Some(UndefinedPolicy::Error));
+  let err = output.unwrap_err();
+  assert_eq!(err.code(), ErrorCode::UndefinedVariable);
+  let position = err.position().unwrap();
+  assert_eq!((position.line, position.col), (3, 16));
+  assert!(err.to_string().contains("Variable `name` is not defined"));
+
+  let (output, warnings) = render(Some(UndefinedPolicy::Warn));
+  assert_eq!(output.unwrap(), "Hello, null!\n\n");
+  assert_eq!(warnings.len(), 1);
+  assert_eq!(warnings[0].severity, Severity::Warning);
+  assert_eq!(warnings[0].code, ErrorCode::UndefinedVariable);
+  let position = warnings[0].position.as_ref().unwrap();
+  assert_eq!((position.line, position.col), (3, 16));
+}
+
+#[test]
+fn test_source_map() {
+  let doc = "<poml>\n  <p>Hello, {{ name }}!</p>\n  <cp caption=\"Rules\" data-section=\"rules\">\n    <p for=\"rule in rules\">{{ rule }}</p>\n  </cp>\n</poml>";
+  let variables = HashMap::from([
+    ("name".to_owned(), json!("Ada")),
+    ("rules".to_owned(), json!(["Be brief", "Be kind"])),
+  ]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables.clone());
+  renderer.options.source_map = true;
+  let output = renderer.render().unwrap();
+  let map = renderer.source_map().unwrap();
+  let line_of = |text: &str| {
+    let entry = map.lookup(output.find(text).unwrap()).unwrap();
+    (entry.tag.clone(), entry.source.line)
+  };
+  assert_eq!(line_of("Hello"), (None, 2));
+  assert_eq!(line_of("# Rules"), (Some("cp".to_string()), 3));
+  assert_eq!(line_of("Be kind"), (None, 4));
+  let entry = map.lookup(output.find("# Rules").unwrap()).unwrap();
+  assert_eq!(entry.data, vec![("section".to_string(), json!("rules"))]);
+  let json = map.to_json();
+  let cp = json.as_array().unwrap().iter().find(|e| e["tag"] == "cp");
+  assert_eq!(cp.unwrap()["data"], json!({"section": "rules"}));
+  let root = &map.entries[0];
+  assert_eq!((root.tag.as_deref(), root.depth), (Some("poml"), 0));
+  assert_eq!(&output[root.output.start..root.output.end], output.trim());
+
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  renderer.render().unwrap();
+  assert!(renderer.source_map().is_none());
+}
+
+#[test]
+fn test_trace() {
+  let doc = "<poml>\n  <p if=\"ready\">Ready</p>\n  <p for=\"n in numbers\" if=\"n > 1\">{{ n }}</p>\n  <include src=\"a.poml\" data-cache=\"no\" />\n</poml>";
+  let variables = HashMap::from([
+    ("ready".to_owned(), json!(false)),
+    ("numbers".to_owned(), json!([1, 2])),
+  ]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  renderer.set_filename("main.poml");
+  renderer
+    .context
+    .set_file_loader(InMemoryLoader::from_iter([("a.poml", "<p>A</p>")]));
+  renderer.options.trace = true;
+  renderer.render().unwrap();
+  let lines: Vec<String> = renderer.trace().iter().map(|e| e.to_string()).collect();
+  assert_eq!(
+    lines,
+    vec![
+      "main.poml:2: `ready` = false",
+      "main.poml:2: <p> condition `ready` is false",
+      "main.poml:2: <p> rendered 0 bytes",
+      "main.poml:3: `n in numbers` = [1,2]",
+      "main.poml:3: <p> loop `n in numbers` ran 2 iterations",
+      "main.poml:3: `n > 1` = false",
+      "main.poml:3: <p> condition `n > 1` is false",
+      "main.poml:3: `n > 1` = true",
+      "main.poml:3: <p> condition `n > 1` is true",
+      "main.poml:3: `n` = 2",
+      "main.poml:3: <p> rendered 3 bytes",
+      "main.poml: included a.poml",
+      "a.poml:1: <p> rendered 3 bytes",
+      "a.poml:1: <poml> rendered 3 bytes",
+      "main.poml:4: <include data-cache=\"no\"> rendered 3 bytes",
+      "main.poml:1: <poml> rendered 6 bytes",
+    ]
+  );
+  assert_eq!(renderer.trace()[1].to_json()["event"], json!("condition"));
+  assert_eq!(
+    renderer.trace()[14].to_json()["data"],
+    json!({"cache": "no"})
+  );
+}
+
+#[test]
+fn test_error_position() {
+  let doc = "<poml>\n  <p>Hello, {{ user.name }}!</p>\n  <p>{{ 1 + }}</p>\n</poml>";
+  let variables = HashMap::from([("user".to_owned(), json!({"name": "Ada"}))]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  let err = renderer.render().unwrap_err();
+  let position = err.position().unwrap();
+  assert_eq!((position.line, position.col), (3, 11));
+  assert_eq!(position.snippet, "  <p>{{ 1 + }}</p>");
+  assert!(err.to_string().contains("(line 3, column 11)"));
+  assert!(
+    err
+      .to_string()
+      .contains("  <p>{{ 1 + }}</p>\n            ^"),
+    "{err}"
+  );
+
+  let doc = "<poml>\n  <p>Hello, {{ name</p>\n</poml>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let err = renderer.render().unwrap_err();
+  assert_eq!(err.position().unwrap().line, 2);
+
+  let doc = "<poml>\n  <p if=\"1 +\">Text</p>\n</poml>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let err = renderer.render().unwrap_err();
+  assert_eq!(
+    (err.position().unwrap().line, err.position().unwrap().col),
+    (2, 12)
+  );
+
+  // Errors of evaluation are located at the failing operator, field or function
+  let doc = "<poml>\n  <p>{{ user.name.first }}</p>\n</poml>";
+  let variables = HashMap::from([("user".to_owned(), json!({"name": "Ada"}))]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  let err = renderer.render().unwrap_err();
+  let position = err.position().unwrap();
+  assert_eq!((position.line, position.col), (2, 19));
+  assert_eq!(position.caret(), "                  ^^^^^");
+
+  let doc = "<poml>\n\t<p for=\"n in count(range(3), 'item +')\">{{ n }}</p>\n</poml>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let err = renderer.render().unwrap_err();
+  let position = err.position().unwrap();
+  assert_eq!((position.line, position.col), (2, 15));
+  assert_eq!(position.caret(), "\t             ^^^^^");
+}
+
+#[test]
+fn test_error_diagnostic() {
+  let doc = "<poml>\n  <p>{{ 1 / 0 }}</p>\n</poml>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let diagnostic = renderer.render().unwrap_err().to_diagnostic();
+  assert_eq!(diagnostic.code, ErrorCode::DivisionByZero);
+  assert_eq!(diagnostic.severity, Severity::Error);
+  assert_eq!(diagnostic.span, Some(Span { start: 17, end: 18 }));
+  assert!(diagnostic.help.is_some());
+  let json = diagnostic.to_json();
+  assert_eq!(json["code"], json!("E204"));
+  assert_eq!(json["line"], json!(2));
+
+  let doc = "<poml>\n  <unknown-tag>Text</unknown-tag>\n</poml>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let err = renderer.render().unwrap_err();
+  assert_eq!(err.code(), ErrorCode::UnknownTag);
+  assert!(err.to_string().starts_with("RendererError[E102]"), "{err}");
+
+  let doc = "<poml>\n  <p>Text</p>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let diagnostic = renderer.render().unwrap_err().to_diagnostic();
+  assert_eq!(diagnostic.code, ErrorCode::UnclosedTag);
+  assert!(diagnostic.message.contains("Document has not finished"));
+
+  let doc = "Intro\n<poml>\n  <p>Text</p>\n</poml>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let err = renderer.render().unwrap_err();
+  assert_eq!(err.code(), ErrorCode::Syntax);
+  assert!(err.to_string().starts_with("RendererError[E000]"), "{err}");
+
+  // Without any specific code, the code is of the kind of the innermost error
+  let cause = Error {
+    kind: ErrorKind::EvaluatorError,
+    message: "Cause".to_string(),
+    source: None,
+    position: None,
+    code: None,
+  };
+  let err = Error {
+    kind: ErrorKind::RendererError,
+    message: "Wrapper".to_string(),
+    source: Some(Box::new(cause)),
+    position: None,
+    code: None,
+  };
+  assert_eq!(err.code(), ErrorCode::Expression);
+}
+
+#[test]
+fn test_check_collects_errors() {
+  let doc = r#"<poml>
+  <p>{{ 1 / 0 }}</p>
+  <p>Fine</p>
+  <unknown-tag>Text</unknown-tag>
+  <p if="1 +">Text</p>
+</poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let diagnostics = renderer.check();
+  let codes: Vec<ErrorCode> = diagnostics.iter().map(|d| d.code).collect();
+  assert_eq!(
+    codes,
+    vec![
+      ErrorCode::DivisionByZero,
+      ErrorCode::UnknownTag,
+      ErrorCode::ExpressionSyntax
+    ]
+  );
+  let lines: Vec<usize> = diagnostics
+    .iter()
+    .map(|d| d.position.as_ref().unwrap().line)
+    .collect();
+  assert_eq!(lines, vec![2, 4, 5]);
+
+  let mut renderer =
+    MarkdownPomlRenderer::create_from_doc_and_variables("<poml><p>Fine</p></poml>", HashMap::new());
+  assert!(renderer.check().is_empty());
+
+  let mut renderer =
+    MarkdownPomlRenderer::create_from_doc_and_variables("<poml><p>Text</poml>", HashMap::new());
+  assert_eq!(renderer.check().len(), 1);
+}
+
+#[test]
+fn test_unknown_tag_policy() {
+  let doc = r#"<poml>
+  <p>Before</p>
+  <callout kind="note"><p>Inside</p></callout>
+</poml>"#;
+  let render = |policy: UnknownTagPolicy| {
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+    renderer.options.unknown_tag_policy = policy;
+    renderer.render()
+  };
+  let err = render(UnknownTagPolicy::Error).unwrap_err();
+  assert_eq!(err.code(), ErrorCode::UnknownTag);
+  assert_eq!(render(UnknownTagPolicy::Ignore).unwrap(), "Before\n\n");
+  assert_eq!(
+    render(UnknownTagPolicy::PassthroughChildren).unwrap(),
+    "Before\n\nInside\n\n"
+  );
+  let output = render(UnknownTagPolicy::Custom(|tag, attributes, children| {
+    let kind = attr::get_attribute(attributes, "kind").and_then(|v| v.as_str());
+    Ok(format!(
+      "> [{}:{}] {}",
+      tag.name,
+      kind.unwrap_or_default(),
+      children.concat().trim()
+    ))
+  }))
+  .unwrap();
+  assert_eq!(output, "Before\n\n> [callout:note] Inside");
+}
+
+#[test]
+fn test_sandbox() {
+  use crate::render::file_loader::Sandbox;
+  let doc = r#"<poml><include src="sub/a.poml" /></poml>"#;
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.set_document_path("prompts/main.poml");
+  renderer.context.set_sandbox(Sandbox {
+    deny_filesystem: true,
+    root: Some("prompts".to_string()),
+  });
+  renderer.context.set_file_loader(InMemoryLoader::from_iter([
+    (
+      "prompts/sub/a.poml",
+      r#"<include src="../../secret.poml" />"#,
+    ),
+    ("secret.poml", "<p>Secret</p>"),
+  ]));
+  let error_message = renderer.render().unwrap_err().to_string();
+  assert!(
+    error_message.contains("outside of the sandbox"),
+    "{error_message}"
+  );
+
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.context.set_sandbox(Sandbox {
+    deny_filesystem: true,
+    root: None,
+  });
+  let error_message = renderer.render().unwrap_err().to_string();
+  assert!(error_message.contains("denied"), "{error_message}");
+}
+
+/**
+ * Sample usages of the tags, which every tag renderer supporting the tag should render.
+ */
+const CONFORMANCE_SAMPLES: &[(&str, &str)] = &[
+  ("poml", "<poml><p>Text</p></poml>"),
+  ("p", "<p>Text</p>"),
+  ("pre", "<pre>Text  with  spaces</pre>"),
+  ("br", "<p>Line<br/>Line</p>"),
+  ("b", "<p><b>Text</b></p>"),
+  ("i", "<p><i>Text</i></p>"),
+  ("s", "<p><s>Text</s></p>"),
+  ("strike", "<p><strike>Text</strike></p>"),
+  ("u", "<p><u>Text</u></p>"),
+  ("sub", "<p>H<sub>2</sub>O</p>"),
+  ("sup", "<p>x<sup>2</sup></p>"),
+  ("q", "<p><q>Text</q></p>"),
+  ("kbd", "<p><kbd>Ctrl</kbd></p>"),
+  ("qa", r#"<qa question="Question">Answer</qa>"#),
+  ("code", "<code>let a = 1;</code>"),
+  ("h", "<h>Title</h>"),
+  ("section", "<section><h>Title</h><p>Text</p></section>"),
+  ("cp", r#"<cp caption="Caption">Text</cp>"#),
+  ("role", "<role>Text</role>"),
+  ("task", "<task>Text</task>"),
+  ("output-format", "<output-format>Text</output-format>"),
+  ("examples", "<examples><example>Text</example></examples>"),
+  (
+    "example",
+    "<example><input>Question</input><output>Answer</output></example>",
+  ),
+  ("input", "<input>Question</input>"),
+  ("output", "<output>Answer</output>"),
+  ("hint", "<hint>Text</hint>"),
+  (
+    "stepwise-instructions",
+    "<stepwise-instructions><list><item>Step</item></list></stepwise-instructions>",
+  ),
+  ("meta", r#"<poml><meta title="Title"/><p>Text</p></poml>"#),
+  ("item", "<list><item>Text</item></list>"),
+  (
+    "list",
+    r#"<list listStyle="decimal"><item>Text</item></list>"#,
+  ),
+  ("table", r#"<table records="{{ [{name: 'a'}] }}" />"#),
+];
+
+/**
+ * Render the samples of the tags supported by the tag renderer. Each result is `Ok` with
+ * the output, or `Err` with the error or panic message.
+ */
+fn conformance_results<T: TagRenderer>(
+  tag_renderer: T,
+) -> Vec<(&'static str, Option<std::result::Result<String, String>>)> {
+  CONFORMANCE_SAMPLES
+    .iter()
+    .map(|(tag, doc)| {
+      if !tag_renderer.is_supported_tag(tag) {
+        return (*tag, None);
+      }
+      let tag_renderer = tag_renderer.clone();
+      let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+        let context = render_context::RenderContext::from_iter(HashMap::<String, Value>::new());
+        Renderer::new(doc, context, tag_renderer).render()
+      }));
+      let result = match result {
+        Ok(Ok(output)) if output.trim().is_empty() => Err("empty output".to_string()),
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("panicked".to_string()),
+      };
+      (*tag, Some(result))
+    })
+    .collect()
+}
+
+#[test]
+fn test_tag_renderer_conformance() {
+  for tag in tag_renderer::markdown::SUPPORTED_TAGS {
+    assert!(
+      CONFORMANCE_SAMPLES.iter().any(|(t, _)| t == tag),
+      "No conformance sample for <{tag}>"
+    );
+  }
+
+  let renderers = [(
+    "markdown",
+    conformance_results(MarkdownTagRenderer::default()),
+  )];
+  let mut report = String::from("| tag |");
+  for (name, _) in renderers.iter() {
+    report += &format!(" {name} |");
+  }
+  report += &format!("\n|---|{}\n", "---|".repeat(renderers.len()));
+  let mut failures = Vec::new();
+  for (i, (tag, _)) in CONFORMANCE_SAMPLES.iter().enumerate() {
+    report += &format!("| {tag} |");
+    for (name, results) in renderers.iter() {
+      let cell = match &results[i].1 {
+        None => "unsupported",
+        Some(Ok(_)) => "ok",
+        Some(Err(e)) => {
+          failures.push(format!("<{tag}> in {name}: {e}"));
+          "FAIL"
+        }
+      };
+      report += &format!(" {cell} |");
+    }
+    report += "\n";
+  }
+  assert!(failures.is_empty(), "{failures:#?}\n{report}");
+}
+
+#[test]
+fn test_render_to() {
+  let doc = "<poml><p>Hello, {{ name }}!</p></poml>";
+  let renderer = || {
+    let variables = HashMap::from([("name".to_owned(), json!("Ada"))]);
+    MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables)
+  };
+  let mut output = String::from("# Prompt\n");
+  renderer().render_to(&mut output).unwrap();
+  assert_eq!(output, "# Prompt\nHello, Ada!\n\n");
+
+  let mut bytes = Vec::new();
+  renderer().render_to_writer(&mut bytes).unwrap();
+  assert_eq!(bytes, b"Hello, Ada!\n\n");
+
+  let mut full = [0u8; 4];
+  let error = renderer().render_to_writer(&mut &mut full[..]).unwrap_err();
+  assert!(error.message.starts_with("Failed to write"));
+}
+
+#[test]
+fn test_render_to_streams_blocks() {
+  use crate::render::options::FormatVersion;
+  use crate::render::post_process::PostProcessStep;
+
+  /** A sink recording each write. */
+  #[derive(Default)]
+  struct Chunks(Vec<String>);
+
+  impl std::fmt::Write for Chunks {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+      self.0.push(s.to_owned());
+      Ok(())
+    }
+  }
+
+  let doc = r#"<poml>
+  <p>First {{ name }}</p>
+  <list><item for="i in [1, 2]">{{ i }}</item></list>
+  <let name="name" value="B"/>
+  <p>Second {{ name }}</p>
+</poml>"#;
+  let renderer = |configure: &dyn Fn(&mut MarkdownPomlRenderer)| {
+    let variables = HashMap::from([("name".to_owned(), json!("A"))]);
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+    configure(&mut renderer);
+    renderer
+  };
+  let expected = renderer(&|_| {}).render().unwrap();
+  let mut chunks = Chunks::default();
+  renderer(&|_| {}).render_to(&mut chunks).unwrap();
+  assert_eq!(chunks.0.concat(), expected);
+  assert!(chunks.0.iter().filter(|c| !c.is_empty()).count() >= 3);
+
+  // Output formatted as a whole is written at once
+  let configurations: [&dyn Fn(&mut MarkdownPomlRenderer); 2] =
+    [&|r| r.options.format_version = FormatVersion::V2, &|r| {
+      r.options.post_process = vec![PostProcessStep::TrimTrailingWhitespace]
+    }];
+  for configure in configurations {
+    let expected = renderer(configure).render().unwrap();
+    let mut chunks = Chunks::default();
+    renderer(configure).render_to(&mut chunks).unwrap();
+    assert_eq!(chunks.0, vec![expected]);
+  }
+
+  // Render errors are reported like `render`, after the blocks before them are written
+  let doc = "<poml><p>Fine</p><p>{{ 1 / 0 }}</p></poml>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  let mut chunks = Chunks::default();
+  let err = renderer.render_to(&mut chunks).unwrap_err();
+  assert_eq!(err.code(), ErrorCode::DivisionByZero);
+  assert_eq!(chunks.0, vec!["Fine\n\n"]);
+}
+
+#[test]
+fn test_render_deeply_nested_tags() {
+  use tag_renderer::{TagMiddleware, TagRendererExt};
+
+  /** Middleware doing nothing, to render the tags by `render_tag_in_state` of Markdown. */
+  #[derive(Clone)]
+  struct NoMiddleware {}
+  impl TagMiddleware for NoMiddleware {}
+
+  let depth = 40;
+  let mut doc = String::from("<poml>");
+  for i in 0..depth {
+    doc += &format!("<section><h>Level {i}</h><p><b>{{{{ name }}}}</b> <i>{i}</i></p>");
+  }
+  doc += &"</section>".repeat(depth);
+  doc += "<list><item>a</item><item><b>b</b></item></list></poml>";
+  let context =
+    || render_context::RenderContext::from(Map::from_iter([("name".to_owned(), json!("Ada"))]));
+  let output = Renderer::new(&doc, context(), MarkdownTagRenderer::default())
+    .render()
+    .unwrap();
+  assert!(output.starts_with("## Level 0\n\n**Ada** *0*\n\n### Level 1\n\n"));
+  assert!(output.ends_with("###### Level 39\n\n**Ada** *39*\n\n- a\n- **b**\n\n"));
+
+  let tag_renderer = MarkdownTagRenderer::default().with_middleware(NoMiddleware {});
+  let expected = Renderer::new(&doc, context(), tag_renderer)
+    .render()
+    .unwrap();
+  assert_eq!(output, expected);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_render_parallel() {
+  let render = |doc: &str, parallel: bool| {
+    let variables = HashMap::from([("name".to_owned(), json!("Ada"))]);
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+    renderer
+      .context
+      .set_file_loader(InMemoryLoader::from_iter([(
+        "footer.poml",
+        "<p>Bye, {{ name }}</p>",
+      )]));
+    match parallel {
+      true => renderer.render_parallel(),
+      false => renderer.render(),
+    }
+  };
+  let docs = [
+    // Independent sections, numbered examples and an include
+    "<poml><section><h>Intro</h><p>Hi, {{ name }}</p></section>\
+     <example><input>1</input><output>2</output></example>\
+     <example if=\"true\"><input>3</input><output>4</output></example>\
+     <list><item for=\"i in [1, 2, 3]\">{{ i }}</item></list>\
+     <include src=\"footer.poml\" /></poml>",
+    // The top-level variable is seen by the later siblings
+    "<poml><let name=\"greeting\" value=\"'Hello'\" /><p>{{ greeting }}</p><p>{{ name }}</p></poml>",
+    // The conditional example decides the number of the later one
+    "<poml><example if=\"false\"><input>1</input></example><example><input>2</input></example></poml>",
+  ];
+  for doc in docs {
+    assert_eq!(render(doc, true).unwrap(), render(doc, false).unwrap());
+  }
+
+  let doc = "<poml><p>{{ name }}</p><p>{{ 1 + }}</p><p>{{ 2 + }}</p></poml>";
+  let error = render(doc, true).unwrap_err();
+  assert_eq!(
+    error.to_string(),
+    render(doc, false).unwrap_err().to_string()
+  );
+}
+
+#[test]
+fn test_parse_before_render() {
+  let doc = "<poml><p>Hello, {{ name }}!</p></poml>";
+  let variables = HashMap::from([("name".to_owned(), json!("Ada"))]);
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables);
+  renderer.parse().unwrap();
+  assert_eq!(renderer.render().unwrap(), "Hello, Ada!\n\n");
+  assert_eq!(renderer.render().unwrap(), "Hello, Ada!\n\n");
+
+  let mut renderer =
+    MarkdownPomlRenderer::create_from_doc_and_variables("<poml><p>Hi</poml>", HashMap::new());
+  let error = renderer.parse().unwrap_err();
+  assert!(error.message.starts_with("Error in render file"));
+}
+
+#[test]
+fn test_render_unicode_identifiers() {
+  let doc = "<poml><let name=\"名前\" value=\"'太郎'\" />\
+    <p for=\"größe in [1, 2]\">{{ 名前 }}さん {{ größe }} {{ user.prénom }}</p></poml>";
+  let variables = HashMap::from([("user".to_owned(), json!({"prénom": "Zoë"}))]);
+  let output = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables)
+    .render()
+    .unwrap();
+  assert_eq!(output, "太郎さん 1 Zoë\n\n太郎さん 2 Zoë\n\n");
+}
+
+#[test]
+fn test_render_limits() {
+  use crate::error::ErrorCode;
+  use crate::render::options::RenderLimits;
+
+  let render = |doc: &str, limits: RenderLimits, items: usize| {
+    let items = json!((0..items).collect::<Vec<_>>());
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(
+      doc,
+      HashMap::from([("items".to_owned(), items)]),
+    );
+    renderer.options.limits = limits;
+    renderer.context.set_file_loader(InMemoryLoader::from_iter([
+      ("self.poml", "<poml><include src=\"self.poml\"/></poml>"),
+      ("a.poml", "<p>A</p>"),
+    ]));
+    renderer.render()
+  };
+  let limits = RenderLimits::default();
+
+  // Deeply nested tags fail to parse, instead of overflowing the stack
+  let nested = format!(
+    "<poml>{}x{}</poml>",
+    "<p>".repeat(10_000),
+    "</p>".repeat(10_000)
+  );
+  let err = render(&nested, limits, 0).unwrap_err();
+  assert_eq!(err.code(), ErrorCode::LimitExceeded);
+  let nested = format!("<poml>{}x{}</poml>", "<b>".repeat(10), "</b>".repeat(10));
+  assert!(render(&nested, limits, 0).is_ok());
+  let shallow = RenderLimits {
+    max_depth: 5,
+    ..limits
+  };
+  let err = render(&nested, shallow, 0).unwrap_err();
+  assert_eq!(err.code(), ErrorCode::LimitExceeded);
+
+  // Documents including themselves are stopped by the depth of the included tags
+  let err = render("<include src=\"self.poml\"/>", limits, 0).unwrap_err();
+  assert_eq!(err.code(), ErrorCode::LimitExceeded);
+
+  let doc = "<poml><p for=\"i in items\">{{ i }}</p></poml>";
+  assert!(render(doc, limits, 100).is_ok());
+  let few_iterations = RenderLimits {
+    max_loop_iterations: 99,
+    ..limits
+  };
+  let err = render(doc, few_iterations, 100).unwrap_err();
+  assert_eq!(err.code(), ErrorCode::LimitExceeded);
+  assert!(format!("{err:?}").contains("limit of 99 loop iterations"));
+  let small_output = RenderLimits {
+    max_output_size: 100,
+    ..limits
+  };
+  let err = render(doc, small_output, 100).unwrap_err();
+  assert_eq!(err.code(), ErrorCode::LimitExceeded);
+
+  let doc = "<poml><include for=\"i in items\" src=\"a.poml\"/></poml>";
+  assert!(render(doc, limits, 3).is_ok());
+  let few_includes = RenderLimits {
+    max_includes: 2,
+    ..limits
+  };
+  let err = render(doc, few_includes, 3).unwrap_err();
+  assert_eq!(err.code(), ErrorCode::LimitExceeded);
+}
+
+#[test]
+fn test_render_state_restored_after_errors() {
+  // A raw tag failing the depth limit doesn't leave the later renders raw
+  let doc = "<poml><p><pre>x</pre></p><p>{{ 1 + 1 }}</p></poml>";
+  let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+  renderer.parse().unwrap();
+  renderer.options.limits.max_depth = 2;
+  let err = renderer.render().unwrap_err();
+  assert_eq!(err.code(), ErrorCode::LimitExceeded);
+  renderer.options.limits.max_depth = crate::render::options::RenderLimits::default().max_depth;
+  assert!(renderer.render().unwrap().contains('2'));
+
+  // A loop failing in an iteration doesn't leave its variables in the context
+  for doc in [
+    r#"<poml><p for="item in [1, 0]">{{ 1 / item }}</p></poml>"#,
+    r#"<poml><p for="item in [1, 0]" if="1 / item">x</p></poml>"#,
+  ] {
+    let mut renderer = MarkdownPomlRenderer::create_from_doc_and_variables(doc, HashMap::new());
+    assert!(renderer.render().is_err(), "{doc}");
+    assert!(renderer.context.get_value("item").is_none(), "{doc}");
+    assert!(renderer.context.get_value("loop").is_none(), "{doc}");
+  }
+}
+
+#[test]
+fn test_render_boolean_shorthand_attributes() {
+  let doc = r#"<poml><code inline raw>{{ name }}</code><p if="flag">Shown</p></poml>"#;
+  let expected =
+    r#"<poml><code inline="true" raw="true">{{ name }}</code><p if="flag">Shown</p></poml>"#;
+  let render = |doc: &str| {
+    let variables = HashMap::from([("flag".to_owned(), json!(true))]);
+    MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables)
+      .render()
+      .unwrap()
+  };
+  assert_eq!(render(doc), render(expected));
+  assert!(render(doc).contains("`{{ name }}`"));
+}
+
+#[test]
+fn test_render_unquoted_expression_attributes() {
+  let doc = r#"<poml>
+<let name="shown" value={{ count > 1 }} />
+<p if={{ shown }}>Shown</p>
+<p if={{ count < 1 }}>Hidden</p>
+<list listStyle={{ style }}><item>{{ count }}</item></list>
+</poml>"#;
+  let variables = HashMap::from([
+    ("count".to_owned(), json!(2)),
+    ("style".to_owned(), json!("decimal")),
+  ]);
+  let output = MarkdownPomlRenderer::create_from_doc_and_variables(doc, variables)
+    .render()
+    .unwrap();
+  assert_eq!(output, "Shown\n\n1. 2\n\n");
+}
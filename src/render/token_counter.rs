@@ -0,0 +1,87 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/**
+ * Count the tokens of a text for budget related features like the `tokenLimit` attribute.
+ *
+ * Implement this trait to plug in the tokenizer of the target model.
+ */
+pub trait TokenCounter: Send + Sync {
+  /**
+   * Count the number of tokens in the text.
+   */
+  fn count_tokens(&self, text: &str) -> usize;
+
+  /**
+   * Return the longest prefix of `text` that fits in `max_tokens` tokens.
+   *
+   * The default implementation binary searches over char boundaries with `count_tokens`,
+   * and backs off to the previous whitespace to avoid cutting a word.
+   */
+  fn truncate<'t>(&self, text: &'t str, max_tokens: usize) -> &'t str {
+    if self.count_tokens(text) <= max_tokens {
+      return text;
+    }
+    // `boundaries[k]` is the end offset of the prefix with `k` chars.
+    let mut boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    boundaries.push(text.len());
+    let mut l = 0;
+    let mut r = boundaries.len() - 1;
+    while l < r {
+      let mid = (l + r).div_ceil(2);
+      if self.count_tokens(&text[..boundaries[mid]]) <= max_tokens {
+        l = mid;
+      } else {
+        r = mid - 1;
+      }
+    }
+    let prefix = &text[..boundaries[l]];
+    // Avoid cutting in the middle of a word when possible.
+    let cut_in_word = !text[prefix.len()..].starts_with(char::is_whitespace);
+    match prefix.rfind(char::is_whitespace) {
+      Some(pos) if cut_in_word => prefix[..pos].trim_end(),
+      _ => prefix,
+    }
+  }
+}
+
+/**
+ * The default token counter, which estimates one token for every four characters
+ * but never fewer tokens than whitespace separated words.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct HeuristicTokenCounter {}
+
+impl TokenCounter for HeuristicTokenCounter {
+  fn count_tokens(&self, text: &str) -> usize {
+    let chars = text.chars().count();
+    let words = text.split_whitespace().count();
+    chars.div_ceil(4).max(words)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_heuristic_token_counter() {
+    let counter = HeuristicTokenCounter {};
+    assert_eq!(counter.count_tokens(""), 0);
+    assert_eq!(counter.count_tokens("abcdefgh"), 2);
+    assert_eq!(counter.count_tokens("a b c d e"), 5);
+  }
+
+  #[test]
+  fn test_truncate() {
+    let counter = HeuristicTokenCounter {};
+    let text = "one two three four five six";
+    let truncated = counter.truncate(text, 3);
+    assert!(counter.count_tokens(truncated) <= 3);
+    assert!(text.starts_with(truncated));
+    assert_eq!(counter.truncate("short", 10), "short");
+  }
+}
@@ -0,0 +1,142 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+/*!
+ * Trace of a render explaining how the output came out, recorded when
+ * `RenderOptions::trace` is enabled, e.g. to find out why a section is missing.
+ */
+
+use crate::error::ErrorPosition;
+use serde_json::{Value, json};
+
+/**
+ * What happened in a step of the render.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEventKind {
+  /**
+   * A tag is rendered, with the length of its output in bytes and its `data-*` metadata
+   * attributes, named without the `data-` prefix.
+   */
+  Tag {
+    name: String,
+    output_length: usize,
+    data: Vec<(String, Value)>,
+  },
+  /** The `if` or `ifPresent` condition of a tag is evaluated. */
+  Condition {
+    tag: String,
+    expression: String,
+    value: bool,
+  },
+  /** The range of a `for` loop is evaluated, with the number of its items. */
+  Loop {
+    tag: String,
+    expression: String,
+    iterations: usize,
+  },
+  /**
+   * A file is loaded by `<include>`, or skipped since a capability of its `requires`
+   * attribute is missing.
+   */
+  Include { src: String, skipped: bool },
+  /** An expression is evaluated to the value. */
+  Expression { expression: String, value: Value },
+}
+
+/**
+ * A step of the render, in the order they happen.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+  pub kind: TraceEventKind,
+  /** Name of the rendered file, which differs from the main file in included files. */
+  pub file: String,
+  /** Where the step is in the file, if known. */
+  pub position: Option<ErrorPosition>,
+}
+
+impl TraceEvent {
+  pub fn to_json(&self) -> Value {
+    let mut event = match &self.kind {
+      TraceEventKind::Tag {
+        name,
+        output_length,
+        data,
+      } => {
+        let data: serde_json::Map<String, Value> = data.iter().cloned().collect();
+        json!({"event": "tag", "tag": name, "outputLength": output_length, "data": data})
+      }
+      TraceEventKind::Condition {
+        tag,
+        expression,
+        value,
+      } => json!({"event": "condition", "tag": tag, "expression": expression, "value": value}),
+      TraceEventKind::Loop {
+        tag,
+        expression,
+        iterations,
+      } => json!({"event": "loop", "tag": tag, "expression": expression, "iterations": iterations}),
+      TraceEventKind::Include { src, skipped } => {
+        json!({"event": "include", "src": src, "skipped": skipped})
+      }
+      TraceEventKind::Expression { expression, value } => {
+        json!({"event": "expression", "expression": expression, "value": value})
+      }
+    };
+    event["file"] = json!(self.file);
+    event["line"] = json!(self.position.as_ref().map(|p| p.line));
+    event
+  }
+}
+
+/**
+ * A line explaining the step, like `prompt.poml:3: <p if="ready"> is false`.
+ */
+impl std::fmt::Display for TraceEvent {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.position {
+      Some(position) => write!(f, "{}:{}: ", self.file, position.line)?,
+      None => write!(f, "{}: ", self.file)?,
+    }
+    match &self.kind {
+      TraceEventKind::Tag {
+        name,
+        output_length,
+        data,
+      } => {
+        write!(f, "<{name}")?;
+        for (key, value) in data {
+          write!(f, " data-{key}={value}")?;
+        }
+        write!(f, "> rendered {output_length} bytes")
+      }
+      TraceEventKind::Condition {
+        tag,
+        expression,
+        value,
+      } => write!(f, "<{tag}> condition `{expression}` is {value}"),
+      TraceEventKind::Loop {
+        tag,
+        expression,
+        iterations,
+      } => write!(f, "<{tag}> loop `{expression}` ran {iterations} iterations"),
+      TraceEventKind::Include {
+        src,
+        skipped: false,
+      } => write!(f, "included {src}"),
+      TraceEventKind::Include { src, skipped: true } => {
+        write!(
+          f,
+          "skipped including {src} without the required capabilities"
+        )
+      }
+      TraceEventKind::Expression { expression, value } => {
+        write!(f, "`{}` = {value}", expression.trim())
+      }
+    }
+  }
+}
@@ -3,11 +3,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
-
-pub fn is_false_value(value: &str) -> bool {
-  let val = value.trim();
-  matches!(val, "0" | "false" | "" | "null" | "NaN")
-}
+use std::borrow::Cow;
 
 pub fn buf_match_str(buf: &[u8], pos: usize, pattern: &str) -> bool {
   if pos + pattern.len() > buf.len() {
@@ -21,3 +17,221 @@ pub fn buf_match_str(buf: &[u8], pos: usize, pattern: &str) -> bool {
   }
   true
 }
+
+/**
+ * Named XML/HTML character entities supported in text and attribute values.
+ */
+const NAMED_ENTITIES: &[(&str, char)] = &[
+  ("lt", '<'),
+  ("gt", '>'),
+  ("amp", '&'),
+  ("quot", '"'),
+  ("apos", '\''),
+  ("nbsp", '\u{a0}'),
+  ("copy", '©'),
+  ("reg", '®'),
+  ("trade", '™'),
+  ("hellip", '…'),
+  ("mdash", '—'),
+  ("ndash", '–'),
+  ("lsquo", '‘'),
+  ("rsquo", '’'),
+  ("ldquo", '“'),
+  ("rdquo", '”'),
+  ("laquo", '«'),
+  ("raquo", '»'),
+  ("bull", '•'),
+  ("middot", '·'),
+  ("times", '×'),
+  ("divide", '÷'),
+  ("plusmn", '±'),
+  ("deg", '°'),
+  ("para", '¶'),
+  ("sect", '§'),
+  ("cent", '¢'),
+  ("pound", '£'),
+  ("yen", '¥'),
+  ("euro", '€'),
+  ("larr", '←'),
+  ("rarr", '→'),
+  ("uarr", '↑'),
+  ("darr", '↓'),
+  ("harr", '↔'),
+  ("le", '≤'),
+  ("ge", '≥'),
+  ("ne", '≠'),
+  ("infin", '∞'),
+];
+
+/**
+ * Decode the XML/HTML character reference starting with `&` at `pos`, like `&lt;`,
+ * `&#39;` or `&#x7B;`.
+ *
+ * Return the decoded char and the position after the ending `;`, or `None` if it is
+ * not a valid character reference.
+ */
+pub fn decode_xml_entity(buf: &[u8], pos: usize) -> Option<(char, usize)> {
+  // The longest supported reference is `&#x10FFFF;`.
+  let end = pos + buf[pos..].iter().take(12).position(|c| *c == b';')?;
+  let name = str::from_utf8(&buf[pos + 1..end]).ok()?;
+  let c = if let Some(hex) = name.strip_prefix("#x").or(name.strip_prefix("#X")) {
+    char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+  } else if let Some(dec) = name.strip_prefix('#') {
+    char::from_u32(dec.parse::<u32>().ok()?)?
+  } else {
+    NAMED_ENTITIES.iter().find(|(n, _)| *n == name)?.1
+  };
+  Some((c, end + 1))
+}
+
+/**
+ * Decode the XML/HTML character references in the text, like the `&lt;` of
+ * `if="a &lt; b"`. Text without references is returned as is.
+ */
+pub fn decode_xml_entities(text: &str) -> Cow<'_, str> {
+  if !text.contains('&') {
+    return Cow::Borrowed(text);
+  }
+  let p = text.as_bytes();
+  let mut answer = String::with_capacity(text.len());
+  let mut rest_start = 0;
+  let mut pos = 0;
+  while let Some(offset) = text[pos..].find('&') {
+    pos += offset;
+    match decode_xml_entity(p, pos) {
+      Some((c, next_pos)) => {
+        answer += &text[rest_start..pos];
+        answer.push(c);
+        pos = next_pos;
+        rest_start = pos;
+      }
+      None => pos += 1,
+    }
+  }
+  answer += &text[rest_start..];
+  Cow::Owned(answer)
+}
+
+/**
+ * Normalize the output for [`FormatVersion::V2`](super::options::FormatVersion::V2).
+ * CRLF line endings of the output are kept.
+ */
+pub fn normalize_output_v2(output: &str) -> String {
+  let line_ending = if output.contains("\r\n") {
+    "\r\n"
+  } else {
+    "\n"
+  };
+  let mut answer = String::with_capacity(output.len());
+  let mut blank_lines = 0;
+  for line in output.trim().lines() {
+    let line = line.trim_end();
+    if line.is_empty() {
+      blank_lines += 1;
+      if blank_lines > 1 {
+        continue;
+      }
+    } else {
+      blank_lines = 0;
+    }
+    answer += line;
+    answer += line_ending;
+  }
+  answer
+}
+
+/**
+ * Heuristic to detect whether a text looks like code: it contains a Markdown code fence,
+ * or it has at least two lines and a third of them look like code.
+ */
+pub fn looks_like_code(text: &str) -> bool {
+  if text.contains("```") || text.contains("~~~") {
+    return true;
+  }
+  let lines: Vec<&str> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+  if lines.len() < 2 {
+    return false;
+  }
+  let code_keywords = [
+    "def ",
+    "fn ",
+    "function ",
+    "class ",
+    "import ",
+    "#include",
+    "return ",
+    "let ",
+    "const ",
+  ];
+  let code_lines = lines
+    .iter()
+    .filter(|l| {
+      let trimmed = l.trim();
+      trimmed.ends_with(';')
+        || trimmed.ends_with('{')
+        || trimmed.ends_with('}')
+        || trimmed.ends_with(':') && l.starts_with(char::is_whitespace)
+        || l.starts_with("    ")
+        || l.starts_with('\t')
+        || code_keywords.iter().any(|k| trimmed.starts_with(k))
+    })
+    .count();
+  code_lines * 3 >= lines.len()
+}
+
+/**
+ * Wrap the text in a code fence longer than any backtick run inside it.
+ */
+pub fn fence_code(text: &str) -> String {
+  let mut longest_run = 0;
+  let mut run = 0;
+  for c in text.chars() {
+    if c == '`' {
+      run += 1;
+      longest_run = longest_run.max(run);
+    } else {
+      run = 0;
+    }
+  }
+  let fence = "`".repeat((longest_run + 1).max(3));
+  format!("\n{fence}\n{}\n{fence}\n", text.trim_end_matches('\n'))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_looks_like_code() {
+    assert!(looks_like_code("fn main() {\n    println!(\"hi\");\n}"));
+    assert!(looks_like_code("Run ```ls```"));
+    assert!(!looks_like_code("Hello world.\nHow are you?"));
+    assert!(!looks_like_code("x = 1;"));
+  }
+
+  #[test]
+  fn test_fence_code() {
+    assert_eq!(fence_code("a\n"), "\n```\na\n```\n");
+    assert_eq!(fence_code("```\na\n```"), "\n````\n```\na\n```\n````\n");
+  }
+
+  #[test]
+  fn test_normalize_output_v2() {
+    assert_eq!(normalize_output_v2("\n a  \n\n\n\nb\n\n"), "a\n\nb\n");
+    assert_eq!(normalize_output_v2(""), "");
+    assert_eq!(normalize_output_v2("a\r\n\r\n\r\nb\r\n"), "a\r\n\r\nb\r\n");
+  }
+
+  #[test]
+  fn test_decode_xml_entity() {
+    assert_eq!(decode_xml_entity(b"&lt;", 0), Some(('<', 4)));
+    assert_eq!(decode_xml_entity(b"a&#39;b", 1), Some(('\'', 6)));
+    assert_eq!(decode_xml_entity(b"&#x7B;", 0), Some(('{', 6)));
+    assert_eq!(decode_xml_entity(b"&unknown;", 0), None);
+    assert_eq!(decode_xml_entity(b"& lt;", 0), None);
+    assert_eq!(decode_xml_entity(b"&lt", 0), None);
+    assert_eq!(decode_xml_entities("a &lt; b &amp;&amp; c"), "a < b && c");
+    assert_eq!(decode_xml_entities("&quot;A&amp B&quot;"), "\"A&amp B\"");
+    assert!(matches!(decode_xml_entities("a < b"), Cow::Borrowed(_)));
+  }
+}